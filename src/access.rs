@@ -0,0 +1,177 @@
+//! A simple capability model for the surfaces which can call into the Worker.
+//!
+//! The UI gets every capability. Other surfaces (like a dashboard or hook commands)
+//! get only the capabilities they were configured with.
+
+use bitflags::bitflags;
+use clap::ValueEnum;
+use displaydoc::Display;
+
+bitflags! {
+    /// The set of operations a caller of the Worker is allowed to perform
+    #[derive(Default)]
+    pub struct AccessPolicy: u8 {
+        /// Read the balances of the account
+        const READ_BALANCES = 1 << 0;
+        /// Read the quote books, and the offers we submitted
+        const READ_BOOK = 1 << 1;
+        /// Cancel offers we submitted
+        const CANCEL_OFFERS = 1 << 2;
+        /// Send payments
+        const SEND = 1 << 3;
+        /// Fill quotes from the quote book
+        const SWAP = 1 << 4;
+        /// Submit offers to the deqs
+        const OFFER = 1 << 5;
+    }
+}
+
+/// A single permission, as named on the command line
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Permission {
+    /// Read the balances of the account
+    ReadBalances,
+    /// Read the quote books, and the offers we submitted
+    ReadBook,
+    /// Cancel offers we submitted
+    CancelOffers,
+    /// Send payments
+    Send,
+    /// Fill quotes from the quote book
+    Swap,
+    /// Submit offers to the deqs
+    Offer,
+}
+
+impl From<Permission> for AccessPolicy {
+    fn from(src: Permission) -> AccessPolicy {
+        match src {
+            Permission::ReadBalances => AccessPolicy::READ_BALANCES,
+            Permission::ReadBook => AccessPolicy::READ_BOOK,
+            Permission::CancelOffers => AccessPolicy::CANCEL_OFFERS,
+            Permission::Send => AccessPolicy::SEND,
+            Permission::Swap => AccessPolicy::SWAP,
+            Permission::Offer => AccessPolicy::OFFER,
+        }
+    }
+}
+
+impl AccessPolicy {
+    /// Combine a list of permissions into a policy
+    pub fn from_permissions(permissions: &[Permission]) -> Self {
+        permissions
+            .iter()
+            .fold(Self::empty(), |policy, permission| {
+                policy | Self::from(*permission)
+            })
+    }
+}
+
+/// Proof of what the caller of a Worker method is allowed to do.
+///
+/// Each external surface holds one of these, and passes it to every Worker method it calls.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccessToken {
+    /// The operations this caller may perform
+    policy: AccessPolicy,
+}
+
+impl AccessToken {
+    /// The token for the UI, which may do everything
+    pub fn ui() -> Self {
+        Self {
+            policy: AccessPolicy::all(),
+        }
+    }
+
+    /// The token for the dashboard, which may do what it was configured to do
+    pub fn dashboard(permissions: &[Permission]) -> Self {
+        Self {
+            policy: AccessPolicy::from_permissions(permissions),
+        }
+    }
+
+    /// The token for hook commands, which may not do anything
+    pub fn hooks() -> Self {
+        Self {
+            policy: AccessPolicy::empty(),
+        }
+    }
+
+    /// The operations this caller may perform
+    pub fn policy(&self) -> AccessPolicy {
+        self.policy
+    }
+
+    /// Check that this caller may perform all of the given operations
+    pub fn require(&self, required: AccessPolicy) -> Result<(), AccessDenied> {
+        if self.policy.contains(required) {
+            Ok(())
+        } else {
+            Err(AccessDenied(required - self.policy))
+        }
+    }
+}
+
+/// The caller is not permitted to perform {0:?}
+#[derive(Clone, Debug, Display, Eq, PartialEq)]
+pub struct AccessDenied(pub AccessPolicy);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_ui_may_do_everything_and_hooks_nothing() {
+        assert_eq!(AccessToken::ui().require(AccessPolicy::all()), Ok(()));
+        assert_eq!(
+            AccessToken::hooks().require(AccessPolicy::READ_BALANCES),
+            Err(AccessDenied(AccessPolicy::READ_BALANCES))
+        );
+    }
+
+    #[test]
+    fn a_dashboard_without_send_can_never_send() {
+        let everything_else = [
+            Permission::ReadBalances,
+            Permission::ReadBook,
+            Permission::CancelOffers,
+            Permission::Swap,
+            Permission::Offer,
+        ];
+        let token = AccessToken::dashboard(&everything_else);
+        assert_eq!(
+            token.require(AccessPolicy::SEND),
+            Err(AccessDenied(AccessPolicy::SEND))
+        );
+        // Nor by asking for send along with what it may do
+        assert_eq!(
+            token.require(AccessPolicy::SEND | AccessPolicy::READ_BALANCES),
+            Err(AccessDenied(AccessPolicy::SEND))
+        );
+        assert_eq!(token.require(AccessPolicy::CANCEL_OFFERS), Ok(()));
+    }
+
+    #[test]
+    fn cancelling_offers_doesnt_allow_making_them() {
+        let token = AccessToken::dashboard(&[Permission::CancelOffers]);
+        assert_eq!(token.require(AccessPolicy::CANCEL_OFFERS), Ok(()));
+        assert_eq!(
+            token.require(AccessPolicy::OFFER),
+            Err(AccessDenied(AccessPolicy::OFFER))
+        );
+    }
+
+    #[test]
+    fn permissions_combine_into_a_policy() {
+        assert_eq!(AccessPolicy::from_permissions(&[]), AccessPolicy::empty());
+        assert_eq!(
+            AccessPolicy::from_permissions(&[
+                Permission::ReadBook,
+                Permission::Send,
+                Permission::ReadBook
+            ]),
+            AccessPolicy::READ_BOOK | AccessPolicy::SEND
+        );
+    }
+}
@@ -1,24 +1,122 @@
-use crate::{Amount, Config, QuoteSelection, TokenId, TokenInfo, Worker};
+use crate::{
+    aggregate_book, app_state, decrypt_keyfile, empty_state, fiat_value, format_fiat,
+    generate_mnemonic, image_export, is_mnemonic_word, parse_mnemonic, preflight, render_qr,
+    sci_debug_view, write_keyfile, AccessToken, ActivityLevel, Amount, CommandError, CommandId,
+    CommandInfo, CommandStatus, Config, ConnectionHealth, DepthLevel, Direction, EmptyStateKind,
+    FillMode, GiftCode, HealthStatus, HeldOffer, HistoryFilter, InitStatus, KeyfileError,
+    NetworkKind, OfferStatus, Payment, PendingTransaction, PreconditionFailed, PriceSample,
+    QrTexture, QuoteBook, QuoteFilter, QuoteInfo, QuoteSelection, QuoteSide, ReceiverStatus,
+    ScaledAmount, Severity, TokenId, TokenInfo, TrackedOffer, TwoSidedQuote, TxOptions, UtxoInfo,
+    Worker, WorkerCommand, WorkerInitError, MNEMONIC_WORDS, SCHEMA_VERSION, STATE_FILE, STATE_KEY,
+};
+use bip39::Mnemonic;
+use egui::plot::{Line, Plot, PlotPoints, Points};
 use egui::{
-    Align, Button, CentralPanel, Color32, ComboBox, Grid, Layout, RichText, ScrollArea,
-    TopBottomPanel,
+    Align, Button, CentralPanel, Color32, ComboBox, Grid, Key, Layout, ProgressBar, RichText,
+    ScrollArea, Slider, TextEdit, TopBottomPanel, Window,
 };
+use mc_crypto_ring_signature::KeyImage;
+use mc_mobilecoind_api::TxStatus;
+use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::{event, Level};
+use zeroize::{Zeroize, Zeroizing};
 
-/// The three panels the app can show
+/// The panels the app can show
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 enum Mode {
     #[default]
     Assets,
     Send,
+    Receive,
+    History,
     Swap,
     OfferSwap,
+    Settings,
+}
+
+/// The color theme the user chose
+#[derive(Clone, Copy, Default, Debug, Eq, PartialEq, Serialize, Deserialize)]
+enum ThemePreference {
+    Dark,
+    Light,
+    /// Whichever theme the OS uses, or dark if it doesn't say
+    #[default]
+    FollowSystem,
+}
+
+impl ThemePreference {
+    /// The theme to draw with, given the one the OS reports, if any
+    fn resolve(self, system_theme: Option<eframe::Theme>) -> eframe::Theme {
+        match self {
+            Self::Dark => eframe::Theme::Dark,
+            Self::Light => eframe::Theme::Light,
+            Self::FollowSystem => system_theme.unwrap_or(eframe::Theme::Dark),
+        }
+    }
+}
+
+/// The base and counter token ids, the generations of the two books, the filter,
+/// and the tick size, which the rows of the book tables were computed from
+type BookRowsKey = (TokenId, TokenId, [u64; 2], QuoteFilter, Decimal);
+
+/// The rows of the quote book tables in Offer Swap, which are only recomputed when
+/// the books change
+struct BookRows {
+    /// What the rows were computed from
+    key: BookRowsKey,
+    /// The deqs each quote came from, and its info, for the quotes of each book
+    /// which can be shown
+    rows: [Vec<(usize, QuoteInfo)>; 2],
+    /// The price levels of those quotes, or why they couldn't be aggregated
+    levels: [Result<Vec<DepthLevel>, String>; 2],
+}
+
+impl BookRows {
+    fn new(
+        key: BookRowsKey,
+        books: &[QuoteBook; 2],
+        token_infos: &[TokenInfo],
+        mid_price: Option<Decimal>,
+    ) -> Self {
+        let (base_token_id, counter_token_id, _, filter, tick) = key;
+        let rows = books.clone().map(|book| {
+            book.iter()
+                .filter_map(|quote| {
+                    match quote.get_quote_info(base_token_id, counter_token_id, token_infos) {
+                        Ok(info) => Some((quote.id.source, info)),
+                        Err(err) => {
+                            event!(Level::ERROR, "get quote info: {}", err);
+                            None
+                        }
+                    }
+                })
+                .filter(|(_, info)| filter.accepts(info.price, mid_price))
+                .collect::<Vec<_>>()
+        });
+        let levels = [0, 1].map(|idx| {
+            let infos: Vec<QuoteInfo> = rows[idx].iter().map(|(_, info)| info.clone()).collect();
+            aggregate_book(&infos, tick)
+        });
+        Self { key, rows, levels }
+    }
+}
+
+/// Which of the amounts in the swap panel the user entered, so that quote
+/// selection computes the other one
+#[derive(Clone, Copy, Default, Debug, Eq, PartialEq)]
+enum SwapInput {
+    /// The user entered the amount to get
+    #[default]
+    To,
+    /// The user entered the amount to pay
+    From,
 }
 
 /// The App implements eframe::App and is called frequently to redraw the state,
@@ -26,14 +124,22 @@ enum Mode {
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 pub struct App {
+    /// The schema version of the state, see app_state
+    schema_version: u32,
     /// Which panel we are rendering right now
     mode: Mode,
     /// Which token we most recently selected to send
     send_token_id: TokenId,
     /// Which quantity we most recently selected to send (per token id)
     send_value: HashMap<TokenId, String>,
+    /// Which subaddress we most recently selected to send from
+    send_subaddress: u64,
+    /// How many blocks past the current height we most recently allowed a payment to land in
+    send_tombstone_offset: u64,
     /// Which public address we most recently selected to send to
     send_to: String,
+    /// Which memo (payment id) we most recently used (per recipient)
+    send_memo: HashMap<String, String>,
     /// Which token we most recently selected to swap from
     swap_from_token_id: TokenId,
     /// Which token value we most recently selected to swap from (per swap_from_token_id)
@@ -42,6 +148,59 @@ pub struct App {
     swap_to_token_id: TokenId,
     /// Which token value we most recently selected to swap for (per swap_to_token_id)
     swap_to_value: HashMap<TokenId, String>,
+    /// The largest move of the cost of a swap to accept, in percent
+    swap_max_slippage: String,
+    /// Whether a swap may fill our own quotes
+    swap_include_mine: bool,
+    /// Which token we most recently selected to pay the fee of a swap in, if not the
+    /// token we swap from
+    swap_fee_token_id: Option<TokenId>,
+    /// How close to the mid-price quotes must be to show them in Offer Swap, in
+    /// percent, or blank to show them all
+    book_price_band: String,
+    /// The tick size to bucket the prices of the Offer Swap books by, or blank for
+    /// exact prices
+    book_tick: String,
+    /// The price levels of the Offer Swap books which are expanded to show their
+    /// quotes, by side
+    #[serde(skip)]
+    expanded_levels: HashSet<(usize, Decimal)>,
+    /// The rows of the book tables in Offer Swap, for the books they were computed from
+    #[serde(skip)]
+    book_rows: Option<BookRows>,
+    /// Which of the swap values the user most recently edited
+    #[serde(skip)]
+    swap_input: SwapInput,
+    /// The from and to values of the swap the user clicked Submit for, and is asked
+    /// to confirm
+    #[serde(skip)]
+    swap_confirming: Option<(u64, u64)>,
+    /// Which token we most recently selected to request
+    receive_token_id: TokenId,
+    /// Which quantity we most recently selected to request (per token id)
+    receive_value: HashMap<TokenId, String>,
+    /// Which memo we most recently put in a payment request
+    receive_memo: String,
+    /// Which token we most recently selected to put in a gift code
+    gift_token_id: TokenId,
+    /// Which quantity we most recently selected to put in a gift code (per token id)
+    gift_value: HashMap<TokenId, String>,
+    /// Which token the history pane is filtered to, if any
+    history_token_id: Option<TokenId>,
+    /// Which direction the history pane is filtered to, if any
+    history_direction: Option<Direction>,
+    /// Where the history pane most recently exported CSV to
+    history_export_path: String,
+    /// The side of the QR codes in the receive pane, in points
+    qr_size: f32,
+    /// Where the receive pane most recently saved a QR code PNG to
+    qr_export_path: String,
+    /// The QR code of the public address
+    #[serde(skip)]
+    address_qr: QrTexture,
+    /// The QR code of the payment request
+    #[serde(skip)]
+    request_qr: QrTexture,
     /// The base token id in the offer_swap pane
     base_token_id: TokenId,
     /// The counter token id in the offer_swap pane
@@ -50,51 +209,564 @@ pub struct App {
     offer_price: String,
     /// The volume in the offer_swap pane
     offer_volume: String,
+    /// The minimum fill in the offer_swap pane, in the base token. Blank for the default.
+    offer_min_fill: String,
+    /// Whether offers are held for preview before they are submitted
+    preview_offers: bool,
+    /// Whether new offers may be filled partially
+    allow_partial_fills: bool,
+    /// Whether new offers are submitted again whenever they expire
+    keep_offers_alive: bool,
+    /// The mid-price we most recently posted both sides around
+    two_sided_mid_price: String,
+    /// The spread between the two sides we most recently posted, in percent
+    two_sided_spread: String,
+    /// The base token volume we most recently asked with, when posting both sides
+    two_sided_ask_volume: String,
+    /// The base token volume we most recently bid for, when posting both sides
+    two_sided_bid_volume: String,
+    /// Whether the assets pane breaks the balances down by subaddress
+    show_subaddress_balances: bool,
+    /// Whether the values entered in forms, like the recipient, the amounts and the
+    /// offer fields, are saved across restarts. See SENSITIVE_FIELDS.
+    persist_form_values: bool,
+    /// The pixels per point the UI is drawn with, or None for the native ones
+    ui_scale: Option<f32>,
+    /// The color theme the UI is drawn with
+    theme: ThemePreference,
+    /// The theme the OS reports, if it does
+    #[serde(skip)]
+    system_theme: Option<eframe::Theme>,
+    /// The scale being dragged on the Settings slider, until it is let go
+    #[serde(skip)]
+    ui_scale_draft: Option<f32>,
+    /// The pixels per point the OS reports for the window, if it does
+    #[serde(skip)]
+    native_scale: Option<f32>,
+    /// The pixels per point last applied to the UI
+    #[serde(skip)]
+    applied_scale: Option<f32>,
+    /// Whether the Activity drawer, listing the recent events, is open
+    #[serde(skip)]
+    show_activity: bool,
+    /// The precondition which changed between the user reviewing a command and the
+    /// worker executing it, if the most recent command was rejected for that reason
+    #[serde(skip)]
+    precondition_changed: Option<PreconditionFailed>,
+    /// The receive subaddress most recently issued in the receive pane
+    #[serde(skip)]
+    receive_subaddress: Option<String>,
+    /// The command issuing a new receive subaddress, until the worker executed it
+    #[serde(skip)]
+    receive_subaddress_command: Option<CommandId>,
+    /// The gift code being entered in the receive pane. Not saved, since it holds funds.
+    #[serde(skip)]
+    redeem_code: String,
+    /// The coins checked in the coins view, which the next payment spends
+    #[serde(skip)]
+    selected_coins: HashSet<KeyImage>,
     /// The worker is doing balance checking with mobilecoind in the background,
     /// and fetching a quotebook from deqs if available.
     #[serde(skip)]
     worker: Option<Arc<Worker>>,
+    /// Our startup parameters, to start the worker with once an encrypted keyfile
+    /// is unlocked
+    #[serde(skip)]
+    config: Option<Config>,
+    /// The passphrase prompt shown while the keyfile is encrypted, and there is no worker
+    #[serde(skip)]
+    unlock: UnlockPrompt,
+    /// The first-run screen, shown while there is no keyfile
+    #[serde(skip)]
+    first_run: Option<FirstRunPrompt>,
+    /// Why the worker could not be started, with a hint, shown instead of the panels
+    /// until starting it again succeeds
+    #[serde(skip)]
+    startup_error: Option<String>,
+    /// Debounces the window focus / visibility that we report to the worker
+    #[serde(skip)]
+    activity_debouncer: ActivityDebouncer,
+    /// Confirms actions like copying to the clipboard
+    #[serde(skip)]
+    toast: Toast,
+    /// The payments submitted from the Send panel which the worker isn't done with,
+    /// and their token ids, whose amounts are cleared once they succeed
+    #[serde(skip)]
+    sent_payments: Vec<(CommandId, TokenId)>,
+}
+
+/// The pixels per point the UI may be drawn with
+pub const UI_SCALE_RANGE: std::ops::RangeInclusive<f32> = 1.0..=4.0;
+
+/// The fields of the App which are only saved if the user opted in with
+/// persist_form_values, since they can hold things like the recipient address
+const SENSITIVE_FIELDS: &[&str] = &[
+    "send_to",
+    "send_value",
+    "send_memo",
+    "swap_from_value",
+    "swap_to_value",
+    "receive_value",
+    "receive_memo",
+    "gift_value",
+    "offer_price",
+    "offer_volume",
+    "offer_min_fill",
+    "two_sided_mid_price",
+    "two_sided_spread",
+    "two_sided_ask_volume",
+    "two_sided_bid_volume",
+];
+
+/// The state of the first-run screen
+#[derive(Default)]
+struct FirstRunPrompt {
+    /// Where the user is in setting up the account
+    step: FirstRunStep,
+    /// Why the last attempt to set up the account failed, if it did
+    error: Option<String>,
+}
+
+/// Where the user is in setting up the account on the first run
+#[derive(Default)]
+enum FirstRunStep {
+    /// Choosing between creating a new account and importing one
+    #[default]
+    Choose,
+    /// Backing up the mnemonic of a new account, until the user confirms they did
+    Backup {
+        /// The mnemonic of the new account
+        mnemonic: Mnemonic,
+        /// Whether the user confirmed they wrote it down
+        confirmed: bool,
+    },
+    /// Entering the words of the mnemonic of an existing account
+    Import {
+        /// The words entered so far
+        words: Vec<Zeroizing<String>>,
+    },
+}
+
+/// The state of the prompt for the passphrase of an encrypted keyfile
+#[derive(Default)]
+struct UnlockPrompt {
+    /// The passphrase being entered, wiped once it was tried
+    passphrase: Zeroizing<String>,
+    /// How many passphrases were wrong so far
+    failed_attempts: u32,
+    /// Why the last attempt failed, if it did
+    error: Option<String>,
+}
+
+/// Debounces the activity level observed each frame, so that rapid focus flapping
+/// doesn't make the worker keep changing its polling rate.
+#[derive(Default)]
+struct ActivityDebouncer {
+    /// The level most recently reported to the worker
+    reported: Option<ActivityLevel>,
+    /// A level different from the reported one, and when we started observing it
+    pending: Option<(ActivityLevel, Instant)>,
+}
+
+impl ActivityDebouncer {
+    /// How long a new activity level must be observed before we report it
+    const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+    /// Observe the activity level of the current frame.
+    /// Returns a level if it has settled and should be reported to the worker.
+    fn observe(&mut self, level: ActivityLevel, now: Instant) -> Option<ActivityLevel> {
+        if self.reported == Some(level) {
+            self.pending = None;
+            return None;
+        }
+        match self.pending {
+            Some((pending, since)) if pending == level => {
+                if now.duration_since(since) >= Self::SETTLE_TIME {
+                    self.reported = Some(level);
+                    self.pending = None;
+                    Some(level)
+                } else {
+                    None
+                }
+            }
+            _ => {
+                self.pending = Some((level, now));
+                None
+            }
+        }
+    }
+}
+
+/// A short message confirming an action, like copying an address, which is shown
+/// for a moment and then goes away
+#[derive(Default)]
+struct Toast {
+    /// The message, and when it stops being shown
+    shown: Option<(String, Instant)>,
+}
+
+impl Toast {
+    /// How long a message is shown
+    const DURATION: Duration = Duration::from_secs(2);
+
+    /// Show a message, replacing the current one
+    fn show(&mut self, message: impl Into<String>, now: Instant) {
+        self.shown = Some((message.into(), now + Self::DURATION));
+    }
+
+    /// The message to show in the current frame, if it hasn't expired
+    fn current(&mut self, now: Instant) -> Option<&str> {
+        if matches!(self.shown, Some((_, until)) if now >= until) {
+            self.shown = None;
+        }
+        self.shown.as_ref().map(|(message, _)| message.as_str())
+    }
 }
 
 // TokenId does not implement default so we have to do this manually
 impl Default for App {
     fn default() -> App {
         App {
+            schema_version: SCHEMA_VERSION,
             mode: Default::default(),
             send_token_id: TokenId::from(0),
             send_value: Default::default(),
             send_to: Default::default(),
+            send_memo: Default::default(),
+            send_subaddress: 0,
+            send_tombstone_offset: TxOptions::DEFAULT_TOMBSTONE_OFFSET,
             swap_from_token_id: TokenId::from(0),
+            swap_fee_token_id: None,
             swap_from_value: Default::default(),
             swap_to_token_id: TokenId::from(1),
             swap_to_value: Default::default(),
+            swap_max_slippage: (preflight::DEFAULT_MAX_SLIPPAGE * Decimal::ONE_HUNDRED)
+                .normalize()
+                .to_string(),
+            swap_include_mine: false,
+            book_price_band: Default::default(),
+            book_tick: Default::default(),
+            expanded_levels: Default::default(),
+            book_rows: None,
+            swap_input: Default::default(),
+            swap_confirming: None,
+            receive_token_id: TokenId::from(0),
+            receive_value: Default::default(),
+            receive_memo: Default::default(),
+            gift_token_id: TokenId::from(0),
+            gift_value: Default::default(),
+            history_token_id: None,
+            history_direction: None,
+            history_export_path: Config::default_data_dir()
+                .join("history.csv")
+                .display()
+                .to_string(),
+            qr_size: 200.0,
+            qr_export_path: Config::default_data_dir()
+                .join("qr.png")
+                .display()
+                .to_string(),
+            address_qr: Default::default(),
+            request_qr: Default::default(),
             base_token_id: TokenId::from(0),
             counter_token_id: TokenId::from(1),
             offer_price: Default::default(),
             offer_volume: Default::default(),
+            offer_min_fill: Default::default(),
+            preview_offers: false,
+            allow_partial_fills: true,
+            keep_offers_alive: false,
+            two_sided_mid_price: Default::default(),
+            two_sided_spread: "1".to_owned(),
+            two_sided_ask_volume: Default::default(),
+            two_sided_bid_volume: Default::default(),
+            show_subaddress_balances: false,
+            persist_form_values: false,
+            ui_scale: None,
+            theme: Default::default(),
+            system_theme: None,
+            ui_scale_draft: None,
+            native_scale: None,
+            applied_scale: None,
+            show_activity: false,
+            precondition_changed: None,
+            receive_subaddress: None,
+            receive_subaddress_command: None,
+            redeem_code: Default::default(),
+            selected_coins: Default::default(),
             worker: None,
+            config: None,
+            unlock: Default::default(),
+            first_run: None,
+            startup_error: None,
+            activity_debouncer: Default::default(),
+            toast: Default::default(),
+            sent_payments: Default::default(),
         }
     }
 }
 
 impl App {
     /// Called once before the first frame.
-    pub fn new(cc: &eframe::CreationContext<'_>, _config: Config, worker: Arc<Worker>) -> Self {
-        // This is also where you can customize the look and feel of egui using
-        // `cc.egui_ctx.set_visuals` and `cc.egui_ctx.set_fonts`.
-
-        // Load previous app state (if any).
+    ///
+    /// Without a worker, the keyfile is encrypted, and the worker is started once
+    /// the user enters its passphrase, or there is no keyfile yet, and the first-run
+    /// screen creates one. If the worker failed to start, the error is shown.
+    pub fn new(
+        cc: &eframe::CreationContext<'_>,
+        config: Config,
+        worker: Result<Option<Arc<Worker>>, WorkerInitError>,
+    ) -> Self {
+        // Load previous app state (if any), from the data dir if there is one, or else
+        // from eframe's storage, where schema version 1 was stored without a version.
         // Note that you must enable the `persistence` feature for this to work.
-        let mut result = if let Some(storage) = cc.storage {
-            eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default()
-        } else {
-            App::default()
+        let mut result: App = match config.data_dir.as_ref() {
+            Some(dir) => std::fs::read_to_string(dir.join(STATE_FILE))
+                .map(|json| app_state::restore(&json))
+                .unwrap_or_default(),
+            None => cc
+                .storage
+                .and_then(|storage| {
+                    storage
+                        .get_string(STATE_KEY)
+                        .map(|json| app_state::restore(&json))
+                        .or_else(|| eframe::get_value(storage, eframe::APP_KEY))
+                })
+                .unwrap_or_default(),
         };
+        // Export paths in the default data dir follow --data-dir
+        let defaults = App::default();
+        if result.history_export_path == defaults.history_export_path {
+            result.history_export_path =
+                config.data_dir().join("history.csv").display().to_string();
+        }
+        if result.qr_export_path == defaults.qr_export_path {
+            result.qr_export_path = config.data_dir().join("qr.png").display().to_string();
+        }
 
-        result.worker = Some(worker);
+        if let Some(ui_scale) = config.ui_scale {
+            result.ui_scale = Some(ui_scale.clamp(*UI_SCALE_RANGE.start(), *UI_SCALE_RANGE.end()));
+        }
+        result.system_theme = cc.integration_info.system_theme;
+        result.apply_theme(&cc.egui_ctx);
+        match worker {
+            Ok(worker) => result.worker = worker,
+            Err(err) => result.startup_error = Some(format!("{err}\n{}", err.hint())),
+        }
+        if result.worker.is_none() && !config.keyfile.exists() {
+            result.first_run = Some(Default::default());
+        }
+        result.config = Some(config);
         result
     }
 
+    /// Helper which shows the first-run screen, which creates a new account or imports
+    /// one from its mnemonic, writes its keyfile and starts the worker
+    fn first_run_screen(&mut self, ctx: &egui::Context) {
+        let config = match self.config.clone() {
+            Some(config) => config,
+            None => return,
+        };
+        let prompt = match self.first_run.as_mut() {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        let red = ctx.style().visuals.error_fg_color;
+        let mut next_step = None;
+        let mut chosen = None;
+        CentralPanel::default().show(ctx, |ui| {
+            ScrollArea::vertical().show(ui, |ui| {
+                ui.vertical_centered(|ui| {
+                    ui.label(format!(
+                        "There is no keyfile at {}",
+                        config.keyfile.display()
+                    ));
+                    match &mut prompt.step {
+                        FirstRunStep::Choose => {
+                            if ui.button("Create new account").clicked() {
+                                next_step = Some(FirstRunStep::Backup {
+                                    mnemonic: generate_mnemonic(),
+                                    confirmed: false,
+                                });
+                            }
+                            if ui.button("Import from mnemonic").clicked() {
+                                next_step = Some(FirstRunStep::Import {
+                                    words: vec![Default::default(); MNEMONIC_WORDS],
+                                });
+                            }
+                        }
+                        FirstRunStep::Backup {
+                            mnemonic,
+                            confirmed,
+                        } => {
+                            ui.label(
+                                "Write these words down, in order. They are the only way to \
+                                 recover the account.",
+                            );
+                            Grid::new("new_mnemonic").num_columns(4).show(ui, |ui| {
+                                for (idx, word) in mnemonic.phrase().split(' ').enumerate() {
+                                    ui.label(format!("{}. {word}", idx + 1));
+                                    if idx % 4 == 3 {
+                                        ui.end_row();
+                                    }
+                                }
+                            });
+                            ui.checkbox(confirmed, "I wrote down these words");
+                            ui.horizontal(|ui| {
+                                if ui.button("Back").clicked() {
+                                    next_step = Some(FirstRunStep::Choose);
+                                }
+                                if ui
+                                    .add_enabled(*confirmed, Button::new("Create account"))
+                                    .clicked()
+                                {
+                                    chosen = Some(Ok(mnemonic.clone()));
+                                }
+                            });
+                        }
+                        FirstRunStep::Import { words } => {
+                            ui.label(format!("Enter the {MNEMONIC_WORDS} words of the mnemonic"));
+                            Grid::new("import_mnemonic").num_columns(4).show(ui, |ui| {
+                                for (idx, word) in words.iter_mut().enumerate() {
+                                    ui.horizontal(|ui| {
+                                        ui.label(format!("{}.", idx + 1));
+                                        ui.add(
+                                            TextEdit::singleline(&mut **word)
+                                                .password(true)
+                                                .desired_width(80.0),
+                                        );
+                                        if !word.trim().is_empty() && !is_mnemonic_word(word) {
+                                            ui.colored_label(red, "✗")
+                                                .on_hover_text("Not in the bip39 wordlist");
+                                        }
+                                    });
+                                    if idx % 4 == 3 {
+                                        ui.end_row();
+                                    }
+                                }
+                            });
+                            let all_valid = words.iter().all(|word| is_mnemonic_word(word));
+                            ui.horizontal(|ui| {
+                                if ui.button("Back").clicked() {
+                                    next_step = Some(FirstRunStep::Choose);
+                                }
+                                if ui.add_enabled(all_valid, Button::new("Import")).clicked() {
+                                    let words: Vec<&str> =
+                                        words.iter().map(|word| word.as_str()).collect();
+                                    chosen = Some(parse_mnemonic(&words));
+                                }
+                            });
+                        }
+                    }
+                    if let Some(err) = prompt.error.as_ref() {
+                        ui.label(RichText::new(err).color(red));
+                    }
+                });
+            });
+        });
+
+        if let Some(step) = next_step {
+            prompt.step = step;
+            prompt.error = None;
+        }
+        let result = match chosen {
+            Some(result) => result,
+            None => return,
+        };
+        let worker = result.and_then(|mnemonic| {
+            let account_key =
+                write_keyfile(&config.keyfile, &mnemonic).map_err(|err| err.to_string())?;
+            Worker::with_account_key(config, account_key)
+                .map_err(|err| format!("{err}\n{}", err.hint()))
+        });
+        match worker {
+            Ok(worker) => {
+                self.first_run = None;
+                self.worker = Some(worker);
+            }
+            Err(err) => prompt.error = Some(err),
+        }
+    }
+
+    /// Helper which shows why the worker could not be started, with a button to try
+    /// again once the problem is fixed
+    fn startup_error_screen(&mut self, ctx: &egui::Context) {
+        let config = match self.config.clone() {
+            Some(config) => config,
+            None => return,
+        };
+        let mut retry = false;
+        CentralPanel::default().show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                if let Some(err) = self.startup_error.as_ref() {
+                    ui.label(RichText::new(err).color(ui.visuals().error_fg_color));
+                }
+                retry = ui.button("Retry").clicked();
+            });
+        });
+        if !retry {
+            return;
+        }
+        match Worker::new(config) {
+            Ok(worker) => {
+                self.startup_error = None;
+                self.worker = Some(worker);
+            }
+            Err(err) => self.startup_error = Some(format!("{err}\n{}", err.hint())),
+        }
+    }
+
+    /// Helper which shows the prompt for the passphrase of an encrypted keyfile, and
+    /// starts the worker once the keyfile is decrypted
+    fn unlock_screen(&mut self, ctx: &egui::Context) {
+        let config = match self.config.clone() {
+            Some(config) => config,
+            None => return,
+        };
+        CentralPanel::default().show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                ui.label(format!("{} is encrypted", config.keyfile.display()));
+                ui.label("Passphrase:");
+                let response =
+                    ui.add(TextEdit::singleline(&mut *self.unlock.passphrase).password(true));
+                let entered = response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter));
+                if let Some(err) = self.unlock.error.as_ref() {
+                    ui.label(RichText::new(err).color(ui.visuals().error_fg_color));
+                }
+                let unlock = ui
+                    .add_enabled(!self.unlock.passphrase.is_empty(), Button::new("Unlock"))
+                    .clicked();
+                if !(unlock || entered) || self.unlock.passphrase.is_empty() {
+                    return;
+                }
+
+                let result = decrypt_keyfile(&config.keyfile, &self.unlock.passphrase);
+                self.unlock.passphrase.zeroize();
+                match result {
+                    Ok(account_key) => {
+                        match Worker::with_account_key(config.clone(), account_key) {
+                            Ok(worker) => {
+                                self.unlock = Default::default();
+                                self.worker = Some(worker);
+                            }
+                            Err(err) => {
+                                self.unlock.error = Some(format!("{err}\n{}", err.hint()));
+                            }
+                        }
+                    }
+                    Err(KeyfileError::WrongPassphrase) => {
+                        self.unlock.failed_attempts += 1;
+                        self.unlock.error = Some(format!(
+                            "Wrong passphrase, try again (attempt {})",
+                            self.unlock.failed_attempts
+                        ));
+                    }
+                    Err(err) => self.unlock.error = Some(err.to_string()),
+                }
+            });
+        });
+    }
+
     /// Helper which renders a drop-down menu for selecting a token-id, followed by a text edit line for a value.
     ///
     /// Arguments:
@@ -103,13 +775,24 @@ impl App {
     /// * token_infos, obtained from worker.get_token_infos
     /// * token_id, mutable reference to state this widget is selecting
     /// * values, mutable reference to the value strings this widget is selecting. These are parsed as scaled decimal values.
+    /// * spend_balances, if the value is spent from these balances. The value is then checked to leave
+    ///   room for the fee, and a "Max" button fills in the balance of the selected token less the fee
+    ///
+    /// The value is checked as it is typed, and an invalid one is shown in red, with the
+    /// problem on hover.
+    ///
+    /// Escape resets the value to 0 while it is being edited.
+    ///
+    /// Returns the response of the text edit line, which is marked changed when the
+    /// user edited the value, including with the "Max" button
     fn amount_selector(
         ui: &mut egui::Ui,
         context: &str,
         token_infos: &[TokenInfo],
         token_id: &mut TokenId,
         values: &mut HashMap<TokenId, String>,
-    ) {
+        spend_balances: Option<&HashMap<TokenId, u64>>,
+    ) -> egui::Response {
         let current_token_info: Option<&TokenInfo> =
             token_infos.iter().find(|info| info.token_id == *token_id);
 
@@ -128,36 +811,2494 @@ impl App {
                 });
 
             let scaled_value_str = values.entry(*token_id).or_insert_with(|| "0".to_string());
-            ui.text_edit_singleline(scaled_value_str);
+            let balance =
+                spend_balances.map(|balances| balances.get(token_id).cloned().unwrap_or(0));
+
+            // Flag an invalid value as it is typed, rather than once it is submitted
+            let validation = current_token_info
+                .map(|info| ScaledAmount::validate(scaled_value_str, info, balance));
+            let mut text_edit = TextEdit::singleline(scaled_value_str);
+            if let Some(Err(_)) = validation {
+                text_edit = text_edit.text_color(ui.visuals().error_fg_color);
+            }
+            let mut response = ui.add(text_edit);
+            if Self::escape_pressed(ui, &response) {
+                *scaled_value_str = "0".to_string();
+                response.mark_changed();
+            }
+            if let Some(Err(err)) = validation {
+                response = response.on_hover_text(err.to_string());
+            }
+
+            // Offer to fill in the entire balance, less the fee
+            if let (Some(balance), Some(info)) = (balance, current_token_info) {
+                match ScaledAmount::max_sendable(balance, info) {
+                    Ok(max) => {
+                        if ui.small_button("Max").clicked() {
+                            *scaled_value_str = max.display(info);
+                            response.mark_changed();
+                        }
+                    }
+                    Err(err) => {
+                        ui.add_enabled(false, Button::new("Max").small())
+                            .on_disabled_hover_text(err);
+                    }
+                }
+            }
+            response
+        })
+        .inner
+    }
+
+    /// Helper which tells whether Escape was pressed while editing a text field,
+    /// so that the field should be cleared. Egui already took the focus away.
+    fn escape_pressed(ui: &egui::Ui, response: &egui::Response) -> bool {
+        (response.has_focus() || response.lost_focus()) && ui.input(|i| i.key_pressed(Key::Escape))
+    }
+
+    /// Helper which tells whether Enter was pressed to finish editing a text field
+    fn enter_pressed(ui: &egui::Ui, response: &egui::Response) -> bool {
+        response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter))
+    }
+
+    /// Helper which lays out the bid and ask books side by side as rows of text,
+    /// for exporting as an image.
+    fn book_snapshot_rows(
+        books: &[QuoteBook; 2],
+        base_token_info: &TokenInfo,
+        counter_token_info: &TokenInfo,
+        token_infos: &[TokenInfo],
+    ) -> Vec<Vec<String>> {
+        let sides: Vec<Vec<QuoteInfo>> = books
+            .iter()
+            .map(|book| {
+                book.iter()
+                    .filter_map(|quote| {
+                        quote
+                            .get_quote_info(
+                                base_token_info.token_id,
+                                counter_token_info.token_id,
+                                token_infos,
+                            )
+                            .ok()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut rows = vec![
+            vec![format!(
+                "{} / {}",
+                base_token_info.symbol, counter_token_info.symbol
+            )],
+            vec![
+                "Bid price".to_owned(),
+                "Volume".to_owned(),
+                "Ask price".to_owned(),
+                "Volume".to_owned(),
+            ],
+        ];
+        let num_rows = sides.iter().map(Vec::len).max().unwrap_or(0);
+        for idx in 0..num_rows {
+            let mut row = Vec::default();
+            for side in sides.iter() {
+                match side.get(idx) {
+                    Some(info) => {
+                        row.push(info.price.to_string());
+                        row.push(info.volume.to_string());
+                    }
+                    None => {
+                        row.push(String::default());
+                        row.push(String::default());
+                    }
+                }
+            }
+            rows.push(row);
+        }
+        rows
+    }
+
+    /// Helper which renders the offers we submitted, and their status.
+    /// Failed offers can be retried, and finished ones dismissed.
+    fn my_offers(
+        ui: &mut egui::Ui,
+        worker: &Arc<Worker>,
+        caller: &AccessToken,
+        offers: &[TrackedOffer],
+        token_infos: &[TokenInfo],
+    ) {
+        if offers.is_empty() {
+            return;
+        }
+        ui.separator();
+        ui.strong("My offers");
+        Grid::new("my_offers_table").show(ui, |ui| {
+            for offer in offers {
+                ui.label(format!(
+                    "{} → {}",
+                    TokenInfo::format_amount(token_infos, offer.from_amount),
+                    TokenInfo::format_amount(token_infos, offer.to_amount)
+                ));
+                // Offers which may still expire can be kept alive
+                let mut keep_alive = offer.keep_alive;
+                let renewable = matches!(
+                    offer.status,
+                    OfferStatus::Submitting | OfferStatus::Live | OfferStatus::Expired
+                );
+                let renewed = if offer.renewals > 0 {
+                    format!("renewed {}×", offer.renewals)
+                } else {
+                    String::new()
+                };
+                ui.horizontal(|ui| {
+                    if ui
+                        .add_enabled(
+                            renewable,
+                            egui::Checkbox::new(&mut keep_alive, "keep alive"),
+                        )
+                        .changed()
+                    {
+                        if let Err(err) = worker.set_offer_keep_alive(caller, offer.id, keep_alive)
+                        {
+                            worker.push_error(err.to_string());
+                        }
+                    }
+                    ui.label(renewed);
+                    // Offers posted together are numbered after the first of them
+                    if let Some(other_id) = offer.paired_with {
+                        ui.small(format!("pair #{}", offer.id.min(other_id)))
+                            .on_hover_text("Posted together with an offer on the other side");
+                    }
+                });
+                match &offer.status {
+                    OfferStatus::Submitting => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label("submitting…");
+                        });
+                    }
+                    OfferStatus::Live | OfferStatus::Expired => {
+                        ui.horizontal(|ui| {
+                            ui.label(if offer.status == OfferStatus::Live {
+                                "open"
+                            } else {
+                                "expired"
+                            });
+                            if ui.small_button("Cancel").clicked() {
+                                if let Err(err) = worker.cancel_offer(caller, offer.id) {
+                                    worker.push_error(err.to_string());
+                                }
+                            }
+                            if offer.status == OfferStatus::Expired
+                                && ui.small_button("Dismiss").clicked()
+                            {
+                                worker.dismiss_offer(offer.id);
+                            }
+                        });
+                    }
+                    OfferStatus::Cancelling => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label("cancelling…");
+                            ui.add_enabled(false, Button::new("Cancel").small());
+                        });
+                    }
+                    OfferStatus::Filled | OfferStatus::Cancelled => {
+                        ui.horizontal(|ui| {
+                            ui.label(if offer.status == OfferStatus::Filled {
+                                "filled"
+                            } else {
+                                "cancelled"
+                            });
+                            if ui.small_button("Dismiss").clicked() {
+                                worker.dismiss_offer(offer.id);
+                            }
+                        });
+                    }
+                    OfferStatus::Failed(err) => {
+                        ui.horizontal(|ui| {
+                            ui.colored_label(Color32::RED, "failed").on_hover_text(err);
+                            if ui.small_button("Retry").clicked() {
+                                if let Err(err) = worker.retry_offer(caller, offer.id) {
+                                    worker.push_error(err.to_string());
+                                }
+                            }
+                            if ui.small_button("Dismiss").clicked() {
+                                worker.dismiss_offer(offer.id);
+                            }
+                        });
+                    }
+                }
+                ui.end_row();
+            }
+        });
+    }
+
+    /// Helper which renders the payments queued during this session, and their status.
+    /// Finished payments can be dismissed.
+    fn recent_payments(
+        ui: &mut egui::Ui,
+        worker: &Arc<Worker>,
+        commands: &[CommandInfo],
+        token_infos: &[TokenInfo],
+    ) {
+        if commands.is_empty() {
+            return;
+        }
+        ui.separator();
+        ui.strong("Recent payments");
+        Grid::new("recent_payments_table").show(ui, |ui| {
+            for info in commands.iter().rev() {
+                match &info.command {
+                    WorkerCommand::Send(Payment {
+                        value,
+                        token_id,
+                        recipient,
+                        ..
+                    }) => {
+                        ui.label(TokenInfo::format_amount(
+                            token_infos,
+                            Amount::new(*value, *token_id),
+                        ))
+                        .on_hover_text(recipient);
+                    }
+                    WorkerCommand::Defragment { token_id } => {
+                        ui.label(format!(
+                            "merge {} coins",
+                            token_infos
+                                .iter()
+                                .find(|info| info.token_id == *token_id)
+                                .map(|info| info.symbol.clone())
+                                .unwrap_or_else(|| token_id.to_string())
+                        ));
+                    }
+                    WorkerCommand::CreateGiftCode {
+                        value, token_id, ..
+                    } => {
+                        ui.label(format!(
+                            "gift code of {}",
+                            TokenInfo::format_amount(token_infos, Amount::new(*value, *token_id))
+                        ));
+                    }
+                    WorkerCommand::RedeemGiftCode { .. } => {
+                        ui.label("redeem gift code");
+                    }
+                    WorkerCommand::IssueReceiveSubaddress => {
+                        ui.label("new receive address");
+                    }
+                    WorkerCommand::OfferSwap { from_amount, .. }
+                    | WorkerCommand::PreviewOffer { from_amount, .. } => {
+                        ui.label(format!(
+                            "offer {}",
+                            TokenInfo::format_amount(token_infos, *from_amount)
+                        ));
+                    }
+                    WorkerCommand::OfferBothSides { quote, .. } => {
+                        ui.label(format!(
+                            "offer {} and {}",
+                            TokenInfo::format_amount(token_infos, quote.ask.0),
+                            TokenInfo::format_amount(token_infos, quote.bid.0)
+                        ));
+                    }
+                    WorkerCommand::SubmitHeldOffer { .. } => {
+                        ui.label("submit offer");
+                    }
+                    WorkerCommand::CancelOffer { .. } => {
+                        ui.label("cancel offer");
+                    }
+                    WorkerCommand::PerformSwap { from_amount, .. } => {
+                        ui.label(format!(
+                            "swap {}",
+                            TokenInfo::format_amount(token_infos, *from_amount)
+                        ));
+                    }
+                }
+                match &info.status {
+                    CommandStatus::Queued => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label("queued…");
+                        });
+                    }
+                    CommandStatus::Submitting => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label("submitting…");
+                        });
+                    }
+                    CommandStatus::Submitted => {
+                        ui.label("submitted");
+                    }
+                    CommandStatus::Failed(err) => {
+                        ui.colored_label(Color32::RED, "failed").on_hover_text(err);
+                    }
+                }
+                if info.status.is_finished() && ui.small_button("Dismiss").clicked() {
+                    worker.dismiss_command(info.id);
+                }
+                ui.end_row();
+            }
         });
     }
-}
 
-impl eframe::App for App {
-    /// Called by the frame work to save state before shutdown.
-    fn save(&mut self, storage: &mut dyn eframe::Storage) {
-        eframe::set_value(storage, eframe::APP_KEY, self);
+    /// The side of a QR code, in points
+    const QR_SIZE_RANGE: std::ops::RangeInclusive<f32> = 100.0..=400.0;
+
+    /// Helper which renders the QR code of `data`, and a button saving it as a PNG
+    /// of about the same size in pixels to `export_path`
+    fn qr_code(
+        ui: &mut egui::Ui,
+        worker: &Worker,
+        texture: &mut QrTexture,
+        data: &str,
+        size: f32,
+        export_path: &str,
+    ) {
+        // The texture has a pixel per module
+        let modules = match texture.get(ui.ctx(), data) {
+            Ok(texture) => {
+                ui.image(texture, egui::Vec2::splat(size));
+                texture.size()[0]
+            }
+            Err(err) => {
+                ui.label(format!("No QR code: {err}"));
+                return;
+            }
+        };
+        if ui.button("Save PNG").clicked() {
+            let path = PathBuf::from(export_path);
+            let result = render_qr(data, size as usize / modules.max(1))
+                .and_then(|image| image_export::encode_png(&image))
+                .and_then(|png| {
+                    std::fs::write(&path, png).map_err(|err| format!("{}: {err}", path.display()))
+                });
+            match result {
+                Ok(()) => worker.push_notice(format!("Saved the QR code to {}", path.display())),
+                Err(err) => worker.push_error(format!("Could not save the QR code: {err}")),
+            }
+        }
+    }
+
+    /// Send a payment validated in the Send panel, from the Submit button or Enter.
+    /// The coins selected in the coins view are cleared once a payment spending them
+    /// is queued.
+    fn submit_send(&mut self, worker: &Worker, caller: &AccessToken, payment: Payment) {
+        let spends_selected = !payment.inputs.is_empty();
+        let token_id = payment.token_id;
+        let result = worker.send(caller, payment);
+        if let Ok(command_id) = result {
+            if spends_selected {
+                self.selected_coins.clear();
+            }
+            self.sent_payments.push((command_id, token_id));
+        }
+        Self::command_result(worker, &mut self.precondition_changed, result.map(|_| ()));
+    }
+
+    /// Submit the payment of the Send panel, when its Submit button is clicked or
+    /// Enter is pressed in one of its fields. Nothing is queued unless the payment
+    /// validated, as (value, fee, change if spending the selected coins), or while
+    /// the worker is still sending the last payment of its token, so that a double
+    /// click doesn't send it twice.
+    fn submit_send_form(
+        &mut self,
+        worker: &Worker,
+        caller: &AccessToken,
+        okay_to_submit: &Result<(u64, u64, Option<u64>), String>,
+        memo_str: &str,
+        options: TxOptions,
+    ) {
+        let (value, expected_fee, change) = match okay_to_submit {
+            Ok(validated) => *validated,
+            Err(_) => return,
+        };
+        if Self::sending(worker, self.send_token_id) {
+            return;
+        }
+        // Spend the selected coins, if the payment validated against them
+        let inputs = if change.is_some() {
+            self.selected_coins.iter().cloned().collect()
+        } else {
+            Vec::new()
+        };
+        let payment = Payment {
+            value,
+            token_id: self.send_token_id,
+            recipient: self.send_to.clone(),
+            expected_fee,
+            payment_request_id: Payment::parse_payment_request_id(memo_str).unwrap_or_default(),
+            inputs,
+            options,
+        };
+        self.submit_send(worker, caller, payment);
+    }
+
+    /// Clear the amount of each payment sent from the Send panel once the worker
+    /// submitted it, so that it can't be sent twice by accident. The recipient is
+    /// cleared too, unless form values are remembered. Failed payments are kept, to
+    /// retry them.
+    fn clear_sent_payments(&mut self, worker: &Worker) {
+        let mut sent = Vec::new();
+        self.sent_payments.retain(|&(command_id, token_id)| {
+            match worker.get_command_status(command_id) {
+                Some(CommandStatus::Queued | CommandStatus::Submitting) => true,
+                Some(CommandStatus::Submitted) => {
+                    sent.push(token_id);
+                    false
+                }
+                Some(CommandStatus::Failed(_)) | None => false,
+            }
+        });
+        for token_id in sent {
+            self.send_value.remove(&token_id);
+            if !self.persist_form_values {
+                self.send_to.clear();
+            }
+        }
+    }
+
+    /// Helper which tells whether a payment of a token is being sent
+    fn sending(worker: &Worker, token_id: TokenId) -> bool {
+        worker.command_in_flight(|command| match command {
+            WorkerCommand::Send(payment) => payment.token_id == token_id,
+            _ => false,
+        })
+    }
+
+    /// Perform a swap the user confirmed in the Swap panel, with the button or Enter
+    fn submit_swap(
+        &mut self,
+        worker: &Worker,
+        caller: &AccessToken,
+        selection: QuoteSelection,
+        fee_token_id: TokenId,
+        max_slippage: Decimal,
+    ) {
+        self.swap_confirming = None;
+        let result = worker.perform_swap(
+            caller,
+            selection.fills,
+            Amount::new(selection.from_u64_value, self.swap_from_token_id),
+            fee_token_id,
+            TxOptions::default(),
+            max_slippage,
+        );
+        Self::command_result(worker, &mut self.precondition_changed, result.map(|_| ()));
+    }
+
+    /// Helper which copies text to the clipboard, and confirms it with a toast
+    fn copy_text(ui: &egui::Ui, toast: &mut Toast, text: String, what: &str) {
+        ui.output_mut(|o| o.copied_text = text);
+        toast.show(format!("Copied {what}"), Instant::now());
+    }
+
+    /// Helper which renders the gift codes created during this session, whether they
+    /// were funded yet, and a button to copy each of them.
+    fn gift_codes(
+        ui: &mut egui::Ui,
+        toast: &mut Toast,
+        gift_codes: &[GiftCode],
+        commands: &[CommandInfo],
+        token_infos: &[TokenInfo],
+    ) {
+        if gift_codes.is_empty() {
+            return;
+        }
+        ui.label("Gift codes are not saved, copy them somewhere safe before closing the app.");
+        Grid::new("gift_codes_table").show(ui, |ui| {
+            for code in gift_codes.iter().rev() {
+                ui.label(TokenInfo::format_amount(
+                    token_infos,
+                    Amount::new(code.value, code.token_id),
+                ));
+                match commands
+                    .iter()
+                    .find(|info| info.id == code.command_id)
+                    .map(|info| &info.status)
+                {
+                    Some(CommandStatus::Queued | CommandStatus::Submitting) => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label("funding…");
+                        });
+                    }
+                    Some(CommandStatus::Submitted) => {
+                        ui.label("funded");
+                    }
+                    Some(CommandStatus::Failed(err)) => {
+                        ui.colored_label(Color32::RED, "funding failed")
+                            .on_hover_text(err);
+                    }
+                    None => {
+                        ui.label("");
+                    }
+                }
+                ui.label(RichText::new(code.b58_code.as_str()).monospace().small());
+                if ui.small_button("Copy 📋").clicked() {
+                    Self::copy_text(ui, toast, code.b58_code.clone(), "the gift code");
+                }
+                ui.end_row();
+            }
+        });
+    }
+
+    /// Helper which renders the unspent txos of each token, which payments are made from,
+    /// with a warning and a defragment button when a token has very many of them.
+    /// Coins can be checked, so that the next payment spends exactly those.
+    ///
+    /// The txos are only fetched while the section is expanded.
+    fn coins(
+        ui: &mut egui::Ui,
+        worker: &Arc<Worker>,
+        caller: &AccessToken,
+        token_infos: &[TokenInfo],
+        selected_coins: &mut HashSet<KeyImage>,
+    ) {
+        // How many coins to list per token
+        const MAX_LISTED: usize = 100;
+
+        ui.collapsing("Coins", |ui| {
+            for info in token_infos {
+                let utxos = worker.get_utxos(caller, info.token_id).unwrap_or_default();
+                ui.horizontal(|ui| {
+                    ui.strong(format!("{}: {} coins", info.symbol, utxos.len()));
+                    if utxos.len() > UtxoInfo::FRAGMENTED_COUNT {
+                        ui.colored_label(Color32::YELLOW, "⚠ fragmented")
+                            .on_hover_text(
+                                "Payments may fail to select inputs, consider defragmenting",
+                            );
+                    }
+                    if utxos.len() > 1
+                        && !worker.is_watch_only()
+                        && ui.small_button("Defragment").clicked()
+                    {
+                        if let Err(err) = worker.defragment(caller, info.token_id) {
+                            worker.push_error(err.to_string());
+                        }
+                    }
+                });
+                Grid::new(("coins_table", *info.token_id)).show(ui, |ui| {
+                    for utxo in utxos.iter().take(MAX_LISTED) {
+                        let mut checked = selected_coins.contains(&utxo.key_image);
+                        if ui.checkbox(&mut checked, "").changed() {
+                            if checked {
+                                selected_coins.insert(utxo.key_image);
+                            } else {
+                                selected_coins.remove(&utxo.key_image);
+                            }
+                        }
+                        ui.label(info.format_u64_compact(utxo.value))
+                            .on_hover_text(info.format_u64(utxo.value));
+                        ui.label(
+                            RichText::new(format!("{:?}", utxo.key_image))
+                                .monospace()
+                                .small(),
+                        );
+                        ui.end_row();
+                    }
+                });
+                if utxos.len() > MAX_LISTED {
+                    ui.label(format!("and {} more", utxos.len() - MAX_LISTED));
+                }
+            }
+        });
+    }
+
+    /// Helper which renders the payments which were submitted, until a little while
+    /// after they landed on the ledger (or failed to). Payments which landed can be
+    /// verified from the recipient's point of view.
+    fn pending_transactions(
+        ui: &mut egui::Ui,
+        worker: &Arc<Worker>,
+        caller: &AccessToken,
+        pending: &[PendingTransaction],
+        token_infos: &[TokenInfo],
+    ) {
+        if pending.is_empty() {
+            return;
+        }
+        ui.separator();
+        ui.strong("Pending");
+        Grid::new("pending_transactions_table").show(ui, |ui| {
+            for tx in pending {
+                ui.label(TokenInfo::format_amount(
+                    token_infos,
+                    Amount::new(tx.value, tx.token_id),
+                ))
+                .on_hover_text(&tx.recipient);
+                match tx.status {
+                    TxStatus::Unknown => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label("pending…");
+                        });
+                    }
+                    TxStatus::Verified => {
+                        ui.colored_label(Color32::GREEN, "landed");
+                    }
+                    TxStatus::TombstoneBlockExceeded => {
+                        ui.colored_label(Color32::RED, "expired");
+                    }
+                    other => {
+                        ui.colored_label(Color32::RED, format!("{other:?}"));
+                    }
+                }
+                let verify_button = |ui: &mut egui::Ui, text: &str| {
+                    if ui.small_button(text).clicked() {
+                        if let Some(receipt) = tx.receipts.get_receiver_tx_receipt_list().first() {
+                            if let Err(err) = worker.verify_receipt(caller, receipt) {
+                                worker.push_error(err.to_string());
+                            }
+                        }
+                    }
+                };
+                match &tx.receiver_status {
+                    None if tx.can_verify() => verify_button(ui, "verify"),
+                    None => {
+                        ui.label("");
+                    }
+                    Some(ReceiverStatus::Checking) => {
+                        ui.spinner();
+                    }
+                    Some(ReceiverStatus::Status(TxStatus::Verified)) => {
+                        ui.colored_label(Color32::GREEN, "received");
+                    }
+                    Some(ReceiverStatus::Status(TxStatus::Unknown)) => {
+                        ui.horizontal(|ui| {
+                            ui.label("not seen by recipient yet");
+                            verify_button(ui, "retry");
+                        });
+                    }
+                    Some(ReceiverStatus::Status(other)) => {
+                        ui.colored_label(Color32::RED, format!("recipient: {other:?}"));
+                    }
+                    Some(ReceiverStatus::Failed(err)) => {
+                        ui.horizontal(|ui| {
+                            ui.colored_label(Color32::RED, "could not verify")
+                                .on_hover_text(err);
+                            verify_button(ui, "retry");
+                        });
+                    }
+                }
+                ui.end_row();
+            }
+        });
+    }
+
+    /// Helper which renders a confirmation window for each offer held for preview,
+    /// showing the decoded sci with Submit and Discard buttons.
+    fn held_offer_windows(
+        ctx: &egui::Context,
+        worker: &Arc<Worker>,
+        caller: &AccessToken,
+        held_offers: &[HeldOffer],
+        preview_pending: bool,
+        token_infos: &[TokenInfo],
+        keep_alive: bool,
+    ) {
+        if preview_pending {
+            Window::new("Offer preview")
+                .id(egui::Id::new("offer_preview_pending"))
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("Generating sci...");
+                    });
+                });
+        }
+
+        let now = Instant::now();
+        for held in held_offers {
+            Window::new("Offer preview")
+                .id(egui::Id::new(("offer_preview", held.handle)))
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    ui.label(format!(
+                        "Offer {} for {}",
+                        TokenInfo::format_amount(token_infos, held.from_amount),
+                        TokenInfo::format_amount(token_infos, held.to_amount)
+                    ));
+                    sci_debug_view(ui, &held.sci, &held.amounts, token_infos);
+                    let remaining = held.expires_at.saturating_duration_since(now);
+                    ui.label(format!(
+                        "Discarded automatically in {}s",
+                        remaining.as_secs()
+                    ));
+                    ui.horizontal(|ui| {
+                        if ui.button("Submit").clicked() {
+                            match worker.submit_held_offer(caller, held.handle, keep_alive) {
+                                Ok(Some(_)) => {}
+                                Ok(None) => worker.push_error(
+                                    "The preview expired, please preview the offer again"
+                                        .to_owned(),
+                                ),
+                                Err(err) => worker.push_error(err.to_string()),
+                            }
+                        }
+                        if ui.button("Discard").clicked() {
+                            if let Err(err) = worker.discard_held_offer(caller, held.handle) {
+                                worker.push_error(err.to_string());
+                            }
+                        }
+                    });
+                });
+        }
+    }
+
+    /// Helper which handles the result of a command the user submitted.
+    ///
+    /// If a precondition changed since the user reviewed the command, it is kept
+    /// to explain it next to the submit button (see precondition_notice), other errors
+    /// are reported through the worker's errors.
+    fn command_result(
+        worker: &Worker,
+        precondition_changed: &mut Option<PreconditionFailed>,
+        result: Result<(), CommandError>,
+    ) {
+        match result {
+            Ok(()) => *precondition_changed = None,
+            Err(CommandError::PreconditionChanged(err)) => *precondition_changed = Some(err),
+            Err(err) => worker.push_error(err.to_string()),
+        }
+    }
+
+    /// Helper which picks the color an event of some severity is shown in, so that
+    /// it is readable in the current theme
+    fn severity_color(visuals: &egui::Visuals, severity: Severity) -> Color32 {
+        match severity {
+            Severity::Info => visuals.hyperlink_color,
+            Severity::Warning => visuals.warn_fg_color,
+            Severity::Error => visuals.error_fg_color,
+        }
+    }
+
+    /// Helper which shows a dot colored by the health of a backend, with its details
+    /// on hover
+    fn health_dot(ui: &mut egui::Ui, health: &ConnectionHealth, backend: &str) {
+        let color = match health.status() {
+            HealthStatus::Healthy => Color32::from_rgb(0, 200, 0),
+            HealthStatus::Degraded => ui.visuals().warn_fg_color,
+            HealthStatus::Down => ui.visuals().error_fg_color,
+        };
+        ui.label(RichText::new("●").color(color))
+            .on_hover_text(format!("{backend}\n{}", health.describe()));
+    }
+
+    /// Helper which shows the screen displayed while the worker registers the account
+    /// with mobilecoind, or after it failed to, with a button to try again.
+    fn connecting_screen(ctx: &egui::Context, worker: &Worker) {
+        CentralPanel::default().show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                let failed = match worker.init_status() {
+                    InitStatus::Failed(err) => {
+                        ui.label(RichText::new(err.to_string()).color(ui.visuals().error_fg_color));
+                        ui.label(err.hint());
+                        true
+                    }
+                    _ => {
+                        ui.spinner();
+                        ui.label(format!(
+                            "Connecting to mobilecoind at {}…",
+                            worker.get_mobilecoind_uri()
+                        ));
+                        false
+                    }
+                };
+                if let Some(proxy) = worker.get_grpc_proxy() {
+                    ui.label(format!("Through the proxy at {proxy}"));
+                }
+                if ui.add_enabled(failed, Button::new("Retry")).clicked() {
+                    worker.retry_init();
+                }
+            });
+        });
+    }
+
+    /// Helper which explains that a precondition changed since the user reviewed the
+    /// command they submitted, so that they can review it and submit it again.
+    fn precondition_notice(
+        ui: &mut egui::Ui,
+        precondition_changed: &mut Option<PreconditionFailed>,
+    ) {
+        if let Some(err) = precondition_changed.as_ref() {
+            let mut dismissed = false;
+            ui.horizontal(|ui| {
+                ui.colored_label(Color32::YELLOW, format!("Not submitted: {err}"));
+                dismissed = ui.small_button("Dismiss").clicked();
+            });
+            ui.label("Review the updated values and submit again.");
+            if dismissed {
+                *precondition_changed = None;
+            }
+        }
+    }
+
+    /// The pixels per point to draw the UI with: the user's choice, or else the native
+    /// ones
+    fn ui_scale(&self) -> f32 {
+        self.ui_scale
+            .or(self.native_scale)
+            .unwrap_or(1.0)
+            .clamp(*UI_SCALE_RANGE.start(), *UI_SCALE_RANGE.end())
+    }
+
+    /// Set the visuals of the theme the user chose. Setting them restyles everything,
+    /// so it is only done when they differ from the current ones, such as when the
+    /// choice changes, or the OS switches theme while we follow it.
+    fn apply_theme(&self, ctx: &egui::Context) {
+        let theme = self.theme.resolve(self.system_theme);
+        if ctx.style().visuals.dark_mode != (theme == eframe::Theme::Dark) {
+            ctx.set_visuals(theme.egui_visuals());
+        }
+    }
+
+    /// Helper which renders the Send mode, and handles the keys of its form
+    fn send_panel(
+        &mut self,
+        ui: &mut egui::Ui,
+        worker: &Arc<Worker>,
+        caller: &AccessToken,
+        token_infos: &[TokenInfo],
+        balances_complete: &Result<(), String>,
+    ) {
+        ui.heading("Send");
+
+        let mut pasted = false;
+        let recipient_field = ui.horizontal(|ui| {
+            ui.label("Recipient b58 address: ");
+            let response = ui.text_edit_singleline(&mut self.send_to);
+            if Self::escape_pressed(ui, &response) {
+                self.send_to.clear();
+            }
+            if ui
+                .button("Paste")
+                .on_hover_text("Paste the address from the clipboard")
+                .clicked()
+            {
+                match arboard::Clipboard::new().and_then(|mut c| c.get_text()) {
+                    // An address copied from a wrapped line may contain breaks
+                    Ok(text) => {
+                        self.send_to = text.split_whitespace().collect();
+                        pasted = true;
+                    }
+                    Err(err) => {
+                        let err = format!("Could not read the clipboard: {err}");
+                        worker.push_error(err)
+                    }
+                }
+            }
+            response
+        });
+        let recipient = worker.validate_recipient(&self.send_to);
+        if pasted {
+            let message = match recipient.as_ref() {
+                Ok(_) => "Pasted the recipient".to_owned(),
+                Err(err) => format!("The pasted address is invalid: {err}"),
+            };
+            self.toast.show(message, Instant::now());
+        }
+        // Flag a malformed address as soon as it is typed or pasted
+        if let (false, Err(err)) = (self.send_to.is_empty(), recipient.as_ref()) {
+            ui.label(
+                RichText::new(err.as_str())
+                    .small()
+                    .color(ui.visuals().error_fg_color),
+            );
+        }
+        if let Some(fog_report_url) = recipient
+            .as_ref()
+            .ok()
+            .and_then(|recipient| recipient.fog_report_url())
+        {
+            ui.label(RichText::new("fog recipient").small())
+                .on_hover_text(format!("Fog report url: {fog_report_url}"));
+        }
+
+        // The amount comes right after the recipient, so that Tab goes from
+        // one to the other. Payments can only spend the balance of the
+        // subaddress they are sent from, which doesn't include change still
+        // pending from earlier payments.
+        let balance_snapshot = worker
+            .get_subaddress_balances(caller, self.send_subaddress)
+            .unwrap_or_default();
+        let mut balances = balance_snapshot.balances.clone();
+
+        let amount_field = Self::amount_selector(
+            ui,
+            "Amount",
+            token_infos,
+            &mut self.send_token_id,
+            &mut self.send_value,
+            Some(&balances),
+        );
+
+        if let Some(info) = token_infos
+            .iter()
+            .find(|info| info.token_id == self.send_token_id)
+        {
+            let value = self
+                .send_value
+                .get(&self.send_token_id)
+                .and_then(|value_str| ScaledAmount::parse(value_str, info).ok());
+            if let Some(value) = value {
+                Self::fiat_estimate(ui, worker, info, value.raw);
+            }
+        }
+
+        // Only remember non-empty memos, so that we don't keep an entry for
+        // every address typed
+        let mut memo_str = self
+            .send_memo
+            .get(&self.send_to)
+            .cloned()
+            .unwrap_or_default();
+        let memo_field = ui
+            .horizontal(|ui| {
+                ui.label("Memo / payment id (optional): ");
+                let response = ui.text_edit_singleline(&mut memo_str);
+                if Self::escape_pressed(ui, &response) {
+                    memo_str.clear();
+                }
+                response
+            })
+            .inner;
+        // Enter in any of the fields submits, if the payment is valid
+        let submit_key = [&recipient_field.inner, &amount_field, &memo_field]
+            .into_iter()
+            .any(|response| Self::enter_pressed(ui, response));
+        if memo_str.is_empty() {
+            self.send_memo.remove(&self.send_to);
+        } else {
+            self.send_memo
+                .insert(self.send_to.clone(), memo_str.clone());
+        }
+
+        ui.collapsing("Advanced", |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Send from subaddress: ");
+                ui.add(
+                    egui::DragValue::new(&mut self.send_subaddress)
+                        .clamp_range(0..=worker.num_subaddresses() - 1),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.label("Expires after (blocks): ");
+                ui.add(
+                    egui::DragValue::new(&mut self.send_tombstone_offset)
+                        .clamp_range(1..=TxOptions::MAX_TOMBSTONE_OFFSET),
+                )
+                .on_hover_text("How many blocks past the current height the payment may land in");
+            });
+        });
+        let send_options = TxOptions {
+            sender_subaddress: self.send_subaddress,
+            tombstone_offset: self.send_tombstone_offset,
+        };
+
+        let current_token_info: Option<&TokenInfo> = token_infos
+            .iter()
+            .find(|info| info.token_id == self.send_token_id);
+
+        let scaled_value_str = self
+            .send_value
+            .entry(self.send_token_id)
+            .or_insert_with(|| "0".to_string());
+
+        match current_token_info.as_ref() {
+            Some(info) => {
+                let balance = ScaledAmount::new(
+                    *balances.entry(self.send_token_id).or_default(),
+                    self.send_token_id,
+                );
+                if self.send_subaddress == 0 {
+                    ui.label(format!("balance: {}", info.format_u64(balance.raw)));
+                } else {
+                    ui.label(format!(
+                        "balance of subaddress {}: {}",
+                        self.send_subaddress,
+                        info.format_u64(balance.raw)
+                    ));
+                }
+            }
+            None => {
+                ui.label("balance:");
+            }
+        }
+        Self::sync_qualifier(ui, balances_complete);
+
+        if !self.selected_coins.is_empty() {
+            ui.horizontal(|ui| {
+                ui.label(format!(
+                    "Spending {} coins selected in the assets pane",
+                    self.selected_coins.len()
+                ));
+                if ui.small_button("Clear").clicked() {
+                    self.selected_coins.clear();
+                }
+            });
+        }
+
+        // This either the u64 value of the token to send, the fee, and the change
+        // if spending selected coins, or a string error to display
+        let mut okay_to_submit: Result<(u64, u64, Option<u64>), String> = current_token_info
+            .ok_or("select a token".to_string())
+            .and_then(
+                |info: &TokenInfo| -> Result<(u64, u64, Option<u64>), String> {
+                    let amount = ScaledAmount::parse(scaled_value_str, info)?;
+                    preflight::check_tx_options(&send_options, worker.num_subaddresses())
+                        .map_err(|err| err.to_string())?;
+
+                    let change = if self.selected_coins.is_empty() {
+                        preflight::check_send(&balance_snapshot, amount, info.fee)
+                            .map_err(|err| err.to_string())?;
+                        None
+                    } else {
+                        if self.send_subaddress != 0 {
+                            return Err("Selected coins are spent from subaddress 0".to_string());
+                        }
+                        // The coins must all be of the token sent, and cover the
+                        // value and the fee
+                        let unspent: Vec<UtxoInfo> = token_infos
+                            .iter()
+                            .flat_map(|info| {
+                                worker.get_utxos(caller, info.token_id).unwrap_or_default()
+                            })
+                            .collect();
+                        let selected: Vec<KeyImage> = self.selected_coins.iter().cloned().collect();
+                        let inputs = preflight::check_inputs_available(
+                            &unspent,
+                            &HashSet::default(),
+                            &selected,
+                        )
+                        .map_err(|err| err.to_string())?;
+                        Some(
+                            preflight::check_inputs(&inputs, amount, info.fee)
+                                .map_err(|err| err.to_string())?,
+                        )
+                    };
+
+                    // Check the send_to field
+                    recipient.as_ref().map_err(Clone::clone)?;
+
+                    // Check the memo field
+                    if Payment::parse_payment_request_id(&memo_str)?.is_some() && change.is_some() {
+                        return Err("No payment id when spending selected coins".to_string());
+                    }
+
+                    Ok((amount.raw, info.fee, change))
+                },
+            );
+
+        // Once the payment validates, show the fee mobilecoind would actually use.
+        // Estimating it builds the transaction, fog hints included, so for a fog
+        // recipient a failed estimate means the payment would fail too.
+        let mut fog_error = None;
+        match (current_token_info, okay_to_submit.as_ref()) {
+            (Some(info), Ok((u64_value, _, _))) => {
+                match worker.get_fee_estimate(
+                    *u64_value,
+                    self.send_token_id,
+                    self.send_to.clone(),
+                    self.send_subaddress,
+                    &self.selected_coins,
+                ) {
+                    Some(Ok(fee)) => {
+                        ui.label(format!("estimated fee: {}", info.format_u64(fee)));
+                    }
+                    Some(Err(err)) if recipient.as_ref().map_or(false, |r| r.has_fog()) => {
+                        ui.label(format!("fee: {}", info.format_u64(info.fee)));
+                        fog_error = Some(format!(
+                            "Can't build a payment to this fog recipient: {err}"
+                        ));
+                    }
+                    Some(Err(err)) => {
+                        ui.label(format!("fee: {}", info.format_u64(info.fee)))
+                            .on_hover_text(format!("Could not estimate the fee: {err}"));
+                    }
+                    None => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label("estimating fee…");
+                        });
+                    }
+                }
+            }
+            (Some(info), Err(_)) => {
+                ui.label(format!("fee: {}", info.format_u64(info.fee)));
+            }
+            (None, _) => {
+                ui.label("fee:");
+            }
+        }
+
+        if let Some(err) = fog_error {
+            okay_to_submit = Err(err);
+        }
+        if worker.is_watch_only() {
+            okay_to_submit = Err(CommandError::WatchOnly.to_string());
+        }
+
+        Self::precondition_notice(ui, &mut self.precondition_changed);
+        let clicked = match okay_to_submit.as_ref() {
+            Ok((_, _, change)) => {
+                ui.label("");
+                let submit_label = match change {
+                    Some(change) => format!(
+                        "Submit (change: {})",
+                        TokenInfo::format_amount(
+                            token_infos,
+                            Amount::new(*change, self.send_token_id)
+                        )
+                    ),
+                    None => "Submit".to_string(),
+                };
+                let sending = Self::sending(worker, self.send_token_id);
+                ui.add_enabled(!sending, Button::new(submit_label))
+                    .on_hover_text("Enter")
+                    .on_disabled_hover_text("A payment of this token is being sent")
+                    .clicked()
+            }
+            Err(err_str) => {
+                ui.label(err_str);
+                ui.add_enabled(false, Button::new("Submit"));
+                false
+            }
+        };
+        if clicked || submit_key {
+            self.submit_send_form(worker, caller, &okay_to_submit, &memo_str, send_options);
+        }
+
+        let commands = worker.get_commands(caller).unwrap_or_default();
+        Self::recent_payments(ui, worker, &commands, token_infos);
+
+        let pending = worker.get_pending_transactions(caller).unwrap_or_default();
+        Self::pending_transactions(ui, worker, caller, &pending, token_infos);
+
+        ui.separator();
+        ui.collapsing("Gift code", |ui| {
+            // Gift codes are funded from subaddress 0
+            let gift_snapshot = worker
+                .get_subaddress_balances(caller, 0)
+                .unwrap_or_default();
+            Self::amount_selector(
+                ui,
+                "Gift",
+                token_infos,
+                &mut self.gift_token_id,
+                &mut self.gift_value,
+                Some(&gift_snapshot.balances),
+            );
+            let gift_value_str = self
+                .gift_value
+                .entry(self.gift_token_id)
+                .or_insert_with(|| "0".to_string());
+
+            // This is either the u64 value of the gift and the fee, or an error to display
+            let okay_to_create: Result<(u64, u64), String> = token_infos
+                .iter()
+                .find(|info| info.token_id == self.gift_token_id)
+                .ok_or("select a token".to_string())
+                .and_then(|info| {
+                    let amount = ScaledAmount::parse(gift_value_str, info)?;
+                    preflight::check_send(&gift_snapshot, amount, info.fee)
+                        .map_err(|err| err.to_string())?;
+                    Ok((amount.raw, info.fee))
+                })
+                .and_then(|created| {
+                    if worker.is_watch_only() {
+                        return Err(CommandError::WatchOnly.to_string());
+                    }
+                    Ok(created)
+                });
+
+            match okay_to_create {
+                Ok((value, fee)) => {
+                    if ui.button("Create gift code").clicked() {
+                        let result =
+                            worker.create_transfer_code(caller, value, self.gift_token_id, fee);
+                        Self::command_result(
+                            worker,
+                            &mut self.precondition_changed,
+                            result.map(|_| ()),
+                        );
+                    }
+                }
+                Err(err_str) => {
+                    ui.label(err_str);
+                    ui.add_enabled(false, Button::new("Create gift code"));
+                }
+            }
+
+            let gift_codes = worker.get_gift_codes(caller).unwrap_or_default();
+            Self::gift_codes(ui, &mut self.toast, &gift_codes, &commands, token_infos);
+        });
+    }
+
+    /// Helper which renders the Swap mode, and handles the keys of its form
+    fn swap_panel(
+        &mut self,
+        ui: &mut egui::Ui,
+        worker: &Arc<Worker>,
+        caller: &AccessToken,
+        token_infos: &[TokenInfo],
+        balances_complete: &Result<(), String>,
+    ) {
+        ui.heading("Swap");
+
+        // Swaps spend from subaddress 0
+        let balance_snapshot = worker
+            .get_subaddress_balances(caller, 0)
+            .unwrap_or_default();
+
+        if !worker.has_deqs() {
+            empty_state(ui, &EmptyStateKind::NoDeqsConfigured, worker);
+            return;
+        }
+
+        // Whichever value the user edited last drives quote selection, and the
+        // other one is filled in from the selected quote
+        let swap_from = Self::amount_selector(
+            ui,
+            "Swap from",
+            token_infos,
+            &mut self.swap_from_token_id,
+            &mut self.swap_from_value,
+            None,
+        );
+        if swap_from.changed() {
+            self.swap_input = SwapInput::From;
+        }
+        ui.label("↓");
+        let swap_to = Self::amount_selector(
+            ui,
+            "Swap to",
+            token_infos,
+            &mut self.swap_to_token_id,
+            &mut self.swap_to_value,
+            None,
+        );
+        if swap_to.changed() {
+            self.swap_input = SwapInput::To;
+        }
+        // Enter in either value asks to confirm the swap, like Submit
+        let submit_key = Self::enter_pressed(ui, &swap_from) || Self::enter_pressed(ui, &swap_to);
+        ui.horizontal(|ui| {
+            ui.label("Max slippage (%)");
+            ui.text_edit_singleline(&mut self.swap_max_slippage)
+                .on_hover_text(
+                    "The swap is aborted if the price moves by more than this \
+                     before it is submitted",
+                );
+        });
+        ui.collapsing("Advanced", |ui| {
+            ui.checkbox(
+                &mut self.swap_include_mine,
+                "Allow filling my own offers (for testing)",
+            )
+            .on_hover_text("Filling your own offer only costs you the fees");
+        });
+        let symbol_of = |token_id: TokenId| {
+            token_infos
+                .iter()
+                .find(|info| info.token_id == token_id)
+                .map(|info| info.symbol.clone())
+                .unwrap_or_default()
+        };
+        ui.horizontal(|ui| {
+            ui.label("Pay fee in");
+            ComboBox::from_id_source("swap_fee_token_id")
+                .selected_text(symbol_of(
+                    self.swap_fee_token_id.unwrap_or(self.swap_from_token_id),
+                ))
+                .show_ui(ui, |ui| {
+                    let from_symbol = symbol_of(self.swap_from_token_id);
+                    ui.selectable_value(
+                        &mut self.swap_fee_token_id,
+                        None,
+                        format!("{from_symbol} (swapped from)"),
+                    );
+                    for info in token_infos.iter() {
+                        if info.token_id != self.swap_from_token_id {
+                            ui.selectable_value(
+                                &mut self.swap_fee_token_id,
+                                Some(info.token_id),
+                                info.symbol.clone(),
+                            );
+                        }
+                    }
+                });
+        });
+        let fee_token_id = self.swap_fee_token_id.unwrap_or(self.swap_from_token_id);
+        let fee_token_info: Option<&TokenInfo> = token_infos
+            .iter()
+            .find(|info| info.token_id == fee_token_id);
+        let max_slippage: Result<Decimal, String> =
+            Decimal::from_str(self.swap_max_slippage.trim())
+                .ok()
+                .filter(|percent| !percent.is_sign_negative())
+                .map(|percent| percent / Decimal::ONE_HUNDRED)
+                .ok_or_else(|| "Max slippage must be a percentage of 0 or more".to_owned());
+        Self::sync_qualifier(ui, balances_complete);
+
+        let quote_book = worker
+            .get_quote_book(caller, self.swap_to_token_id, self.swap_from_token_id)
+            .unwrap_or_default();
+        let book_age = worker.get_quote_book_age(self.swap_to_token_id, self.swap_from_token_id);
+        let max_quote_age = worker.max_quote_age();
+        Self::book_age(ui, book_age);
+        // Until the book is first polled, any quote selection would fail
+        // for lack of liquidity
+        if book_age.is_none() && self.swap_from_token_id != self.swap_to_token_id {
+            Self::loading_quotes(ui);
+            return;
+        }
+
+        let swap_from_token_info: Option<&TokenInfo> = token_infos
+            .iter()
+            .find(|info| info.token_id == self.swap_from_token_id);
+
+        let swap_to_token_info: Option<&TokenInfo> = token_infos
+            .iter()
+            .find(|info| info.token_id == self.swap_to_token_id);
+
+        // Returns the SCIs we selected to swap against, and the partial fill values to
+        // fill them to, or an error message
+        let okay_to_submit: Result<QuoteSelection, String> = swap_from_token_info
+            .zip(swap_to_token_info)
+            .zip(fee_token_info)
+            .ok_or("".to_string())
+            .and_then(|((from_info, to_info), fee_info)| {
+                if self.swap_from_token_id == self.swap_to_token_id {
+                    return Err("".to_string());
+                }
+                max_slippage.as_ref().map_err(Clone::clone)?;
+                if let Some(book_age) = book_age {
+                    preflight::check_quotes_fresh(book_age, max_quote_age)
+                        .map_err(|err| err.to_string())?;
+                }
+
+                let qs = match self.swap_input {
+                    SwapInput::To => {
+                        let to_u64_value = to_info.try_scaled_to_u64(
+                            self.swap_to_value
+                                .entry(self.swap_to_token_id)
+                                .or_insert_with(|| "0".to_string()),
+                        )?;
+                        let to_amount = Amount::new(to_u64_value, self.swap_to_token_id);
+                        QuoteSelection::new_multi(
+                            &quote_book,
+                            self.swap_from_token_id,
+                            from_info,
+                            to_amount,
+                            to_info,
+                            self.swap_include_mine,
+                        )?
+                    }
+                    SwapInput::From => {
+                        let from_u64_value = from_info.try_scaled_to_u64(
+                            self.swap_from_value
+                                .entry(self.swap_from_token_id)
+                                .or_insert_with(|| "0".to_string()),
+                        )?;
+                        let from_amount = Amount::new(from_u64_value, self.swap_from_token_id);
+                        QuoteSelection::for_from_amount(
+                            &quote_book,
+                            from_amount,
+                            from_info,
+                            to_info,
+                            self.swap_include_mine,
+                        )?
+                    }
+                };
+
+                // Check if we have sufficient funds to do this, and to pay the fee
+                preflight::check_swap_with_fee(
+                    &balance_snapshot,
+                    quote_book.iter(),
+                    &qs.key_images(),
+                    ScaledAmount::new(qs.from_u64_value, self.swap_from_token_id),
+                    ScaledAmount::fee(fee_info),
+                )
+                .map_err(|err| err.to_string())?;
+                Ok(qs)
+            });
+
+        Self::precondition_notice(ui, &mut self.precondition_changed);
+        match okay_to_submit {
+            Ok(qs) => {
+                match (self.swap_input, swap_to_token_info) {
+                    (SwapInput::From, Some(to_info)) => {
+                        *self.swap_to_value.entry(self.swap_to_token_id).or_default() =
+                            ScaledAmount::new(qs.to_u64_value, self.swap_to_token_id)
+                                .display(to_info);
+                    }
+                    _ => {
+                        *self
+                            .swap_from_value
+                            .entry(self.swap_from_token_id)
+                            .or_default() = qs.from_value_decimal.to_string();
+                    }
+                }
+                if qs.fills.len() > 1 {
+                    ui.label(format!("Fills {} quotes", qs.fills.len()));
+                } else {
+                    ui.label("");
+                }
+
+                // Show what the swap costs, before asking to confirm it
+                let mut price_text = qs.price.normalize().to_string();
+                if let Some(((from_info, to_info), fee_info)) = swap_from_token_info
+                    .zip(swap_to_token_info)
+                    .zip(fee_token_info)
+                {
+                    price_text = format!(
+                        "{} {} per {}",
+                        qs.price.round_dp(from_info.decimals).normalize(),
+                        from_info.symbol,
+                        to_info.symbol
+                    );
+                    // The fee only adds to the total if it is paid in the
+                    // from token
+                    let fee = ScaledAmount::fee(fee_info);
+                    let from_amount = ScaledAmount::new(qs.from_u64_value, self.swap_from_token_id);
+                    let total = if fee_token_id == self.swap_from_token_id {
+                        from_amount.try_add(fee)
+                    } else {
+                        Ok(from_amount)
+                    };
+                    let best_price = QuoteSelection::best_price(
+                        &quote_book,
+                        from_info,
+                        to_info,
+                        self.swap_include_mine,
+                    );
+                    Grid::new("swap_cost").num_columns(2).show(ui, |ui| {
+                        ui.label("Price");
+                        ui.label(&price_text);
+                        ui.end_row();
+                        ui.label("Network fee");
+                        ui.label(fee_info.format_u64(fee.raw));
+                        ui.end_row();
+                        ui.label("Total");
+                        ui.label(match &total {
+                            Ok(total) => from_info.format_u64(total.raw),
+                            Err(err) => err.clone(),
+                        });
+                        ui.end_row();
+                        let difference = best_price.and_then(|best_price| {
+                            (qs.price - best_price)
+                                .checked_div(best_price)?
+                                .checked_mul(Decimal::ONE_HUNDRED)
+                        });
+                        if let Some(difference) = difference {
+                            ui.label("vs. best quote");
+                            let difference = difference.round_dp(2).normalize();
+                            ui.label(format!("{difference}%"));
+                            ui.end_row();
+                        }
+                    });
+                }
+
+                // Ask to confirm the swap, so that one click can't submit it
+                // Enter confirms, and Escape cancels, while no field has focus
+                let selection = (qs.from_u64_value, qs.to_u64_value);
+                // Until the worker is done with the last swap, so that a double
+                // click doesn't submit it twice
+                let swapping = worker.command_in_flight(|command| {
+                    matches!(command, WorkerCommand::PerformSwap { .. })
+                });
+                let mut confirmed = false;
+                if swapping {
+                    self.swap_confirming = None;
+                    ui.add_enabled(false, Button::new("Submit"))
+                        .on_disabled_hover_text("A swap is being submitted");
+                } else if self.swap_confirming == Some(selection) {
+                    let (enter, escape) =
+                        ui.input(|i| (i.key_pressed(Key::Enter), i.key_pressed(Key::Escape)));
+                    let unfocused = ui.memory(|mem| mem.focus().is_none());
+                    ui.horizontal(|ui| {
+                        confirmed = ui
+                            .button(format!("Confirm swap at price {price_text}"))
+                            .on_hover_text("Enter")
+                            .clicked()
+                            || (enter && unfocused);
+                        if ui.button("Cancel").on_hover_text("Escape").clicked()
+                            || (escape && unfocused)
+                        {
+                            self.swap_confirming = None;
+                        }
+                    });
+                } else {
+                    // The selection changed since Submit was clicked, if it was
+                    self.swap_confirming = None;
+                    if worker.is_watch_only() {
+                        ui.label(CommandError::WatchOnly.to_string());
+                        ui.add_enabled(false, Button::new("Submit"));
+                    } else if ui.button("Submit").clicked() || submit_key {
+                        self.swap_confirming = Some(selection);
+                    }
+                }
+                if confirmed {
+                    if let Ok(max_slippage) = max_slippage {
+                        self.submit_swap(worker, caller, qs, fee_token_id, max_slippage);
+                    }
+                }
+            }
+            Err(err_str) => {
+                self.swap_confirming = None;
+                if err_str == QuoteSelection::ONLY_MINE {
+                    ui.label(
+                        "Only your own offers match this swap. Filling them \
+                         would only cost you the fees.",
+                    );
+                } else {
+                    ui.label(err_str);
+                }
+                ui.add_enabled(false, Button::new("Submit"));
+            }
+        }
+
+        if self.swap_from_token_id != self.swap_to_token_id {
+            let status = worker.get_deqs_status(self.swap_to_token_id, self.swap_from_token_id);
+            if let Some(kind) = EmptyStateKind::for_quote_book(&status, quote_book.len()) {
+                ui.separator();
+                empty_state(ui, &kind, worker);
+            }
+        }
+    }
+
+    /// Helper which renders the Offer Swap mode, and handles the keys of its form
+    fn offer_panel(
+        &mut self,
+        ui: &mut egui::Ui,
+        worker: &Arc<Worker>,
+        caller: &AccessToken,
+        token_infos: &[TokenInfo],
+        balances_complete: &Result<(), String>,
+    ) {
+        ui.heading("Offer Swap");
+
+        // Offers spend from subaddress 0
+        let balance_snapshot = worker
+            .get_subaddress_balances(caller, 0)
+            .unwrap_or_default();
+
+        if !worker.has_deqs() {
+            empty_state(ui, &EmptyStateKind::NoDeqsConfigured, worker);
+            return;
+        }
+
+        let offers = worker.get_offers(caller).unwrap_or_default();
+        Self::my_offers(ui, worker, caller, &offers, token_infos);
+
+        let base_token_info: Option<&TokenInfo> = token_infos
+            .iter()
+            .find(|info| info.token_id == self.base_token_id);
+
+        let counter_token_info: Option<&TokenInfo> = token_infos
+            .iter()
+            .find(|info| info.token_id == self.counter_token_id);
+
+        // Show the asset pair as two side-by-side drop-down menus
+        ui.horizontal(|ui| {
+            ComboBox::from_id_source("base_token_id")
+                .selected_text(
+                    base_token_info
+                        .map(|info| info.symbol.clone())
+                        .unwrap_or_default(),
+                )
+                .show_ui(ui, |ui| {
+                    for info in token_infos.iter() {
+                        ui.selectable_value(
+                            &mut self.base_token_id,
+                            info.token_id,
+                            info.symbol.clone(),
+                        );
+                    }
+                });
+            ui.label("/");
+            ComboBox::from_id_source("counter_token_id")
+                .selected_text(
+                    counter_token_info
+                        .map(|info| info.symbol.clone())
+                        .unwrap_or_default(),
+                )
+                .show_ui(ui, |ui| {
+                    for info in token_infos.iter() {
+                        ui.selectable_value(
+                            &mut self.counter_token_id,
+                            info.token_id,
+                            info.symbol.clone(),
+                        );
+                    }
+                });
+        });
+
+        // In these states, we can't proceed, don't render any more ui.
+        if self.base_token_id == self.counter_token_id {
+            return;
+        }
+
+        let base_token_info = match base_token_info {
+            Some(base_token_info) => base_token_info,
+            None => {
+                return;
+            }
+        };
+
+        let counter_token_info = match counter_token_info {
+            Some(counter_token_info) => counter_token_info,
+            None => {
+                return;
+            }
+        };
+
+        let best_prices = worker
+            .get_best_prices(caller, self.base_token_id, self.counter_token_id)
+            .unwrap_or_default();
+
+        // User-specified price for base-token in terms of counter token
+        ui.horizontal(|ui| {
+            ui.label(format!("Price ({})", counter_token_info.symbol.clone()));
+            ui.text_edit_singleline(&mut self.offer_price);
+            let (best_bid, best_ask) = best_prices.unzip();
+            if ui
+                .add_enabled(best_bid.is_some(), Button::new("Use best bid").small())
+                .clicked()
+            {
+                if let Some(best_bid) = best_bid {
+                    self.offer_price = best_bid.normalize().to_string();
+                }
+            }
+            if ui
+                .add_enabled(best_ask.is_some(), Button::new("Use best ask").small())
+                .clicked()
+            {
+                if let Some(best_ask) = best_ask {
+                    self.offer_price = best_ask.normalize().to_string();
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label(format!("Volume ({})", base_token_info.symbol.clone()));
+            ui.text_edit_singleline(&mut self.offer_volume);
+        });
+        ui.collapsing("Advanced", |ui| {
+            ui.horizontal(|ui| {
+                ui.label(format!("Minimum fill ({})", base_token_info.symbol));
+                ui.add_enabled(
+                    self.allow_partial_fills,
+                    egui::TextEdit::singleline(&mut self.offer_min_fill),
+                )
+                .on_hover_text(format!(
+                    "The smallest part of the offer which can be filled. \
+                     If blank, {} times the network fee.",
+                    FillMode::DEFAULT_MIN_FILL_FEES
+                ));
+            });
+        });
+        Self::sync_qualifier(ui, balances_complete);
+
+        let base_volume = Decimal::from_str(&self.offer_volume).map_err(|err| err.to_string());
+        let price = Decimal::from_str(&self.offer_price).map_err(|err| err.to_string());
+
+        // The minimum fill the user picked, if any, in the token each side offers.
+        // The worker clamps it between the network fee and the offered volume.
+        let min_fill_str = Some(self.offer_min_fill.trim())
+            .filter(|min_fill| self.allow_partial_fills && !min_fill.is_empty());
+        let sell_min_fill: Result<Option<u64>, String> = min_fill_str
+            .map(|min_fill| base_token_info.try_scaled_to_u64(min_fill))
+            .transpose()
+            .map_err(|err| format!("Invalid minimum fill: {err}"));
+        let buy_min_fill: Result<Option<u64>, String> = min_fill_str
+            .map(|min_fill| {
+                let min_fill = Decimal::from_str(min_fill).map_err(|err| err.to_string())?;
+                let counter_min_fill = min_fill
+                    .checked_mul(price.clone()?)
+                    .ok_or_else(|| "decimal overflow".to_owned())?;
+                counter_token_info.try_decimal_to_u64_rounded(counter_min_fill)
+            })
+            .transpose()
+            .map_err(|err| format!("Invalid minimum fill: {err}"));
+        let counter_volume = base_volume.clone().and_then(|base_volume_decimal| {
+            price.and_then(|price_decimal| {
+                base_volume_decimal
+                    .checked_mul(price_decimal)
+                    .ok_or_else(|| "decimal overflow".to_owned())
+            })
+        });
+        let base_amount =
+            base_volume.and_then(|base_vol| ScaledAmount::from_decimal(base_vol, base_token_info));
+        let counter_amount = counter_volume.and_then(|counter_vol| {
+            ScaledAmount::from_decimal_rounded(counter_vol, counter_token_info)
+        });
+
+        // Computes the hint text for the buy button. The result is Ok if we can buy,
+        // and Err if we cannot buy for some reason.
+        let mut buy_is_possible: Result<String, String> =
+            counter_amount.clone().and_then(|counter_amount| {
+                base_amount.clone().and_then(|base_amount| {
+                    preflight::check_offer(&balance_snapshot, counter_amount).map_err(|err| {
+                        match err {
+                            PreconditionFailed::InsufficientFunds { .. } => {
+                                format!("Insufficient {}", counter_token_info.symbol)
+                            }
+                            err => err.to_string(),
+                        }
+                    })?;
+                    Ok(format!(
+                        "Offer to trade {}\n for {}",
+                        counter_token_info.format_u64(counter_amount.raw),
+                        base_token_info.format_u64(base_amount.raw)
+                    ))
+                })
+            });
+
+        // Computes the hint text for the sell button. The result is Ok if we can sell,
+        // and Err if we cannot sell for some reason.
+        let mut sell_is_possible: Result<String, String> =
+            base_amount.clone().and_then(|base_amount| {
+                counter_amount.clone().and_then(|counter_amount| {
+                    preflight::check_offer(&balance_snapshot, base_amount).map_err(
+                        |err| match err {
+                            PreconditionFailed::InsufficientFunds { .. } => {
+                                format!("Insufficient {}", base_token_info.symbol)
+                            }
+                            err => err.to_string(),
+                        },
+                    )?;
+                    Ok(format!(
+                        "Offer to trade {}\n for {}",
+                        base_token_info.format_u64(base_amount.raw),
+                        counter_token_info.format_u64(counter_amount.raw)
+                    ))
+                })
+            });
+
+        buy_is_possible = buy_is_possible.and_then(|hint| buy_min_fill.clone().map(|_| hint));
+        sell_is_possible = sell_is_possible.and_then(|hint| sell_min_fill.clone().map(|_| hint));
+
+        // Offers are generated one at a time, so wait for the one in progress
+        let progress = worker.get_operation_progress();
+        if let Some(progress) = progress {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label(format!("Offer in progress: {progress}"));
+            });
+            let busy = "Wait for the offer in progress".to_string();
+            buy_is_possible = Err(busy.clone());
+            sell_is_possible = Err(busy);
+        } else if worker.command_in_flight(|command| {
+            matches!(
+                command,
+                WorkerCommand::OfferSwap { .. } | WorkerCommand::PreviewOffer { .. }
+            )
+        }) {
+            // The offer is queued, but the worker didn't start on it yet, and a
+            // double click mustn't submit it twice
+            let busy = "Wait for the offer being submitted".to_string();
+            buy_is_possible = Err(busy.clone());
+            sell_is_possible = Err(busy);
+        }
+        if worker.is_watch_only() {
+            let watch_only = CommandError::WatchOnly.to_string();
+            buy_is_possible = Err(watch_only.clone());
+            sell_is_possible = Err(watch_only);
+        }
+        let buy_hint_text = match buy_is_possible.as_ref() {
+            Ok(text) => text,
+            Err(text) => text,
+        };
+        let sell_hint_text = match sell_is_possible.as_ref() {
+            Ok(text) => text,
+            Err(text) => text,
+        };
+
+        // Add buy and sell buttons, and the toggle to preview offers before submitting
+        let mut new_offer: Option<(Amount, Amount, Option<u64>)> = None;
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(buy_is_possible.is_ok(), Button::new("Buy"))
+                .on_hover_text(buy_hint_text)
+                .on_disabled_hover_text(buy_hint_text)
+                .clicked()
+            {
+                let from_amount = counter_amount.clone().unwrap().into();
+                let to_amount = base_amount.clone().unwrap().into();
+                let min_fill_value = buy_min_fill.clone().unwrap();
+                new_offer = Some((from_amount, to_amount, min_fill_value));
+            }
+            if ui
+                .add_enabled(sell_is_possible.is_ok(), Button::new("Sell"))
+                .on_hover_text(sell_hint_text)
+                .on_disabled_hover_text(sell_hint_text)
+                .clicked()
+            {
+                let from_amount = base_amount.unwrap().into();
+                let to_amount = counter_amount.unwrap().into();
+                let min_fill_value = sell_min_fill.clone().unwrap();
+                new_offer = Some((from_amount, to_amount, min_fill_value));
+            }
+            ui.toggle_value(&mut self.preview_offers, "Preview")
+                .on_hover_text("Review the sci mobilecoind signs before it is submitted");
+            ui.checkbox(&mut self.allow_partial_fills, "allow partial fills")
+                .on_hover_text(
+                    "If unchecked, the offer can only be filled entirely, or not at all",
+                );
+            ui.checkbox(&mut self.keep_offers_alive, "keep alive")
+                .on_hover_text(
+                    "Submit the offer again whenever it expires, until it is filled or \
+                 cancelled, or the balance no longer covers it",
+                );
+        });
+        Self::precondition_notice(ui, &mut self.precondition_changed);
+        if let Some((from_amount, to_amount, min_fill_value)) = new_offer {
+            let fill_mode = if self.allow_partial_fills {
+                FillMode::Partial { min_fill_value }
+            } else {
+                FillMode::AllOrNothing
+            };
+            let result = if self.preview_offers {
+                worker
+                    .preview_offer(caller, from_amount, to_amount, 0, fill_mode)
+                    .map(|_| ())
+            } else {
+                worker
+                    .offer_swap(
+                        caller,
+                        from_amount,
+                        to_amount,
+                        0,
+                        fill_mode,
+                        self.keep_offers_alive,
+                    )
+                    .map(|_| ())
+            };
+            Self::command_result(worker, &mut self.precondition_changed, result);
+        }
+
+        // Market makers post a bid and an ask around a mid-price at once
+        ui.collapsing("Post both sides", |ui| {
+            ui.horizontal(|ui| {
+                ui.label(format!("Mid-price ({})", counter_token_info.symbol));
+                ui.text_edit_singleline(&mut self.two_sided_mid_price);
+                if let Some((best_bid, best_ask)) = best_prices {
+                    if ui.small_button("Use mid-price").clicked() {
+                        let mid_price = (best_bid + best_ask) / Decimal::TWO;
+                        self.two_sided_mid_price = mid_price.normalize().to_string();
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Spread (%)");
+                ui.text_edit_singleline(&mut self.two_sided_spread);
+            });
+            ui.horizontal(|ui| {
+                ui.label(format!("Ask volume ({})", base_token_info.symbol));
+                ui.text_edit_singleline(&mut self.two_sided_ask_volume);
+            });
+            ui.horizontal(|ui| {
+                ui.label(format!("Bid volume ({})", base_token_info.symbol));
+                ui.text_edit_singleline(&mut self.two_sided_bid_volume);
+            });
+
+            let parse = |src: &str, what: &str| {
+                Decimal::from_str(src.trim()).map_err(|err| format!("{what}: {err}"))
+            };
+            let quote: Result<TwoSidedQuote, String> =
+                parse(&self.two_sided_mid_price, "Mid-price").and_then(|mid_price| {
+                    TwoSidedQuote::new(
+                        mid_price,
+                        parse(&self.two_sided_spread, "Spread")? / Decimal::ONE_HUNDRED,
+                        parse(&self.two_sided_ask_volume, "Ask volume")?,
+                        parse(&self.two_sided_bid_volume, "Bid volume")?,
+                        base_token_info,
+                        counter_token_info,
+                    )
+                });
+            let quote = quote.and_then(|quote| {
+                if worker.is_watch_only() {
+                    return Err(CommandError::WatchOnly.to_string());
+                }
+                Ok(quote)
+            });
+            let hint = match (&quote, &progress) {
+                (Err(err), _) => err.clone(),
+                (Ok(_), Some(_)) => "Wait for the offer in progress".to_owned(),
+                (Ok(quote), None) => format!(
+                    "Offer {} for {}\nand {} for {}",
+                    TokenInfo::format_amount(token_infos, quote.ask.0),
+                    TokenInfo::format_amount(token_infos, quote.ask.1),
+                    TokenInfo::format_amount(token_infos, quote.bid.0),
+                    TokenInfo::format_amount(token_infos, quote.bid.1),
+                ),
+            };
+            let enabled = quote.is_ok() && progress.is_none();
+            if ui
+                .add_enabled(enabled, Button::new("Post both sides"))
+                .on_hover_text(&hint)
+                .on_disabled_hover_text(&hint)
+                .clicked()
+            {
+                let fill_mode = if self.allow_partial_fills {
+                    FillMode::Partial {
+                        min_fill_value: None,
+                    }
+                } else {
+                    FillMode::AllOrNothing
+                };
+                let result = worker
+                    .offer_both_sides(caller, quote.unwrap(), 0, fill_mode, self.keep_offers_alive)
+                    .map(|_| ());
+                Self::command_result(worker, &mut self.precondition_changed, result);
+            }
+        });
+
+        ui.separator();
+
+        // Where the mid-price has been during the session
+        egui::CollapsingHeader::new(format!("Mid-price ({})", counter_token_info.symbol))
+            .default_open(true)
+            .show(ui, |ui| {
+                let samples = worker.get_price_history((self.base_token_id, self.counter_token_id));
+                Self::price_chart(ui, &samples);
+            });
+
+        // Show the quote book, once either side was polled
+        let book_ages = [
+            worker.get_quote_book_age(self.base_token_id, self.counter_token_id),
+            worker.get_quote_book_age(self.counter_token_id, self.base_token_id),
+        ];
+        if book_ages.iter().all(Option::is_none) {
+            Self::loading_quotes(ui);
+            return;
+        }
+
+        let books = [
+            worker
+                .get_quote_book(caller, self.base_token_id, self.counter_token_id)
+                .unwrap_or_default(),
+            worker
+                .get_quote_book(caller, self.counter_token_id, self.base_token_id)
+                .unwrap_or_default(),
+        ];
+        let headings = ["Bid", "Ask"];
+
+        // Offers we are still submitting are shown in the book right away,
+        // until the deqs returns them to us
+        let pending_offers: Vec<QuoteInfo> = offers
+            .iter()
+            .filter(|offer| offer.status == OfferStatus::Submitting)
+            .filter(|offer| {
+                !books
+                    .iter()
+                    .flat_map(|book| book.iter())
+                    .any(|quote| Some(quote.id.key_image) == offer.key_image)
+            })
+            .filter_map(|offer| {
+                offer
+                    .get_quote_info(self.base_token_id, self.counter_token_id, token_infos)
+                    .ok()
+            })
+            .collect();
+
+        let status = worker.get_deqs_status(self.base_token_id, self.counter_token_id);
+        let num_quotes = books.iter().map(|book| book.len()).sum::<usize>() + pending_offers.len();
+        if let Some(kind) = EmptyStateKind::for_quote_book(&status, num_quotes) {
+            empty_state(ui, &kind, worker);
+            return;
+        }
+
+        // The user may only want to see the quotes near the market
+        ui.horizontal(|ui| {
+            ui.label("Show quotes within (% of mid-price)");
+            ui.text_edit_singleline(&mut self.book_price_band)
+                .on_hover_text("Leave blank to show the whole book");
+        });
+        let filter = match QuoteFilter::parse_price_band(&self.book_price_band) {
+            Ok(filter) => filter,
+            Err(err) => {
+                ui.label(RichText::new(err).color(Color32::RED));
+                QuoteFilter::default()
+            }
+        };
+        if filter != worker.get_quote_filter() {
+            worker.set_quote_filter(filter);
+        }
+        let mid_price = best_prices.map(|(bid, ask)| (bid + ask) / Decimal::TWO);
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "Price levels every ({})",
+                counter_token_info.symbol
+            ));
+            ui.text_edit_singleline(&mut self.book_tick)
+                .on_hover_text("Leave blank to group quotes at the same price only");
+        });
+        let tick = match DepthLevel::parse_tick(&self.book_tick) {
+            Ok(tick) => tick,
+            Err(err) => {
+                ui.label(RichText::new(err).color(Color32::RED));
+                Decimal::ZERO
+            }
+        };
+
+        // Only recompute the rows of the tables when the books, the
+        // filter or the tick size changed
+        let rows_key = (
+            self.base_token_id,
+            self.counter_token_id,
+            [books[0].generation, books[1].generation],
+            filter,
+            tick,
+        );
+        if self
+            .book_rows
+            .as_ref()
+            .map_or(false, |rows| rows.key != rows_key)
+        {
+            self.book_rows = None;
+        }
+        let book_rows = self
+            .book_rows
+            .get_or_insert_with(|| BookRows::new(rows_key, &books, token_infos, mid_price));
+
+        let num_listed: usize = books.iter().map(|book| book.len()).sum();
+        let num_shown: usize = book_rows.rows.iter().map(Vec::len).sum();
+        ui.horizontal(|ui| {
+            if num_shown < num_listed {
+                ui.label(format!("Showing {num_shown} of {num_listed} quotes"));
+            } else {
+                ui.label(format!("Showing {num_listed} quotes"));
+            }
+            // The older of the two sides
+            Self::book_age(ui, book_ages.into_iter().flatten().max());
+        });
+        if let Some((best_bid, best_ask)) = best_prices {
+            let decimals = counter_token_info.decimals;
+            ui.label(format!(
+                "Spread: {} {}    Mid-price: {} {}",
+                (best_ask - best_bid).round_dp(decimals).normalize(),
+                counter_token_info.symbol,
+                mid_price.unwrap_or_default().round_dp(decimals).normalize(),
+                counter_token_info.symbol,
+            ));
+        }
+
+        if ui.button("Copy as image").clicked() {
+            let rows =
+                Self::book_snapshot_rows(&books, base_token_info, counter_token_info, token_infos);
+            let image = image_export::render_table(&rows);
+            match image_export::copy_image(&image, "book", &worker.data_dir()) {
+                Ok(None) => {}
+                Ok(Some(path)) => worker.push_notice(format!(
+                    "Clipboard images are not supported here, saved the book to {}",
+                    path.display()
+                )),
+                Err(err) => worker.push_error(format!("Could not export the book: {err}")),
+            }
+        }
+        // When several deqs are configured, mark each quote with the deqs it came from
+        let deqs_uris = worker.get_deqs_uris();
+        let show_origin = deqs_uris.len() > 1;
+
+        ScrollArea::vertical().show(ui, |ui| {
+            ui.columns(2, |columns| {
+                for idx in 0..2 {
+                    let ui = &mut columns[idx];
+
+                    ui.heading(headings[idx]);
+
+                    Grid::new(format!("{}_table", headings[idx])).show(ui, |ui| {
+                        ui.label("");
+                        ui.label("Price              ");
+                        ui.label("Volume             ");
+                        ui.label("Total              ");
+                        if show_origin {
+                            ui.label("");
+                        }
+                        ui.end_row();
+
+                        for info in pending_offers.iter().filter(|info| {
+                            matches!(
+                                (idx, &info.quote_side),
+                                (0, QuoteSide::Bid) | (1, QuoteSide::Ask)
+                            )
+                        }) {
+                            ui.label("");
+                            ui.weak(info.price.to_string());
+                            ui.weak(info.volume.to_string());
+                            ui.label("");
+                            ui.weak("submitting…");
+                            ui.end_row();
+                        }
+
+                        let rows = &book_rows.rows[idx];
+                        let levels = match &book_rows.levels[idx] {
+                            Ok(levels) => levels,
+                            Err(err) => {
+                                ui.label(RichText::new(err).color(Color32::RED));
+                                ui.end_row();
+                                return;
+                            }
+                        };
+                        let mut prev_cumulative_volume = Decimal::ZERO;
+                        for level in levels.iter() {
+                            let level_key = (idx, level.price);
+                            let expanded = self.expanded_levels.contains(&level_key);
+                            let arrow = if expanded { "⏷" } else { "⏵" };
+                            if ui.small_button(arrow).clicked() {
+                                if expanded {
+                                    self.expanded_levels.remove(&level_key);
+                                } else {
+                                    self.expanded_levels.insert(level_key);
+                                }
+                            }
+                            let has_mine = level.quotes.iter().any(|i| rows[*i].1.is_mine);
+                            let color = if has_mine {
+                                Color32::LIGHT_BLUE
+                            } else {
+                                ui.visuals().text_color()
+                            };
+                            ui.colored_label(color, level.price.to_string())
+                                .on_hover_text(format!("{} quotes", level.quotes.len()));
+                            ui.colored_label(
+                                color,
+                                (level.cumulative_volume - prev_cumulative_volume).to_string(),
+                            );
+                            ui.label(level.cumulative_volume.to_string());
+                            prev_cumulative_volume = level.cumulative_volume;
+                            if show_origin {
+                                ui.label("");
+                            }
+                            ui.end_row();
+
+                            if !expanded {
+                                continue;
+                            }
+                            for (source, info) in level.quotes.iter().map(|i| &rows[*i]) {
+                                ui.label("");
+                                if info.is_mine {
+                                    ui.colored_label(Color32::LIGHT_BLUE, info.price.to_string())
+                                        .on_hover_text("Your offer");
+                                    ui.colored_label(Color32::LIGHT_BLUE, info.volume.to_string());
+                                } else {
+                                    ui.weak(info.price.to_string());
+                                    ui.weak(info.volume.to_string());
+                                }
+                                ui.label("");
+                                if show_origin {
+                                    ui.small(format!("#{}", source + 1))
+                                        .on_hover_text(deqs_uris[*source].clone());
+                                }
+                                ui.end_row();
+                            }
+                        }
+                    });
+                }
+            });
+        });
+    }
+
+    /// Helper which renders the Settings mode
+    fn settings_panel(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Settings");
+        ui.horizontal(|ui| {
+            ui.label("UI scale");
+            // Rescaling moves the slider from under the pointer, so the scale is only
+            // applied once the slider is let go
+            let mut value = self.ui_scale_draft.unwrap_or_else(|| self.ui_scale());
+            let response = ui.add(Slider::new(&mut value, UI_SCALE_RANGE).step_by(0.25));
+            if response.dragged() {
+                self.ui_scale_draft = Some(value);
+            } else if response.drag_released() || response.changed() {
+                self.ui_scale = Some(value);
+                self.ui_scale_draft = None;
+            }
+            if ui
+                .add_enabled(self.ui_scale.is_some(), Button::new("Native"))
+                .on_hover_text("Scale the UI like the rest of the system")
+                .clicked()
+            {
+                self.ui_scale = None;
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Theme");
+            let before = self.theme;
+            ui.radio_value(&mut self.theme, ThemePreference::FollowSystem, "System")
+                .on_hover_text("Follow the theme of the OS");
+            ui.radio_value(&mut self.theme, ThemePreference::Dark, "Dark");
+            ui.radio_value(&mut self.theme, ThemePreference::Light, "Light");
+            if self.theme != before {
+                self.apply_theme(ui.ctx());
+            }
+        });
+        ui.checkbox(&mut self.persist_form_values, "Remember form values")
+            .on_hover_text(
+                "Save the recipient, the amounts and the offer fields across restarts. \
+                 They are saved in plaintext.",
+            );
+    }
+
+    /// The pair whose quote books the current mode shows, if any.
+    ///
+    /// Swap looks at the book of the token it gets for the token it pays, and Offer
+    /// Swap at the base / counter pair of its own selectors.
+    fn polled_pair(&self) -> Option<(TokenId, TokenId)> {
+        match self.mode {
+            Mode::Swap => Some((self.swap_to_token_id, self.swap_from_token_id)),
+            Mode::OfferSwap => Some((self.base_token_id, self.counter_token_id)),
+            Mode::Assets | Mode::Send | Mode::Receive | Mode::History | Mode::Settings => None,
+        }
+    }
+
+    /// Helper which switches to a mode, and tells the worker which pair to poll the
+    /// deqs for in that mode
+    fn switch_mode(&mut self, mode: Mode, worker: &Worker) {
+        self.mode = mode;
+        self.sync_polled_pair(worker);
+    }
+
+    /// Helper which tells the worker which pair to poll the deqs for in the current
+    /// mode. Called every frame, so that the pair follows the mode however it changed.
+    fn sync_polled_pair(&self, worker: &Worker) {
+        worker.set_active_pair(self.polled_pair());
+    }
+
+    /// Helper which renders the approximate value in USD of an amount of a token, or
+    /// nothing if the token has no recent price
+    fn fiat_estimate(ui: &mut egui::Ui, worker: &Worker, info: &TokenInfo, value: u64) {
+        let estimate = worker
+            .get_indicative_price(info.token_id)
+            .and_then(|price| fiat_value(info, value, price));
+        match estimate {
+            Some(estimate) => {
+                ui.label(RichText::new(format_fiat(estimate)).weak())
+                    .on_hover_text("Estimated from the mid-price of the deqs books against EUSD");
+            }
+            None => {
+                ui.label("");
+            }
+        }
+    }
+
+    /// Helper which charts the sampled mid-prices of a pair against how many minutes
+    /// ago they were sampled. A sample without a mid-price breaks the line, rather
+    /// than the line being drawn across it.
+    fn price_chart(ui: &mut egui::Ui, samples: &[PriceSample]) {
+        let now = Instant::now();
+        let mut segments: Vec<Vec<[f64; 2]>> = vec![Vec::new()];
+        for sample in samples {
+            match sample.price.and_then(|price| price.to_f64()) {
+                Some(price) => {
+                    let minutes_ago = now.saturating_duration_since(sample.at).as_secs_f64() / 60.0;
+                    if let Some(segment) = segments.last_mut() {
+                        segment.push([-minutes_ago, price]);
+                    }
+                }
+                None => {
+                    if segments.last().map_or(false, |segment| !segment.is_empty()) {
+                        segments.push(Vec::new());
+                    }
+                }
+            }
+        }
+        if segments.iter().all(Vec::is_empty) {
+            ui.label("No mid-price sampled yet");
+            return;
+        }
+        Plot::new("price_chart")
+            .height(120.0)
+            .allow_scroll(false)
+            .show(ui, |plot_ui| {
+                for segment in segments {
+                    // A lone sample between gaps makes no line, so draw it as a point
+                    if segment.len() == 1 {
+                        plot_ui.points(Points::new(PlotPoints::from(segment)).radius(2.0));
+                    } else {
+                        plot_ui.line(Line::new(PlotPoints::from(segment)));
+                    }
+                }
+            });
+        ui.label(RichText::new("minutes ago").small().weak());
+    }
+
+    /// Helper which renders a placeholder for a quote book which wasn't polled yet
+    fn loading_quotes(ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.spinner();
+            ui.label("loading quotes…");
+        });
+    }
+
+    /// How old a quote book may get before its age is shown in red
+    const BOOK_AGE_WARNING: Duration = Duration::from_secs(10);
+
+    /// Whether a quote book of this age is getting old
+    fn is_book_old(age: Duration) -> bool {
+        age > Self::BOOK_AGE_WARNING
+    }
+
+    /// Helper which renders how long ago a quote book was updated, in red once it is
+    /// getting old, since the deqs may have stopped responding.
+    fn book_age(ui: &mut egui::Ui, age: Option<Duration>) {
+        if let Some(age) = age {
+            let text = format!("updated {}s ago", age.as_secs());
+            if Self::is_book_old(age) {
+                ui.colored_label(Color32::RED, text);
+            } else {
+                ui.weak(text);
+            }
+        }
+    }
+
+    /// Helper which renders a spinner and an explanation when the balances are incomplete,
+    /// because the monitor is still scanning the ledger.
+    fn sync_qualifier(ui: &mut egui::Ui, balances_complete: &Result<(), String>) {
+        if let Err(msg) = balances_complete {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label(msg.as_str());
+            });
+        }
+    }
+}
+
+impl eframe::App for App {
+    /// Called by the frame work to save state before shutdown.
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        self.schema_version = SCHEMA_VERSION;
+        let mut state = match serde_json::to_value(&*self) {
+            Ok(state) => state,
+            Err(err) => {
+                event!(Level::ERROR, "serializing the app state: {}", err);
+                return;
+            }
+        };
+        if !self.persist_form_values {
+            if let Some(fields) = state.as_object_mut() {
+                fields.retain(|key, _| !SENSITIVE_FIELDS.contains(&key.as_str()));
+            }
+        }
+        let json = state.to_string();
+        // The unversioned state could hold form values, so it isn't kept around
+        if storage
+            .get_string(eframe::APP_KEY)
+            .map_or(false, |legacy| !legacy.is_empty())
+        {
+            storage.set_string(eframe::APP_KEY, String::new());
+        }
+        match self
+            .config
+            .as_ref()
+            .and_then(|config| config.data_dir.as_ref())
+        {
+            Some(dir) => {
+                let path = dir.join(STATE_FILE);
+                if let Err(err) =
+                    std::fs::create_dir_all(dir).and_then(|()| std::fs::write(&path, json))
+                {
+                    event!(
+                        Level::ERROR,
+                        "saving the app state to {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+            }
+            None => storage.set_string(STATE_KEY, json),
+        }
+    }
+
+    /// Called when the user closes the window. The worker is stopped here rather than
+    /// whenever it gets dropped, so that a submission in flight can finish.
+    fn on_close_event(&mut self) -> bool {
+        if let Some(worker) = self.worker.as_ref() {
+            worker.shutdown();
+        }
+        true
     }
 
     /// Called each time the UI needs repainting, which may be many times per second.
     /// Put your widgets into a `SidePanel`, `TopPanel`, `CentralPanel`, `Window` or `Area`.
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        let worker = self
-            .worker
-            .as_mut()
-            .expect("intialization failed, no worker is present");
-
-        // Makes the font appear large enough to read
-        ctx.set_pixels_per_point(4.0);
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        // Scale the UI as the user chose, or else like the OS does. Setting the scale
+        // lays everything out again, so it is only done when the scale changes.
+        let info = frame.info();
+        self.native_scale = info.native_pixels_per_point;
+        self.system_theme = info.system_theme;
+        self.apply_theme(ctx);
+        let ui_scale = self.ui_scale();
+        if self.applied_scale != Some(ui_scale) {
+            ctx.set_pixels_per_point(ui_scale);
+            self.applied_scale = Some(ui_scale);
+        }
+
+        // Hold our own handle to the worker, so that it doesn't keep self borrowed.
+        // There is none until an encrypted keyfile is unlocked, or a keyfile is created.
+        let worker = &match self.worker.clone() {
+            Some(worker) => worker,
+            None if self.first_run.is_some() => {
+                self.first_run_screen(ctx);
+                return;
+            }
+            None if self.startup_error.is_some() => {
+                self.startup_error_screen(ctx);
+                return;
+            }
+            None => {
+                self.unlock_screen(ctx);
+                return;
+            }
+        };
+        // The UI may do everything
+        let caller = AccessToken::ui();
+
+        // Let the worker know if we are being looked at, so it can poll less when we aren't
+        let observed_activity = if frame.info().window_info.minimized {
+            ActivityLevel::Hidden
+        } else if ctx.input(|i| i.focused) {
+            ActivityLevel::Active
+        } else {
+            ActivityLevel::Background
+        };
+        if let Some(level) = self
+            .activity_debouncer
+            .observe(observed_activity, Instant::now())
+        {
+            worker.set_activity_level(level);
+        }
+
         // Make the app redraw itself even without movement
         ctx.request_repaint_after(Duration::from_millis(100));
 
+        // Every panel needs the monitor, so nothing else is shown until the account
+        // is registered with mobilecoind
+        if !matches!(worker.init_status(), InitStatus::Ready) {
+            Self::connecting_screen(ctx, worker);
+            return;
+        }
+
+        // The pair to poll follows the mode every frame, before any book is read, so
+        // that a mode restored from the saved state polls from the first frame, and
+        // leaving the swap modes by any path stops polling
+        self.sync_polled_pair(worker);
+        self.clear_sent_payments(worker);
+
         // The top panel is always shown no matter what mode we are in,
         // it shows the public address and sync %
         TopBottomPanel::top("top_panel").show(ctx, |ui| {
             ui.with_layout(Layout::top_down(Align::Center), |ui| {
                 // Add a display of the network we are connected to
-                ui.strong(format!("Network: {}", worker.get_chain_id()));
+                let chain_id = worker.get_chain_id();
+                match NetworkKind::from_chain_id(&chain_id) {
+                    Some(kind) => ui.strong(format!("Network: {} ({chain_id})", kind.name())),
+                    None => ui.strong("Network: connecting..."),
+                };
+                if worker.is_watch_only() {
+                    ui.label("Watch-only: nothing can be sent, swapped or offered");
+                }
 
                 // Add a display of the public address, and a copy button
                 let public_address = worker.get_b58_address();
@@ -167,29 +3308,61 @@ impl eframe::App for App {
                         &public_address[..8],
                         &public_address[public_address.len() - 8..]
                     ))
+                    .on_hover_text(public_address.as_str())
                     .clicked()
                 {
-                    ui.output_mut(|o| o.copied_text = public_address);
+                    Self::copy_text(ui, &mut self.toast, public_address, "the public address");
                 }
 
-                // Add a display of the sync %
+                // Add a display of the sync progress
                 let (synced_blocks, total_blocks) = worker.get_sync_progress();
-                let fraction = synced_blocks as f64 / total_blocks as f64;
-                let sync_percent = format!("{:.1}", fraction * 100f64);
-                ui.label(format!(
-                    "Ledger sync: {sync_percent}% ({synced_blocks} / {total_blocks})"
-                ));
+                let (mobilecoind_health, deqs_health) = worker.get_connection_health();
+                ui.horizontal(|ui| {
+                    ui.label("Ledger sync:");
+                    let progress_bar = match worker.get_sync_progress_fraction() {
+                        Some(fraction) => ProgressBar::new(fraction).text(format!(
+                            "{:.1}% ({synced_blocks} / {total_blocks})",
+                            fraction * 100.0
+                        )),
+                        None => ProgressBar::new(0.0).animate(true).text("syncing…"),
+                    };
+                    ui.add(progress_bar.desired_width(240.0));
+                    Self::health_dot(
+                        ui,
+                        &mobilecoind_health,
+                        &format!("mobilecoind at {}", worker.get_mobilecoind_uri()),
+                    );
+                    if let Some(deqs_health) = deqs_health {
+                        Self::health_dot(
+                            ui,
+                            &deqs_health,
+                            &format!("deqs at {}", worker.get_deqs_uris().join(", ")),
+                        );
+                    }
+                });
 
                 // Add a warning if we have a debug build
                 egui::warn_if_debug_build(ui);
 
+                // Show what the worker recovered from, if it just did
+                if let Some(notice) = worker.get_notice() {
+                    ui.label(
+                        RichText::new(notice)
+                            .color(Self::severity_color(ui.visuals(), Severity::Info)),
+                    );
+                }
+
                 // Check if the worker has reported any error, if so, show it
                 ui.horizontal(|ui| {
-                    if let Some(err_str) = worker.top_error() {
+                    ui.toggle_value(&mut self.show_activity, "Activity");
+                    if let Some(entry) = worker.top_error() {
                         if ui.button("⊗").clicked() {
                             worker.pop_error();
                         }
-                        ui.label(RichText::new(err_str).color(Color32::from_rgb(255, 0, 0)));
+                        ui.label(
+                            RichText::new(entry.describe())
+                                .color(Self::severity_color(ui.visuals(), entry.severity)),
+                        );
                     } else {
                         ui.label("");
                     }
@@ -199,35 +3372,40 @@ impl eframe::App for App {
 
         // The bottom panel is always shown, it allows the user to switch modes.
         TopBottomPanel::bottom("bottom_panel").show(ctx, |ui| {
-            ui.columns(4, |columns| {
+            ui.columns(7, |columns| {
                 columns[0].vertical_centered(|ui| {
                     if ui.button("Assets").clicked() {
-                        self.mode = Mode::Assets;
-                        worker.stop_quotes();
+                        self.switch_mode(Mode::Assets, worker);
                     }
                 });
                 columns[1].vertical_centered(|ui| {
                     if ui.button("Send").clicked() {
-                        self.mode = Mode::Send;
-                        worker.stop_quotes();
+                        self.switch_mode(Mode::Send, worker);
                     }
                 });
                 columns[2].vertical_centered(|ui| {
-                    if ui.button("Swap").clicked() {
-                        self.mode = Mode::Swap;
-                        worker.get_quotes_for_token_ids(
-                            self.swap_to_token_id,
-                            self.swap_from_token_id,
-                        );
+                    if ui.button("Receive").clicked() {
+                        self.switch_mode(Mode::Receive, worker);
                     }
                 });
                 columns[3].vertical_centered(|ui| {
+                    if ui.button("History").clicked() {
+                        self.switch_mode(Mode::History, worker);
+                    }
+                });
+                columns[4].vertical_centered(|ui| {
+                    if ui.button("Swap").clicked() {
+                        self.switch_mode(Mode::Swap, worker);
+                    }
+                });
+                columns[5].vertical_centered(|ui| {
                     if ui.button("Offer Swap").clicked() {
-                        self.mode = Mode::OfferSwap;
-                        worker.get_quotes_for_token_ids(
-                            self.swap_to_token_id,
-                            self.swap_from_token_id,
-                        );
+                        self.switch_mode(Mode::OfferSwap, worker);
+                    }
+                });
+                columns[6].vertical_centered(|ui| {
+                    if ui.button("Settings").clicked() {
+                        self.switch_mode(Mode::Settings, worker);
                     }
                 });
             });
@@ -237,438 +3415,670 @@ impl eframe::App for App {
         // This contains whatever ui elements are needed for the current mode.
         CentralPanel::default().show(ctx, |ui| {
             let token_infos = worker.get_token_info();
-            let mut balances = worker.get_balances();
+            let balance_snapshot = worker.get_balances(&caller).unwrap_or_default();
+            // Balances may be missing funds while the monitor is still scanning the ledger
+            let balances_complete = balance_snapshot.require_complete();
+            let mut balances = balance_snapshot.balances.clone();
 
             match self.mode {
                 Mode::Assets => {
                     ui.heading("Assets");
+                    Self::sync_qualifier(ui, &balances_complete);
+                    ui.toggle_value(&mut self.show_subaddress_balances, "By subaddress");
 
+                    let by_subaddress = if self.show_subaddress_balances {
+                        worker
+                            .get_balances_by_subaddress(&caller)
+                            .unwrap_or_default()
+                    } else {
+                        Default::default()
+                    };
+
+                    // Change from our own payments which isn't synced yet, so that funds
+                    // don't look missing right after a payment
+                    let detailed = worker.get_balances_detailed(&caller).unwrap_or_default();
                     Grid::new("assets_table").show(ui, |ui| {
                         for token_info in token_infos.iter() {
                             ui.label(token_info.symbol.clone());
                             let value = balances.entry(token_info.token_id).or_default();
-                            let value_i64 = i64::try_from(*value).unwrap_or(i64::MAX);
-                            let scaled_value = Decimal::new(value_i64, token_info.decimals);
-                            ui.label(scaled_value.to_string());
+                            let detail = detailed.get(&token_info.token_id).copied();
+                            match detail.filter(|detail| {
+                                detail.pending_incoming > 0 || detail.pending_outgoing > 0
+                            }) {
+                                Some(detail) => {
+                                    ui.label(format!(
+                                        "{} (+{} pending)",
+                                        token_info.format_u64(*value),
+                                        token_info.format_u64_compact(detail.pending_incoming),
+                                    ))
+                                    .on_hover_text(format!(
+                                        "{} is being paid by transactions which didn't land \
+                                         yet, and {} comes back as change once they do. Only \
+                                         {} can be spent until then.",
+                                        token_info.format_u64(detail.pending_outgoing),
+                                        token_info.format_u64(detail.pending_incoming),
+                                        token_info.format_u64(detail.spendable),
+                                    ));
+                                }
+                                None => {
+                                    ui.label(token_info.format_u64(*value));
+                                }
+                            }
+                            Self::fiat_estimate(ui, worker, token_info, *value);
                             ui.end_row();
+
+                            // Where the funds of this token sit
+                            for (index, subaddress_balances) in by_subaddress.iter() {
+                                let value = subaddress_balances
+                                    .get(&token_info.token_id)
+                                    .cloned()
+                                    .unwrap_or(0);
+                                if value == 0 {
+                                    continue;
+                                }
+                                ui.label(RichText::new(format!("  subaddress {index}")).small());
+                                ui.label(RichText::new(token_info.format_u64(value)).small());
+                                ui.end_row();
+                            }
                         }
                     });
+
+                    ui.separator();
+                    Self::coins(ui, worker, &caller, &token_infos, &mut self.selected_coins);
+
+                    if let Some(kind) = EmptyStateKind::for_assets(
+                        &balances,
+                        &worker.get_b58_address(),
+                        &worker.get_chain_id(),
+                    ) {
+                        ui.separator();
+                        empty_state(ui, &kind, worker);
+                    }
                 }
                 Mode::Send => {
-                    ui.heading("Send");
-
-                    ui.horizontal(|ui| {
-                        ui.label("Recipient b58 address: ");
-                        ui.text_edit_singleline(&mut self.send_to);
-                    });
+                    self.send_panel(ui, worker, &caller, &token_infos, &balances_complete)
+                }
+                Mode::Receive => {
+                    ui.heading("Receive");
 
                     Self::amount_selector(
                         ui,
-                        "Amount",
+                        "Request",
                         &token_infos,
-                        &mut self.send_token_id,
-                        &mut self.send_value,
+                        &mut self.receive_token_id,
+                        &mut self.receive_value,
+                        None,
                     );
-
-                    let current_token_info: Option<&TokenInfo> = token_infos
-                        .iter()
-                        .find(|info| info.token_id == self.send_token_id);
+                    ui.horizontal(|ui| {
+                        ui.label("Memo (optional): ");
+                        ui.text_edit_singleline(&mut self.receive_memo);
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("QR size: ");
+                        ui.add(Slider::new(&mut self.qr_size, Self::QR_SIZE_RANGE));
+                        ui.label("Save PNG to: ");
+                        ui.text_edit_singleline(&mut self.qr_export_path);
+                    });
 
                     let scaled_value_str = self
-                        .send_value
-                        .entry(self.send_token_id)
+                        .receive_value
+                        .entry(self.receive_token_id)
                         .or_insert_with(|| "0".to_string());
 
-                    match current_token_info.as_ref() {
-                        Some(info) => {
-                            let scale = Decimal::new(1, info.decimals);
-                            if let Some(balance) =
-                                Decimal::from(*balances.entry(self.send_token_id).or_default())
-                                    .checked_mul(scale)
-                            {
-                                ui.label(format!("balance: {}", balance));
-                            } else {
-                                ui.label("balance: (overflow)");
-                            }
-                            if let Some(fee) = Decimal::from(info.fee).checked_mul(scale) {
-                                ui.label(format!("fee: {}", fee));
-                            } else {
-                                ui.label("fee: (overflow)");
-                            }
-                        }
-                        None => {
-                            ui.label("balance:");
-                            ui.label("fee:");
-                        }
-                    }
-
-                    // This either the u64 value of the token to send, or a string error to display
-                    let okay_to_submit: Result<u64, String> = current_token_info
+                    // This is either the b58 payment request code, or a string error to display
+                    let payment_request: Result<String, String> = token_infos
+                        .iter()
+                        .find(|info| info.token_id == self.receive_token_id)
                         .ok_or("select a token".to_string())
-                        .and_then(|info: &TokenInfo| -> Result<u64, String> {
-                            let u64_value = info.try_scaled_to_u64(scaled_value_str)?;
-
-                            let u64_value_with_fee = u64_value
-                                .checked_add(info.fee)
-                                .ok_or("u64 overflow with fee".to_string())?;
-                            if u64_value_with_fee > *balances.entry(self.send_token_id).or_default()
-                            {
-                                return Err("insufficient funds".to_string());
-                            }
-
-                            // Check the send_to field
-                            Worker::decode_b58_address(&self.send_to)?;
-
-                            Ok(u64_value)
+                        .and_then(|info| ScaledAmount::parse(scaled_value_str, info))
+                        .and_then(|amount| {
+                            worker.create_payment_request_b58(
+                                amount.raw,
+                                amount.token_id,
+                                &self.receive_memo,
+                            )
                         });
 
-                    match okay_to_submit {
-                        Ok(u64_value) => {
-                            ui.label("");
-                            if ui.button("Submit").clicked() {
-                                worker.send(u64_value, self.send_token_id, self.send_to.clone());
+                    match payment_request {
+                        Ok(b58_code) => {
+                            ui.label(RichText::new(b58_code.as_str()).monospace().small());
+                            if ui.button("Copy payment request 📋").clicked() {
+                                Self::copy_text(
+                                    ui,
+                                    &mut self.toast,
+                                    b58_code.clone(),
+                                    "the payment request",
+                                );
                             }
+                            Self::qr_code(
+                                ui,
+                                worker,
+                                &mut self.request_qr,
+                                &b58_code,
+                                self.qr_size,
+                                &self.qr_export_path,
+                            );
                         }
                         Err(err_str) => {
                             ui.label(err_str);
-                            ui.add_enabled(false, Button::new("Submit"));
+                            ui.add_enabled(false, Button::new("Copy payment request 📋"));
                         }
                     }
-                }
-                Mode::Swap => {
-                    ui.heading("Swap");
-
-                    if !worker.has_deqs() {
-                        ui.label("No deqs uri was configured, swap is not available.");
-                        return;
-                    }
-
-                    Self::amount_selector(
-                        ui,
-                        "Swap from",
-                        &token_infos,
-                        &mut self.swap_from_token_id,
-                        &mut self.swap_from_value,
-                    );
-                    ui.label("↓");
-                    Self::amount_selector(
-                        ui,
-                        "Swap to",
-                        &token_infos,
-                        &mut self.swap_to_token_id,
-                        &mut self.swap_to_value,
-                    );
 
-                    worker.get_quotes_for_token_ids(self.swap_to_token_id, self.swap_from_token_id);
-
-                    let quote_book =
-                        worker.get_quote_book(self.swap_to_token_id, self.swap_from_token_id);
-
-                    let swap_from_token_info: Option<&TokenInfo> = token_infos
-                        .iter()
-                        .find(|info| info.token_id == self.swap_from_token_id);
+                    ui.separator();
+                    ui.collapsing("Public address", |ui| {
+                        let public_address = worker.get_b58_address();
+                        ui.label(RichText::new(public_address.as_str()).monospace().small());
+                        Self::qr_code(
+                            ui,
+                            worker,
+                            &mut self.address_qr,
+                            &public_address,
+                            self.qr_size,
+                            &self.qr_export_path,
+                        );
+                    });
 
-                    let swap_to_token_info: Option<&TokenInfo> = token_infos
-                        .iter()
-                        .find(|info| info.token_id == self.swap_to_token_id);
-
-                    // Returns an SCI we selected to swap against, and the partial fill value to fill it to, or an error message
-                    let okay_to_submit: Result<QuoteSelection, String> = swap_from_token_info
-                        .zip(swap_to_token_info)
-                        .ok_or("".to_string())
-                        .and_then(|(from_info, to_info)| -> Result<QuoteSelection, String> {
-                            if self.swap_from_token_id == self.swap_to_token_id {
-                                return Err("".to_string());
+                    // A fresh subaddress per invoice tells apart who paid
+                    ui.separator();
+                    let issuing = self.receive_subaddress_command.is_some();
+                    if ui
+                        .add_enabled(!issuing, Button::new("New address"))
+                        .clicked()
+                    {
+                        match worker.next_receive_subaddress(&caller) {
+                            Ok(command_id) => self.receive_subaddress_command = Some(command_id),
+                            Err(err) => worker.push_error(err.to_string()),
+                        }
+                    }
+                    if let Some(command_id) = self.receive_subaddress_command {
+                        match worker.get_command_status(command_id) {
+                            Some(CommandStatus::Queued | CommandStatus::Submitting) => {
+                                ui.horizontal(|ui| {
+                                    ui.spinner();
+                                    ui.label("issuing address…");
+                                });
                             }
-
-                            let to_u64_value = to_info.try_scaled_to_u64(
-                                self.swap_to_value
-                                    .entry(self.swap_to_token_id)
-                                    .or_insert_with(|| "0".to_string()),
-                            )?;
-
-                            let to_amount = Amount::new(to_u64_value, self.swap_to_token_id);
-
-                            // TODO: If the user is modifying the swap_from_value field, it would be nice to do
-                            // quote selection based on that, and update the swap_to_value field. Uniswap works this way.
-                            // At this revision we only pay attention to the swap_to_value field, and always update swap_from_value
-                            // based on that.
-                            let qs = QuoteSelection::new(
-                                &quote_book,
-                                self.swap_from_token_id,
-                                from_info,
-                                to_amount,
-                            )?;
-
-                            // Check if we have sufficient funds to do this
-                            let from_token_balance =
-                                balances.get(&self.swap_from_token_id).cloned().unwrap_or(0);
-                            let from_token_fee = from_info.fee;
-                            if from_token_balance < qs.from_u64_value + from_token_fee {
-                                return Err("insufficient funds".to_string());
+                            Some(CommandStatus::Submitted) => {
+                                self.receive_subaddress = worker
+                                    .get_issued_subaddress(&caller, command_id)
+                                    .unwrap_or_default();
+                                self.receive_subaddress_command = None;
                             }
-                            Ok(qs)
-                        });
-
-                    match okay_to_submit {
-                        Ok(qs) => {
-                            *self
-                                .swap_from_value
-                                .entry(self.swap_from_token_id)
-                                .or_default() = qs.from_value_decimal.to_string();
-                            ui.label("");
-                            if ui.button("Submit").clicked() {
-                                // We pay the fee in the from_token_id
-                                let fee_token_id = self.swap_from_token_id;
-                                worker.perform_swap(
-                                    qs.sci,
-                                    qs.partial_fill_value,
-                                    self.swap_from_token_id,
-                                    fee_token_id,
-                                );
+                            // The worker queued the error
+                            Some(CommandStatus::Failed(_)) | None => {
+                                self.receive_subaddress_command = None;
                             }
                         }
-                        Err(err_str) => {
-                            ui.label(err_str);
-                            ui.add_enabled(false, Button::new("Submit"));
-                        }
                     }
-                }
-                Mode::OfferSwap => {
-                    ui.heading("Offer Swap");
-
-                    if !worker.has_deqs() {
-                        ui.label("No deqs uri was configured, swap is not available.");
-                        return;
+                    if let Some(b58_address) = self.receive_subaddress.as_ref() {
+                        ui.label(RichText::new(b58_address.as_str()).monospace().small());
+                        if ui.button("Copy address 📋").clicked() {
+                            Self::copy_text(
+                                ui,
+                                &mut self.toast,
+                                b58_address.clone(),
+                                "the address",
+                            );
+                        }
                     }
 
-                    let base_token_info: Option<&TokenInfo> = token_infos
-                        .iter()
-                        .find(|info| info.token_id == self.base_token_id);
+                    // Gift codes are swept into subaddress 0
+                    ui.separator();
+                    ui.collapsing("Gift code", |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("Gift code: ");
+                            ui.text_edit_singleline(&mut self.redeem_code);
+                        });
+                        let okay_to_redeem = if worker.is_watch_only() {
+                            Err(CommandError::WatchOnly.to_string())
+                        } else {
+                            Worker::decode_transfer_code(&self.redeem_code)
+                        };
+                        match okay_to_redeem {
+                            Ok(()) => {
+                                if ui.button("Redeem").clicked() {
+                                    let result =
+                                        worker.redeem_transfer_code(&caller, &self.redeem_code);
+                                    if result.is_ok() {
+                                        self.redeem_code.clear();
+                                    }
+                                    Self::command_result(
+                                        worker,
+                                        &mut self.precondition_changed,
+                                        result.map(|_| ()),
+                                    );
+                                }
+                            }
+                            Err(err_str) => {
+                                ui.label(err_str);
+                                ui.add_enabled(false, Button::new("Redeem"));
+                            }
+                        }
 
-                    let counter_token_info: Option<&TokenInfo> = token_infos
-                        .iter()
-                        .find(|info| info.token_id == self.counter_token_id);
+                        let redemptions: Vec<CommandInfo> = worker
+                            .get_commands(&caller)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .filter(|info| {
+                                matches!(info.command, WorkerCommand::RedeemGiftCode { .. })
+                            })
+                            .collect();
+                        Self::recent_payments(ui, worker, &redemptions, &token_infos);
+                    });
+                }
+                Mode::History => {
+                    ui.heading("History");
 
-                    // Show the asset pair as two side-by-side drop-down menus
                     ui.horizontal(|ui| {
-                        ComboBox::from_id_source("base_token_id")
+                        ComboBox::from_id_source("history_token")
                             .selected_text(
-                                base_token_info
+                                self.history_token_id
+                                    .and_then(|token_id| {
+                                        token_infos.iter().find(|info| info.token_id == token_id)
+                                    })
                                     .map(|info| info.symbol.clone())
-                                    .unwrap_or_default(),
+                                    .unwrap_or_else(|| "All tokens".to_owned()),
                             )
                             .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut self.history_token_id, None, "All tokens");
                                 for info in token_infos.iter() {
                                     ui.selectable_value(
-                                        &mut self.base_token_id,
-                                        info.token_id,
+                                        &mut self.history_token_id,
+                                        Some(info.token_id),
                                         info.symbol.clone(),
                                     );
                                 }
                             });
-                        ui.label("/");
-                        ComboBox::from_id_source("counter_token_id")
-                            .selected_text(
-                                counter_token_info
-                                    .map(|info| info.symbol.clone())
-                                    .unwrap_or_default(),
-                            )
-                            .show_ui(ui, |ui| {
-                                for info in token_infos.iter() {
-                                    ui.selectable_value(
-                                        &mut self.counter_token_id,
-                                        info.token_id,
-                                        info.symbol.clone(),
-                                    );
+                        ui.selectable_value(&mut self.history_direction, None, "All");
+                        ui.selectable_value(
+                            &mut self.history_direction,
+                            Some(Direction::Sent),
+                            "Sent",
+                        );
+                        ui.selectable_value(
+                            &mut self.history_direction,
+                            Some(Direction::Received),
+                            "Received",
+                        );
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.label("Export to: ");
+                        ui.text_edit_singleline(&mut self.history_export_path);
+                        if ui.button("Export CSV").clicked() {
+                            // Exporting reads the whole history, keep it off the UI thread
+                            let worker = worker.clone();
+                            let caller = caller.clone();
+                            let path = PathBuf::from(&self.history_export_path);
+                            std::thread::spawn(move || {
+                                match worker.export_history_csv(&caller, &path) {
+                                    Ok(count) => worker.push_notice(format!(
+                                        "Exported {count} history entries to {}",
+                                        path.display()
+                                    )),
+                                    Err(err) => {
+                                        worker.push_error(format!("CSV export failed: {err}"))
+                                    }
                                 }
                             });
+                        }
                     });
 
-                    worker.get_quotes_for_token_ids(self.base_token_id, self.counter_token_id);
-
-                    // In these states, we can't proceed, don't render any more ui.
-                    if self.base_token_id == self.counter_token_id {
-                        return;
-                    }
-
-                    let base_token_info = match base_token_info {
-                        Some(base_token_info) => base_token_info,
-                        None => {
-                            return;
-                        }
+                    let filter = HistoryFilter {
+                        token_id: self.history_token_id,
+                        direction: self.history_direction,
+                        ..Default::default()
                     };
+                    let (entries, more) = worker.get_history(&caller, filter).unwrap_or_default();
 
-                    let counter_token_info = match counter_token_info {
-                        Some(counter_token_info) => counter_token_info,
-                        None => {
-                            return;
-                        }
-                    };
+                    ScrollArea::vertical().show(ui, |ui| {
+                        Grid::new("history_table").striped(true).show(ui, |ui| {
+                            for entry in entries.iter() {
+                                ui.label(format!("block {}", entry.block));
+                                ui.label(match entry.direction {
+                                    Direction::Sent => "sent",
+                                    Direction::Received => "received",
+                                });
+                                ui.label(TokenInfo::format_amount(
+                                    &token_infos,
+                                    Amount::new(entry.value, entry.token_id),
+                                ));
+                                ui.end_row();
+                            }
+                        });
 
-                    // User-specified price for base-token in terms of counter token
-                    ui.horizontal(|ui| {
-                        ui.label(format!("Price ({})", counter_token_info.symbol.clone()));
-                        ui.text_edit_singleline(&mut self.offer_price);
-                    });
-                    ui.horizontal(|ui| {
-                        ui.label(format!("Volume ({})", base_token_info.symbol.clone()));
-                        ui.text_edit_singleline(&mut self.offer_volume);
+                        // Fetch the next page once the user scrolls to the bottom
+                        if more {
+                            let response = ui
+                                .horizontal(|ui| {
+                                    ui.spinner();
+                                    ui.label("loading…");
+                                })
+                                .response;
+                            if ui.is_rect_visible(response.rect) {
+                                worker.load_more_history();
+                            }
+                        } else if entries.is_empty() {
+                            ui.label("No history yet");
+                        }
                     });
+                }
+                Mode::Swap => {
+                    self.swap_panel(ui, worker, &caller, &token_infos, &balances_complete)
+                }
+                Mode::OfferSwap => {
+                    self.offer_panel(ui, worker, &caller, &token_infos, &balances_complete)
+                }
+                Mode::Settings => self.settings_panel(ui),
+            }
+        });
 
-                    let base_volume =
-                        Decimal::from_str(&self.offer_volume).map_err(|err| err.to_string());
-                    let price = Decimal::from_str(&self.offer_price).map_err(|err| err.to_string());
-                    let counter_volume = base_volume.clone().and_then(|base_volume_decimal| {
-                        price.and_then(|price_decimal| {
-                            base_volume_decimal
-                                .checked_mul(price_decimal)
-                                .ok_or_else(|| "decimal overflow".to_owned())
-                        })
-                    });
-                    let base_u64_value = base_volume
-                        .and_then(|base_vol| base_token_info.try_decimal_to_u64(base_vol));
-                    let counter_u64_value = counter_volume
-                        .and_then(|counter_vol| counter_token_info.try_decimal_to_u64(counter_vol));
-
-                    // Computes the hint text for the buy button. The result is Ok if we can buy,
-                    // and Err if we cannot buy for some reason.
-                    let buy_is_possible: Result<String, String> =
-                        counter_u64_value.clone().and_then(|counter_u64_value| {
-                            base_u64_value.clone().and_then(|base_u64_value| {
-                                if *balances.entry(self.counter_token_id).or_default()
-                                    >= counter_u64_value
-                                {
-                                    // FIXME: check for i64 overflow
-                                    Ok(format!(
-                                        "Offer to trade {} {}\n for {} {}",
-                                        Decimal::new(
-                                            counter_u64_value as i64,
-                                            counter_token_info.decimals
-                                        ),
-                                        counter_token_info.symbol,
-                                        Decimal::new(
-                                            base_u64_value as i64,
-                                            base_token_info.decimals
-                                        ),
-                                        base_token_info.symbol
-                                    ))
-                                } else {
-                                    Err(format!("Insufficient {}", counter_token_info.symbol))
-                                }
-                            })
-                        });
-                    let buy_hint_text = match buy_is_possible.as_ref() {
-                        Ok(text) => text,
-                        Err(text) => text,
-                    };
+        // Offers held for preview get a confirmation window, whatever the mode
+        let (held_offers, preview_pending) = worker.get_held_offers(&caller).unwrap_or_default();
+        Self::held_offer_windows(
+            ctx,
+            worker,
+            &caller,
+            &held_offers,
+            preview_pending,
+            &worker.get_token_info(),
+            self.keep_offers_alive,
+        );
 
-                    // Computes the hint text for the sell button. The result is Ok if we can sell,
-                    // and Err if we cannot sell for some reason.
-                    let sell_is_possible: Result<String, String> =
-                        base_u64_value.clone().and_then(|base_u64_value| {
-                            counter_u64_value.clone().and_then(|counter_u64_value| {
-                                if *balances.entry(self.base_token_id).or_default()
-                                    >= base_u64_value
-                                {
-                                    // FIXME: check for i64 overflow
-                                    Ok(format!(
-                                        "Offer to trade {} {}\n for {} {}",
-                                        Decimal::new(
-                                            base_u64_value as i64,
-                                            base_token_info.decimals
-                                        ),
-                                        base_token_info.symbol,
-                                        Decimal::new(
-                                            counter_u64_value as i64,
-                                            counter_token_info.decimals
-                                        ),
-                                        counter_token_info.symbol
-                                    ))
-                                } else {
-                                    Err(format!("Insufficient {}", base_token_info.symbol))
-                                }
-                            })
-                        });
-                    let sell_hint_text = match sell_is_possible.as_ref() {
-                        Ok(text) => text,
-                        Err(text) => text,
-                    };
+        if let Some(message) = self.toast.current(Instant::now()) {
+            egui::Area::new("toast")
+                .anchor(egui::Align2::CENTER_BOTTOM, egui::vec2(0.0, -48.0))
+                .show(ctx, |ui| {
+                    egui::Frame::popup(ui.style()).show(ui, |ui| ui.label(message));
+                });
+        }
 
-                    // Add buy and sell buttons
-                    ui.horizontal(|ui| {
-                        if ui
-                            .add_enabled(buy_is_possible.is_ok(), Button::new("Buy"))
-                            .on_hover_text(buy_hint_text)
-                            .on_disabled_hover_text(buy_hint_text)
-                            .clicked()
-                        {
-                            let from_amount = Amount::new(
-                                counter_u64_value.clone().unwrap(),
-                                self.counter_token_id,
+        Window::new("Activity")
+            .open(&mut self.show_activity)
+            .show(ctx, |ui| {
+                let events = worker.get_recent_events();
+                if events.is_empty() {
+                    ui.label("Nothing happened yet");
+                }
+                ScrollArea::vertical().show(ui, |ui| {
+                    Grid::new("activity_table").striped(true).show(ui, |ui| {
+                        for entry in events.iter() {
+                            ui.label(format!("{}s ago", entry.last_seen.elapsed().as_secs()));
+                            ui.label(
+                                RichText::new(format!("{:?}", entry.severity))
+                                    .color(Self::severity_color(ui.visuals(), entry.severity)),
                             );
-                            let to_amount =
-                                Amount::new(base_u64_value.clone().unwrap(), self.base_token_id);
-                            worker.offer_swap(from_amount, to_amount);
-                        }
-                        if ui
-                            .add_enabled(sell_is_possible.is_ok(), Button::new("Sell"))
-                            .on_hover_text(sell_hint_text)
-                            .on_disabled_hover_text(sell_hint_text)
-                            .clicked()
-                        {
-                            let from_amount =
-                                Amount::new(base_u64_value.unwrap(), self.base_token_id);
-                            let to_amount =
-                                Amount::new(counter_u64_value.unwrap(), self.counter_token_id);
-                            worker.offer_swap(from_amount, to_amount);
+                            ui.label(entry.describe());
+                            ui.end_row();
                         }
                     });
+                });
+            });
+    }
+}
 
-                    ui.separator();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                    // Show the quote book
+    #[test]
+    fn activity_is_reported_once_it_settles() {
+        let start = Instant::now();
+        let mut debouncer = ActivityDebouncer::default();
+        assert_eq!(debouncer.observe(ActivityLevel::Active, start), None);
+        let settled = start + ActivityDebouncer::SETTLE_TIME;
+        assert_eq!(
+            debouncer.observe(ActivityLevel::Active, settled),
+            Some(ActivityLevel::Active)
+        );
+        // Already reported
+        assert_eq!(
+            debouncer.observe(
+                ActivityLevel::Active,
+                settled + ActivityDebouncer::SETTLE_TIME
+            ),
+            None
+        );
+    }
 
-                    let books = [
-                        worker.get_quote_book(self.base_token_id, self.counter_token_id),
-                        worker.get_quote_book(self.counter_token_id, self.base_token_id),
-                    ];
-                    let headings = ["Bid", "Ask"];
+    #[test]
+    fn focus_flapping_is_not_reported() {
+        let start = Instant::now();
+        let mut debouncer = ActivityDebouncer::default();
+        debouncer.observe(ActivityLevel::Active, start);
+        debouncer.observe(
+            ActivityLevel::Active,
+            start + ActivityDebouncer::SETTLE_TIME,
+        );
+        let step = ActivityDebouncer::SETTLE_TIME / 4;
+        let mut now = start + ActivityDebouncer::SETTLE_TIME;
+        for _ in 0..20 {
+            now += step;
+            assert_eq!(debouncer.observe(ActivityLevel::Background, now), None);
+            now += step;
+            assert_eq!(debouncer.observe(ActivityLevel::Active, now), None);
+        }
+        // Once it stays unfocused, that is reported
+        now += step;
+        assert_eq!(debouncer.observe(ActivityLevel::Background, now), None);
+        assert_eq!(
+            debouncer.observe(
+                ActivityLevel::Background,
+                now + ActivityDebouncer::SETTLE_TIME
+            ),
+            Some(ActivityLevel::Background)
+        );
+    }
 
-                    ScrollArea::vertical().show(ui, |ui| {
-                        ui.columns(2, |columns| {
-                            for idx in 0..2 {
-                                let ui = &mut columns[idx];
-
-                                ui.heading(headings[idx]);
-
-                                Grid::new(format!("{}_table", headings[idx])).show(ui, |ui| {
-                                    ui.label("Price              ");
-                                    ui.label("Volume             ");
-                                    ui.end_row();
-
-                                    for validated_quote in books.get(idx).unwrap() {
-                                        match validated_quote.get_quote_info(
-                                            self.base_token_id,
-                                            self.counter_token_id,
-                                            &token_infos,
-                                        ) {
-                                            Ok(info) => {
-                                                ui.label(info.price.to_string());
-                                                ui.label(info.volume.to_string());
-                                                ui.end_row();
-                                            }
-                                            Err(err) => {
-                                                event!(Level::ERROR, "get quote info: {}", err);
-                                            }
-                                        }
-                                    }
-                                });
-                            }
-                        });
-                    });
-                }
+    #[test]
+    fn offer_swap_polls_the_pair_of_its_own_selectors() {
+        let mut app = App {
+            mode: Mode::OfferSwap,
+            swap_from_token_id: TokenId::from(5),
+            swap_to_token_id: TokenId::from(6),
+            ..Default::default()
+        };
+        assert_eq!(
+            app.polled_pair(),
+            Some((app.base_token_id, app.counter_token_id))
+        );
+
+        // Changing the pair drop-downs changes the book right away
+        app.base_token_id = TokenId::from(2);
+        app.counter_token_id = TokenId::from(3);
+        assert_eq!(
+            app.polled_pair(),
+            Some((TokenId::from(2), TokenId::from(3)))
+        );
+
+        // and the Swap panel's selectors only matter in Swap
+        app.mode = Mode::Swap;
+        assert_eq!(
+            app.polled_pair(),
+            Some((TokenId::from(6), TokenId::from(5)))
+        );
+    }
+
+    #[test]
+    fn only_the_swap_modes_poll_a_pair() {
+        let mut app = App {
+            swap_from_token_id: TokenId::from(1),
+            swap_to_token_id: TokenId::MOB,
+            base_token_id: TokenId::from(2),
+            counter_token_id: TokenId::from(3),
+            ..Default::default()
+        };
+        for (mode, pair) in [
+            (Mode::Assets, None),
+            (Mode::Send, None),
+            (Mode::Receive, None),
+            (Mode::History, None),
+            (Mode::Swap, Some((TokenId::MOB, TokenId::from(1)))),
+            (Mode::OfferSwap, Some((TokenId::from(2), TokenId::from(3)))),
+            (Mode::Settings, None),
+        ] {
+            app.mode = mode.clone();
+            assert_eq!(app.polled_pair(), pair, "{mode:?}");
+        }
+    }
+
+    // A watch-only worker which never reaches a mobilecoind, in a temp data dir
+    fn offline_worker(name: &str) -> (Arc<Worker>, std::path::PathBuf) {
+        use clap::Parser;
+        let dir = std::env::temp_dir().join(format!("buddy-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keyfile = dir.join("view_only.json");
+        std::fs::write(
+            &keyfile,
+            serde_json::json!({
+                "view_private_key": "01".repeat(32),
+                "spend_public_key":
+                    "e2f2ae0a6abc4e71a884a961c500515f58e30b6aa582dd8db6a65945e08d2d76",
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let config = Config::try_parse_from([
+            "mobilecoind-buddy",
+            "--watch-only",
+            "--keyfile",
+            keyfile.to_str().unwrap(),
+            "--data-dir",
+            dir.to_str().unwrap(),
+            "--mobilecoind-uri",
+            "insecure-mobilecoind://127.0.0.1:1/",
+        ])
+        .unwrap();
+        (Worker::new(config).unwrap(), dir)
+    }
+
+    #[test]
+    fn switching_modes_in_any_order_polls_the_pair_of_the_mode() {
+        let (worker, dir) = offline_worker("switch-modes");
+        let mut app = App {
+            swap_from_token_id: TokenId::from(1),
+            swap_to_token_id: TokenId::MOB,
+            base_token_id: TokenId::from(2),
+            counter_token_id: TokenId::from(3),
+            ..Default::default()
+        };
+        let modes = [
+            Mode::Assets,
+            Mode::Send,
+            Mode::Receive,
+            Mode::History,
+            Mode::Swap,
+            Mode::OfferSwap,
+            Mode::Settings,
+        ];
+        let expected = |mode: &Mode| match mode {
+            Mode::Swap => Some((TokenId::MOB, TokenId::from(1))),
+            Mode::OfferSwap => Some((TokenId::from(2), TokenId::from(3))),
+            _ => None,
+        };
+        for from in &modes {
+            for to in &modes {
+                // With the bottom bar
+                app.switch_mode(from.clone(), &worker);
+                assert_eq!(worker.get_active_pair(), expected(from), "{from:?}");
+                app.switch_mode(to.clone(), &worker);
+                assert_eq!(worker.get_active_pair(), expected(to), "{from:?} to {to:?}");
+
+                // By any other path, like a restored mode, the next frame catches up
+                app.switch_mode(from.clone(), &worker);
+                app.mode = to.clone();
+                app.sync_polled_pair(&worker);
+                assert_eq!(worker.get_active_pair(), expected(to), "{from:?} to {to:?}");
             }
-        });
+        }
+
+        // Setting the same pair again changes nothing, and stopping is idempotent
+        app.sync_polled_pair(&worker);
+        worker.stop_quotes();
+        worker.stop_quotes();
+        assert_eq!(worker.get_active_pair(), None);
+
+        worker.shutdown();
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn invalid_payments_are_not_submitted_on_enter() {
+        let (worker, dir) = offline_worker("invalid-payment");
+        let caller = AccessToken::ui();
+        let mut app = App {
+            send_to: "not an address".to_string(),
+            ..Default::default()
+        };
+        app.selected_coins.insert(KeyImage::from(1));
+        app.submit_send_form(
+            &worker,
+            &caller,
+            &Err("Invalid amount".to_string()),
+            "",
+            TxOptions::default(),
+        );
+        assert!(worker.get_commands(&caller).unwrap().is_empty());
+        assert!(app.sent_payments.is_empty());
+        assert_eq!(app.selected_coins.len(), 1);
+
+        worker.shutdown();
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn refused_payments_keep_the_selected_coins() {
+        let (worker, dir) = offline_worker("refused-payment");
+        let caller = AccessToken::ui();
+        let mut app = App::default();
+        app.selected_coins.insert(KeyImage::from(1));
+        // A watch-only worker refuses every payment, even a valid one
+        app.submit_send_form(
+            &worker,
+            &caller,
+            &Ok((1_000, 400, Some(600))),
+            "",
+            TxOptions::default(),
+        );
+        assert!(worker.get_commands(&caller).unwrap().is_empty());
+        assert!(app.sent_payments.is_empty());
+        assert_eq!(app.selected_coins.len(), 1);
+
+        worker.shutdown();
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn submitting_a_swap_ends_its_confirmation() {
+        let (worker, dir) = offline_worker("submit-swap");
+        let caller = AccessToken::ui();
+        let mut app = App {
+            swap_confirming: Some((1_000, 2_000)),
+            ..Default::default()
+        };
+        let selection = QuoteSelection {
+            fills: Vec::new(),
+            from_u64_value: 1_000,
+            to_u64_value: 2_000,
+            from_value_decimal: Decimal::ONE,
+            price: Decimal::TWO,
+        };
+        app.submit_swap(&worker, &caller, selection, TokenId::MOB, Decimal::ZERO);
+        assert_eq!(app.swap_confirming, None);
+        // Whether or not the worker accepts the swap
+        assert!(worker.get_commands(&caller).unwrap().is_empty());
+
+        worker.shutdown();
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn book_ages_turn_red_past_the_warning() {
+        assert!(!App::is_book_old(Duration::ZERO));
+        assert!(!App::is_book_old(App::BOOK_AGE_WARNING));
+        assert!(App::is_book_old(
+            App::BOOK_AGE_WARNING + Duration::from_millis(1)
+        ));
     }
 }
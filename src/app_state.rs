@@ -0,0 +1,164 @@
+//! Restoring the persisted state of the App across versions.
+//!
+//! The state is stored as json with a schema version. An older state is migrated
+//! to the current schema, and a field which no longer deserializes is dropped on
+//! its own, rather than the whole state falling back to its default.
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use tracing::{event, Level};
+
+/// The version of the schema of the persisted App state.
+///
+/// Version 1 was stored by eframe without a schema version. Bump this, and add a
+/// step to migrate, whenever a persisted field is renamed or changes meaning.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// The key the state is stored under in eframe's storage
+pub const STATE_KEY: &str = "app_state";
+
+/// The name of the file the state is stored in, when there is a --data-dir
+pub const STATE_FILE: &str = "app_state.json";
+
+/// Upgrade the fields of a persisted state to the current schema version
+pub fn migrate(mut fields: Map<String, Value>) -> Map<String, Value> {
+    let version = fields
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(1);
+    if version > u64::from(SCHEMA_VERSION) {
+        event!(
+            Level::WARN,
+            "the saved state has schema version {}, newer than {}",
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    // Version 1 only lacks the schema version, the fields are the same as version 2
+    fields.insert("schema_version".to_owned(), Value::from(SCHEMA_VERSION));
+    fields
+}
+
+/// Restore a persisted state from json, migrating it to the current schema.
+///
+/// If the whole state doesn't deserialize, every field which does on its own is
+/// kept, and the others get their defaults, so `T` must use `#[serde(default)]`.
+pub fn restore<T: DeserializeOwned + Default>(json: &str) -> T {
+    let fields = match serde_json::from_str(json) {
+        Ok(Value::Object(fields)) => migrate(fields),
+        Ok(_) | Err(_) => {
+            event!(
+                Level::WARN,
+                "the saved state is not a json object, ignoring it"
+            );
+            return T::default();
+        }
+    };
+    if let Ok(state) = T::deserialize(Value::Object(fields.clone())) {
+        return state;
+    }
+
+    let (kept, dropped): (Map<String, Value>, Map<String, Value>) =
+        fields.into_iter().partition(|(key, value)| {
+            let single = Map::from_iter([(key.clone(), value.clone())]);
+            T::deserialize(Value::Object(single)).is_ok()
+        });
+    event!(
+        Level::WARN,
+        "dropped fields of the saved state which no longer deserialize: {:?}",
+        dropped.keys().collect::<Vec<_>>()
+    );
+    T::deserialize(Value::Object(kept)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Default, Deserialize, PartialEq)]
+    enum Mode {
+        #[default]
+        Assets,
+        Swap,
+    }
+
+    // The shape of the App state: enums, maps keyed by token id, and optional fields
+    #[derive(Debug, Default, Deserialize, PartialEq)]
+    #[serde(default)]
+    struct State {
+        schema_version: u32,
+        mode: Mode,
+        send_token_id: u64,
+        send_value: HashMap<u64, String>,
+        swap_max_slippage: String,
+        ui_scale: Option<f32>,
+    }
+
+    // A state as stored by version 1, without a schema version
+    const V1_STATE: &str = r#"{
+        "mode": "Swap",
+        "send_token_id": 1,
+        "send_value": {"0": "1.5", "1": "20"},
+        "swap_max_slippage": "0.5",
+        "ui_scale": 1.25
+    }"#;
+
+    fn v1_state() -> State {
+        State {
+            schema_version: SCHEMA_VERSION,
+            mode: Mode::Swap,
+            send_token_id: 1,
+            send_value: HashMap::from([(0, "1.5".to_owned()), (1, "20".to_owned())]),
+            swap_max_slippage: "0.5".to_owned(),
+            ui_scale: Some(1.25),
+        }
+    }
+
+    #[test]
+    fn version_1_states_are_restored_intact() {
+        assert_eq!(restore::<State>(V1_STATE), v1_state());
+    }
+
+    #[test]
+    fn a_corrupt_field_is_dropped_on_its_own() {
+        let json = V1_STATE
+            .replace(r#""Swap""#, r#""Teleport""#)
+            .replace("1.25", r#""large""#);
+        assert_eq!(
+            restore::<State>(&json),
+            State {
+                mode: Mode::Assets,
+                ui_scale: None,
+                ..v1_state()
+            }
+        );
+    }
+
+    #[test]
+    fn newer_states_keep_the_fields_we_know() {
+        let json = V1_STATE.replacen(
+            '{',
+            r#"{"schema_version": 99, "added_in_99": [1, 2, 3],"#,
+            1,
+        );
+        assert_eq!(restore::<State>(&json), v1_state());
+    }
+
+    #[test]
+    fn states_which_are_not_objects_are_ignored() {
+        for json in ["", "[]", "null", "{\"mode\": "] {
+            assert_eq!(restore::<State>(json), State::default());
+        }
+    }
+
+    #[test]
+    fn migrating_sets_the_current_schema_version() {
+        let migrated = migrate(Map::new());
+        assert_eq!(
+            migrated.get("schema_version"),
+            Some(&Value::from(SCHEMA_VERSION))
+        );
+    }
+}
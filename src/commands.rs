@@ -0,0 +1,362 @@
+//! Commands which the UI queues for the worker thread to execute, so that slow
+//! rpcs never block the UI thread.
+
+use crate::{Amount, FillMode, HoldHandle, OfferId, SciFill, TokenId, TwoSidedQuote, TxOptions};
+use mc_crypto_ring_signature::KeyImage;
+use mc_transaction_extra::SignedContingentInput;
+use rust_decimal::Decimal;
+
+/// Identifies a command queued with the worker
+pub type CommandId = u64;
+
+/// A command for the worker thread
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WorkerCommand {
+    /// Send a payment
+    Send(Payment),
+    /// Merge the smallest unspent txos of a token into one, with a self-payment
+    Defragment {
+        /// The token id whose txos to merge
+        token_id: TokenId,
+    },
+    /// Fund a new gift code from subaddress 0
+    CreateGiftCode {
+        /// The value the code holds
+        value: u64,
+        /// The token id of the value
+        token_id: TokenId,
+        /// The fee the user reviewed the gift code with
+        expected_fee: u64,
+    },
+    /// Sweep the txo of a gift code into subaddress 0
+    RedeemGiftCode {
+        /// The b58 transfer code
+        b58_code: String,
+    },
+    /// Issue the next subaddress for receiving payments, and fetch its b58 address
+    IssueReceiveSubaddress,
+    /// Generate and submit a swap offer, which is already tracked
+    OfferSwap {
+        /// The tracked offer
+        offer_id: OfferId,
+        /// The amount offered
+        from_amount: Amount,
+        /// The amount asked for in return
+        to_amount: Amount,
+        /// The subaddress whose funds are offered
+        sender_subaddress: u64,
+        /// Whether the offer may be filled partially
+        fill_mode: FillMode,
+    },
+    /// Generate and submit the two offers of a two-sided quote, which are already
+    /// tracked, one after the other
+    OfferBothSides {
+        /// The tracked offer selling base
+        ask_offer_id: OfferId,
+        /// The tracked offer buying base
+        bid_offer_id: OfferId,
+        /// The amounts of both offers
+        quote: TwoSidedQuote,
+        /// The subaddress whose funds are offered
+        sender_subaddress: u64,
+        /// Whether the offers may be filled partially
+        fill_mode: FillMode,
+    },
+    /// Generate the sci of a swap offer, and hold it for preview
+    PreviewOffer {
+        /// The handle the offer is held under
+        handle: HoldHandle,
+        /// The amount offered
+        from_amount: Amount,
+        /// The amount asked for in return
+        to_amount: Amount,
+        /// The subaddress whose funds are offered
+        sender_subaddress: u64,
+        /// Whether the offer may be filled partially
+        fill_mode: FillMode,
+    },
+    /// Submit the sci of an offer which was held for preview, and is now tracked
+    SubmitHeldOffer {
+        /// The tracked offer
+        offer_id: OfferId,
+        /// The sci which was previewed
+        sci: SignedContingentInput,
+    },
+    /// Cancel an open offer, which is already marked as cancelling, by spending its input
+    CancelOffer {
+        /// The tracked offer
+        offer_id: OfferId,
+    },
+    /// Fill quotes from the quote book, in one transaction
+    PerformSwap {
+        /// The scis of the quotes, and how much of each to fill
+        fills: Vec<SciFill>,
+        /// The amount we pay to fill them
+        from_amount: Amount,
+        /// The token id to pay the fee in
+        fee_token_id: TokenId,
+        /// Where to pay from, and how long the transaction stays valid
+        options: TxOptions,
+        /// How far the cost may move from from_amount, relative to it
+        max_slippage: Decimal,
+    },
+}
+
+impl WorkerCommand {
+    /// A short name of the kind of command, for the log of recent events
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Send(_) => "Payment",
+            Self::Defragment { .. } => "Defragmentation",
+            Self::CreateGiftCode { .. } => "Gift code",
+            Self::RedeemGiftCode { .. } => "Gift code redemption",
+            Self::IssueReceiveSubaddress => "Receive address",
+            Self::OfferSwap { .. } => "Offer",
+            Self::OfferBothSides { .. } => "Two-sided quote",
+            Self::PreviewOffer { .. } => "Offer preview",
+            Self::SubmitHeldOffer { .. } => "Offer",
+            Self::CancelOffer { .. } => "Offer cancellation",
+            Self::PerformSwap { .. } => "Swap",
+        }
+    }
+}
+
+/// A payment to send
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Payment {
+    /// The value to send
+    pub value: u64,
+    /// The token id to send
+    pub token_id: TokenId,
+    /// The b58 address to send to
+    pub recipient: String,
+    /// The fee the user reviewed the payment with
+    pub expected_fee: u64,
+    /// The payment request id to put in the sender memo, if any
+    pub payment_request_id: Option<u64>,
+    /// The key images of the txos the user picked to spend. If empty, mobilecoind
+    /// selects the inputs.
+    pub inputs: Vec<KeyImage>,
+    /// Where to spend from, and how long the transaction stays valid
+    pub options: TxOptions,
+}
+
+/// Options of a transaction which spends from the account
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TxOptions {
+    /// The subaddress to spend from, which also receives the change
+    pub sender_subaddress: u64,
+    /// How many blocks past the current height of the ledger the transaction may
+    /// land in, before it expires
+    pub tombstone_offset: u64,
+}
+
+impl Default for TxOptions {
+    fn default() -> Self {
+        Self {
+            sender_subaddress: 0,
+            tombstone_offset: Self::DEFAULT_TOMBSTONE_OFFSET,
+        }
+    }
+}
+
+impl TxOptions {
+    /// The tombstone offset used unless the user picks another
+    pub const DEFAULT_TOMBSTONE_OFFSET: u64 = 100;
+    /// The furthest past the current height the protocol accepts a tombstone block
+    pub const MAX_TOMBSTONE_OFFSET: u64 = 20160;
+
+    /// The tombstone block of a transaction built against a ledger of `num_blocks` blocks
+    pub fn tombstone_block(&self, num_blocks: u64) -> u64 {
+        num_blocks.saturating_add(self.tombstone_offset)
+    }
+}
+
+impl Payment {
+    /// Parse a user-specified payment request id. An empty string means no id.
+    ///
+    /// The RTH sender memo only has room for a 64-bit payment request id, so anything
+    /// which doesn't fit is rejected rather than truncated.
+    pub fn parse_payment_request_id(src: &str) -> Result<Option<u64>, String> {
+        let src = src.trim();
+        if src.is_empty() {
+            return Ok(None);
+        }
+        src.parse::<u64>().map(Some).map_err(|_| {
+            format!(
+                "Payment id must be a whole number no larger than {}",
+                u64::MAX
+            )
+        })
+    }
+}
+
+/// The status of a queued command
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CommandStatus {
+    /// The command is waiting for the worker thread
+    Queued,
+    /// The worker thread is executing the command
+    Submitting,
+    /// The command was executed successfully
+    Submitted,
+    /// The command failed
+    Failed(String),
+}
+
+impl CommandStatus {
+    /// Whether the worker thread is done with the command, one way or the other
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Submitted | Self::Failed(_))
+    }
+}
+
+/// A queued command, and what became of it
+#[derive(Clone, Debug)]
+pub struct CommandInfo {
+    /// The id of the command
+    pub id: CommandId,
+    /// The command
+    pub command: WorkerCommand,
+    /// The status of the command
+    pub status: CommandStatus,
+}
+
+/// The commands queued with the worker during this session, and their status
+#[derive(Default)]
+pub struct CommandLog {
+    /// The id to give the next command
+    next_id: CommandId,
+    /// The commands, in the order they were queued
+    commands: Vec<CommandInfo>,
+}
+
+impl CommandLog {
+    /// How many finished commands to remember
+    pub const MAX_FINISHED: usize = 20;
+
+    /// Record a new command, which is queued
+    pub fn begin(&mut self, command: WorkerCommand) -> CommandId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.commands.push(CommandInfo {
+            id,
+            command,
+            status: CommandStatus::Queued,
+        });
+
+        // Forget the oldest finished commands
+        let num_finished = self
+            .commands
+            .iter()
+            .filter(|info| info.status.is_finished())
+            .count();
+        let mut excess = num_finished.saturating_sub(Self::MAX_FINISHED);
+        self.commands.retain(|info| {
+            if excess > 0 && info.status.is_finished() {
+                excess -= 1;
+                return false;
+            }
+            true
+        });
+        id
+    }
+
+    /// Update the status of a command
+    pub fn set_status(&mut self, id: CommandId, status: CommandStatus) {
+        if let Some(info) = self.commands.iter_mut().find(|info| info.id == id) {
+            info.status = status;
+        }
+    }
+
+    /// The status of a command, if it wasn't forgotten
+    pub fn status(&self, id: CommandId) -> Option<CommandStatus> {
+        self.commands
+            .iter()
+            .find(|info| info.id == id)
+            .map(|info| info.status.clone())
+    }
+
+    /// Mark the commands being executed as failed with `err`, and return how many
+    /// there were
+    pub fn fail_submitting(&mut self, err: &str) -> usize {
+        self.fail_where(err, |status| *status == CommandStatus::Submitting)
+    }
+
+    /// Mark the commands being executed or still queued as failed with `err`, and
+    /// return how many there were
+    pub fn fail_unfinished(&mut self, err: &str) -> usize {
+        self.fail_where(err, |status| !status.is_finished())
+    }
+
+    fn fail_where(&mut self, err: &str, pred: impl Fn(&CommandStatus) -> bool) -> usize {
+        let mut failed = 0;
+        for info in self.commands.iter_mut().filter(|info| pred(&info.status)) {
+            info.status = CommandStatus::Failed(err.to_string());
+            failed += 1;
+        }
+        failed
+    }
+
+    /// Forget about a command
+    pub fn dismiss(&mut self, id: CommandId) {
+        self.commands.retain(|info| info.id != id);
+    }
+
+    /// The commands, in the order they were queued
+    pub fn commands(&self) -> &[CommandInfo] {
+        &self.commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defragment() -> WorkerCommand {
+        WorkerCommand::Defragment {
+            token_id: TokenId::MOB,
+        }
+    }
+
+    // A log with a queued, an executing, a submitted and a failed command
+    fn log() -> (CommandLog, [CommandId; 4]) {
+        let mut log = CommandLog::default();
+        let ids = [(); 4].map(|_| log.begin(defragment()));
+        log.set_status(ids[1], CommandStatus::Submitting);
+        log.set_status(ids[2], CommandStatus::Submitted);
+        log.set_status(ids[3], CommandStatus::Failed("earlier".to_string()));
+        (log, ids)
+    }
+
+    #[test]
+    fn fail_submitting_leaves_queued_and_finished_commands() {
+        let (mut log, ids) = log();
+        assert_eq!(log.fail_submitting("crashed"), 1);
+        assert_eq!(log.status(ids[0]), Some(CommandStatus::Queued));
+        assert_eq!(
+            log.status(ids[1]),
+            Some(CommandStatus::Failed("crashed".to_string()))
+        );
+        assert_eq!(log.status(ids[2]), Some(CommandStatus::Submitted));
+        assert_eq!(
+            log.status(ids[3]),
+            Some(CommandStatus::Failed("earlier".to_string()))
+        );
+        assert_eq!(log.fail_submitting("crashed"), 0);
+    }
+
+    #[test]
+    fn fail_unfinished_fails_queued_and_executing_commands() {
+        let (mut log, ids) = log();
+        assert_eq!(log.fail_unfinished("abandoned"), 2);
+        for id in &ids[..2] {
+            assert_eq!(
+                log.status(*id),
+                Some(CommandStatus::Failed("abandoned".to_string()))
+            );
+        }
+        assert_eq!(log.status(ids[2]), Some(CommandStatus::Submitted));
+        assert!(log.commands().iter().all(|info| info.status.is_finished()));
+    }
+}
@@ -1,4 +1,5 @@
-use clap::Parser;
+use crate::{AuthToken, Permission};
+use clap::{Parser, ValueEnum};
 use deqs_api::DeqsClientUri;
 use mc_mobilecoind_api::MobilecoindUri;
 use std::path::PathBuf;
@@ -9,9 +10,24 @@ use std::path::PathBuf;
 #[clap(name = "mobilecoind-buddy", about = "A front-end for mobilecoind")]
 pub struct Config {
     /// Path to json-formatted key file, containing mnemonic or root entropy.
+    ///
+    /// The keyfile may be encrypted with a passphrase (see --encrypt-keyfile), which
+    /// is then asked for on startup.
     #[clap(long, env = "MC_KEYFILE")]
     pub keyfile: PathBuf,
 
+    /// Encrypt the keyfile in place with a passphrase read from the terminal, and exit.
+    #[clap(long)]
+    pub encrypt_keyfile: bool,
+
+    /// Only watch the balances and incoming payments of the account. Nothing can be
+    /// sent, swapped or offered, but the quote books can still be browsed.
+    ///
+    /// The keyfile must then be a view-only account file, with the view private key
+    /// and the spend public key only; a keyfile holding the spend key is refused.
+    #[clap(long, env = "MC_WATCH_ONLY")]
+    pub watch_only: bool,
+
     /// MobileCoinD URI.
     #[clap(
         long,
@@ -21,6 +37,220 @@ pub struct Config {
     pub mobilecoind_uri: MobilecoindUri,
 
     /// Deqs URI. (Optional)
-    #[clap(long, env = "MC_DEQS_URI")]
-    pub deqs_uri: Option<DeqsClientUri>,
+    ///
+    /// May be repeated (or comma-separated) to merge the quote books of several deqs.
+    #[clap(long, env = "MC_DEQS_URI", value_delimiter = ',')]
+    pub deqs_uri: Vec<DeqsClientUri>,
+
+    /// Path to a PEM file with the client certificate chain and private key to
+    /// present to mobilecoind, for proxies which require client certificates. (Optional)
+    #[clap(long, env = "MC_MOBILECOIND_TLS_IDENTITY")]
+    pub mobilecoind_tls_identity: Option<PathBuf>,
+
+    /// Path to a PEM file with the client certificate chain and private key to
+    /// present to the deqs, for proxies which require client certificates. (Optional)
+    #[clap(long, env = "MC_DEQS_TLS_IDENTITY")]
+    pub deqs_tls_identity: Option<PathBuf>,
+
+    /// The http CONNECT proxy to connect to mobilecoind and the deqs through, like
+    /// http://proxy.example.com:3128. Defaults to the https_proxy environment
+    /// variable. (Optional)
+    #[clap(long, env = "MC_GRPC_PROXY")]
+    pub grpc_proxy: Option<String>,
+
+    /// Bearer token to authenticate to the deqs with, for deployments which require
+    /// one. (Optional)
+    #[clap(long, env = "MC_DEQS_AUTH_TOKEN", hide_env_values = true)]
+    pub deqs_auth_token: Option<AuthToken>,
+
+    /// Which deqs to submit quotes to, when several are configured.
+    #[clap(long, value_enum, default_value_t, env = "MC_DEQS_SUBMIT_MODE")]
+    pub deqs_submit_mode: DeqsSubmitMode,
+
+    /// What the web dashboard is permitted to do (comma-separated).
+    ///
+    /// The UI may always do everything, and hook commands may not do anything.
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "read-balances,read-book",
+        env = "MC_DASHBOARD_PERMISSIONS"
+    )]
+    pub dashboard_permissions: Vec<Permission>,
+
+    /// How many finished offers to keep track of, in the My offers list.
+    #[clap(long, default_value = "100", env = "MC_OFFERS_RETENTION")]
+    pub offers_retention: usize,
+
+    /// How many quote books to keep, for pairs which are no longer being viewed.
+    #[clap(long, default_value = "8", env = "MC_QUOTE_BOOKS_RETENTION")]
+    pub quote_books_retention: usize,
+
+    /// How long to keep the quote book of a pair which is no longer being viewed, in seconds.
+    #[clap(long, default_value = "300", env = "MC_QUOTE_BOOKS_RETENTION_SECS")]
+    pub quote_books_retention_secs: u64,
+
+    /// How many seconds rpcs which only read, like polls, may take before they fail.
+    #[clap(long, default_value = "10", env = "MC_RPC_TIMEOUT_SECS")]
+    pub rpc_timeout_secs: u64,
+
+    /// How many seconds rpcs which build or submit transactions and offers may take
+    /// before they fail.
+    #[clap(long, default_value = "30", env = "MC_SUBMIT_TIMEOUT_SECS")]
+    pub submit_timeout_secs: u64,
+
+    /// How many seconds a grpc channel may be idle before it is pinged to check
+    /// that it is alive. Clamped to 1..=3600.
+    #[clap(long, default_value = "10", env = "MC_GRPC_KEEPALIVE_SECS")]
+    pub grpc_keepalive_secs: u64,
+
+    /// How many milliseconds to wait before the first attempt to reconnect a
+    /// dropped grpc channel. Clamped to 100..=120000, and to at most the maximum
+    /// backoff.
+    #[clap(
+        long,
+        default_value = "1000",
+        env = "MC_GRPC_INITIAL_RECONNECT_BACKOFF_MS"
+    )]
+    pub grpc_initial_reconnect_backoff_ms: u64,
+
+    /// The most milliseconds to wait between attempts to reconnect a dropped grpc
+    /// channel. Clamped to 100..=120000.
+    #[clap(long, default_value = "2000", env = "MC_GRPC_MAX_RECONNECT_BACKOFF_MS")]
+    pub grpc_max_reconnect_backoff_ms: u64,
+
+    /// How many quotes to request from each deqs, for each side of the book.
+    #[clap(long, default_value = "50", env = "MC_DEQS_QUOTES_LIMIT")]
+    pub deqs_quotes_limit: u64,
+
+    /// How old a quote book may be, in seconds, before swaps against it are refused
+    /// until it is refreshed.
+    #[clap(long, default_value = "30", env = "MC_MAX_QUOTE_AGE_SECS")]
+    pub max_quote_age_secs: u64,
+
+    /// How often to sample the mid-price of the pair being polled, for its chart on
+    /// the Offer Swap panel, in seconds.
+    #[clap(long, default_value = "30", env = "MC_PRICE_SAMPLE_INTERVAL_SECS")]
+    pub price_sample_interval_secs: u64,
+
+    /// How many samples of the mid-price of each pair to keep in memory, 2880 being
+    /// 24 hours at the default interval.
+    #[clap(long, default_value = "2880", env = "MC_PRICE_HISTORY_SAMPLES")]
+    pub price_history_samples: usize,
+
+    /// How many times to attempt a payment, swap or offer which fails with a transient
+    /// error, like the ledger being mid-sync.
+    #[clap(long, default_value = "4", env = "MC_SEND_ATTEMPTS")]
+    pub send_attempts: u32,
+
+    /// How often to poll mobilecoind while the window is focused, in milliseconds.
+    #[clap(long, default_value = "20", env = "MC_POLL_INTERVAL_MS")]
+    pub poll_interval_ms: u64,
+
+    /// How often to poll the deqs for quotes while the window is focused, in milliseconds.
+    #[clap(long, default_value = "20", env = "MC_QUOTE_POLL_INTERVAL_MS")]
+    pub quote_poll_interval_ms: u64,
+
+    /// The chain id mobilecoind must be on, like "main" or "test". The account is not
+    /// registered with a mobilecoind on another network. (Optional)
+    #[clap(long, env = "MC_EXPECTED_CHAIN_ID")]
+    pub expected_chain_id: Option<String>,
+
+    /// Path to a json file with the symbols and decimals of tokens, merged over the
+    /// built-in ones. (Optional)
+    #[clap(long, env = "MC_TOKEN_METADATA")]
+    pub token_metadata: Option<PathBuf>,
+
+    /// The directory to keep the app's files in, like its saved state, the history
+    /// and the saved offers, instead of the platform's data dir. Useful for portable
+    /// installs, and to run instances for different accounts side by side. (Optional)
+    #[clap(long, env = "MC_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// The pixels per point to draw the UI with, from 1.0 to 4.0. Defaults to the
+    /// one chosen in Settings, or else to the native one. (Optional)
+    #[clap(long, env = "MC_UI_SCALE")]
+    pub ui_scale: Option<f32>,
+}
+
+/// The app id, which determines the data dir that eframe uses
+pub const APP_ID: &str = "mobilecoind_buddy";
+
+impl Config {
+    /// The directory where the app keeps its files, like exported images and history
+    pub fn data_dir(&self) -> PathBuf {
+        self.data_dir.clone().unwrap_or_else(Self::default_data_dir)
+    }
+
+    /// The platform's data dir for the app, which eframe keeps its storage in
+    pub fn default_data_dir() -> PathBuf {
+        eframe::storage_dir(APP_ID).unwrap_or_else(std::env::temp_dir)
+    }
+}
+
+/// Which of the configured deqs a new quote is submitted to
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum DeqsSubmitMode {
+    /// Submit to every configured deqs
+    #[default]
+    All,
+    /// Submit only to the first configured deqs
+    Primary,
+}
+
+impl DeqsSubmitMode {
+    /// Get the indices of the deqs to submit to, out of `num_deqs` configured deqs
+    pub fn targets(&self, num_deqs: usize) -> std::ops::Range<usize> {
+        match self {
+            Self::All => 0..num_deqs,
+            Self::Primary => 0..num_deqs.min(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AccessPolicy, AccessToken};
+
+    fn parse(args: &[&str]) -> Result<Config, clap::Error> {
+        Config::try_parse_from(
+            ["mobilecoind-buddy", "--keyfile", "account.json"]
+                .iter()
+                .chain(args),
+        )
+    }
+
+    #[test]
+    fn the_dashboard_may_only_read_by_default() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(
+            config.dashboard_permissions,
+            vec![Permission::ReadBalances, Permission::ReadBook]
+        );
+        let token = AccessToken::dashboard(&config.dashboard_permissions);
+        assert!(token.require(AccessPolicy::SEND).is_err());
+        assert!(token.require(AccessPolicy::CANCEL_OFFERS).is_err());
+    }
+
+    #[test]
+    fn dashboard_permissions_are_a_comma_separated_list() {
+        let config = parse(&["--dashboard-permissions", "read-book,cancel-offers"]).unwrap();
+        assert_eq!(
+            config.dashboard_permissions,
+            vec![Permission::ReadBook, Permission::CancelOffers]
+        );
+        let token = AccessToken::dashboard(&config.dashboard_permissions);
+        assert_eq!(
+            token.policy(),
+            AccessPolicy::READ_BOOK | AccessPolicy::CANCEL_OFFERS
+        );
+        assert!(token.require(AccessPolicy::SEND).is_err());
+    }
+
+    #[test]
+    fn unknown_dashboard_permissions_are_rejected() {
+        assert!(parse(&["--dashboard-permissions", "read-book,everything"]).is_err());
+    }
 }
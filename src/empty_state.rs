@@ -0,0 +1,183 @@
+//! Empty states for the panes, which explain why there is nothing to show
+//! and what the user can do about it.
+
+use crate::{DeqsStatus, TokenId, Worker, MAINNET_CHAIN_ID};
+use egui::{Align, Layout, RichText, Ui};
+use std::collections::HashMap;
+
+/// The reason a pane has nothing to show
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EmptyStateKind {
+    /// The account doesn't hold any funds
+    NoFunds {
+        /// The b58 address the user can receive funds at
+        b58_address: String,
+        /// Whether we are connected to a test network
+        is_test_network: bool,
+    },
+    /// No deqs was configured, so swaps are not available
+    NoDeqsConfigured,
+    /// We haven't received the quote book from the deqs yet
+    QuotesLoading,
+    /// The deqs could not be reached
+    DeqsUnreachable(String),
+    /// The deqs doesn't have any quotes for this pair
+    NoQuotes,
+}
+
+impl EmptyStateKind {
+    /// Decide if the Assets pane is empty, given the balances of the account.
+    pub fn for_assets(
+        balances: &HashMap<TokenId, u64>,
+        b58_address: &str,
+        chain_id: &str,
+    ) -> Option<Self> {
+        if balances.values().any(|value| *value != 0) {
+            return None;
+        }
+        Some(Self::NoFunds {
+            b58_address: b58_address.to_owned(),
+            is_test_network: chain_id != MAINNET_CHAIN_ID,
+        })
+    }
+
+    /// Decide if a quote book is empty, and why, given the deqs status for the
+    /// pair and the number of quotes in the book.
+    pub fn for_quote_book(status: &DeqsStatus, num_quotes: usize) -> Option<Self> {
+        match status {
+            DeqsStatus::NotConfigured => Some(Self::NoDeqsConfigured),
+            DeqsStatus::Unreachable(err) => Some(Self::DeqsUnreachable(err.clone())),
+            _ if num_quotes > 0 => None,
+            DeqsStatus::Loading => Some(Self::QuotesLoading),
+            DeqsStatus::Ready => Some(Self::NoQuotes),
+        }
+    }
+}
+
+/// Render an empty state, explaining why there is nothing to show.
+/// Where the user can do something about it, a button is shown which asks the worker to do it.
+pub fn empty_state(ui: &mut Ui, kind: &EmptyStateKind, worker: &Worker) {
+    ui.with_layout(Layout::top_down(Align::Center), |ui| match kind {
+        EmptyStateKind::NoFunds {
+            b58_address,
+            is_test_network,
+        } => {
+            ui.strong("This account doesn't hold any funds yet.");
+            ui.label("Send funds to its public address to get started:");
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(b58_address.as_str()).monospace().small());
+                if ui.button("📋").clicked() {
+                    ui.output_mut(|o| o.copied_text = b58_address.clone());
+                }
+            });
+            if *is_test_network {
+                ui.label(
+                    "This is a test network, test funds can be requested from the MobileCoin developer community.",
+                );
+            }
+        }
+        EmptyStateKind::NoDeqsConfigured => {
+            ui.label("No deqs uri was configured, swap is not available.");
+            ui.label("Restart with --deqs-uri to connect to a deqs.");
+        }
+        EmptyStateKind::QuotesLoading => {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label("Loading quotes...");
+            });
+        }
+        EmptyStateKind::DeqsUnreachable(err) => {
+            ui.strong("The deqs could not be reached.");
+            ui.label(RichText::new(err.as_str()).small());
+            if ui.button("Retry").clicked() {
+                worker.request_refresh();
+            }
+        }
+        EmptyStateKind::NoQuotes => {
+            ui.strong("Nobody is offering this pair right now.");
+            ui.label("You can post an offer yourself in the Offer Swap pane.");
+            if ui.button("Retry").clicked() {
+                worker.request_refresh();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "b58address";
+
+    fn balances(values: &[(u64, u64)]) -> HashMap<TokenId, u64> {
+        values
+            .iter()
+            .map(|(token_id, value)| (TokenId::from(*token_id), *value))
+            .collect()
+    }
+
+    fn no_funds(is_test_network: bool) -> Option<EmptyStateKind> {
+        Some(EmptyStateKind::NoFunds {
+            b58_address: ADDRESS.to_owned(),
+            is_test_network,
+        })
+    }
+
+    #[test]
+    fn assets_are_empty_without_funds() {
+        assert_eq!(
+            EmptyStateKind::for_assets(&balances(&[]), ADDRESS, MAINNET_CHAIN_ID),
+            no_funds(false)
+        );
+        assert_eq!(
+            EmptyStateKind::for_assets(&balances(&[(0, 0), (1, 0)]), ADDRESS, MAINNET_CHAIN_ID),
+            no_funds(false)
+        );
+        assert_eq!(
+            EmptyStateKind::for_assets(&balances(&[(0, 0), (1, 5)]), ADDRESS, MAINNET_CHAIN_ID),
+            None
+        );
+    }
+
+    #[test]
+    fn assets_suggest_test_funds_off_mainnet() {
+        assert_eq!(
+            EmptyStateKind::for_assets(&balances(&[(0, 0)]), ADDRESS, "test"),
+            no_funds(true)
+        );
+        assert_eq!(
+            EmptyStateKind::for_assets(&balances(&[(0, 1)]), ADDRESS, "test"),
+            None
+        );
+    }
+
+    #[test]
+    fn quote_books_explain_why_they_are_empty() {
+        let unreachable = DeqsStatus::Unreachable("connection refused".to_owned());
+        for num_quotes in [0, 3] {
+            assert_eq!(
+                EmptyStateKind::for_quote_book(&DeqsStatus::NotConfigured, num_quotes),
+                Some(EmptyStateKind::NoDeqsConfigured)
+            );
+            assert_eq!(
+                EmptyStateKind::for_quote_book(&unreachable, num_quotes),
+                Some(EmptyStateKind::DeqsUnreachable(
+                    "connection refused".to_owned()
+                ))
+            );
+        }
+        assert_eq!(
+            EmptyStateKind::for_quote_book(&DeqsStatus::Loading, 0),
+            Some(EmptyStateKind::QuotesLoading)
+        );
+        assert_eq!(
+            EmptyStateKind::for_quote_book(&DeqsStatus::Ready, 0),
+            Some(EmptyStateKind::NoQuotes)
+        );
+        assert_eq!(
+            EmptyStateKind::for_quote_book(&DeqsStatus::Loading, 3),
+            None
+        );
+        assert_eq!(EmptyStateKind::for_quote_book(&DeqsStatus::Ready, 3), None);
+    }
+}
@@ -0,0 +1,148 @@
+//! Keyfiles encrypted with a passphrase, so that the mnemonic or root entropy
+//! isn't stored in plaintext.
+//!
+//! An encrypted keyfile is a json envelope holding the plaintext keyfile sealed
+//! with AES-256-GCM, under a key derived from the passphrase with Argon2id. The
+//! decrypted keyfile only ever lives in memory, and is wiped once it is parsed.
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use argon2::Argon2;
+use displaydoc::Display;
+use mc_account_keys::AccountKey;
+use mc_util_keyfile::read_keyfile_data;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// The format field of an encrypted keyfile, which tells it apart from a
+/// plaintext one
+const FORMAT: &str = "mobilecoind-buddy-encrypted-keyfile";
+/// The version of the envelope written by this crate
+const VERSION: u32 = 1;
+/// The length of the Argon2 salt, in bytes
+const SALT_LEN: usize = 16;
+
+/// An error reading, decrypting or encrypting a keyfile. None of them include
+/// the contents of the keyfile.
+#[derive(Clone, Debug, Display, Eq, PartialEq)]
+pub enum KeyfileError {
+    /// Keyfile io: {0}
+    Io(String),
+    /// Wrong passphrase, or the keyfile was tampered with
+    WrongPassphrase,
+    /// Invalid encrypted keyfile: {0}
+    Invalid(String),
+    /// The keyfile is already encrypted
+    AlreadyEncrypted,
+}
+
+/// The json envelope of an encrypted keyfile
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Envelope {
+    /// Always FORMAT
+    format: String,
+    /// The version of the envelope
+    version: u32,
+    /// The hex Argon2id salt
+    salt: String,
+    /// The hex AES-GCM nonce
+    nonce: String,
+    /// The hex sealed keyfile
+    ciphertext: String,
+}
+
+/// Whether the file at `path` is an encrypted keyfile. A file which can't be read
+/// isn't, so that the plaintext keyfile reader reports the error.
+pub fn is_encrypted_keyfile(path: &Path) -> bool {
+    std::fs::read(path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<Envelope>(&bytes).ok())
+        .map_or(false, |envelope| envelope.format == FORMAT)
+}
+
+/// Read and decrypt the encrypted keyfile at `path`
+pub fn decrypt_keyfile(path: &Path, passphrase: &str) -> Result<AccountKey, KeyfileError> {
+    let bytes = std::fs::read(path)
+        .map_err(|err| KeyfileError::Io(format!("{}: {err}", path.display())))?;
+    let envelope: Envelope =
+        serde_json::from_slice(&bytes).map_err(|err| KeyfileError::Invalid(err.to_string()))?;
+    if envelope.format != FORMAT || envelope.version != VERSION {
+        return Err(KeyfileError::Invalid(format!(
+            "unsupported format {:?} version {}",
+            envelope.format, envelope.version
+        )));
+    }
+    let decode = |field: &str, hex_str: &str| {
+        hex::decode(hex_str).map_err(|err| KeyfileError::Invalid(format!("{field}: {err}")))
+    };
+    let salt = decode("salt", &envelope.salt)?;
+    let nonce = decode("nonce", &envelope.nonce)?;
+    let ciphertext = decode("ciphertext", &envelope.ciphertext)?;
+    if nonce.len() != 12 {
+        return Err(KeyfileError::Invalid("nonce: wrong length".to_owned()));
+    }
+
+    let cipher = cipher(passphrase, &salt)?;
+    let plaintext = Zeroizing::new(
+        cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| KeyfileError::WrongPassphrase)?,
+    );
+    // The parse error could quote the keyfile, so it isn't passed on
+    read_keyfile_data(&mut plaintext.as_slice()).map_err(|_| {
+        KeyfileError::Invalid("the decrypted keyfile holds no mnemonic or root entropy".to_owned())
+    })
+}
+
+/// Encrypt the plaintext keyfile at `path` with a passphrase, replacing it.
+///
+/// The keyfile is checked to be readable first, and the encrypted one is written
+/// next to it and renamed over it, so that a failure leaves the plaintext intact.
+pub fn encrypt_keyfile_in_place(path: &Path, passphrase: &str) -> Result<(), KeyfileError> {
+    if is_encrypted_keyfile(path) {
+        return Err(KeyfileError::AlreadyEncrypted);
+    }
+    let plaintext = Zeroizing::new(
+        std::fs::read(path)
+            .map_err(|err| KeyfileError::Io(format!("{}: {err}", path.display())))?,
+    );
+    read_keyfile_data(&mut plaintext.as_slice()).map_err(|_| {
+        KeyfileError::Invalid("the keyfile holds no mnemonic or root entropy".to_owned())
+    })?;
+
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher(passphrase, &salt)?
+        .encrypt(&nonce, plaintext.as_slice())
+        .map_err(|_| KeyfileError::Invalid("encryption failed".to_owned()))?;
+    let envelope = Envelope {
+        format: FORMAT.to_owned(),
+        version: VERSION,
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    };
+    let bytes =
+        serde_json::to_vec_pretty(&envelope).map_err(|err| KeyfileError::Io(err.to_string()))?;
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    std::fs::write(&tmp_path, bytes)
+        .and_then(|()| std::fs::rename(&tmp_path, path))
+        .map_err(|err| KeyfileError::Io(format!("{}: {err}", path.display())))
+}
+
+// The cipher keyed with the passphrase
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm, KeyfileError> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut_slice())
+        .map_err(|err| KeyfileError::Invalid(format!("key derivation: {err}")))?;
+    Aes256Gcm::new_from_slice(key.as_slice())
+        .map_err(|_| KeyfileError::Invalid("key derivation: wrong key length".to_owned()))
+}
@@ -0,0 +1,253 @@
+//! The queue of errors shown to the user, one at a time, most severe first, and
+//! the log of recent events shown in the Activity drawer.
+//!
+//! A condition like an unreachable server makes the worker report the same error
+//! over and over, so identical errors are counted rather than queued again, and an
+//! error the user dismissed stays dismissed while it keeps being reported.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How serious a reported event is
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Severity {
+    /// Something the user may want to know, like a submission which succeeded
+    Info,
+    /// Something which went wrong, but may resolve itself, like a failed poll
+    Warning,
+    /// Something which failed, like a submission
+    Error,
+}
+
+/// An error in the queue or the log of recent events, and how often it was reported
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ErrorEntry {
+    /// How serious the error is
+    pub severity: Severity,
+    /// The error
+    pub message: String,
+    /// When the error was first reported
+    pub first_seen: Instant,
+    /// When the error was most recently reported
+    pub last_seen: Instant,
+    /// How many times the error was reported
+    pub count: u32,
+}
+
+impl ErrorEntry {
+    /// The error, with how many times it was reported if more than once
+    pub fn describe(&self) -> String {
+        if self.count > 1 {
+            format!("{} (×{})", self.message, self.count)
+        } else {
+            self.message.clone()
+        }
+    }
+}
+
+/// The errors which the user hasn't dismissed yet, oldest first, and the recent events
+#[derive(Debug, Default)]
+pub struct ErrorQueue {
+    /// The errors
+    entries: VecDeque<ErrorEntry>,
+    /// The most recent events, including dismissed errors and info, oldest first
+    recent: VecDeque<ErrorEntry>,
+    /// The errors the user dismissed, and until when they are not queued again
+    suppressed: HashMap<String, Instant>,
+}
+
+impl ErrorQueue {
+    /// How long a dismissed error isn't queued again. Every time it is reported in
+    /// the meantime, the window starts over.
+    pub const SUPPRESSION_WINDOW: Duration = Duration::from_secs(30);
+
+    /// How many recent events are kept
+    pub const MAX_RECENT: usize = 50;
+
+    /// Report an error. If the same error is already queued, its count goes up
+    /// instead, and if the user dismissed it recently, it is only logged.
+    pub fn push(&mut self, severity: Severity, message: String) {
+        self.push_at(severity, message, Instant::now());
+    }
+
+    // Report an error at `now`
+    fn push_at(&mut self, severity: Severity, message: String, now: Instant) {
+        self.log(severity, &message, now);
+        self.suppressed.retain(|_, until| *until > now);
+        if let Some(until) = self.suppressed.get_mut(&message) {
+            *until = now + Self::SUPPRESSION_WINDOW;
+            return;
+        }
+        match self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.message == message)
+        {
+            Some(entry) => {
+                entry.last_seen = now;
+                entry.count = entry.count.saturating_add(1);
+                entry.severity = entry.severity.max(severity);
+            }
+            None => self.entries.push_back(ErrorEntry {
+                severity,
+                message,
+                first_seen: now,
+                last_seen: now,
+                count: 1,
+            }),
+        }
+    }
+
+    /// Record an event in the log of recent events only, without queueing it
+    pub fn note(&mut self, severity: Severity, message: String) {
+        self.log(severity, &message, Instant::now());
+    }
+
+    // Add an event to the log of recent events, counting it with the newest one if
+    // they are the same
+    fn log(&mut self, severity: Severity, message: &str, now: Instant) {
+        match self.recent.back_mut() {
+            Some(last) if last.severity == severity && last.message == message => {
+                last.last_seen = now;
+                last.count = last.count.saturating_add(1);
+            }
+            _ => {
+                self.recent.push_back(ErrorEntry {
+                    severity,
+                    message: message.to_owned(),
+                    first_seen: now,
+                    last_seen: now,
+                    count: 1,
+                });
+                if self.recent.len() > Self::MAX_RECENT {
+                    self.recent.pop_front();
+                }
+            }
+        }
+    }
+
+    // The index of the most severe queued error, the oldest of them if several
+    fn top_index(&self) -> Option<usize> {
+        let severity = self.entries.iter().map(|entry| entry.severity).max()?;
+        self.entries
+            .iter()
+            .position(|entry| entry.severity == severity)
+    }
+
+    /// The most severe queued error, the oldest of them if several
+    pub fn top(&self) -> Option<&ErrorEntry> {
+        self.entries.get(self.top_index()?)
+    }
+
+    /// Dismiss the error returned by top. It isn't queued again for SUPPRESSION_WINDOW.
+    pub fn pop(&mut self) {
+        self.pop_at(Instant::now());
+    }
+
+    // Dismiss the top error at `now`
+    fn pop_at(&mut self, now: Instant) {
+        if let Some(entry) = self.top_index().and_then(|idx| self.entries.remove(idx)) {
+            self.suppressed
+                .insert(entry.message, now + Self::SUPPRESSION_WINDOW);
+        }
+    }
+
+    /// The recent events, newest first
+    pub fn recent(&self) -> Vec<ErrorEntry> {
+        self.recent.iter().rev().cloned().collect()
+    }
+
+    /// Keep only the errors for which `keep` returns true
+    pub fn retain(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        self.entries.retain(|entry| keep(&entry.message));
+    }
+
+    /// The number of queued errors
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no error is queued
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_errors_are_counted_not_queued() {
+        let start = Instant::now();
+        let mut errors = ErrorQueue::default();
+        for secs in 0..5 {
+            errors.push_at(
+                Severity::Warning,
+                "unreachable".to_owned(),
+                start + Duration::from_secs(secs),
+            );
+        }
+        assert_eq!(errors.len(), 1);
+        let top = errors.top().unwrap();
+        assert_eq!(top.count, 5);
+        assert_eq!(top.first_seen, start);
+        assert_eq!(top.last_seen, start + Duration::from_secs(4));
+        assert_eq!(top.describe(), "unreachable (×5)");
+        // The log of recent events counts them the same way
+        assert_eq!(errors.recent().len(), 1);
+    }
+
+    #[test]
+    fn the_most_severe_error_is_shown_first() {
+        let now = Instant::now();
+        let mut errors = ErrorQueue::default();
+        errors.push_at(Severity::Warning, "slow".to_owned(), now);
+        errors.push_at(Severity::Error, "failed".to_owned(), now);
+        errors.push_at(Severity::Error, "also failed".to_owned(), now);
+        assert_eq!(errors.top().unwrap().message, "failed");
+        // Once a warning is reported as an error, it ranks as one, and it is older
+        errors.push_at(Severity::Error, "slow".to_owned(), now);
+        assert_eq!(errors.top().unwrap().message, "slow");
+        assert_eq!(errors.top().unwrap().describe(), "slow (×2)");
+    }
+
+    #[test]
+    fn dismissed_errors_stay_dismissed_while_they_keep_being_reported() {
+        let start = Instant::now();
+        let mut errors = ErrorQueue::default();
+        errors.push_at(Severity::Warning, "unreachable".to_owned(), start);
+        errors.pop_at(start);
+        assert!(errors.is_empty());
+
+        // Every report restarts the window
+        let mut now = start;
+        for _ in 0..4 {
+            now += ErrorQueue::SUPPRESSION_WINDOW / 2;
+            errors.push_at(Severity::Warning, "unreachable".to_owned(), now);
+            assert!(errors.is_empty());
+        }
+        // Other errors are queued as usual
+        errors.push_at(Severity::Warning, "other".to_owned(), now);
+        assert_eq!(errors.len(), 1);
+
+        // Once the condition stops for a whole window, it is shown again
+        now += ErrorQueue::SUPPRESSION_WINDOW + Duration::from_secs(1);
+        errors.push_at(Severity::Warning, "unreachable".to_owned(), now);
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn the_log_of_recent_events_is_bounded() {
+        let now = Instant::now();
+        let mut errors = ErrorQueue::default();
+        for idx in 0..ErrorQueue::MAX_RECENT + 10 {
+            errors.note(Severity::Info, format!("event {idx}"));
+        }
+        errors.push_at(Severity::Error, "failed".to_owned(), now);
+        let recent = errors.recent();
+        assert_eq!(recent.len(), ErrorQueue::MAX_RECENT);
+        assert_eq!(recent[0].message, "failed");
+        assert_eq!(errors.len(), 1);
+    }
+}
@@ -0,0 +1,87 @@
+//! Setting up the account on the first run, when there is no keyfile yet: either
+//! a new account is created from fresh entropy, or one is imported from its
+//! mnemonic. Either way, a keyfile is written to the configured path.
+
+use crate::KeyfileError;
+use bip39::{Language, Mnemonic, MnemonicType};
+use mc_account_keys::AccountKey;
+use mc_util_keyfile::read_keyfile_data;
+use std::{fs::OpenOptions, io::Write, path::Path};
+use zeroize::Zeroizing;
+
+/// The number of words of the mnemonics we create and import
+pub const MNEMONIC_WORDS: usize = 24;
+
+/// A new mnemonic, from fresh entropy
+pub fn generate_mnemonic() -> Mnemonic {
+    Mnemonic::new(MnemonicType::Words24, Language::English)
+}
+
+/// Whether a word is in the English bip39 wordlist
+pub fn is_mnemonic_word(word: &str) -> bool {
+    let word = word.trim();
+    Language::English
+        .wordlist()
+        .get_words_by_prefix(word)
+        .contains(&word)
+}
+
+/// Parse the words of a mnemonic, checking that there are MNEMONIC_WORDS of them,
+/// that each is in the wordlist, and that the checksum matches
+pub fn parse_mnemonic(words: &[impl AsRef<str>]) -> Result<Mnemonic, String> {
+    if words.len() != MNEMONIC_WORDS {
+        return Err(format!(
+            "a mnemonic has {MNEMONIC_WORDS} words, not {}",
+            words.len()
+        ));
+    }
+    if let Some(idx) = words
+        .iter()
+        .position(|word| !is_mnemonic_word(word.as_ref()))
+    {
+        return Err(format!("word {} is not in the bip39 wordlist", idx + 1));
+    }
+    let phrase = Zeroizing::new(
+        words
+            .iter()
+            .map(|word| word.as_ref().trim().to_lowercase())
+            .collect::<Vec<_>>()
+            .join(" "),
+    );
+    // The error would quote the phrase, so it isn't passed on
+    Mnemonic::from_phrase(&phrase, Language::English)
+        .map_err(|_| "the words don't match the checksum, check each of them".to_owned())
+}
+
+/// Write a keyfile holding a mnemonic to `path`, readable only by us, and load the
+/// account key from it. An existing file is never overwritten.
+pub fn write_keyfile(path: &Path, mnemonic: &Mnemonic) -> Result<AccountKey, KeyfileError> {
+    let io_err = |err: std::io::Error| KeyfileError::Io(format!("{}: {err}", path.display()));
+    let json = Zeroizing::new(
+        serde_json::to_vec_pretty(&serde_json::json!({
+            "mnemonic": mnemonic.phrase(),
+            "account_index": 0,
+        }))
+        .map_err(|err| KeyfileError::Io(err.to_string()))?,
+    );
+    // Parse it first, so that a keyfile which can't be loaded is never written
+    let account_key = read_keyfile_data(&mut json.as_slice()).map_err(|_| {
+        KeyfileError::Invalid("the mnemonic doesn't make a valid keyfile".to_owned())
+    })?;
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent).map_err(io_err)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).map_err(io_err)?;
+    file.write_all(&json)
+        .and_then(|()| file.sync_all())
+        .map_err(io_err)?;
+    Ok(account_key)
+}
@@ -0,0 +1,62 @@
+//! Gift codes, which mobilecoind calls transfer codes.
+//!
+//! A gift code is a b58 code carrying the bip39 entropy of a throwaway account,
+//! and the public key of a txo which was paid to that account. Whoever holds the
+//! code can derive the account key, and sweep the txo into their own account.
+
+use crate::{CommandId, TokenId};
+use bip39::{Language, Mnemonic};
+use mc_account_keys::AccountKey;
+use mc_core::slip10::Slip10KeyGenerator;
+
+/// A gift code we created during this session
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GiftCode {
+    /// The b58 transfer code
+    pub b58_code: String,
+    /// The value the code holds, once funded
+    pub value: u64,
+    /// The token id of the value
+    pub token_id: TokenId,
+    /// The command which funds the code, whose status tells whether it was funded
+    pub command_id: CommandId,
+}
+
+/// Errors from mobilecoind or consensus which mean a txo was already spent
+/// (lowercase, without spaces)
+const SPENT_KEY_IMAGE_ERRORS: &[&str] = &["containsspentkeyimage", "keyimagealreadyspent"];
+
+/// Whether an error from spending a txo means the txo was already spent, like
+/// the txo of a gift code which was redeemed, or the input of an offer which was
+/// filled
+pub fn is_spent_key_image_error(err: &str) -> bool {
+    let normalized: String = err
+        .to_lowercase()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    SPENT_KEY_IMAGE_ERRORS
+        .iter()
+        .any(|spent| normalized.contains(spent))
+}
+
+/// Derive the account key of a gift code from the bip39 entropy in the code.
+///
+/// Transfer codes use the account at index 0 of the mnemonic, like mobilecoind.
+pub fn gift_account_key(bip39_entropy: &[u8]) -> Result<AccountKey, String> {
+    if bip39_entropy.is_empty() {
+        return Err("gift codes without bip39 entropy are not supported".to_string());
+    }
+    let mnemonic = Mnemonic::from_entropy(bip39_entropy, Language::English)
+        .map_err(|err| format!("invalid gift code entropy: {err}"))?;
+    Ok(AccountKey::from(mnemonic.derive_slip10_key(0)))
+}
+
+/// Turn the error of redeeming a gift code into one the user can act on
+pub fn describe_redeem_error(err: &str) -> String {
+    if is_spent_key_image_error(err) {
+        "Gift code not redeemed: it was already redeemed".to_string()
+    } else {
+        format!("Gift code not redeemed: {err}")
+    }
+}
@@ -0,0 +1,87 @@
+//! The health of the connections to mobilecoind and the deqs, judged from the
+//! outcome of the worker's recent polls.
+
+use std::time::{Duration, Instant};
+
+/// How a backend is doing
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HealthStatus {
+    /// The most recent poll succeeded
+    Healthy,
+    /// The backend wasn't polled yet, or a few polls in a row failed
+    Degraded,
+    /// At least DOWN_AFTER polls in a row failed
+    Down,
+}
+
+/// The outcome of the recent polls of a backend
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionHealth {
+    /// When a poll last succeeded, if one did
+    pub last_success: Option<Instant>,
+    /// How many polls failed since the last success
+    pub consecutive_failures: u32,
+    /// The error of the most recent failed poll, if any
+    pub last_error: Option<String>,
+}
+
+impl ConnectionHealth {
+    /// How many polls in a row must fail before the backend is considered down
+    pub const DOWN_AFTER: u32 = 3;
+    /// How long to wait after the first failed poll
+    pub const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+    /// The longest wait after failed polls
+    pub const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+    /// How long to wait before polling again after a failure. The wait doubles with
+    /// every failure in a row, up to MAX_BACKOFF.
+    pub fn backoff(&self) -> Duration {
+        let doublings = self.consecutive_failures.saturating_sub(1).min(16);
+        Self::INITIAL_BACKOFF
+            .saturating_mul(1 << doublings)
+            .min(Self::MAX_BACKOFF)
+    }
+
+    /// How the backend is doing
+    pub fn status(&self) -> HealthStatus {
+        if self.consecutive_failures >= Self::DOWN_AFTER {
+            HealthStatus::Down
+        } else if self.consecutive_failures > 0 || self.last_success.is_none() {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Healthy
+        }
+    }
+
+    /// Record a successful poll
+    pub fn record_success(&mut self) {
+        self.last_success = Some(Instant::now());
+        self.consecutive_failures = 0;
+    }
+
+    /// Record a failed poll. Returns whether the backend just went down.
+    pub fn record_failure(&mut self, err: String) -> bool {
+        let was_down = self.status() == HealthStatus::Down;
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.last_error = Some(err);
+        !was_down && self.status() == HealthStatus::Down
+    }
+
+    /// A description of the last success and the last error, for hover text
+    pub fn describe(&self) -> String {
+        let last_success = match self.last_success {
+            Some(at) => format!("{}s ago", at.elapsed().as_secs()),
+            None => "never".to_string(),
+        };
+        let mut result = format!("Last success: {last_success}");
+        if self.consecutive_failures > 0 {
+            if let Some(err) = self.last_error.as_ref() {
+                result += &format!(
+                    "\nLast error ({} in a row): {err}",
+                    self.consecutive_failures
+                );
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,344 @@
+//! The history of the monitored account: the txos it received and spent, as
+//! reported by mobilecoind for each processed block.
+//!
+//! The history can grow to tens of thousands of entries, so it lives in a spill
+//! file, and is read back one filtered page at a time.
+
+use crate::{SpillError, SpillFile, TokenId};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Whether funds came in or went out
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Direction {
+    /// A txo we spent
+    Sent,
+    /// A txo we received
+    Received,
+}
+
+/// A txo received or spent by the monitored account
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HistoryEntry {
+    /// The block the txo was received or spent in
+    pub block: u64,
+    /// The token id of the txo
+    pub token_id: TokenId,
+    /// The value of the txo
+    pub value: u64,
+    /// Whether the txo was received or spent
+    pub direction: Direction,
+    /// The subaddress which owns the txo
+    pub subaddress_index: u64,
+}
+
+impl HistoryEntry {
+    /// The size of an encoded entry
+    const ENCODED_LEN: usize = 33;
+
+    /// Encode as a spill file record
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::ENCODED_LEN);
+        bytes.extend_from_slice(&self.block.to_le_bytes());
+        bytes.extend_from_slice(&self.token_id.to_le_bytes());
+        bytes.extend_from_slice(&self.value.to_le_bytes());
+        bytes.push(match self.direction {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        });
+        bytes.extend_from_slice(&self.subaddress_index.to_le_bytes());
+        bytes
+    }
+
+    /// Decode a spill file record, returning None if it is malformed
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::ENCODED_LEN {
+            return None;
+        }
+        let u64_at = |offset: usize| -> Option<u64> {
+            Some(u64::from_le_bytes(
+                bytes.get(offset..offset + 8)?.try_into().ok()?,
+            ))
+        };
+        let direction = match bytes[24] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            _ => return None,
+        };
+        Some(Self {
+            block: u64_at(0)?,
+            token_id: TokenId::from(u64_at(8)?),
+            value: u64_at(16)?,
+            direction,
+            subaddress_index: u64_at(25)?,
+        })
+    }
+}
+
+/// Which history entries to return, and how many
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HistoryFilter {
+    /// Only entries of this token id, if set
+    pub token_id: Option<TokenId>,
+    /// Only entries in this direction, if set
+    pub direction: Option<Direction>,
+    /// Only entries at or after this block, if set
+    pub since_block: Option<u64>,
+    /// The maximum number of entries in a page
+    pub limit: usize,
+}
+
+impl Default for HistoryFilter {
+    fn default() -> Self {
+        Self {
+            token_id: None,
+            direction: None,
+            since_block: None,
+            limit: 50,
+        }
+    }
+}
+
+impl HistoryFilter {
+    /// Whether an entry passes the filter
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        self.token_id
+            .map_or(true, |token_id| entry.token_id == token_id)
+            && self
+                .direction
+                .map_or(true, |direction| entry.direction == direction)
+            && self
+                .since_block
+                .map_or(true, |since_block| entry.block >= since_block)
+    }
+}
+
+/// A page of history entries, newest first
+#[derive(Clone, Debug, Default)]
+pub struct HistoryPage {
+    /// The entries which passed the filter
+    pub entries: Vec<HistoryEntry>,
+    /// The number of records to skip to read the next page
+    pub next_skip: usize,
+    /// Whether there are no more entries which could pass the filter
+    pub exhausted: bool,
+}
+
+/// The history of the account, stored in a spill file
+pub struct History {
+    /// The file holding the entries, oldest first
+    file: SpillFile,
+    /// The next block to look for entries in
+    next_block: u64,
+}
+
+impl History {
+    /// How many records to read from the file at a time while filtering
+    const READ_CHUNK: usize = 1000;
+
+    /// Open the history at a path. The blocks up to and including the newest
+    /// entry in the file were already processed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, SpillError> {
+        let file = SpillFile::new(path);
+        let next_block = file
+            .read_page(0, 1)?
+            .first()
+            .and_then(|bytes| HistoryEntry::from_bytes(bytes))
+            .map(|entry| entry.block + 1)
+            .unwrap_or(0);
+        Ok(Self { file, next_block })
+    }
+
+    /// The next block to look for entries in
+    pub fn next_block(&self) -> u64 {
+        self.next_block
+    }
+
+    /// Record the entries of a processed block. Blocks must be recorded in order.
+    pub fn record_block(&mut self, block: u64, entries: &[HistoryEntry]) -> Result<(), SpillError> {
+        if !entries.is_empty() {
+            let records: Vec<Vec<u8>> = entries.iter().map(HistoryEntry::to_bytes).collect();
+            self.file.append(&records)?;
+        }
+        self.next_block = block + 1;
+        Ok(())
+    }
+
+    /// Read every entry, oldest first
+    pub fn all(&self) -> Result<Vec<HistoryEntry>, SpillError> {
+        let mut records = self.file.read_page(0, usize::MAX)?;
+        records.reverse();
+        Ok(records
+            .iter()
+            .filter_map(|bytes| HistoryEntry::from_bytes(bytes))
+            .collect())
+    }
+
+    /// Read a page of entries which pass the filter, newest first, skipping the
+    /// `skip` newest records of the file (see HistoryPage::next_skip).
+    pub fn query(&self, filter: &HistoryFilter, skip: usize) -> Result<HistoryPage, SpillError> {
+        let mut page = HistoryPage {
+            next_skip: skip,
+            ..Default::default()
+        };
+        while page.entries.len() < filter.limit {
+            let records = self.file.read_page(page.next_skip, Self::READ_CHUNK)?;
+            if records.is_empty() {
+                page.exhausted = true;
+                break;
+            }
+            for bytes in records {
+                page.next_skip += 1;
+                let Some(entry) = HistoryEntry::from_bytes(&bytes) else {
+                    continue;
+                };
+                // Entries are in block order, nothing older can pass the filter
+                if filter
+                    .since_block
+                    .map_or(false, |since_block| entry.block < since_block)
+                {
+                    page.exhausted = true;
+                    return Ok(page);
+                }
+                if filter.matches(&entry) {
+                    page.entries.push(entry);
+                    if page.entries.len() == filter.limit {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // A history file path in a temp dir, removed when the guard is dropped
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("buddy-history-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(name);
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn entry(block: u64, token_id: u64, direction: Direction) -> HistoryEntry {
+        HistoryEntry {
+            block,
+            token_id: TokenId::from(token_id),
+            value: block * 1_000,
+            direction,
+            subaddress_index: 0,
+        }
+    }
+
+    // Ten blocks, each with a MOB entry received and an EUSD entry sent
+    fn history(path: &TempPath) -> History {
+        let mut history = History::open(&path.0).unwrap();
+        for block in 0..10 {
+            let entries = [
+                entry(block, 0, Direction::Received),
+                entry(block, 1, Direction::Sent),
+            ];
+            history.record_block(block, &entries).unwrap();
+        }
+        history
+    }
+
+    #[test]
+    fn entries_round_trip_through_their_encoding() {
+        let entry = HistoryEntry {
+            block: u64::MAX,
+            token_id: TokenId::from(u64::MAX - 1),
+            value: u64::MAX - 2,
+            direction: Direction::Sent,
+            subaddress_index: u64::MAX - 3,
+        };
+        let bytes = entry.to_bytes();
+        assert_eq!(bytes.len(), HistoryEntry::ENCODED_LEN);
+        assert_eq!(HistoryEntry::from_bytes(&bytes), Some(entry));
+
+        assert_eq!(HistoryEntry::from_bytes(&bytes[1..]), None);
+        let mut bad_direction = bytes;
+        bad_direction[24] = 2;
+        assert_eq!(HistoryEntry::from_bytes(&bad_direction), None);
+    }
+
+    #[test]
+    fn reopening_resumes_after_the_newest_entry() {
+        let path = TempPath::new("resume.spill");
+        assert_eq!(History::open(&path.0).unwrap().next_block(), 0);
+        assert_eq!(history(&path).next_block(), 10);
+        let reopened = History::open(&path.0).unwrap();
+        assert_eq!(reopened.next_block(), 10);
+        assert_eq!(reopened.all().unwrap().len(), 20);
+        assert_eq!(reopened.all().unwrap()[0], entry(0, 0, Direction::Received));
+    }
+
+    #[test]
+    fn pages_continue_where_the_last_one_stopped() {
+        let path = TempPath::new("pages.spill");
+        let history = history(&path);
+        let filter = HistoryFilter {
+            token_id: Some(TokenId::from(0)),
+            limit: 4,
+            ..Default::default()
+        };
+
+        let first = history.query(&filter, 0).unwrap();
+        let blocks = |page: &HistoryPage| page.entries.iter().map(|e| e.block).collect::<Vec<_>>();
+        assert_eq!(blocks(&first), vec![9, 8, 7, 6]);
+        assert!(!first.exhausted);
+
+        let second = history.query(&filter, first.next_skip).unwrap();
+        assert_eq!(blocks(&second), vec![5, 4, 3, 2]);
+
+        let last = history.query(&filter, second.next_skip).unwrap();
+        assert_eq!(blocks(&last), vec![1, 0]);
+        assert!(last.exhausted);
+    }
+
+    #[test]
+    fn pages_stop_at_the_since_block() {
+        let path = TempPath::new("since.spill");
+        let history = history(&path);
+        let filter = HistoryFilter {
+            direction: Some(Direction::Sent),
+            since_block: Some(8),
+            ..Default::default()
+        };
+        let page = history.query(&filter, 0).unwrap();
+        assert_eq!(
+            page.entries,
+            vec![entry(9, 1, Direction::Sent), entry(8, 1, Direction::Sent)]
+        );
+        assert!(page.exhausted);
+    }
+
+    #[test]
+    fn corrupt_files_make_the_history_unavailable() {
+        let path = TempPath::new("corrupt.spill");
+        history(&path);
+        let mut bytes = std::fs::read(&path.0).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        std::fs::write(&path.0, &bytes).unwrap();
+        assert!(matches!(
+            History::open(&path.0),
+            Err(SpillError::Corrupt(_))
+        ));
+    }
+}
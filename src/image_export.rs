@@ -0,0 +1,251 @@
+//! Helpers to export simple images (like a snapshot of a quote book) as PNG,
+//! either to the clipboard or, where the platform doesn't support clipboard
+//! images, to a file.
+
+use egui::{Color32, ColorImage};
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
+use tracing::{event, Level};
+
+/// Glyph width in font pixels
+const GLYPH_WIDTH: usize = 5;
+/// Glyph height in font pixels
+const GLYPH_HEIGHT: usize = 7;
+/// How many image pixels each font pixel takes
+const FONT_SCALE: usize = 2;
+/// Padding around the table and between cells, in image pixels
+const PADDING: usize = 8;
+
+/// Render rows of text as a table, in a small built-in bitmap font.
+///
+/// Text is rendered in upper case, and characters the font doesn't know are rendered as '?'.
+pub fn render_table(rows: &[Vec<String>]) -> ColorImage {
+    let num_columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let column_chars: Vec<usize> = (0..num_columns)
+        .map(|col| {
+            rows.iter()
+                .filter_map(|row| row.get(col))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let char_width = (GLYPH_WIDTH + 1) * FONT_SCALE;
+    let row_height = (GLYPH_HEIGHT + 2) * FONT_SCALE;
+    let width = PADDING
+        + column_chars
+            .iter()
+            .map(|chars| chars * char_width + PADDING)
+            .sum::<usize>();
+    let height = 2 * PADDING + rows.len() * row_height;
+
+    let mut image = ColorImage::new([width, height], Color32::WHITE);
+    for (row_idx, row) in rows.iter().enumerate() {
+        let mut x = PADDING;
+        let y = PADDING + row_idx * row_height;
+        for (col_idx, cell) in row.iter().enumerate() {
+            for (char_idx, c) in cell.chars().enumerate() {
+                draw_glyph(&mut image, x + char_idx * char_width, y, c);
+            }
+            x += column_chars[col_idx] * char_width + PADDING;
+        }
+    }
+    image
+}
+
+/// Encode an image as PNG
+pub fn encode_png(image: &ColorImage) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::<u8>::default();
+    {
+        let mut encoder =
+            png::Encoder::new(&mut bytes, image.width() as u32, image.height() as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+        writer
+            .write_image_data(&rgba_bytes(image))
+            .map_err(|err| err.to_string())?;
+    }
+    Ok(bytes)
+}
+
+/// Copy an image to the clipboard.
+///
+/// If the platform doesn't support clipboard images, the image is saved as a PNG
+/// in the data dir instead, and the path is returned.
+pub fn copy_image(
+    image: &ColorImage,
+    file_stem: &str,
+    data_dir: &Path,
+) -> Result<Option<PathBuf>, String> {
+    let clipboard_result = arboard::Clipboard::new().and_then(|mut clipboard| {
+        clipboard.set_image(arboard::ImageData {
+            width: image.width(),
+            height: image.height(),
+            bytes: Cow::Owned(rgba_bytes(image)),
+        })
+    });
+    match clipboard_result {
+        Ok(()) => Ok(None),
+        Err(err) => {
+            event!(
+                Level::WARN,
+                "could not copy image to clipboard, saving it instead: {}",
+                err
+            );
+            save_png(image, file_stem, data_dir).map(Some)
+        }
+    }
+}
+
+/// Save an image as a PNG in the data dir, returning the path
+pub fn save_png(image: &ColorImage, file_stem: &str, dir: &Path) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let path = dir.join(format!("{file_stem}-{timestamp}.png"));
+    std::fs::write(&path, encode_png(image)?)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(path)
+}
+
+// The pixels of an image as non-premultiplied rgba bytes
+fn rgba_bytes(image: &ColorImage) -> Vec<u8> {
+    image
+        .pixels
+        .iter()
+        .flat_map(|pixel| pixel.to_srgba_unmultiplied())
+        .collect()
+}
+
+// Draw one character with its top left corner at (x, y)
+fn draw_glyph(image: &mut ColorImage, x: usize, y: usize, c: char) {
+    let rows = glyph(c.to_ascii_uppercase());
+    for (gy, bits) in rows.iter().enumerate() {
+        for gx in 0..GLYPH_WIDTH {
+            if bits & (1 << (GLYPH_WIDTH - 1 - gx)) == 0 {
+                continue;
+            }
+            for dy in 0..FONT_SCALE {
+                for dx in 0..FONT_SCALE {
+                    let px = x + gx * FONT_SCALE + dx;
+                    let py = y + gy * FONT_SCALE + dy;
+                    if px < image.width() && py < image.height() {
+                        image[(px, py)] = Color32::BLACK;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// A 5x7 bitmap font, each row is the low 5 bits of a byte
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decode a PNG, returning its dimensions and rgba bytes
+    fn decode_png(bytes: &[u8]) -> (u32, u32, Vec<u8>) {
+        let mut reader = png::Decoder::new(bytes).read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        buf.truncate(info.buffer_size());
+        (info.width, info.height, buf)
+    }
+
+    fn book() -> Vec<Vec<String>> {
+        vec![
+            vec!["Price".to_owned(), "Volume (MOB)".to_owned()],
+            vec!["1.25".to_owned(), "1,000.5".to_owned()],
+            vec!["1.5".to_owned(), "20".to_owned()],
+        ]
+    }
+
+    #[test]
+    fn tables_render_to_an_image_which_fits_their_text() {
+        let image = render_table(&book());
+        let char_width = (GLYPH_WIDTH + 1) * FONT_SCALE;
+        assert!(image.width() >= ("Price".len() + "Volume (MOB)".len()) * char_width);
+        assert!(image.height() >= 3 * GLYPH_HEIGHT * FONT_SCALE);
+        assert!(image.pixels.iter().any(|pixel| *pixel == Color32::BLACK));
+
+        let empty = render_table(&[]);
+        assert_eq!(empty.size, [PADDING, 2 * PADDING]);
+        assert!(empty.pixels.iter().all(|pixel| *pixel == Color32::WHITE));
+    }
+
+    #[test]
+    fn encoded_pngs_decode_to_the_same_pixels() {
+        let image = render_table(&book());
+        let (width, height, pixels) = decode_png(&encode_png(&image).unwrap());
+        assert_eq!([width as usize, height as usize], image.size);
+        assert_eq!(pixels, rgba_bytes(&image));
+    }
+
+    #[test]
+    fn saved_pngs_land_in_the_data_dir() {
+        let dir = std::env::temp_dir().join(format!("buddy-image-export-{}", std::process::id()));
+        let image = render_table(&book());
+        let path = save_png(&image, "book", &dir).unwrap();
+        assert!(path.starts_with(&dir));
+        let (width, height, _) = decode_png(&std::fs::read(&path).unwrap());
+        assert_eq!([width as usize, height as usize], image.size);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
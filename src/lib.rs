@@ -1,11 +1,81 @@
+mod access;
 mod app;
+pub mod app_state;
+mod commands;
 mod config;
+mod empty_state;
+mod encrypted_keyfile;
+mod error_queue;
+mod first_run;
+mod gift_codes;
 mod grpcio_extensions;
+mod health;
+mod history;
+mod image_export;
+mod offers;
+mod pending;
+pub mod preflight;
+mod prices;
+mod qr;
+mod quote_stream;
+mod recipient;
+mod retention;
+mod retry;
+mod sci_debug_view;
+mod spill;
+mod subaddresses;
+mod token_metadata;
 mod types;
+mod view_only_keyfile;
 mod worker;
 
-pub use app::App;
-pub use config::Config;
-pub use grpcio_extensions::ConnectionUriGrpcioChannel;
-pub use types::{Amount, QuoteInfo, QuoteSelection, TokenId, TokenInfo, ValidatedQuote};
-pub use worker::Worker;
+pub use access::{AccessDenied, AccessPolicy, AccessToken, Permission};
+pub use app::{App, UI_SCALE_RANGE};
+pub use app_state::{SCHEMA_VERSION, STATE_FILE, STATE_KEY};
+pub use commands::{
+    CommandId, CommandInfo, CommandLog, CommandStatus, Payment, TxOptions, WorkerCommand,
+};
+pub use config::{Config, DeqsSubmitMode};
+pub use empty_state::{empty_state, EmptyStateKind};
+pub use encrypted_keyfile::{
+    decrypt_keyfile, encrypt_keyfile_in_place, is_encrypted_keyfile, KeyfileError,
+};
+pub use error_queue::{ErrorEntry, ErrorQueue, Severity};
+pub use first_run::{
+    generate_mnemonic, is_mnemonic_word, parse_mnemonic, write_keyfile, MNEMONIC_WORDS,
+};
+pub use gift_codes::{describe_redeem_error, gift_account_key, is_spent_key_image_error, GiftCode};
+pub use grpcio_extensions::{
+    describe_rpc_error, AuthToken, ConnectionUriGrpcioChannel, GrpcProxy, GrpcTuning, RpcTimeouts,
+    TlsIdentity,
+};
+pub use health::{ConnectionHealth, HealthStatus};
+pub use history::{Direction, History, HistoryEntry, HistoryFilter, HistoryPage};
+pub use offers::{
+    FillMode, HeldOffer, HeldOffers, HoldHandle, InputMatch, InputPreparationError, OfferId,
+    OfferStatus, OfferTracker, OperationProgress, SavedOffer, SavedOfferStatus, SubmitOutcome,
+    TrackedOffer, TwoSidedQuote,
+};
+pub use pending::{PendingTransaction, ReceiverStatus};
+pub use preflight::PreconditionFailed;
+pub use prices::{fiat_value, format_fiat, mid_price, IndicativePrices, PriceSample, PriceSampler};
+pub use qr::{render_qr, QrTexture};
+pub use quote_stream::{QuoteStream, QuoteUpdate, StreamEvent};
+pub use recipient::{check_recipient_network, NetworkKind, Recipient, MAINNET_CHAIN_ID};
+pub use retention::{RetentionPolicies, RetentionPolicy};
+pub use retry::RetryPolicy;
+pub use sci_debug_view::sci_debug_view;
+pub use spill::{SpillError, SpillFile};
+pub use subaddresses::{IssuedSubaddress, SubaddressBalances};
+pub use token_metadata::{TokenMetadata, TokenRegistry};
+pub use types::{
+    aggregate_book, normalize_amount_input, Amount, AmountError, BalanceSnapshot, DepthLevel,
+    DetailedBalance, QuoteBook, QuoteFilter, QuoteId, QuoteInfo, QuoteSelection, QuoteSide,
+    ScaledAmount, SciFill, TokenId, TokenInfo, UtxoInfo, ValidatedQuote,
+};
+pub use view_only_keyfile::{
+    read_view_only_keyfile, view_account_key_from_json, ViewOnlyKeyfileError,
+};
+pub use worker::{
+    ActivityLevel, CommandError, DeqsStatus, InitStatus, PollIntervals, Worker, WorkerInitError,
+};
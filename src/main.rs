@@ -1,6 +1,9 @@
 use clap::Parser;
 use egui::Vec2;
-use mobilecoind_buddy::{App, Config, Worker};
+use mobilecoind_buddy::{
+    encrypt_keyfile_in_place, is_encrypted_keyfile, App, Config, Worker, UI_SCALE_RANGE,
+};
+use zeroize::Zeroizing;
 
 fn main() -> eframe::Result<()> {
     // Log to stdout (if you run with `RUST_LOG=debug`).
@@ -8,10 +11,31 @@ fn main() -> eframe::Result<()> {
 
     let config = Config::parse();
 
-    let worker = Worker::new(config.clone()).expect("initialization failed");
+    if config.encrypt_keyfile {
+        encrypt_keyfile(&config);
+        return Ok(());
+    }
 
+    // An encrypted keyfile is decrypted once the App asks for its passphrase, and a
+    // missing one is created or imported on the App's first-run screen. If the worker
+    // fails to start, the App shows why. A watch-only wallet only loads a view-only
+    // account file, so it is never asked for a passphrase nor offered a new keyfile.
+    let worker = if !config.watch_only
+        && (!config.keyfile.exists() || is_encrypted_keyfile(&config.keyfile))
+    {
+        Ok(None)
+    } else {
+        Worker::new(config.clone()).map(Some)
+    };
+
+    // The window is sized in native points, so a scale chosen on the command line
+    // enlarges it, to fit what used to fit at the native scale
+    let ui_scale = config
+        .ui_scale
+        .unwrap_or(1.0)
+        .clamp(*UI_SCALE_RANGE.start(), *UI_SCALE_RANGE.end());
     let native_options = eframe::NativeOptions {
-        initial_window_size: Some(Vec2 { x: 600.0, y: 480.0 }),
+        initial_window_size: Some(Vec2 { x: 600.0, y: 480.0 } * ui_scale),
         centered: true,
         ..Default::default()
     };
@@ -22,3 +46,27 @@ fn main() -> eframe::Result<()> {
         Box::new(|cc| Box::new(App::new(cc, config, worker))),
     )
 }
+
+// Encrypt the keyfile in place with a passphrase read from the terminal, exiting
+// with an error if that fails
+fn encrypt_keyfile(config: &Config) {
+    let prompt = |prompt: &str| {
+        Zeroizing::new(rpassword::prompt_password(prompt).expect("reading the passphrase"))
+    };
+    let passphrase = prompt("New keyfile passphrase: ");
+    if passphrase.is_empty() {
+        eprintln!("The passphrase may not be empty");
+        std::process::exit(1);
+    }
+    if passphrase != prompt("Repeat the passphrase: ") {
+        eprintln!("The passphrases don't match");
+        std::process::exit(1);
+    }
+    match encrypt_keyfile_in_place(&config.keyfile, &passphrase) {
+        Ok(()) => println!("Encrypted {}", config.keyfile.display()),
+        Err(err) => {
+            eprintln!("{}: {err}", config.keyfile.display());
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,1286 @@
+//! Tracking of the swap offers that we submit to the deqs.
+
+use crate::{
+    Amount, QuoteId, QuoteInfo, QuoteSide, RetentionPolicy, ScaledAmount, TokenId, TokenInfo,
+    ValidatedQuote,
+};
+use deqs_api::deqs as d_api;
+use displaydoc::Display;
+use grpcio::RpcStatusCode;
+use mc_api::external;
+use mc_crypto_ring_signature::KeyImage;
+use mc_transaction_extra::{SignedContingentInput, SignedContingentInputAmounts};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Identifies an offer we submitted during this session
+pub type OfferId = u64;
+
+/// The status of an offer we submitted
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OfferStatus {
+    /// We are generating the sci and submitting it to the deqs
+    Submitting,
+    /// The deqs accepted the offer, and it is open
+    Live,
+    /// The submission failed
+    Failed(String),
+    /// The input of the offer was spent, so someone filled it
+    Filled,
+    /// The deqs no longer lists the offer, although its input is unspent
+    Expired,
+    /// We are spending the input of the offer to cancel it
+    Cancelling,
+    /// The input of the offer was spent by us, cancelling it
+    Cancelled,
+}
+
+/// Whether the counterparty of an offer may fill part of it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FillMode {
+    /// The offer may be filled partially, down to a minimum fill value
+    Partial {
+        /// The minimum fill value, in the offered token. If None, a multiple of the
+        /// network fee is used.
+        min_fill_value: Option<u64>,
+    },
+    /// The offer must be filled entirely, or not at all
+    AllOrNothing,
+}
+
+impl FillMode {
+    /// The minimum fill value, in network fees, unless the user picks one
+    pub const DEFAULT_MIN_FILL_FEES: u64 = 10;
+
+    /// The minimum fill value of an offer of `volume`, whose token has a network
+    /// fee of `fee`, or None if the offer can't be filled partially.
+    ///
+    /// The value is clamped to at least the fee and at most the volume, so that the
+    /// offer stays fillable.
+    pub fn min_fill_value(&self, fee: u64, volume: u64) -> Option<u64> {
+        match self {
+            Self::Partial { min_fill_value } => Some(
+                min_fill_value
+                    .unwrap_or(fee.saturating_mul(Self::DEFAULT_MIN_FILL_FEES))
+                    .max(fee)
+                    .min(volume),
+            ),
+            Self::AllOrNothing => None,
+        }
+    }
+}
+
+/// An offer we submitted, and what we know about it
+#[derive(Clone, Debug)]
+pub struct TrackedOffer {
+    /// The id of this offer
+    pub id: OfferId,
+    /// The amount we are offering
+    pub from_amount: Amount,
+    /// The amount we are asking for in return
+    pub to_amount: Amount,
+    /// The subaddress whose txo is offered
+    pub sender_subaddress: u64,
+    /// Whether the offer may be filled partially
+    pub fill_mode: FillMode,
+    /// The key image of the sci, once it has been generated
+    pub key_image: Option<KeyImage>,
+    /// The quotes which represent this offer at the deqs which accepted it
+    pub quote_ids: Vec<QuoteId>,
+    /// The status of the offer
+    pub status: OfferStatus,
+    /// When the user asked for the offer, or when it was last renewed
+    pub submitted_at: Instant,
+    /// Whether to submit the offer again, with a fresh sci, whenever it expires
+    pub keep_alive: bool,
+    /// How many times the offer was submitted again after it expired
+    pub renewals: u32,
+    /// The other side, if the offer was posted together with one on the other side
+    /// of the book
+    pub paired_with: Option<OfferId>,
+}
+
+impl TrackedOffer {
+    /// Get the information to render this offer as part of the quote book of
+    /// the given pair, like ValidatedQuote::get_quote_info.
+    pub fn get_quote_info(
+        &self,
+        base_token_id: TokenId,
+        counter_token_id: TokenId,
+        token_infos: &[TokenInfo],
+    ) -> Result<QuoteInfo, String> {
+        let base_token_info: &TokenInfo = token_infos
+            .iter()
+            .find(|info| info.token_id == base_token_id)
+            .ok_or("missing base token info".to_owned())?;
+
+        let counter_token_info: &TokenInfo = token_infos
+            .iter()
+            .find(|info| info.token_id == counter_token_id)
+            .ok_or("missing counter token info".to_owned())?;
+
+        let (quote_side, base_amount, counter_amount) = if self.from_amount.token_id
+            == base_token_id
+            && self.to_amount.token_id == counter_token_id
+        {
+            // We are offering the base token, so this is an ask
+            (QuoteSide::Ask, self.from_amount, self.to_amount)
+        } else if self.from_amount.token_id == counter_token_id
+            && self.to_amount.token_id == base_token_id
+        {
+            // We are offering the counter token, so this is a bid
+            (QuoteSide::Bid, self.to_amount, self.from_amount)
+        } else {
+            return Err("Offer does not belong to this book".to_owned());
+        };
+
+        let volume = ScaledAmount::new(base_amount.value, base_amount.token_id)
+            .to_decimal(base_token_info)?;
+        let counter_volume = ScaledAmount::new(counter_amount.value, counter_amount.token_id)
+            .to_decimal(counter_token_info)?;
+        let price = counter_volume
+            .checked_div(volume)
+            .ok_or("Offer has zero volume".to_owned())?;
+
+        Ok(QuoteInfo {
+            quote_side,
+            price,
+            volume,
+            is_partial_fill: true,
+            timestamp: 0,
+            is_mine: true,
+        })
+    }
+
+    /// The deqs a cancellation of this offer goes to, by index: every deqs which
+    /// accepted the offer or was seen listing it, whatever the submit mode is now.
+    /// Spending the input invalidates the sci at all of them, and its quotes are
+    /// dropped from their books once the spend lands.
+    pub fn cancel_targets(&self) -> Vec<usize> {
+        let mut targets: Vec<usize> = self.quote_ids.iter().map(|id| id.source).collect();
+        targets.sort_unstable();
+        targets.dedup();
+        targets
+    }
+}
+
+/// The metadata of an offer which is kept across restarts. The sci itself isn't
+/// kept, only what is needed to show the offer and follow its status.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SavedOffer {
+    /// The amount we are offering
+    pub from_amount: Amount,
+    /// The amount we are asking for in return
+    pub to_amount: Amount,
+    /// The subaddress whose txo is offered
+    pub sender_subaddress: u64,
+    /// The key image of the sci
+    pub key_image: KeyImage,
+    /// Whether the offer was filled or expired, rather than open
+    pub status: SavedOfferStatus,
+    /// When the user asked for the offer, in seconds since the unix epoch
+    pub submitted_at: u64,
+}
+
+/// The status of a saved offer
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SavedOfferStatus {
+    /// The offer is open
+    Open,
+    /// The offer was filled
+    Filled,
+    /// The offer expired
+    Expired,
+    /// The offer was cancelled
+    Cancelled,
+}
+
+impl SavedOffer {
+    /// Read the saved offers from a file. A missing file means no offers.
+    pub fn load(path: impl AsRef<Path>) -> Result<Vec<Self>, String> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|err| err.to_string()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    /// Write saved offers to a file, replacing its contents
+    pub fn store(path: impl AsRef<Path>, offers: &[Self]) -> Result<(), String> {
+        let bytes = serde_json::to_vec_pretty(offers).map_err(|err| err.to_string())?;
+        std::fs::write(path, bytes).map_err(|err| err.to_string())
+    }
+}
+
+/// Tracks the offers we submitted, from the moment the user asks for them.
+///
+/// An offer starts out Submitting, and becomes Live when the deqs accepts it,
+/// or Failed if the submission fails. Since the worker polls the deqs while the
+/// submission is in flight, we may see our own quote in the book before the
+/// submission returns, in which case the offer becomes Live right away.
+///
+/// A Live offer becomes Filled once its input is spent, or Expired if the deqs
+/// stops listing it while its input is unspent. A Live or Expired offer which the
+/// user cancels is Cancelling until we see its input spent, and then Cancelled,
+/// unless it turns out that someone filled it first. An Expired offer which the
+/// user wants kept alive is renewed: it is Submitting again, with a fresh sci.
+#[derive(Default)]
+pub struct OfferTracker {
+    /// The id to give the next offer
+    next_id: OfferId,
+    /// The offers, in the order they were submitted
+    offers: Vec<TrackedOffer>,
+}
+
+impl OfferTracker {
+    /// How often the worker checks whether live offers were filled or expired
+    pub const POLL_INTERVAL: Duration = Duration::from_secs(10);
+    /// How long after submitting an offer the deqs may take to list it, before
+    /// its absence from the quote book means it expired
+    pub const LISTING_GRACE: Duration = Duration::from_secs(30);
+
+    /// Start tracking a new offer, which is being submitted
+    pub fn begin(
+        &mut self,
+        from_amount: Amount,
+        to_amount: Amount,
+        sender_subaddress: u64,
+        fill_mode: FillMode,
+        keep_alive: bool,
+    ) -> OfferId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.offers.push(TrackedOffer {
+            id,
+            from_amount,
+            to_amount,
+            sender_subaddress,
+            fill_mode,
+            key_image: None,
+            quote_ids: Default::default(),
+            status: OfferStatus::Submitting,
+            submitted_at: Instant::now(),
+            keep_alive,
+            renewals: 0,
+            paired_with: None,
+        });
+        id
+    }
+
+    /// Record that two offers were posted together, on either side of the book
+    pub fn pair(&mut self, id: OfferId, other_id: OfferId) {
+        if let Some(offer) = self.get_mut(id) {
+            offer.paired_with = Some(other_id);
+        }
+        if let Some(offer) = self.get_mut(other_id) {
+            offer.paired_with = Some(id);
+        }
+    }
+
+    /// Set whether to renew an offer whenever it expires
+    pub fn set_keep_alive(&mut self, id: OfferId, keep_alive: bool) {
+        if let Some(offer) = self.get_mut(id) {
+            offer.keep_alive = keep_alive;
+        }
+    }
+
+    /// Record that an expired offer which is kept alive is being submitted again.
+    /// Returns false if the offer isn't expired, or isn't kept alive.
+    pub fn begin_renewal(&mut self, id: OfferId) -> bool {
+        match self.get_mut(id) {
+            Some(offer) if offer.status == OfferStatus::Expired && offer.keep_alive => {
+                offer.status = OfferStatus::Submitting;
+                offer.key_image = None;
+                offer.quote_ids.clear();
+                offer.submitted_at = Instant::now();
+                offer.renewals += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Record the key image of the sci that was generated for an offer
+    pub fn set_key_image(&mut self, id: OfferId, key_image: KeyImage) {
+        if let Some(offer) = self.get_mut(id) {
+            offer.key_image = Some(key_image);
+        }
+    }
+
+    /// Record that the deqs accepted an offer
+    pub fn mark_live(&mut self, id: OfferId, quote_ids: Vec<QuoteId>) {
+        if let Some(offer) = self.get_mut(id) {
+            for quote_id in quote_ids {
+                if !offer.quote_ids.contains(&quote_id) {
+                    offer.quote_ids.push(quote_id);
+                }
+            }
+            offer.status = OfferStatus::Live;
+        }
+    }
+
+    /// Record that the submission of an offer failed.
+    ///
+    /// If we already saw the offer in the quote book, it stays live.
+    pub fn mark_failed(&mut self, id: OfferId, err: String) {
+        if let Some(offer) = self.get_mut(id) {
+            if offer.status == OfferStatus::Submitting {
+                offer.status = OfferStatus::Failed(err);
+            }
+        }
+    }
+
+    /// Record that the input of a live offer, or one we are cancelling, was spent
+    /// by someone filling it
+    pub fn mark_filled(&mut self, id: OfferId) {
+        if let Some(offer) = self.get_mut(id) {
+            if offer.status == OfferStatus::Live || offer.status == OfferStatus::Cancelling {
+                offer.status = OfferStatus::Filled;
+            }
+        }
+    }
+
+    /// Record that we are cancelling an offer. Returns false if the offer is
+    /// neither live nor expired, so there is nothing to cancel.
+    pub fn mark_cancelling(&mut self, id: OfferId) -> bool {
+        match self.get_mut(id) {
+            Some(offer)
+                if offer.status == OfferStatus::Live || offer.status == OfferStatus::Expired =>
+            {
+                offer.status = OfferStatus::Cancelling;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Record that the input of an offer we are cancelling was spent
+    pub fn mark_cancelled(&mut self, id: OfferId) {
+        if let Some(offer) = self.get_mut(id) {
+            if offer.status == OfferStatus::Cancelling {
+                offer.status = OfferStatus::Cancelled;
+            }
+        }
+    }
+
+    /// Record that cancelling an offer failed, so that it is live again
+    pub fn cancel_failed(&mut self, id: OfferId) {
+        if let Some(offer) = self.get_mut(id) {
+            if offer.status == OfferStatus::Cancelling {
+                offer.status = OfferStatus::Live;
+            }
+        }
+    }
+
+    /// Record that the deqs no longer lists a live offer
+    pub fn mark_expired(&mut self, id: OfferId) {
+        if let Some(offer) = self.get_mut(id) {
+            if offer.status == OfferStatus::Live {
+                offer.status = OfferStatus::Expired;
+            }
+        }
+    }
+
+    /// The metadata of the offers which made it to the deqs, to keep across restarts
+    pub fn saved(&self) -> Vec<SavedOffer> {
+        let now = Instant::now();
+        let now_unix = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.offers
+            .iter()
+            .filter_map(|offer| {
+                let status = match offer.status {
+                    OfferStatus::Live | OfferStatus::Cancelling => SavedOfferStatus::Open,
+                    OfferStatus::Filled => SavedOfferStatus::Filled,
+                    OfferStatus::Expired => SavedOfferStatus::Expired,
+                    OfferStatus::Cancelled => SavedOfferStatus::Cancelled,
+                    OfferStatus::Submitting | OfferStatus::Failed(_) => return None,
+                };
+                let age = now.saturating_duration_since(offer.submitted_at);
+                Some(SavedOffer {
+                    from_amount: offer.from_amount,
+                    to_amount: offer.to_amount,
+                    sender_subaddress: offer.sender_subaddress,
+                    key_image: offer.key_image?,
+                    status,
+                    submitted_at: now_unix.saturating_sub(age).as_secs(),
+                })
+            })
+            .collect()
+    }
+
+    /// Track offers which were saved in a previous session.
+    ///
+    /// Their fill mode isn't saved, which doesn't matter since only failed offers
+    /// are ever submitted again: restored offers aren't kept alive.
+    pub fn restore(&mut self, saved: Vec<SavedOffer>) {
+        let now = Instant::now();
+        let now_unix = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        for offer in saved {
+            let age = now_unix.saturating_sub(Duration::from_secs(offer.submitted_at));
+            let id = self.next_id;
+            self.next_id += 1;
+            self.offers.push(TrackedOffer {
+                id,
+                from_amount: offer.from_amount,
+                to_amount: offer.to_amount,
+                sender_subaddress: offer.sender_subaddress,
+                fill_mode: FillMode::Partial {
+                    min_fill_value: None,
+                },
+                key_image: Some(offer.key_image),
+                quote_ids: Default::default(),
+                status: match offer.status {
+                    SavedOfferStatus::Open => OfferStatus::Live,
+                    SavedOfferStatus::Filled => OfferStatus::Filled,
+                    SavedOfferStatus::Expired => OfferStatus::Expired,
+                    SavedOfferStatus::Cancelled => OfferStatus::Cancelled,
+                },
+                submitted_at: now.checked_sub(age).unwrap_or(now),
+                keep_alive: false,
+                renewals: 0,
+                paired_with: None,
+            });
+        }
+    }
+
+    /// Check a freshly polled quote book for offers which are still submitting,
+    /// and mark them live if they appear in it.
+    pub fn observe_book(&mut self, book: &[ValidatedQuote]) {
+        for offer in self.offers.iter_mut() {
+            if offer.status != OfferStatus::Submitting {
+                continue;
+            }
+            if let Some(key_image) = offer.key_image {
+                if let Some(quote) = book.iter().find(|quote| quote.id.key_image == key_image) {
+                    offer.quote_ids.push(quote.id);
+                    offer.status = OfferStatus::Live;
+                }
+            }
+        }
+    }
+
+    /// Whether the worker acts on some offer by itself, and so needs fresh data even
+    /// while nobody looks at the app: an open offer which is kept alive, since it is
+    /// renewed as soon as it expires, or an open side of a two-sided quote, since a
+    /// fill of one side changes what the other side is worth.
+    pub fn automation_armed(&self) -> bool {
+        self.offers.iter().any(|offer| {
+            let open = matches!(offer.status, OfferStatus::Submitting | OfferStatus::Live);
+            (offer.keep_alive && (open || offer.status == OfferStatus::Expired))
+                || (offer.paired_with.is_some() && open)
+        })
+    }
+
+    /// Stop tracking an offer, returning it
+    pub fn remove(&mut self, id: OfferId) -> Option<TrackedOffer> {
+        let idx = self.offers.iter().position(|offer| offer.id == id)?;
+        Some(self.offers.remove(idx))
+    }
+
+    /// The offers we are tracking
+    pub fn offers(&self) -> &[TrackedOffer] {
+        &self.offers
+    }
+
+    /// Stop tracking the offers which the retention policy doesn't keep, returning them.
+    ///
+    /// Offers which are still submitting or cancelling are always kept, and don't count
+    /// towards the policy.
+    pub fn compact(&mut self, policy: &RetentionPolicy, now: Instant) -> Vec<TrackedOffer> {
+        let mut removed = Vec::default();
+        let mut kept = Vec::default();
+        let mut idx_from_newest = 0;
+        for offer in self.offers.drain(..).rev() {
+            if offer.status == OfferStatus::Submitting || offer.status == OfferStatus::Cancelling {
+                kept.push(offer);
+                continue;
+            }
+            if policy.keeps(
+                idx_from_newest,
+                now.saturating_duration_since(offer.submitted_at),
+            ) {
+                kept.push(offer);
+            } else {
+                removed.push(offer);
+            }
+            idx_from_newest += 1;
+        }
+        kept.reverse();
+        self.offers = kept;
+        removed
+    }
+
+    /// Get an offer we are tracking
+    pub fn get(&self, id: OfferId) -> Option<&TrackedOffer> {
+        self.offers.iter().find(|offer| offer.id == id)
+    }
+
+    fn get_mut(&mut self, id: OfferId) -> Option<&mut TrackedOffer> {
+        self.offers.iter_mut().find(|offer| offer.id == id)
+    }
+}
+
+/// Offers on both sides of a pair around a mid-price, which market makers post
+/// together
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TwoSidedQuote {
+    /// The amount of base token the ask offers, and the counter token it asks for
+    pub ask: (Amount, Amount),
+    /// The amount of counter token the bid offers, and the base token it asks for
+    pub bid: (Amount, Amount),
+}
+
+impl TwoSidedQuote {
+    /// Quote both sides `spread` apart (0.01 is 1%) around `mid_price`: selling
+    /// `ask_volume` of base at mid_price × (1 + spread/2), and buying `bid_volume`
+    /// of base at mid_price × (1 - spread/2).
+    pub fn new(
+        mid_price: Decimal,
+        spread: Decimal,
+        ask_volume: Decimal,
+        bid_volume: Decimal,
+        base_token_info: &TokenInfo,
+        counter_token_info: &TokenInfo,
+    ) -> Result<Self, String> {
+        if !mid_price.is_sign_positive() || mid_price.is_zero() {
+            return Err("The mid-price must be larger than 0".to_owned());
+        }
+        let half_spread = spread / Decimal::TWO;
+        if half_spread.is_sign_negative() || half_spread >= Decimal::ONE {
+            return Err("The spread must be at least 0% and below 200%".to_owned());
+        }
+        let overflow = || "decimal overflow".to_owned();
+        let ask_price = mid_price
+            .checked_mul(Decimal::ONE + half_spread)
+            .ok_or_else(overflow)?;
+        let bid_price = mid_price
+            .checked_mul(Decimal::ONE - half_spread)
+            .ok_or_else(overflow)?;
+        let ask_counter = ask_volume.checked_mul(ask_price).ok_or_else(overflow)?;
+        let bid_counter = bid_volume.checked_mul(bid_price).ok_or_else(overflow)?;
+        Ok(Self {
+            ask: (
+                ScaledAmount::from_decimal(ask_volume, base_token_info)?.into(),
+                ScaledAmount::from_decimal_rounded(ask_counter, counter_token_info)?.into(),
+            ),
+            bid: (
+                ScaledAmount::from_decimal_rounded(bid_counter, counter_token_info)?.into(),
+                ScaledAmount::from_decimal(bid_volume, base_token_info)?.into(),
+            ),
+        })
+    }
+}
+
+/// Identifies an offer which is held for preview
+pub type HoldHandle = u64;
+
+/// An sci which mobilecoind generated for an offer, held until the user
+/// submits or discards it
+#[derive(Clone, Debug)]
+pub struct HeldOffer {
+    /// The handle of this held offer
+    pub handle: HoldHandle,
+    /// The amount we are offering
+    pub from_amount: Amount,
+    /// The amount we are asking for in return
+    pub to_amount: Amount,
+    /// The subaddress whose txo is offered
+    pub sender_subaddress: u64,
+    /// Whether the offer may be filled partially
+    pub fill_mode: FillMode,
+    /// The sci exactly as mobilecoind generated it, which is what gets submitted
+    pub proto_sci: external::SignedContingentInput,
+    /// The decoded sci
+    pub sci: SignedContingentInput,
+    /// The amounts of the sci, as determined by validation
+    pub amounts: SignedContingentInputAmounts,
+    /// When the held offer is discarded automatically
+    pub expires_at: Instant,
+}
+
+/// Offers which the user asked to preview before they are submitted.
+///
+/// An offer is pending while its sci is generated, and then held until it is
+/// submitted, discarded, or expires.
+#[derive(Default)]
+pub struct HeldOffers {
+    /// The handle to give the next offer
+    next_handle: HoldHandle,
+    /// The offers whose sci is being generated
+    pending: HashSet<HoldHandle>,
+    /// The offers whose sci has been generated
+    held: Vec<HeldOffer>,
+}
+
+impl HeldOffers {
+    /// How long a generated sci is held before it is discarded
+    pub const HOLD_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Start generating an offer for preview
+    pub fn begin(&mut self) -> HoldHandle {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.pending.insert(handle);
+        handle
+    }
+
+    /// Hold the sci generated for a pending offer.
+    ///
+    /// Returns false if the offer is no longer pending (it was discarded while
+    /// the sci was generated), in which case it isn't held.
+    pub fn hold(&mut self, held: HeldOffer) -> bool {
+        if !self.pending.remove(&held.handle) {
+            return false;
+        }
+        self.held.push(held);
+        true
+    }
+
+    /// Hold an offer again, which was taken but couldn't be submitted
+    pub fn hold_again(&mut self, held: HeldOffer) {
+        self.held.push(held);
+    }
+
+    /// Stop generating or holding an offer, returning it if it was held
+    pub fn take(&mut self, handle: HoldHandle) -> Option<HeldOffer> {
+        self.pending.remove(&handle);
+        let idx = self.held.iter().position(|held| held.handle == handle)?;
+        Some(self.held.remove(idx))
+    }
+
+    /// Remove the held offers which expired at `now`, returning them
+    pub fn expire(&mut self, now: Instant) -> Vec<HeldOffer> {
+        let (expired, held) = self.held.drain(..).partition(|held| held.expires_at <= now);
+        self.held = held;
+        expired
+    }
+
+    /// Whether any offer is still being generated
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The held offers
+    pub fn held(&self) -> &[HeldOffer] {
+        &self.held
+    }
+}
+
+/// The phase an offer is in while the worker thread generates and submits it
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq)]
+pub enum OperationProgress {
+    /// selecting an input of the offered value
+    SelectingInput,
+    /// paying ourselves the offered value, to make an input of it
+    SelfPayment,
+    /// waiting for the self-payment to land
+    WaitingForConfirmation,
+    /// generating the sci
+    GeneratingSci,
+    /// submitting the quote to the deqs
+    SubmittingQuote,
+}
+
+/// Which txos may be used as the input of an offer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InputMatch {
+    /// Only a txo of exactly the offered value
+    Exact,
+    /// A txo of at least the offered value, if there is none of exactly that value
+    AtLeast,
+}
+
+impl InputMatch {
+    /// Whether a txo of `value` may be the input of an offer of `offered`
+    pub fn accepts(&self, value: u64, offered: u64) -> bool {
+        match self {
+            Self::Exact => value == offered,
+            Self::AtLeast => value >= offered,
+        }
+    }
+}
+
+/// Why no input of the offered value could be prepared for an offer
+#[derive(Clone, Debug, Display, Eq, PartialEq)]
+pub enum InputPreparationError {
+    /// {0}
+    Mobilecoind(String),
+    /// The self-payment of the offered value failed: {0}
+    SelfPayment(String),
+    /// The self-payment of the offered value didn't show up within {0:?}
+    Timeout(Duration),
+}
+
+/// How a deqs responded to one of the quotes we submitted to it
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SubmitOutcome {
+    /// The deqs created the quote
+    Created,
+    /// The deqs already had this quote, for instance from an earlier attempt
+    /// whose response we didn't get. The quote is live, which is what we wanted.
+    /// The id of the existing quote is recovered from the response, if it was there.
+    AlreadyExists(Option<QuoteId>),
+    /// The deqs rejected the quote, and submitting it again won't help
+    Rejected(String),
+    /// The deqs could not take the quote right now, submitting it again later may help
+    Retryable(String),
+}
+
+impl SubmitOutcome {
+    /// Whether the quote is live at the deqs
+    pub fn is_accepted(&self) -> bool {
+        matches!(self, Self::Created | Self::AlreadyExists(_))
+    }
+
+    /// Classify the outcome of each quote in a response to submit_quotes, in the
+    /// order the quotes were submitted. `source` is the index of the deqs we
+    /// submitted to.
+    pub fn from_response(response: &d_api::SubmitQuotesResponse, source: usize) -> Vec<Self> {
+        if response.status_codes.is_empty() {
+            return vec![Self::Rejected("deqs returned no status code".to_owned())];
+        }
+        response
+            .status_codes
+            .iter()
+            .enumerate()
+            .map(|(idx, status_code)| {
+                Self::from_status_code(
+                    *status_code,
+                    response.error_messages.get(idx).map(String::as_str),
+                    response.quotes.get(idx),
+                    source,
+                )
+            })
+            .collect()
+    }
+
+    /// Classify the response to submitting a single quote.
+    ///
+    /// The deqs should return one status code, but if it returns more, the first
+    /// one decides the outcome, and the failures among the others are returned as
+    /// well, so that none of them goes unreported.
+    pub fn from_single_response(
+        response: &d_api::SubmitQuotesResponse,
+        source: usize,
+    ) -> (Self, Vec<String>) {
+        let mut outcomes = Self::from_response(response, source).into_iter();
+        let outcome = outcomes
+            .next()
+            .expect("from_response returns at least one outcome");
+        let extra_failures = outcomes
+            .filter_map(|extra| match extra {
+                Self::Rejected(err) | Self::Retryable(err) => {
+                    Some(format!("unexpected extra status code: {err}"))
+                }
+                Self::Created | Self::AlreadyExists(_) => None,
+            })
+            .collect();
+        (outcome, extra_failures)
+    }
+
+    /// Classify the status code the deqs returned for a single quote, together
+    /// with the error message and the quote it returned for it, if any.
+    pub fn from_status_code(
+        status_code: d_api::QuoteStatusCode,
+        error_message: Option<&str>,
+        quote: Option<&d_api::Quote>,
+        source: usize,
+    ) -> Self {
+        let error_message = error_message
+            .filter(|msg| !msg.is_empty())
+            .unwrap_or("no error message...");
+        match status_code {
+            d_api::QuoteStatusCode::CREATED => Self::Created,
+            d_api::QuoteStatusCode::QUOTE_ALREADY_EXISTS => {
+                Self::AlreadyExists(quote.and_then(|quote| {
+                    ValidatedQuote::try_from_quote(quote, source)
+                        .ok()
+                        .map(|quote| quote.id)
+                }))
+            }
+            d_api::QuoteStatusCode::INVALID_SCI => {
+                Self::Rejected(format!("invalid sci: {error_message}"))
+            }
+            d_api::QuoteStatusCode::UNSUPPORTED_SCI => {
+                Self::Rejected(format!("unsupported sci: {error_message}"))
+            }
+            d_api::QuoteStatusCode::INVALID_RING_SIZE => {
+                Self::Rejected(format!("invalid ring size: {error_message}"))
+            }
+            d_api::QuoteStatusCode::QUOTE_IS_STALE => {
+                Self::Rejected(format!("quote is stale: {error_message}"))
+            }
+            _ => Self::Rejected(format!("{status_code:?}: {error_message}")),
+        }
+    }
+
+    /// Classify an rpc error from submit_quotes.
+    ///
+    /// If the deqs is unavailable or throttling us, the submission can be retried.
+    pub fn from_rpc_error(err: &grpcio::Error) -> Self {
+        match err {
+            grpcio::Error::RpcFailure(status)
+                if matches!(
+                    status.code(),
+                    RpcStatusCode::UNAVAILABLE
+                        | RpcStatusCode::RESOURCE_EXHAUSTED
+                        | RpcStatusCode::DEADLINE_EXCEEDED
+                ) =>
+            {
+                Self::Retryable(err.to_string())
+            }
+            _ => Self::Rejected(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote_id(source: usize, key_image: u64) -> QuoteId {
+        QuoteId {
+            source,
+            key_image: KeyImage::from(key_image),
+        }
+    }
+
+    fn begin(tracker: &mut OfferTracker) -> OfferId {
+        tracker.begin(
+            Amount::new(1_000, TokenId::MOB),
+            Amount::new(2_000, TokenId::from(1)),
+            0,
+            FillMode::AllOrNothing,
+            false,
+        )
+    }
+
+    #[test]
+    fn cancellation_goes_to_every_deqs_which_listed_the_offer() {
+        let mut tracker = OfferTracker::default();
+        let id = begin(&mut tracker);
+        tracker.set_key_image(id, KeyImage::from(3));
+        tracker.mark_live(id, vec![quote_id(2, 3), quote_id(0, 3)]);
+        // Seen again at a deqs which already accepted it
+        tracker.mark_live(id, vec![quote_id(2, 3)]);
+        assert_eq!(tracker.get(id).unwrap().cancel_targets(), vec![0, 2]);
+    }
+
+    #[test]
+    fn automation_is_armed_by_open_offers_kept_alive() {
+        let mut tracker = OfferTracker::default();
+        let id = begin(&mut tracker);
+        assert!(!tracker.automation_armed());
+        tracker.set_keep_alive(id, true);
+        assert!(tracker.automation_armed());
+        tracker.mark_live(id, vec![quote_id(0, 1)]);
+        tracker.mark_expired(id);
+        // Waiting to be renewed
+        assert!(tracker.automation_armed());
+        tracker.set_keep_alive(id, false);
+        assert!(!tracker.automation_armed());
+    }
+
+    #[test]
+    fn automation_is_armed_by_open_two_sided_quotes() {
+        let mut tracker = OfferTracker::default();
+        let bid = begin(&mut tracker);
+        let ask = begin(&mut tracker);
+        tracker.pair(bid, ask);
+        assert!(tracker.automation_armed());
+        tracker.mark_live(bid, vec![quote_id(0, 1)]);
+        tracker.mark_failed(ask, "rejected".to_owned());
+        assert!(tracker.automation_armed());
+        tracker.mark_filled(bid);
+        assert!(!tracker.automation_armed());
+    }
+
+    #[test]
+    fn cancellation_of_an_unlisted_offer_goes_nowhere() {
+        let mut tracker = OfferTracker::default();
+        let id = begin(&mut tracker);
+        assert!(tracker.get(id).unwrap().cancel_targets().is_empty());
+    }
+
+    fn own_quote(source: usize, key_image: u64) -> ValidatedQuote {
+        ValidatedQuote::swap_fixture(
+            source,
+            key_image,
+            Amount::new(1_000, TokenId::MOB),
+            Amount::new(2_000, TokenId::from(1)),
+            false,
+        )
+    }
+
+    #[test]
+    fn submitted_offers_go_live_with_the_quote_ids_of_the_response() {
+        let mut tracker = OfferTracker::default();
+        let id = begin(&mut tracker);
+        assert_eq!(tracker.get(id).unwrap().status, OfferStatus::Submitting);
+        tracker.set_key_image(id, KeyImage::from(5));
+        tracker.mark_live(id, vec![quote_id(0, 5)]);
+        let offer = tracker.get(id).unwrap();
+        assert_eq!(offer.status, OfferStatus::Live);
+        assert_eq!(offer.quote_ids, vec![quote_id(0, 5)]);
+        assert_eq!(offer.key_image, Some(KeyImage::from(5)));
+    }
+
+    #[test]
+    fn failed_submissions_keep_the_error() {
+        let mut tracker = OfferTracker::default();
+        let id = begin(&mut tracker);
+        tracker.set_key_image(id, KeyImage::from(5));
+        tracker.mark_failed(id, "QUOTE_ALREADY_EXISTS".to_owned());
+        let offer = tracker.get(id).unwrap();
+        assert_eq!(
+            offer.status,
+            OfferStatus::Failed("QUOTE_ALREADY_EXISTS".to_owned())
+        );
+        assert!(offer.quote_ids.is_empty());
+        // A late response doesn't revive it, and it can be dismissed
+        tracker.observe_book(&[own_quote(0, 5)]);
+        assert!(matches!(
+            tracker.get(id).unwrap().status,
+            OfferStatus::Failed(_)
+        ));
+        assert!(tracker.remove(id).is_some());
+        assert!(tracker.offers().is_empty());
+    }
+
+    #[test]
+    fn offers_seen_in_the_book_before_the_response_are_live_once() {
+        let mut tracker = OfferTracker::default();
+        let id = begin(&mut tracker);
+        tracker.set_key_image(id, KeyImage::from(5));
+        // A poll fetched our quote before the submission returned
+        tracker.observe_book(&[own_quote(0, 4), own_quote(0, 5)]);
+        assert_eq!(tracker.get(id).unwrap().status, OfferStatus::Live);
+        assert_eq!(tracker.get(id).unwrap().quote_ids, vec![quote_id(0, 5)]);
+        // The response names the same quote, which isn't recorded twice
+        tracker.mark_live(id, vec![quote_id(0, 5)]);
+        assert_eq!(tracker.get(id).unwrap().quote_ids, vec![quote_id(0, 5)]);
+        assert_eq!(tracker.offers().len(), 1);
+    }
+
+    #[test]
+    fn offers_seen_in_the_book_stay_live_if_the_response_fails() {
+        let mut tracker = OfferTracker::default();
+        let id = begin(&mut tracker);
+        tracker.set_key_image(id, KeyImage::from(5));
+        tracker.observe_book(&[own_quote(1, 5)]);
+        // Say, the connection dropped after the deqs accepted it
+        tracker.mark_failed(id, "deadline exceeded".to_owned());
+        assert_eq!(tracker.get(id).unwrap().status, OfferStatus::Live);
+        assert_eq!(tracker.get(id).unwrap().quote_ids, vec![quote_id(1, 5)]);
+    }
+
+    #[test]
+    fn offers_without_a_key_image_are_not_matched_in_the_book() {
+        let mut tracker = OfferTracker::default();
+        let id = begin(&mut tracker);
+        tracker.observe_book(&[own_quote(0, 5)]);
+        assert_eq!(tracker.get(id).unwrap().status, OfferStatus::Submitting);
+    }
+
+    fn response(codes: &[(d_api::QuoteStatusCode, &str)]) -> d_api::SubmitQuotesResponse {
+        let mut response = d_api::SubmitQuotesResponse::new();
+        response.set_status_codes(codes.iter().map(|(code, _)| *code).collect());
+        response.set_error_messages(
+            codes
+                .iter()
+                .map(|(_, message)| message.to_string())
+                .collect::<Vec<_>>()
+                .into(),
+        );
+        response
+    }
+
+    #[test]
+    fn each_status_code_maps_to_its_outcome() {
+        use d_api::QuoteStatusCode::*;
+        let outcome = |code| SubmitOutcome::from_status_code(code, Some("details"), None, 0);
+        assert_eq!(outcome(CREATED), SubmitOutcome::Created);
+        assert_eq!(
+            outcome(QUOTE_ALREADY_EXISTS),
+            SubmitOutcome::AlreadyExists(None)
+        );
+        assert_eq!(
+            outcome(INVALID_SCI),
+            SubmitOutcome::Rejected("invalid sci: details".to_owned())
+        );
+        assert_eq!(
+            outcome(UNSUPPORTED_SCI),
+            SubmitOutcome::Rejected("unsupported sci: details".to_owned())
+        );
+        assert_eq!(
+            outcome(INVALID_RING_SIZE),
+            SubmitOutcome::Rejected("invalid ring size: details".to_owned())
+        );
+        assert_eq!(
+            outcome(QUOTE_IS_STALE),
+            SubmitOutcome::Rejected("quote is stale: details".to_owned())
+        );
+    }
+
+    #[test]
+    fn missing_error_messages_are_called_out() {
+        let outcome =
+            SubmitOutcome::from_status_code(d_api::QuoteStatusCode::INVALID_SCI, Some(""), None, 0);
+        assert_eq!(
+            outcome,
+            SubmitOutcome::Rejected("invalid sci: no error message...".to_owned())
+        );
+    }
+
+    #[test]
+    fn already_existing_quotes_are_accepted() {
+        assert!(SubmitOutcome::Created.is_accepted());
+        assert!(SubmitOutcome::AlreadyExists(None).is_accepted());
+        assert!(SubmitOutcome::AlreadyExists(Some(quote_id(1, 2))).is_accepted());
+        assert!(!SubmitOutcome::Rejected("no".to_owned()).is_accepted());
+        assert!(!SubmitOutcome::Retryable("later".to_owned()).is_accepted());
+        // A quote in the response which doesn't validate can't tell its id
+        let outcome = SubmitOutcome::from_status_code(
+            d_api::QuoteStatusCode::QUOTE_ALREADY_EXISTS,
+            None,
+            Some(&d_api::Quote::new()),
+            0,
+        );
+        assert_eq!(outcome, SubmitOutcome::AlreadyExists(None));
+    }
+
+    #[test]
+    fn multi_quote_responses_classify_each_quote() {
+        use d_api::QuoteStatusCode::*;
+        let outcomes = SubmitOutcome::from_response(
+            &response(&[
+                (CREATED, ""),
+                (QUOTE_ALREADY_EXISTS, ""),
+                (INVALID_SCI, "bad signature"),
+            ]),
+            0,
+        );
+        assert_eq!(
+            outcomes,
+            vec![
+                SubmitOutcome::Created,
+                SubmitOutcome::AlreadyExists(None),
+                SubmitOutcome::Rejected("invalid sci: bad signature".to_owned()),
+            ]
+        );
+        assert_eq!(
+            SubmitOutcome::from_response(&response(&[]), 0),
+            vec![SubmitOutcome::Rejected(
+                "deqs returned no status code".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn throttling_and_unavailability_are_retryable() {
+        let rpc_error = |code| {
+            SubmitOutcome::from_rpc_error(&grpcio::Error::RpcFailure(grpcio::RpcStatus::new(code)))
+        };
+        for code in [
+            RpcStatusCode::UNAVAILABLE,
+            RpcStatusCode::RESOURCE_EXHAUSTED,
+            RpcStatusCode::DEADLINE_EXCEEDED,
+        ] {
+            assert!(matches!(rpc_error(code), SubmitOutcome::Retryable(_)));
+        }
+        for code in [
+            RpcStatusCode::INVALID_ARGUMENT,
+            RpcStatusCode::UNAUTHENTICATED,
+            RpcStatusCode::INTERNAL,
+        ] {
+            assert!(matches!(rpc_error(code), SubmitOutcome::Rejected(_)));
+        }
+    }
+
+    fn held_offer(handle: HoldHandle, expires_at: Instant) -> HeldOffer {
+        let from_amount = Amount::new(1_000, TokenId::MOB);
+        HeldOffer {
+            handle,
+            from_amount,
+            to_amount: Amount::new(2_000, TokenId::from(1)),
+            sender_subaddress: 0,
+            fill_mode: FillMode::AllOrNothing,
+            proto_sci: external::SignedContingentInput::new(),
+            sci: SignedContingentInput::default(),
+            amounts: SignedContingentInputAmounts {
+                pseudo_output: from_amount,
+                required_outputs: vec![Amount::new(2_000, TokenId::from(1))],
+                partial_fill_outputs: vec![],
+                partial_fill_change: None,
+            },
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn held_offers_are_taken_once() {
+        let mut held_offers = HeldOffers::default();
+        let handle = held_offers.begin();
+        assert!(held_offers.has_pending());
+        assert!(held_offers.hold(held_offer(handle, Instant::now())));
+        assert!(!held_offers.has_pending());
+        assert_eq!(held_offers.held().len(), 1);
+        assert_eq!(held_offers.take(handle).unwrap().handle, handle);
+        assert!(held_offers.take(handle).is_none());
+        assert!(held_offers.held().is_empty());
+    }
+
+    #[test]
+    fn offers_discarded_while_generating_are_not_held() {
+        let mut held_offers = HeldOffers::default();
+        let handle = held_offers.begin();
+        assert!(held_offers.take(handle).is_none());
+        assert!(!held_offers.has_pending());
+        assert!(!held_offers.hold(held_offer(handle, Instant::now())));
+        assert!(held_offers.held().is_empty());
+    }
+
+    #[test]
+    fn held_offers_expire_after_the_timeout() {
+        let now = Instant::now();
+        let mut held_offers = HeldOffers::default();
+        let first = held_offers.begin();
+        let second = held_offers.begin();
+        assert_ne!(first, second);
+        held_offers.hold(held_offer(first, now + HeldOffers::HOLD_TIMEOUT));
+        held_offers.hold(held_offer(second, now + 2 * HeldOffers::HOLD_TIMEOUT));
+
+        assert!(held_offers.expire(now).is_empty());
+        let expired = held_offers.expire(now + HeldOffers::HOLD_TIMEOUT);
+        assert_eq!(
+            expired.iter().map(|held| held.handle).collect::<Vec<_>>(),
+            vec![first]
+        );
+        assert_eq!(held_offers.held().len(), 1);
+        assert!(held_offers.take(first).is_none());
+    }
+
+    #[test]
+    fn held_offers_which_failed_to_submit_are_held_again() {
+        let mut held_offers = HeldOffers::default();
+        let handle = held_offers.begin();
+        held_offers.hold(held_offer(handle, Instant::now()));
+        let held = held_offers.take(handle).unwrap();
+        held_offers.hold_again(held);
+        assert!(held_offers.take(handle).is_some());
+    }
+
+    #[test]
+    fn compaction_keeps_the_newest_offers_and_those_in_flight() {
+        let now = Instant::now();
+        let mut tracker = OfferTracker::default();
+        let in_flight = begin(&mut tracker);
+        let oldest = begin(&mut tracker);
+        let newest = begin(&mut tracker);
+        for (idx, id) in [oldest, newest].into_iter().enumerate() {
+            tracker.mark_live(id, vec![quote_id(0, idx as u64)]);
+        }
+        let policy = RetentionPolicy {
+            max_entries: 1,
+            max_age: None,
+        };
+
+        let removed = tracker.compact(&policy, now);
+        assert_eq!(
+            removed.iter().map(|offer| offer.id).collect::<Vec<_>>(),
+            vec![oldest]
+        );
+        assert_eq!(
+            tracker
+                .offers()
+                .iter()
+                .map(|offer| offer.id)
+                .collect::<Vec<_>>(),
+            vec![in_flight, newest]
+        );
+        // Compaction is idempotent
+        assert!(tracker.compact(&policy, now).is_empty());
+    }
+
+    #[test]
+    fn compaction_drops_offers_older_than_the_max_age() {
+        let mut tracker = OfferTracker::default();
+        let id = begin(&mut tracker);
+        tracker.mark_live(id, vec![quote_id(0, 1)]);
+        let policy = RetentionPolicy {
+            max_entries: usize::MAX,
+            max_age: Some(Duration::from_secs(60)),
+        };
+        let submitted_at = tracker.get(id).unwrap().submitted_at;
+        assert!(tracker.compact(&policy, submitted_at).is_empty());
+        let later = submitted_at + Duration::from_secs(61);
+        assert_eq!(tracker.compact(&policy, later).len(), 1);
+        assert!(tracker.offers().is_empty());
+    }
+
+    #[test]
+    fn the_first_status_code_decides_a_single_submission() {
+        use d_api::QuoteStatusCode::*;
+        let (outcome, extra) = SubmitOutcome::from_single_response(
+            &response(&[
+                (CREATED, ""),
+                (QUOTE_ALREADY_EXISTS, ""),
+                (UNSUPPORTED_SCI, "two outputs"),
+                (QUOTE_IS_STALE, ""),
+            ]),
+            0,
+        );
+        assert_eq!(outcome, SubmitOutcome::Created);
+        // Every extra failure is reported, but not the extra successes
+        assert_eq!(
+            extra,
+            vec![
+                "unexpected extra status code: unsupported sci: two outputs".to_owned(),
+                "unexpected extra status code: quote is stale: no error message...".to_owned(),
+            ]
+        );
+
+        let (outcome, extra) = SubmitOutcome::from_single_response(
+            &response(&[(INVALID_SCI, "bad"), (CREATED, "")]),
+            0,
+        );
+        assert_eq!(
+            outcome,
+            SubmitOutcome::Rejected("invalid sci: bad".to_owned())
+        );
+        assert!(extra.is_empty());
+    }
+
+    #[test]
+    fn single_submissions_without_a_status_code_are_rejected() {
+        let (outcome, extra) = SubmitOutcome::from_single_response(&response(&[]), 0);
+        assert_eq!(
+            outcome,
+            SubmitOutcome::Rejected("deqs returned no status code".to_owned())
+        );
+        assert!(extra.is_empty());
+    }
+
+    #[test]
+    fn status_codes_without_a_message_still_classify() {
+        use d_api::QuoteStatusCode::*;
+        let mut response = response(&[(INVALID_SCI, "bad")]);
+        response.mut_status_codes().push(UNSUPPORTED_SCI);
+        assert_eq!(
+            SubmitOutcome::from_response(&response, 0),
+            vec![
+                SubmitOutcome::Rejected("invalid sci: bad".to_owned()),
+                SubmitOutcome::Rejected("unsupported sci: no error message...".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! Payments which were submitted to mobilecoind, tracked until they land on the
+//! ledger (or fail to), and optionally until the recipient's side of the ledger
+//! confirms them too.
+
+use crate::TokenId;
+use mc_mobilecoind_api::{self as mcd_api, TxStatus};
+use std::time::{Duration, Instant};
+
+/// What mobilecoind says about a payment from the recipient's point of view
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ReceiverStatus {
+    /// The user asked to verify the receiver receipts, and the worker hasn't yet
+    Checking,
+    /// The status mobilecoind reported for the receiver receipts
+    Status(TxStatus),
+    /// mobilecoind couldn't check the receiver receipts
+    Failed(String),
+}
+
+/// A payment we submitted, and its most recently known status
+#[derive(Clone, Debug)]
+pub struct PendingTransaction {
+    /// The token id which was sent
+    pub token_id: TokenId,
+    /// The value which was sent
+    pub value: u64,
+    /// The b58 address it was sent to
+    pub recipient: String,
+    /// The receipts from mobilecoind, used to ask for the status of the transaction.
+    /// The receiver receipts are dropped once the recipient's side is verified.
+    pub receipts: mcd_api::SubmitTxResponse,
+    /// The block at which the transaction expires if it hasn't landed, 0 if unknown
+    pub tombstone_block: u64,
+    /// The most recently known status of the transaction
+    pub status: TxStatus,
+    /// When the status became terminal, if it did
+    pub resolved_at: Option<Instant>,
+    /// The fee paid, if known
+    pub fee: u64,
+    /// The value coming back to us once the transaction lands: the change, and the
+    /// value itself when we pay ourselves. 0 if unknown.
+    pub change: u64,
+    /// The number of blocks in the ledger when the transaction was seen to land, so
+    /// that the balances reflect it once the monitor synced that far
+    pub landed_by_block: Option<u64>,
+    /// The status from the recipient's point of view, if the user asked to verify it
+    pub receiver_status: Option<ReceiverStatus>,
+    /// When the status from the recipient's point of view became terminal, if it did
+    pub receiver_resolved_at: Option<Instant>,
+}
+
+impl PendingTransaction {
+    /// How often to ask mobilecoind for the status of pending transactions
+    pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+    /// How long to keep showing a transaction after its status became terminal
+    pub const LINGER: Duration = Duration::from_secs(5);
+    /// How long to keep showing a transaction which landed, while the recipient's
+    /// side of it can still be verified
+    pub const UNVERIFIED_LINGER: Duration = Duration::from_secs(600);
+
+    /// Track a payment which was just submitted
+    pub fn new(
+        token_id: TokenId,
+        value: u64,
+        recipient: String,
+        receipts: mcd_api::SubmitTxResponse,
+    ) -> Self {
+        Self {
+            token_id,
+            value,
+            recipient,
+            tombstone_block: receipts.get_sender_tx_receipt().tombstone,
+            receipts,
+            status: TxStatus::Unknown,
+            resolved_at: None,
+            fee: 0,
+            change: 0,
+            landed_by_block: None,
+            receiver_status: None,
+            receiver_resolved_at: None,
+        }
+    }
+
+    /// Record the fee and change of the transaction from its proposal. A payment to
+    /// ourselves comes back to us entirely.
+    pub fn with_proposal(mut self, proposal: &mcd_api::TxProposal, to_self: bool) -> Self {
+        let inputs = proposal
+            .get_input_list()
+            .iter()
+            .fold(0u64, |sum, utxo| sum.saturating_add(utxo.value));
+        let outlays = proposal
+            .get_outlay_list()
+            .iter()
+            .fold(0u64, |sum, outlay| sum.saturating_add(outlay.value));
+        self.fee = proposal.fee;
+        self.change = inputs.saturating_sub(outlays).saturating_sub(proposal.fee);
+        if to_self {
+            self.change = self.change.saturating_add(self.value);
+        }
+        self
+    }
+
+    /// Whether the balances, synced to `synced_blocks`, don't reflect this transaction
+    /// yet, while it can still land or just did. Failed transactions never will.
+    pub fn is_balance_pending(&self, synced_blocks: u64) -> bool {
+        match self.status {
+            TxStatus::Unknown => true,
+            TxStatus::Verified => self
+                .landed_by_block
+                .map_or(true, |landed_by_block| synced_blocks < landed_by_block),
+            _ => false,
+        }
+    }
+
+    /// Whether the ledger, which has `num_blocks` blocks, moved past the tombstone
+    /// block, so that the transaction can't land anymore
+    pub fn is_past_tombstone(&self, num_blocks: u64) -> bool {
+        self.tombstone_block != 0 && num_blocks > self.tombstone_block
+    }
+
+    /// Whether this is the transaction with a receiver receipt for this tx public key
+    pub fn has_receiver_receipt(&self, tx_public_key: &[u8]) -> bool {
+        self.receipts
+            .get_receiver_tx_receipt_list()
+            .iter()
+            .any(|receipt| receipt.get_tx_public_key().get_data() == tx_public_key)
+    }
+
+    /// Whether the recipient's side can be verified, because the transaction landed
+    /// and we still hold receiver receipts
+    pub fn can_verify(&self) -> bool {
+        self.status == TxStatus::Verified
+            && !self.receipts.get_receiver_tx_receipt_list().is_empty()
+    }
+
+    /// Ask the worker to verify the recipient's side
+    pub fn request_verification(&mut self) {
+        if self.can_verify() {
+            self.receiver_status = Some(ReceiverStatus::Checking);
+        }
+    }
+
+    /// Whether the worker should verify the recipient's side
+    pub fn needs_verification(&self) -> bool {
+        self.receiver_status == Some(ReceiverStatus::Checking)
+    }
+
+    /// Record the status of the receiver receipts reported by mobilecoind. Once they
+    /// are verified we don't need them anymore.
+    pub fn set_receiver_status(&mut self, result: Result<TxStatus, String>, now: Instant) {
+        self.receiver_status = Some(match result {
+            Ok(status) => {
+                if status == TxStatus::Verified {
+                    self.receipts.clear_receiver_tx_receipt_list();
+                }
+                if status != TxStatus::Unknown {
+                    self.receiver_resolved_at = Some(now);
+                }
+                ReceiverStatus::Status(status)
+            }
+            Err(err) => ReceiverStatus::Failed(err),
+        });
+    }
+
+    /// Record a status reported by mobilecoind, when the ledger has `num_blocks` blocks
+    pub fn set_status(&mut self, status: TxStatus, num_blocks: u64, now: Instant) {
+        self.status = status;
+        if status == TxStatus::Verified && self.landed_by_block.is_none() {
+            self.landed_by_block = Some(num_blocks);
+        }
+        if status != TxStatus::Unknown && self.resolved_at.is_none() {
+            self.resolved_at = Some(now);
+        }
+    }
+
+    /// Whether the status is terminal, so that we don't need to ask about it anymore
+    pub fn is_resolved(&self) -> bool {
+        self.resolved_at.is_some()
+    }
+
+    /// Whether the transaction was resolved long enough ago that we can forget about it
+    pub fn is_expired(&self, now: Instant) -> bool {
+        if self.needs_verification() {
+            return false;
+        }
+        let (since, linger) = match self.receiver_resolved_at {
+            Some(receiver_resolved_at) => (Some(receiver_resolved_at), Self::LINGER),
+            None if self.can_verify() => (self.resolved_at, Self::UNVERIFIED_LINGER),
+            None => (self.resolved_at, Self::LINGER),
+        };
+        since
+            .map(|since| now.saturating_duration_since(since) >= linger)
+            .unwrap_or(false)
+    }
+}
@@ -0,0 +1,897 @@
+//! Checks of the preconditions of the commands which move funds.
+//!
+//! The UI runs these against the snapshot it renders, to decide whether a command
+//! can be submitted, and the worker runs them again against its current state
+//! right before executing the command, since the world may have changed in between.
+//! Sharing the checks keeps the two layers from drifting apart.
+
+use crate::{BalanceSnapshot, ScaledAmount, SciFill, TokenId, TxOptions, UtxoInfo, ValidatedQuote};
+use displaydoc::Display;
+use mc_crypto_ring_signature::KeyImage;
+use mc_transaction_extra::SignedContingentInputAmounts;
+use rust_decimal::Decimal;
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// How far the cost of a swap may move from the one the user reviewed, relative
+/// to it (0.5%), unless the user picks another tolerance
+pub const DEFAULT_MAX_SLIPPAGE: Decimal = Decimal::from_parts(5, 0, 0, false, 3);
+
+/// A precondition of a command which doesn't hold (anymore)
+#[derive(Clone, Debug, Display, Eq, PartialEq)]
+pub enum PreconditionFailed {
+    /// Balances are not trusted yet: {0}
+    SyncIncomplete(String),
+    /// Insufficient funds of token id {token_id}: {needed} needed, {available} available
+    InsufficientFunds {
+        /// The token id
+        token_id: TokenId,
+        /// The value needed, including fees
+        needed: u64,
+        /// The balance available
+        available: u64,
+    },
+    /// The amount plus fee overflows
+    Overflow,
+    /// The fee of token id {token_id} changed from {expected} to {current}
+    FeeChanged {
+        /// The token id
+        token_id: TokenId,
+        /// The fee the command was reviewed with
+        expected: u64,
+        /// The current fee
+        current: u64,
+    },
+    /// The quote is no longer in the quote book
+    QuoteGone,
+    /// The quote expired at block {0}
+    QuoteExpired(u64),
+    /// Quotes are stale, waiting for refresh
+    QuotesStale,
+    /// The offer is no longer open
+    OfferNotOpen,
+    /// Only a failed offer can be submitted again
+    OfferNotFailed,
+    /// The quote is no longer valid: {0}
+    InvalidQuote(String),
+    /// The price moved, please re-confirm: the swap costs {current} instead of {expected}
+    PriceMoved {
+        /// The cost the swap was reviewed with
+        expected: u64,
+        /// The current cost
+        current: u64,
+    },
+    /// No coins are selected to spend
+    NoInputs,
+    /// Too many coins are selected, a transaction can spend at most {0}
+    TooManyInputs(usize),
+    /// Too fragmented, please defragment: the amount needs more than {0} coins
+    TooFragmented(usize),
+    /// A selected coin is no longer unspent
+    InputGone,
+    /// A selected coin is reserved by a swap offer
+    InputReserved,
+    /// Subaddress {0} is not covered by the monitor
+    UnknownSubaddress(u64),
+    /// The tombstone offset must be between 1 and {max} blocks, not {offset}
+    TombstoneOutOfRange {
+        /// The tombstone offset
+        offset: u64,
+        /// The largest tombstone offset the protocol accepts
+        max: u64,
+    },
+    /// A selected coin is of token id {found}, but token id {expected} is being sent
+    MixedTokens {
+        /// The token id being sent
+        expected: TokenId,
+        /// The token id of the selected coin
+        found: TokenId,
+    },
+}
+
+/// Check that the balances can be trusted
+pub fn check_sync(snapshot: &BalanceSnapshot) -> Result<(), PreconditionFailed> {
+    snapshot
+        .require_complete()
+        .map_err(PreconditionFailed::SyncIncomplete)
+}
+
+/// Check that the balances cover an amount
+pub fn check_funds(
+    snapshot: &BalanceSnapshot,
+    required: ScaledAmount,
+) -> Result<(), PreconditionFailed> {
+    let available = snapshot
+        .balances
+        .get(&required.token_id)
+        .cloned()
+        .unwrap_or(0);
+    if available < required.raw {
+        return Err(PreconditionFailed::InsufficientFunds {
+            token_id: required.token_id,
+            needed: required.raw,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// Check that the fee is still the one the command was reviewed with
+pub fn check_fee(token_id: TokenId, expected: u64, current: u64) -> Result<(), PreconditionFailed> {
+    if expected != current {
+        return Err(PreconditionFailed::FeeChanged {
+            token_id,
+            expected,
+            current,
+        });
+    }
+    Ok(())
+}
+
+/// Check that a subaddress is one of the `num_subaddresses` the monitor covers
+pub fn check_subaddress(
+    subaddress_index: u64,
+    num_subaddresses: u64,
+) -> Result<(), PreconditionFailed> {
+    if subaddress_index >= num_subaddresses {
+        return Err(PreconditionFailed::UnknownSubaddress(subaddress_index));
+    }
+    Ok(())
+}
+
+/// Check the options of a transaction: the sender subaddress must be one of the
+/// `num_subaddresses` the monitor covers, and the tombstone offset within protocol limits
+pub fn check_tx_options(
+    options: &TxOptions,
+    num_subaddresses: u64,
+) -> Result<(), PreconditionFailed> {
+    check_subaddress(options.sender_subaddress, num_subaddresses)?;
+    if options.tombstone_offset == 0 || options.tombstone_offset > TxOptions::MAX_TOMBSTONE_OFFSET {
+        return Err(PreconditionFailed::TombstoneOutOfRange {
+            offset: options.tombstone_offset,
+            max: TxOptions::MAX_TOMBSTONE_OFFSET,
+        });
+    }
+    Ok(())
+}
+
+/// Check that a quote is still in the quote book
+pub fn check_quote_present<'a>(
+    book: impl IntoIterator<Item = &'a ValidatedQuote>,
+    key_image: &KeyImage,
+) -> Result<(), PreconditionFailed> {
+    if book
+        .into_iter()
+        .any(|quote| &quote.id.key_image == key_image)
+    {
+        Ok(())
+    } else {
+        Err(PreconditionFailed::QuoteGone)
+    }
+}
+
+/// Check the preconditions of sending an amount, paying the fee in the same token
+pub fn check_send(
+    snapshot: &BalanceSnapshot,
+    amount: ScaledAmount,
+    fee: u64,
+) -> Result<(), PreconditionFailed> {
+    check_sync(snapshot)?;
+    let with_fee = amount
+        .try_add(ScaledAmount::new(fee, amount.token_id))
+        .map_err(|_| PreconditionFailed::Overflow)?;
+    check_funds(snapshot, with_fee)
+}
+
+/// Pick unreserved txos of the token of `required` to spend on it, using at most
+/// `max_inputs` of them. `required` includes the fee, if paid in the same token.
+///
+/// The largest txos are picked first, which needs the fewest inputs. The last one
+/// picked is then swapped for the smallest txo which still covers the rest, to
+/// keep the change small.
+pub fn select_inputs(
+    unspent: &[UtxoInfo],
+    reserved: &HashSet<KeyImage>,
+    required: ScaledAmount,
+    max_inputs: usize,
+) -> Result<Vec<UtxoInfo>, PreconditionFailed> {
+    let mut candidates: Vec<&UtxoInfo> = unspent
+        .iter()
+        .filter(|utxo| utxo.token_id == required.token_id && !reserved.contains(&utxo.key_image))
+        .collect();
+    candidates.sort_by(|a, b| b.value.cmp(&a.value));
+
+    let mut selected: Vec<UtxoInfo> = Vec::new();
+    let mut total = 0u64;
+    for utxo in candidates.iter() {
+        if total >= required.raw {
+            break;
+        }
+        total = total
+            .checked_add(utxo.value)
+            .ok_or(PreconditionFailed::Overflow)?;
+        selected.push((*utxo).clone());
+    }
+    if total < required.raw {
+        return Err(PreconditionFailed::InsufficientFunds {
+            token_id: required.token_id,
+            needed: required.raw,
+            available: total,
+        });
+    }
+    if selected.len() > max_inputs {
+        return Err(PreconditionFailed::TooFragmented(max_inputs));
+    }
+
+    // The candidates after the ones selected are no larger than the last one
+    let num_selected = selected.len();
+    if let Some(last) = selected.pop() {
+        let missing = required.raw - (total - last.value);
+        let replacement = candidates[num_selected..]
+            .iter()
+            .rev()
+            .find(|utxo| utxo.value >= missing)
+            .map_or(last, |utxo| (*utxo).clone());
+        selected.push(replacement);
+    }
+    Ok(selected)
+}
+
+/// Look up the txos selected to spend among the unspent ones, checking that none
+/// of them was spent or reserved in the meantime
+pub fn check_inputs_available(
+    unspent: &[UtxoInfo],
+    reserved: &HashSet<KeyImage>,
+    selected: &[KeyImage],
+) -> Result<Vec<UtxoInfo>, PreconditionFailed> {
+    selected
+        .iter()
+        .map(|key_image| {
+            if reserved.contains(key_image) {
+                return Err(PreconditionFailed::InputReserved);
+            }
+            unspent
+                .iter()
+                .find(|utxo| &utxo.key_image == key_image)
+                .cloned()
+                .ok_or(PreconditionFailed::InputGone)
+        })
+        .collect()
+}
+
+/// Check the preconditions of sending an amount from specific txos, paying the fee
+/// in the same token. Returns the change which goes back to the account.
+pub fn check_inputs(
+    inputs: &[UtxoInfo],
+    amount: ScaledAmount,
+    fee: u64,
+) -> Result<u64, PreconditionFailed> {
+    if inputs.is_empty() {
+        return Err(PreconditionFailed::NoInputs);
+    }
+    if inputs.len() > UtxoInfo::MAX_INPUTS {
+        return Err(PreconditionFailed::TooManyInputs(UtxoInfo::MAX_INPUTS));
+    }
+    if let Some(utxo) = inputs.iter().find(|utxo| utxo.token_id != amount.token_id) {
+        return Err(PreconditionFailed::MixedTokens {
+            expected: amount.token_id,
+            found: utxo.token_id,
+        });
+    }
+    let available = inputs
+        .iter()
+        .try_fold(0u64, |total, utxo| total.checked_add(utxo.value))
+        .ok_or(PreconditionFailed::Overflow)?;
+    let needed = amount
+        .raw
+        .checked_add(fee)
+        .ok_or(PreconditionFailed::Overflow)?;
+    if available < needed {
+        return Err(PreconditionFailed::InsufficientFunds {
+            token_id: amount.token_id,
+            needed,
+            available,
+        });
+    }
+    Ok(available - needed)
+}
+
+/// Check the preconditions of filling quotes, paying `from_amount` plus the fee
+/// in the same token
+pub fn check_swap<'a>(
+    snapshot: &BalanceSnapshot,
+    book: impl IntoIterator<Item = &'a ValidatedQuote> + Clone,
+    key_images: &[KeyImage],
+    from_amount: ScaledAmount,
+    fee: u64,
+) -> Result<(), PreconditionFailed> {
+    for key_image in key_images {
+        check_quote_present(book.clone(), key_image)?;
+    }
+    check_send(snapshot, from_amount, fee)
+}
+
+/// Check the preconditions of filling quotes, paying `from_amount` and the fee,
+/// which may be in another token
+pub fn check_swap_with_fee<'a>(
+    snapshot: &BalanceSnapshot,
+    book: impl IntoIterator<Item = &'a ValidatedQuote> + Clone,
+    key_images: &[KeyImage],
+    from_amount: ScaledAmount,
+    fee: ScaledAmount,
+) -> Result<(), PreconditionFailed> {
+    if fee.token_id == from_amount.token_id {
+        check_swap(snapshot, book, key_images, from_amount, fee.raw)
+    } else {
+        check_swap(snapshot, book, key_images, from_amount, 0)?;
+        check_funds(snapshot, fee)
+    }
+}
+
+/// Check that a quote book polled `age` ago is recent enough to swap against
+pub fn check_quotes_fresh(age: Duration, max_age: Duration) -> Result<(), PreconditionFailed> {
+    if age > max_age {
+        return Err(PreconditionFailed::QuotesStale);
+    }
+    Ok(())
+}
+
+/// Check that none of the scis expired in a ledger of `num_blocks` blocks
+pub fn check_quotes_unexpired(
+    fills: &[SciFill],
+    num_blocks: u64,
+) -> Result<(), PreconditionFailed> {
+    for fill in fills {
+        match fill.max_tombstone_block() {
+            Some(max_tombstone_block) if max_tombstone_block <= num_blocks => {
+                return Err(PreconditionFailed::QuoteExpired(max_tombstone_block));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Check that the scis are still valid, and that filling them still costs
+/// `expected` of `from_token_id`, give or take `max_slippage` of it (0.005 is 0.5%)
+pub fn check_swap_price(
+    fills: &[SciFill],
+    from_token_id: TokenId,
+    expected: u64,
+    max_slippage: Decimal,
+) -> Result<(), PreconditionFailed> {
+    let validated = fills
+        .iter()
+        .map(|fill| {
+            let amounts = fill
+                .sci
+                .validate()
+                .map_err(|err| PreconditionFailed::InvalidQuote(err.to_string()))?;
+            Ok((amounts, fill.partial_fill_value))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    check_fill_cost(&validated, from_token_id, expected, max_slippage)
+}
+
+/// Check that filling scis with the given amounts, each to its partial fill value,
+/// costs `expected` of `from_token_id`, give or take `max_slippage` of it
+pub fn check_fill_cost(
+    fills: &[(SignedContingentInputAmounts, u64)],
+    from_token_id: TokenId,
+    expected: u64,
+    max_slippage: Decimal,
+) -> Result<(), PreconditionFailed> {
+    let mut current: u64 = 0;
+    for (amounts, partial_fill_value) in fills {
+        let balance_sheet = amounts
+            .compute_balance_sheet(*partial_fill_value)
+            .map_err(|err| PreconditionFailed::InvalidQuote(err.to_string()))?;
+        let cost = balance_sheet.get(&from_token_id).ok_or_else(|| {
+            PreconditionFailed::InvalidQuote(format!("it doesn't ask for token id {from_token_id}"))
+        })?;
+        current = current
+            .checked_add(*cost as u64)
+            .ok_or(PreconditionFailed::Overflow)?;
+    }
+
+    let difference = Decimal::from(current.abs_diff(expected));
+    let tolerance = Decimal::from(expected)
+        .checked_mul(max_slippage)
+        .ok_or(PreconditionFailed::Overflow)?;
+    if difference > tolerance {
+        return Err(PreconditionFailed::PriceMoved { expected, current });
+    }
+    Ok(())
+}
+
+/// Check the preconditions of offering an amount
+pub fn check_offer(
+    snapshot: &BalanceSnapshot,
+    from_amount: ScaledAmount,
+) -> Result<(), PreconditionFailed> {
+    check_sync(snapshot)?;
+    check_funds(snapshot, from_amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Amount;
+    use std::collections::HashMap;
+
+    const MOB: TokenId = TokenId::MOB;
+
+    fn snapshot(balance: u64, synced_blocks: u64, total_blocks: u64) -> BalanceSnapshot {
+        BalanceSnapshot {
+            balances: HashMap::from([(MOB, balance)]),
+            fetched: true,
+            synced_blocks,
+            total_blocks,
+        }
+    }
+
+    #[test]
+    fn incomplete_balances_block_sends_they_seem_to_cover() {
+        let behind = 100 - BalanceSnapshot::COMPLETE_WITHIN_BLOCKS - 1;
+        let result = check_send(&snapshot(1_000, behind, 100), ScaledAmount::new(10, MOB), 1);
+        assert!(matches!(result, Err(PreconditionFailed::SyncIncomplete(_))));
+    }
+
+    #[test]
+    fn incomplete_balances_block_sends_they_seem_not_to_cover() {
+        // The funds may well be there, in the blocks not scanned yet
+        let result = check_send(&snapshot(0, 10, 100), ScaledAmount::new(10, MOB), 1);
+        assert!(matches!(result, Err(PreconditionFailed::SyncIncomplete(_))));
+    }
+
+    #[test]
+    fn balances_within_the_threshold_are_trusted() {
+        let synced = 100 - BalanceSnapshot::COMPLETE_WITHIN_BLOCKS;
+        let snapshot = snapshot(11, synced, 100);
+        assert_eq!(check_send(&snapshot, ScaledAmount::new(10, MOB), 1), Ok(()));
+        assert_eq!(
+            check_send(&snapshot, ScaledAmount::new(11, MOB), 1),
+            Err(PreconditionFailed::InsufficientFunds {
+                token_id: MOB,
+                needed: 12,
+                available: 11,
+            })
+        );
+    }
+
+    #[test]
+    fn offers_need_complete_balances() {
+        let result = check_offer(&snapshot(1_000, 0, 100), ScaledAmount::new(10, MOB));
+        assert!(matches!(result, Err(PreconditionFailed::SyncIncomplete(_))));
+        let result = check_offer(&snapshot(1_000, 100, 100), ScaledAmount::new(10, MOB));
+        assert_eq!(result, Ok(()));
+    }
+
+    fn utxo(value: u64, key_image: u64) -> UtxoInfo {
+        UtxoInfo {
+            value,
+            token_id: MOB,
+            subaddress_index: 0,
+            key_image: KeyImage::from(key_image),
+        }
+    }
+
+    #[test]
+    fn inputs_reserved_by_a_held_offer_are_not_spent_again() {
+        let unspent = vec![utxo(100, 1), utxo(50, 2)];
+        let reserved = HashSet::from([KeyImage::from(1)]);
+        let selected = select_inputs(&unspent, &reserved, ScaledAmount::new(40, MOB), 16).unwrap();
+        assert_eq!(selected, vec![utxo(50, 2)]);
+        assert_eq!(
+            select_inputs(&unspent, &reserved, ScaledAmount::new(60, MOB), 16),
+            Err(PreconditionFailed::InsufficientFunds {
+                token_id: MOB,
+                needed: 60,
+                available: 50,
+            })
+        );
+        assert_eq!(
+            check_inputs_available(&unspent, &reserved, &[KeyImage::from(1)]),
+            Err(PreconditionFailed::InputReserved)
+        );
+    }
+
+    fn quote(key_image: u64) -> ValidatedQuote {
+        ValidatedQuote::swap_fixture(
+            0,
+            key_image,
+            Amount::new(10, TokenId::from(1)),
+            Amount::new(20, MOB),
+            false,
+        )
+    }
+
+    #[test]
+    fn changed_fees_name_the_old_and_new_fee() {
+        assert_eq!(check_fee(MOB, 400, 400), Ok(()));
+        assert_eq!(
+            check_fee(MOB, 400, 800),
+            Err(PreconditionFailed::FeeChanged {
+                token_id: MOB,
+                expected: 400,
+                current: 800,
+            })
+        );
+    }
+
+    #[test]
+    fn tx_options_must_be_within_the_monitor_and_the_protocol() {
+        let options = |sender_subaddress, tombstone_offset| TxOptions {
+            sender_subaddress,
+            tombstone_offset,
+        };
+        assert_eq!(check_tx_options(&TxOptions::default(), 1), Ok(()));
+        assert_eq!(
+            check_tx_options(&options(1, 100), 1),
+            Err(PreconditionFailed::UnknownSubaddress(1))
+        );
+        for offset in [0, TxOptions::MAX_TOMBSTONE_OFFSET + 1] {
+            assert_eq!(
+                check_tx_options(&options(0, offset), 1),
+                Err(PreconditionFailed::TombstoneOutOfRange {
+                    offset,
+                    max: TxOptions::MAX_TOMBSTONE_OFFSET,
+                })
+            );
+        }
+        assert_eq!(
+            check_tx_options(&options(0, TxOptions::MAX_TOMBSTONE_OFFSET), 1),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn swaps_need_every_quote_still_in_the_book() {
+        let book = vec![quote(1), quote(2)];
+        let snapshot = snapshot(1_000, 100, 100);
+        let from_amount = ScaledAmount::new(20, MOB);
+        let key_images = [KeyImage::from(1), KeyImage::from(2)];
+        assert_eq!(
+            check_swap(&snapshot, &book, &key_images, from_amount, 1),
+            Ok(())
+        );
+        assert_eq!(
+            check_swap(&snapshot, &book[..1], &key_images, from_amount, 1),
+            Err(PreconditionFailed::QuoteGone)
+        );
+        assert_eq!(
+            check_swap(
+                &snapshot,
+                &book,
+                &key_images,
+                ScaledAmount::new(1_000, MOB),
+                1
+            ),
+            Err(PreconditionFailed::InsufficientFunds {
+                token_id: MOB,
+                needed: 1_001,
+                available: 1_000,
+            })
+        );
+    }
+
+    #[test]
+    fn fees_in_another_token_are_checked_against_its_balance() {
+        let eusd = TokenId::from(1);
+        let mut snapshot = snapshot(1_000, 100, 100);
+        snapshot.balances.insert(eusd, 5);
+        let book = vec![quote(1)];
+        let key_images = [KeyImage::from(1)];
+        let from_amount = ScaledAmount::new(1_000, MOB);
+        assert_eq!(
+            check_swap_with_fee(
+                &snapshot,
+                &book,
+                &key_images,
+                from_amount,
+                ScaledAmount::new(5, eusd)
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check_swap_with_fee(
+                &snapshot,
+                &book,
+                &key_images,
+                from_amount,
+                ScaledAmount::new(6, eusd)
+            ),
+            Err(PreconditionFailed::InsufficientFunds {
+                token_id: eusd,
+                needed: 6,
+                available: 5,
+            })
+        );
+        // The same fee in the token being swapped no longer fits
+        assert!(matches!(
+            check_swap_with_fee(
+                &snapshot,
+                &book,
+                &key_images,
+                from_amount,
+                ScaledAmount::new(5, MOB)
+            ),
+            Err(PreconditionFailed::InsufficientFunds { .. })
+        ));
+    }
+
+    #[test]
+    fn stale_quote_books_block_swaps() {
+        let max_age = Duration::from_secs(10);
+        assert_eq!(check_quotes_fresh(max_age, max_age), Ok(()));
+        assert_eq!(
+            check_quotes_fresh(max_age + Duration::from_millis(1), max_age),
+            Err(PreconditionFailed::QuotesStale)
+        );
+    }
+
+    #[test]
+    fn spent_inputs_are_gone() {
+        let unspent = vec![utxo(100, 1)];
+        let reserved = HashSet::default();
+        assert_eq!(
+            check_inputs_available(&unspent, &reserved, &[KeyImage::from(1)]),
+            Ok(unspent.clone())
+        );
+        assert_eq!(
+            check_inputs_available(&unspent, &reserved, &[KeyImage::from(1), KeyImage::from(2)]),
+            Err(PreconditionFailed::InputGone)
+        );
+    }
+
+    #[test]
+    fn selected_inputs_must_cover_the_amount_and_fee() {
+        let amount = ScaledAmount::new(90, MOB);
+        assert_eq!(
+            check_inputs(&[], amount, 1),
+            Err(PreconditionFailed::NoInputs)
+        );
+        assert_eq!(check_inputs(&[utxo(60, 1), utxo(40, 2)], amount, 1), Ok(9));
+        assert_eq!(
+            check_inputs(&[utxo(60, 1), utxo(30, 2)], amount, 1),
+            Err(PreconditionFailed::InsufficientFunds {
+                token_id: MOB,
+                needed: 91,
+                available: 90,
+            })
+        );
+        assert_eq!(
+            check_inputs(&[utxo(u64::MAX, 1), utxo(1, 2)], amount, 1),
+            Err(PreconditionFailed::Overflow)
+        );
+        assert_eq!(
+            check_inputs(&[utxo(100, 1)], ScaledAmount::new(u64::MAX, MOB), 1),
+            Err(PreconditionFailed::Overflow)
+        );
+
+        let mut eusd_utxo = utxo(100, 2);
+        eusd_utxo.token_id = TokenId::from(1);
+        assert_eq!(
+            check_inputs(&[utxo(100, 1), eusd_utxo], amount, 1),
+            Err(PreconditionFailed::MixedTokens {
+                expected: MOB,
+                found: TokenId::from(1),
+            })
+        );
+
+        let too_many: Vec<UtxoInfo> = (0..=UtxoInfo::MAX_INPUTS as u64)
+            .map(|idx| utxo(100, idx))
+            .collect();
+        assert_eq!(
+            check_inputs(&too_many, amount, 1),
+            Err(PreconditionFailed::TooManyInputs(UtxoInfo::MAX_INPUTS))
+        );
+    }
+
+    // Filling `quote` to `partial_fill_value`, as check_swap_price sees it once the
+    // sci is validated
+    fn fill_cost(
+        quote: &ValidatedQuote,
+        partial_fill_value: u64,
+    ) -> (SignedContingentInputAmounts, u64) {
+        (quote.amounts.clone(), partial_fill_value)
+    }
+
+    #[test]
+    fn swaps_abort_when_the_price_moved_too_far() {
+        let eusd = TokenId::from(1);
+        // Reviewed at 2,000 EUSD for 1,000 MOB, the counterparty now asks for more
+        let reviewed = 2_000;
+        let quote = |asked| {
+            ValidatedQuote::swap_fixture(
+                0,
+                1,
+                Amount::new(1_000, MOB),
+                Amount::new(asked, eusd),
+                false,
+            )
+        };
+        let check = |asked| {
+            check_fill_cost(
+                &[fill_cost(&quote(asked), 0)],
+                eusd,
+                reviewed,
+                DEFAULT_MAX_SLIPPAGE,
+            )
+        };
+        assert_eq!(check(2_000), Ok(()));
+        // Within 0.5% either way
+        assert_eq!(check(2_010), Ok(()));
+        assert_eq!(check(1_990), Ok(()));
+        assert_eq!(
+            check(2_011),
+            Err(PreconditionFailed::PriceMoved {
+                expected: 2_000,
+                current: 2_011,
+            })
+        );
+        assert!(matches!(
+            check(1_989),
+            Err(PreconditionFailed::PriceMoved { .. })
+        ));
+    }
+
+    #[test]
+    fn the_cost_of_several_fills_is_checked_together() {
+        let eusd = TokenId::from(1);
+        let partial = ValidatedQuote::swap_fixture(
+            0,
+            1,
+            Amount::new(1_000, MOB),
+            Amount::new(2_000, eusd),
+            true,
+        );
+        let full = ValidatedQuote::swap_fixture(
+            0,
+            2,
+            Amount::new(100, MOB),
+            Amount::new(300, eusd),
+            false,
+        );
+        let fills = [fill_cost(&partial, 500), fill_cost(&full, 0)];
+        assert_eq!(check_fill_cost(&fills, eusd, 1_300, Decimal::ZERO), Ok(()));
+        assert_eq!(
+            check_fill_cost(&fills, eusd, 1_200, Decimal::ZERO),
+            Err(PreconditionFailed::PriceMoved {
+                expected: 1_200,
+                current: 1_300,
+            })
+        );
+    }
+
+    #[test]
+    fn quotes_which_no_longer_ask_for_the_token_abort() {
+        let quote = ValidatedQuote::swap_fixture(
+            0,
+            1,
+            Amount::new(1_000, MOB),
+            Amount::new(2_000, TokenId::from(2)),
+            false,
+        );
+        assert!(matches!(
+            check_fill_cost(
+                &[fill_cost(&quote, 0)],
+                TokenId::from(1),
+                2_000,
+                DEFAULT_MAX_SLIPPAGE
+            ),
+            Err(PreconditionFailed::InvalidQuote(_))
+        ));
+    }
+
+    #[test]
+    fn scis_which_are_no_longer_valid_abort() {
+        let quote = ValidatedQuote::swap_fixture(
+            0,
+            1,
+            Amount::new(1_000, MOB),
+            Amount::new(2_000, TokenId::from(1)),
+            false,
+        );
+        let fill = SciFill {
+            sci: quote.sci,
+            partial_fill_value: 0,
+            source: 0,
+        };
+        assert!(matches!(
+            check_swap_price(&[fill], TokenId::from(1), 2_000, DEFAULT_MAX_SLIPPAGE),
+            Err(PreconditionFailed::InvalidQuote(_))
+        ));
+    }
+
+    #[test]
+    fn fills_of_expired_quotes_abort() {
+        let fill = |quote: ValidatedQuote| SciFill {
+            sci: quote.sci,
+            partial_fill_value: 0,
+            source: 0,
+        };
+        let expiring = quote(1).with_max_tombstone_block(100);
+        let fills = [fill(quote(2)), fill(expiring)];
+        assert_eq!(check_quotes_unexpired(&fills, 99), Ok(()));
+        assert_eq!(
+            check_quotes_unexpired(&fills, 100),
+            Err(PreconditionFailed::QuoteExpired(100))
+        );
+    }
+
+    fn values(utxos: &[UtxoInfo]) -> Vec<u64> {
+        utxos.iter().map(|utxo| utxo.value).collect()
+    }
+
+    #[test]
+    fn inputs_are_picked_largest_first_keeping_the_change_small() {
+        let unspent = vec![
+            utxo(30, 1),
+            utxo(100, 2),
+            utxo(5, 3),
+            utxo(60, 4),
+            utxo(50, 5),
+        ];
+        let reserved = HashSet::default();
+        let select = |raw| select_inputs(&unspent, &reserved, ScaledAmount::new(raw, MOB), 16);
+        // 100 and 60 would do, but 100 and 30 leave less change
+        assert_eq!(values(&select(120).unwrap()), vec![100, 30]);
+        assert_eq!(values(&select(100).unwrap()), vec![100]);
+        assert_eq!(values(&select(4).unwrap()), vec![5]);
+        assert_eq!(values(&select(245).unwrap()), vec![100, 60, 50, 30, 5]);
+        assert!(matches!(
+            select(246),
+            Err(PreconditionFailed::InsufficientFunds { available: 245, .. })
+        ));
+    }
+
+    #[test]
+    fn inputs_of_other_tokens_are_not_picked() {
+        let mut eusd_utxo = utxo(1_000, 1);
+        eusd_utxo.token_id = TokenId::from(1);
+        let unspent = vec![eusd_utxo, utxo(10, 2)];
+        let selected = select_inputs(
+            &unspent,
+            &HashSet::default(),
+            ScaledAmount::new(10, MOB),
+            16,
+        )
+        .unwrap();
+        assert_eq!(selected, vec![utxo(10, 2)]);
+    }
+
+    #[test]
+    fn fragmented_accounts_are_asked_to_defragment() {
+        let unspent: Vec<UtxoInfo> = (0..20).map(|idx| utxo(10, idx)).collect();
+        let reserved = HashSet::default();
+        let select = |raw| select_inputs(&unspent, &reserved, ScaledAmount::new(raw, MOB), 10);
+        assert_eq!(select(100).unwrap().len(), 10);
+        assert_eq!(select(101), Err(PreconditionFailed::TooFragmented(10)));
+    }
+
+    #[test]
+    fn input_totals_which_overflow_are_an_error() {
+        let unspent = vec![utxo(u64::MAX, 1), utxo(u64::MAX, 2)];
+        assert_eq!(
+            select_inputs(
+                &unspent,
+                &HashSet::default(),
+                ScaledAmount::new(u64::MAX, MOB),
+                16
+            )
+            .map(|selected| selected.len()),
+            Ok(1)
+        );
+        let unspent = vec![utxo(u64::MAX - 1, 1), utxo(2, 2)];
+        assert_eq!(
+            select_inputs(
+                &unspent,
+                &HashSet::default(),
+                ScaledAmount::new(u64::MAX, MOB),
+                16
+            ),
+            Err(PreconditionFailed::Overflow)
+        );
+    }
+}
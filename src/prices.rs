@@ -0,0 +1,296 @@
+//! Indicative prices of the tokens in USD, from the mid-prices of their deqs books
+//! against EUSD, to show approximate values of the balances and amounts, and the
+//! history of the mid-price of the active pair over the session, to chart it.
+//!
+//! They are only estimates: a book may be thin, and they are never used to decide
+//! what is sent or swapped. Nothing here is persisted.
+
+use crate::{TokenId, TokenInfo};
+use rust_decimal::Decimal;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// The mid-price of each token against the reference token, and when it was seen
+#[derive(Clone, Debug, Default)]
+pub struct IndicativePrices {
+    /// The token the prices are in, once it is known
+    reference: Option<TokenId>,
+    /// The most recent mid-price of each token, and when its books were polled
+    prices: HashMap<TokenId, (Decimal, Instant)>,
+}
+
+impl IndicativePrices {
+    /// The symbol of the token the prices are in, which tracks USD
+    pub const REFERENCE_SYMBOL: &'static str = "EUSD";
+    /// How often the books of every token against the reference token are polled,
+    /// whichever pair the user is looking at
+    pub const POLL_INTERVAL: Duration = Duration::from_secs(30);
+    /// How old a price may get before it is hidden rather than shown stale
+    pub const MAX_AGE: Duration = Duration::from_secs(120);
+
+    /// Set the token the prices are in
+    pub fn set_reference(&mut self, token_id: TokenId) {
+        if self.reference != Some(token_id) {
+            self.reference = Some(token_id);
+            self.prices.clear();
+        }
+    }
+
+    /// Record the mid-price of a token, from books polled at `now`. A book without
+    /// a mid-price forgets the previous one.
+    pub fn record(&mut self, token_id: TokenId, price: Option<Decimal>, now: Instant) {
+        match price {
+            Some(price) => {
+                self.prices.insert(token_id, (price, now));
+            }
+            None => {
+                self.prices.remove(&token_id);
+            }
+        }
+    }
+
+    /// The price of one token in the reference token as of `now`, unless it is
+    /// unknown or older than MAX_AGE. The reference token is worth 1.
+    pub fn get(&self, token_id: TokenId, now: Instant) -> Option<Decimal> {
+        if self.reference == Some(token_id) {
+            return Some(Decimal::ONE);
+        }
+        self.prices
+            .get(&token_id)
+            .filter(|(_, seen)| now.saturating_duration_since(*seen) <= Self::MAX_AGE)
+            .map(|(price, _)| *price)
+    }
+}
+
+/// A sample of the mid-price of a pair
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PriceSample {
+    /// When the sample was taken
+    pub at: Instant,
+    /// The mid-price, in units of the counter token, or None if a side of the book
+    /// had no quotes, or the book wasn't fresh
+    pub price: Option<Decimal>,
+}
+
+/// The mid-prices of the pairs polled during the session, sampled at a regular
+/// interval into a bounded buffer per pair
+#[derive(Clone, Debug)]
+pub struct PriceSampler {
+    /// How often to sample the active pair
+    interval: Duration,
+    /// How many samples to keep per pair, dropping the oldest
+    capacity: usize,
+    /// When the last sample was taken
+    last_sample: Option<Instant>,
+    /// The samples of each (base, counter) pair, oldest first
+    histories: HashMap<(TokenId, TokenId), VecDeque<PriceSample>>,
+}
+
+impl Default for PriceSampler {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_INTERVAL, Self::DEFAULT_CAPACITY)
+    }
+}
+
+impl PriceSampler {
+    /// The default sample interval
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+    /// The default number of samples per pair, 24 hours at the default interval
+    pub const DEFAULT_CAPACITY: usize = 2880;
+
+    /// A sampler taking a sample every `interval`, and keeping `capacity` of them per
+    /// pair. Both are at least 1.
+    pub fn new(interval: Duration, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(Duration::from_secs(1)),
+            capacity: capacity.max(1),
+            last_sample: None,
+            histories: Default::default(),
+        }
+    }
+
+    /// Whether the next sample is due at `now`
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.last_sample.map_or(true, |last| {
+            now.saturating_duration_since(last) >= self.interval
+        })
+    }
+
+    /// Record a sample of the mid-price of a pair
+    pub fn record(&mut self, pair: (TokenId, TokenId), price: Option<Decimal>, now: Instant) {
+        self.last_sample = Some(now);
+        let history = self.histories.entry(pair).or_default();
+        history.push_back(PriceSample { at: now, price });
+        while history.len() > self.capacity {
+            history.pop_front();
+        }
+    }
+
+    /// The samples of a (base, counter) pair, oldest first
+    pub fn history(&self, pair: (TokenId, TokenId)) -> Vec<PriceSample> {
+        self.histories
+            .get(&pair)
+            .map(|history| history.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// The mid-price between the best bid and the best ask. A book with quotes on one
+/// side only, or none, has no mid-price.
+pub fn mid_price(best_bid: Option<Decimal>, best_ask: Option<Decimal>) -> Option<Decimal> {
+    let (bid, ask) = best_bid.zip(best_ask)?;
+    bid.checked_add(ask)?.checked_div(Decimal::TWO)
+}
+
+/// The value in USD of `value` of a token, at a price in the reference token
+pub fn fiat_value(info: &TokenInfo, value: u64, price: Decimal) -> Option<Decimal> {
+    info.try_u64_to_decimal(value).ok()?.checked_mul(price)
+}
+
+/// Format an approximate value in USD, to the cent
+pub fn format_fiat(value: Decimal) -> String {
+    format!("≈ ${:.2}", value.round_dp(2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn dec(src: &str) -> Decimal {
+        Decimal::from_str(src).unwrap()
+    }
+
+    fn mob() -> TokenInfo {
+        TokenInfo {
+            token_id: TokenId::MOB,
+            symbol: "MOB".to_owned(),
+            fee: 400_000_000,
+            decimals: 12,
+        }
+    }
+
+    #[test]
+    fn books_need_both_sides_for_a_mid_price() {
+        assert_eq!(
+            mid_price(Some(dec("1.9")), Some(dec("2.1"))),
+            Some(dec("2"))
+        );
+        assert_eq!(mid_price(Some(dec("1")), Some(dec("2"))), Some(dec("1.5")));
+        assert_eq!(mid_price(Some(dec("2")), None), None);
+        assert_eq!(mid_price(None, Some(dec("2"))), None);
+        assert_eq!(mid_price(None, None), None);
+        assert_eq!(mid_price(Some(Decimal::MAX), Some(Decimal::MAX)), None);
+    }
+
+    #[test]
+    fn prices_are_hidden_past_max_age() {
+        let start = Instant::now();
+        let mut prices = IndicativePrices::default();
+        prices.set_reference(TokenId::from(1));
+        prices.record(TokenId::MOB, Some(dec("5")), start);
+        assert_eq!(prices.get(TokenId::MOB, start), Some(dec("5")));
+        assert_eq!(
+            prices.get(TokenId::MOB, start + IndicativePrices::MAX_AGE),
+            Some(dec("5"))
+        );
+        assert_eq!(
+            prices.get(
+                TokenId::MOB,
+                start + IndicativePrices::MAX_AGE + Duration::from_millis(1)
+            ),
+            None
+        );
+        assert_eq!(prices.get(TokenId::from(2), start), None);
+    }
+
+    #[test]
+    fn the_reference_token_is_worth_one() {
+        let now = Instant::now();
+        let mut prices = IndicativePrices::default();
+        assert_eq!(prices.get(TokenId::from(1), now), None);
+        prices.set_reference(TokenId::from(1));
+        assert_eq!(prices.get(TokenId::from(1), now), Some(Decimal::ONE));
+
+        // Prices in another reference token are forgotten
+        prices.record(TokenId::MOB, Some(dec("5")), now);
+        prices.set_reference(TokenId::from(1));
+        assert_eq!(prices.get(TokenId::MOB, now), Some(dec("5")));
+        prices.set_reference(TokenId::from(3));
+        assert_eq!(prices.get(TokenId::MOB, now), None);
+    }
+
+    #[test]
+    fn a_book_without_a_mid_price_forgets_the_last_one() {
+        let now = Instant::now();
+        let mut prices = IndicativePrices::default();
+        prices.set_reference(TokenId::from(1));
+        prices.record(TokenId::MOB, Some(dec("5")), now);
+        prices.record(TokenId::MOB, None, now);
+        assert_eq!(prices.get(TokenId::MOB, now), None);
+    }
+
+    #[test]
+    fn fiat_values_scale_by_the_token_decimals() {
+        assert_eq!(
+            fiat_value(&mob(), 1_500_000_000_000, dec("2")),
+            Some(dec("3"))
+        );
+        assert_eq!(fiat_value(&mob(), 0, dec("2")), Some(Decimal::ZERO));
+        assert_eq!(
+            fiat_value(&mob(), u64::MAX, dec("1")),
+            Some(dec("18446744.073709551615"))
+        );
+        assert_eq!(fiat_value(&mob(), u64::MAX, Decimal::MAX), None);
+        assert_eq!(format_fiat(dec("3.14159")), "≈ $3.14");
+        assert_eq!(format_fiat(dec("3")), "≈ $3.00");
+    }
+
+    #[test]
+    fn samples_are_due_once_per_interval() {
+        let start = Instant::now();
+        let mut sampler = PriceSampler::new(Duration::from_secs(10), 4);
+        assert!(sampler.is_due(start));
+        sampler.record((TokenId::MOB, TokenId::from(1)), Some(dec("5")), start);
+        assert!(!sampler.is_due(start + Duration::from_secs(9)));
+        assert!(sampler.is_due(start + Duration::from_secs(10)));
+
+        // The interval is at least a second
+        let mut sampler = PriceSampler::new(Duration::ZERO, 4);
+        sampler.record((TokenId::MOB, TokenId::from(1)), None, start);
+        assert!(!sampler.is_due(start + Duration::from_millis(999)));
+        assert!(sampler.is_due(start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn histories_are_bounded_per_pair_oldest_dropped_first() {
+        let start = Instant::now();
+        let pair = (TokenId::MOB, TokenId::from(1));
+        let other = (TokenId::from(1), TokenId::MOB);
+        let mut sampler = PriceSampler::new(Duration::from_secs(1), 3);
+        for idx in 0..5u64 {
+            let at = start + Duration::from_secs(idx);
+            let price = (idx != 3).then(|| Decimal::from(idx));
+            sampler.record(pair, price, at);
+        }
+        sampler.record(other, Some(dec("7")), start);
+
+        let history = sampler.history(pair);
+        assert_eq!(
+            history
+                .iter()
+                .map(|sample| sample.price)
+                .collect::<Vec<_>>(),
+            vec![Some(dec("2")), None, Some(dec("4"))]
+        );
+        assert_eq!(history[0].at, start + Duration::from_secs(2));
+        assert_eq!(sampler.history(other).len(), 1);
+        assert!(sampler.history((TokenId::from(2), TokenId::MOB)).is_empty());
+
+        // Keeping no samples keeps one
+        let mut sampler = PriceSampler::new(Duration::from_secs(1), 0);
+        sampler.record(pair, None, start);
+        sampler.record(pair, Some(dec("1")), start);
+        assert_eq!(sampler.history(pair).len(), 1);
+    }
+}
@@ -0,0 +1,103 @@
+//! QR codes of the strings a user may want to move to their phone, like the
+//! public address or a payment request, rendered as egui textures.
+
+use egui::{Color32, ColorImage, TextureHandle, TextureOptions};
+use qrcode::{Color, QrCode};
+
+/// The width of the light border around a QR code, in modules, which scanners need
+const QUIET_ZONE: usize = 4;
+
+/// Render a QR code of `data`, with each module `module_pixels` pixels wide
+pub fn render_qr(data: &str, module_pixels: usize) -> Result<ColorImage, String> {
+    let code = QrCode::new(data.as_bytes()).map_err(|err| err.to_string())?;
+    let modules = code.width();
+    let module_pixels = module_pixels.max(1);
+    let side = (modules + 2 * QUIET_ZONE) * module_pixels;
+
+    let mut image = ColorImage::new([side, side], Color32::WHITE);
+    for (idx, color) in code.to_colors().into_iter().enumerate() {
+        if color != Color::Dark {
+            continue;
+        }
+        let x = (idx % modules + QUIET_ZONE) * module_pixels;
+        let y = (idx / modules + QUIET_ZONE) * module_pixels;
+        for dy in 0..module_pixels {
+            for dx in 0..module_pixels {
+                image[(x + dx, y + dy)] = Color32::BLACK;
+            }
+        }
+    }
+    Ok(image)
+}
+
+/// A QR code texture, which is only rendered again when the string it encodes
+/// changes, rather than every frame
+#[derive(Default)]
+pub struct QrTexture {
+    /// The string encoded, and its texture or the error rendering it
+    cached: Option<(String, Result<TextureHandle, String>)>,
+}
+
+impl QrTexture {
+    /// The texture of a QR code of `data`, rendering it if `data` changed
+    pub fn get(&mut self, ctx: &egui::Context, data: &str) -> Result<&TextureHandle, String> {
+        if self.cached.as_ref().map(|(cached, _)| cached.as_str()) != Some(data) {
+            // One pixel per module, drawn scaled up without smoothing
+            let texture = render_qr(data, 1)
+                .map(|image| ctx.load_texture("qr", image, TextureOptions::NEAREST));
+            self.cached = Some((data.to_owned(), texture));
+        }
+        match self.cached.as_ref() {
+            Some((_, Ok(texture))) => Ok(texture),
+            Some((_, Err(err))) => Err(err.clone()),
+            None => Err("no QR code".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Scan the QR codes in an image, like a phone would
+    fn scan(image: &ColorImage) -> Vec<String> {
+        let mut prepared =
+            rqrr::PreparedImage::prepare_from_greyscale(image.width(), image.height(), |x, y| {
+                image[(x, y)].r()
+            });
+        prepared
+            .detect_grids()
+            .into_iter()
+            .map(|grid| grid.decode().unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn rendered_qr_codes_scan_back_to_their_data() {
+        let data = "mob:///b58/2Dcm6BjUbjUHGdJzwHdEHeRJXGwudLMcFdbaRZuLTLeoqqfmdeDWVwaWfhhvaDG\
+                    4MFSYwSVLxizPh3aWGvKzVE8TMSNjP6Tz8DtzZvMvbZm";
+        for module_pixels in [1, 4] {
+            let image = render_qr(data, module_pixels).unwrap();
+            assert_eq!(scan(&image), vec![data.to_owned()]);
+        }
+    }
+
+    #[test]
+    fn rendered_qr_codes_have_a_quiet_zone() {
+        let image = render_qr("hello", 3).unwrap();
+        let modules = QrCode::new(b"hello").unwrap().width();
+        let side = (modules + 2 * QUIET_ZONE) * 3;
+        assert_eq!(image.size, [side, side]);
+        for idx in 0..side {
+            assert_eq!(image[(idx, 0)], Color32::WHITE);
+            assert_eq!(image[(0, idx)], Color32::WHITE);
+            assert_eq!(image[(idx, side - 1)], Color32::WHITE);
+            assert_eq!(image[(side - 1, idx)], Color32::WHITE);
+        }
+    }
+
+    #[test]
+    fn data_too_long_for_a_qr_code_is_an_error() {
+        assert!(render_qr(&"x".repeat(8_000), 1).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Live updates of the quote books from a deqs, which spare polling it for the
+//! whole book many times a second while the user looks at a pair.
+//!
+//! Each stream is read on a thread of its own, since reading it blocks. The thread
+//! reports to the worker thread over a channel, so that only the worker thread
+//! touches the stored books.
+
+use crate::{AuthToken, TokenId, ValidatedQuote};
+use deqs_api::{deqs as d_api, deqs_grpc::DeqsClientApiClient as DeqsClient};
+use futures::{channel::oneshot, executor::block_on, StreamExt};
+use grpcio::{CallOption, RpcStatusCode};
+use mc_crypto_ring_signature::KeyImage;
+use mc_transaction_extra::SignedContingentInput;
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::{event, Level};
+
+/// A change to the quote book of a pair
+#[derive(Debug)]
+pub enum QuoteUpdate {
+    /// A quote was added to the book
+    Added {
+        /// The base and counter token ids of the book
+        pair: (TokenId, TokenId),
+        /// The quote
+        quote: ValidatedQuote,
+    },
+    /// A quote was removed from the book
+    Removed {
+        /// The base and counter token ids of the book
+        pair: (TokenId, TokenId),
+        /// The key image of the quote's sci
+        key_image: KeyImage,
+    },
+}
+
+/// What a live update stream reports to the worker thread
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// The deqs at index `source` doesn't support live updates, so its books must
+    /// be polled
+    Unsupported {
+        /// The index of the deqs
+        source: usize,
+    },
+    /// A book changed
+    Update(QuoteUpdate),
+    /// The stream from the deqs at index `source` ended
+    Ended {
+        /// The index of the deqs
+        source: usize,
+        /// The error the stream failed with, if any
+        err: Option<String>,
+    },
+}
+
+/// A live update stream from one of the configured deqs. Dropping it closes the
+/// stream, and waits for its thread to exit.
+pub struct QuoteStream {
+    /// The index of the deqs, in the order they were configured
+    pub source: usize,
+    /// Dropping this tells the stream thread to stop
+    stop: Option<oneshot::Sender<()>>,
+    /// The thread reading the stream
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl QuoteStream {
+    /// How often to poll the whole book anyway while every deqs streams its
+    /// updates, in case an update was missed
+    pub const RESYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Open the live update stream of the deqs at index `source`, authenticating
+    /// with `auth_token` if it requires one, and reporting what happens to `events`
+    pub fn open(
+        source: usize,
+        client: DeqsClient,
+        auth_token: Option<AuthToken>,
+        events: Sender<StreamEvent>,
+    ) -> Self {
+        let (stop, stop_receiver) = oneshot::channel();
+        let join_handle = std::thread::spawn(move || {
+            let run = Self::run(source, &client, auth_token.as_ref(), stop_receiver, &events);
+            if let Some(event) = run {
+                // The worker may have hung up already, if it is shutting down
+                let _ = events.send(event);
+            }
+        });
+        Self {
+            source,
+            stop: Some(stop),
+            join_handle: Some(join_handle),
+        }
+    }
+
+    // Read the stream until it ends or we are told to stop. Returns how the stream
+    // ended, or None if we were told to stop.
+    fn run(
+        source: usize,
+        client: &DeqsClient,
+        auth_token: Option<&AuthToken>,
+        stop: oneshot::Receiver<()>,
+        events: &Sender<StreamEvent>,
+    ) -> Option<StreamEvent> {
+        let options = match auth_token {
+            Some(auth_token) => auth_token.authorize(CallOption::default()),
+            None => CallOption::default(),
+        };
+        let redact = |err: &grpcio::Error| match auth_token {
+            Some(auth_token) => auth_token.redact(&err.to_string()),
+            None => err.to_string(),
+        };
+        let receiver = match client.live_updates_opt(&d_api::LiveUpdatesRequest::new(), options) {
+            Ok(receiver) => receiver,
+            Err(err) => {
+                return Some(StreamEvent::Ended {
+                    source,
+                    err: Some(redact(&err)),
+                })
+            }
+        };
+
+        // The stop receiver resolves when the sender is dropped, which wakes us up
+        // even while we wait for the next update
+        let mut updates = receiver.take_until(stop);
+        let mut result = None;
+        while let Some(update) = block_on(updates.next()) {
+            match update {
+                Ok(update) => {
+                    if let Some(update) = Self::parse_update(source, &update) {
+                        if events.send(StreamEvent::Update(update)).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(grpcio::Error::RpcFailure(status))
+                    if status.code() == RpcStatusCode::UNIMPLEMENTED =>
+                {
+                    return Some(StreamEvent::Unsupported { source });
+                }
+                Err(err) => {
+                    result = Some(redact(&err));
+                    break;
+                }
+            }
+        }
+        if updates.is_stopped() {
+            updates.get_mut().cancel();
+            return None;
+        }
+        Some(StreamEvent::Ended {
+            source,
+            err: result,
+        })
+    }
+
+    // Turn a live update into a change of a book, if it is one we understand
+    fn parse_update(source: usize, update: &d_api::LiveUpdate) -> Option<QuoteUpdate> {
+        if update.has_new_quote() {
+            let quote = update.get_new_quote();
+            match ValidatedQuote::try_from_quote(quote, source) {
+                Ok(validated_quote) => Some(QuoteUpdate::Added {
+                    pair: Self::pair_of(quote),
+                    quote: validated_quote,
+                }),
+                Err(err) => {
+                    event!(Level::ERROR, "validating live quote: {}", err);
+                    None
+                }
+            }
+        } else if update.has_removed_quote() {
+            let quote = update.get_removed_quote();
+            match SignedContingentInput::try_from(quote.get_sci()) {
+                Ok(sci) => Some(QuoteUpdate::Removed {
+                    pair: Self::pair_of(quote),
+                    key_image: sci.key_image(),
+                }),
+                Err(err) => {
+                    event!(Level::ERROR, "decoding removed live quote: {}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        }
+    }
+
+    // The book a quote belongs to
+    fn pair_of(quote: &d_api::Quote) -> (TokenId, TokenId) {
+        let pair = quote.get_pair();
+        (
+            TokenId::from(pair.base_token_id),
+            TokenId::from(pair.counter_token_id),
+        )
+    }
+}
+
+impl Drop for QuoteStream {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+    }
+}
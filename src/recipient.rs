@@ -0,0 +1,194 @@
+//! Checks of recipient addresses against the network mobilecoind is connected to.
+//!
+//! A b58 public address doesn't say which network it is for, but the fog report
+//! url of a fog address names the fog service of a particular network, which is
+//! enough to catch a testnet address pasted while connected to mainnet (or vice
+//! versa). Addresses without fog can't be checked this way.
+
+use mc_api::external;
+
+/// The chain id of the MobileCoin main network
+pub const MAINNET_CHAIN_ID: &str = "main";
+
+/// The kind of network an address or a mobilecoind is for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NetworkKind {
+    /// The main network
+    Main,
+    /// A test network
+    Test,
+}
+
+impl NetworkKind {
+    /// Host name fragments of the fog services of each kind of network
+    const FOG_HOST_HINTS: &'static [(&'static str, NetworkKind)] = &[
+        (".prod.", NetworkKind::Main),
+        ("production", NetworkKind::Main),
+        (".test.", NetworkKind::Test),
+        ("staging", NetworkKind::Test),
+    ];
+
+    /// The kind of network with a chain id, if it is known. The chain id is empty
+    /// until mobilecoind reported it.
+    pub fn from_chain_id(chain_id: &str) -> Option<Self> {
+        match chain_id {
+            "" => None,
+            MAINNET_CHAIN_ID => Some(Self::Main),
+            _ => Some(Self::Test),
+        }
+    }
+
+    /// The kind of network a fog report url is for, if its host is recognized
+    pub fn from_fog_report_url(fog_report_url: &str) -> Option<Self> {
+        let host = fog_report_url
+            .split("://")
+            .last()
+            .unwrap_or_default()
+            .split(['/', ':'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        Self::FOG_HOST_HINTS
+            .iter()
+            .find(|(hint, _)| host.contains(hint))
+            .map(|(_, kind)| *kind)
+    }
+
+    /// A short name, for the top panel
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Main => "mainnet",
+            Self::Test => "testnet",
+        }
+    }
+
+    /// A description for error messages
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::Main => "main network",
+            Self::Test => "test network",
+        }
+    }
+}
+
+/// A decoded recipient address
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recipient {
+    /// The public address
+    pub address: external::PublicAddress,
+}
+
+impl Recipient {
+    /// The fog report url of the address, if it is a fog address.
+    ///
+    /// Payments to fog addresses need fog hints, which mobilecoind can only build
+    /// if it can reach the fog report server at this url.
+    pub fn fog_report_url(&self) -> Option<&str> {
+        Some(self.address.get_fog_report_url()).filter(|url| !url.is_empty())
+    }
+
+    /// Whether this is a fog address
+    pub fn has_fog(&self) -> bool {
+        self.fog_report_url().is_some()
+    }
+}
+
+/// Check that a recipient is for the network with the given chain id, as far as
+/// can be told from its fog report url. Nothing is checked until the chain id is
+/// known.
+pub fn check_recipient_network(recipient: &Recipient, chain_id: &str) -> Result<(), String> {
+    let Some(connected) = NetworkKind::from_chain_id(chain_id) else {
+        return Ok(());
+    };
+    match recipient
+        .fog_report_url()
+        .and_then(NetworkKind::from_fog_report_url)
+    {
+        Some(kind) if kind != connected => Err(format!(
+            "address appears to be for {}, but mobilecoind is connected to {} (chain id \"{}\")",
+            kind.describe(),
+            connected.describe(),
+            chain_id
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Worker;
+
+    // Public addresses whose view and spend public keys are the ristretto basepoint
+    const PLAIN_ADDRESS: &str =
+        "3HYSdaKvjkQHLDgyUD7Zb14oY6bxv1z1bwRczrc9twcrfcAXw4UgZNhsDe59jseGR7MdqTbmAzrKof5N\
+        rsfSpJ9stnU1MV2Q2CSXBzDGkUM";
+    // With fog report url fog://fog.prod.mobilecoinww.com
+    const MAINNET_FOG_ADDRESS: &str =
+        "95L1oDguoGfto2eWTUgWRa3rgL1S36GYaJPFAXV62gAsRSWpnHFqDEBUxvBuDj4Ep92rm1EoeCRMMHuC\
+        U69YGZ1qKtnL5j15dMLDadJBErJPHAFKUHBqHxmj798tYfi5Su9EAddWKQ3mQhNswgCwajBS";
+    // With fog report url fog://fog.test.mobilecoin.com
+    const TESTNET_FOG_ADDRESS: &str =
+        "Cv9Km9k1uv7tMbGN5pL9s633rubCb2gjb1vyJvjv276T3tWytgeNAFwFNsn3btqz36BzsRMy3LU5KVnC\
+        q2T1bU14T4YUCp5bsBh4nqgTYjUZa3RqUF9qeJcoxS9rXPNTP9SSRkUZxCsSPKnkKCssS";
+
+    fn decode(b58_address: &str) -> Recipient {
+        Worker::decode_b58_address(b58_address).unwrap()
+    }
+
+    #[test]
+    fn b58_addresses_decode_with_their_fog_report_url() {
+        assert_eq!(decode(PLAIN_ADDRESS).fog_report_url(), None);
+        assert!(!decode(PLAIN_ADDRESS).has_fog());
+        assert_eq!(
+            decode(MAINNET_FOG_ADDRESS).fog_report_url(),
+            Some("fog://fog.prod.mobilecoinww.com")
+        );
+        assert_eq!(
+            decode(TESTNET_FOG_ADDRESS).fog_report_url(),
+            Some("fog://fog.test.mobilecoin.com")
+        );
+        // A typo breaks the checksum
+        let typo = PLAIN_ADDRESS.replacen('3', "4", 1);
+        assert!(Worker::decode_b58_address(&typo).is_err());
+    }
+
+    #[test]
+    fn chain_ids_are_unknown_until_reported() {
+        assert_eq!(NetworkKind::from_chain_id(""), None);
+        assert_eq!(NetworkKind::from_chain_id("main"), Some(NetworkKind::Main));
+        assert_eq!(NetworkKind::from_chain_id("test"), Some(NetworkKind::Test));
+    }
+
+    #[test]
+    fn fog_addresses_must_match_the_network() {
+        let mainnet = decode(MAINNET_FOG_ADDRESS);
+        let testnet = decode(TESTNET_FOG_ADDRESS);
+        assert_eq!(check_recipient_network(&mainnet, "main"), Ok(()));
+        assert_eq!(check_recipient_network(&testnet, "test"), Ok(()));
+        assert_eq!(
+            check_recipient_network(&testnet, "main"),
+            Err(
+                "address appears to be for test network, but mobilecoind is connected to \
+                 main network (chain id \"main\")"
+                    .to_owned()
+            )
+        );
+        assert!(check_recipient_network(&mainnet, "test")
+            .unwrap_err()
+            .starts_with("address appears to be for main network"));
+    }
+
+    #[test]
+    fn addresses_are_not_checked_until_the_chain_id_is_known() {
+        let testnet = decode(TESTNET_FOG_ADDRESS);
+        assert_eq!(check_recipient_network(&testnet, ""), Ok(()));
+    }
+
+    #[test]
+    fn addresses_without_fog_pass_on_any_network() {
+        let plain = decode(PLAIN_ADDRESS);
+        assert_eq!(check_recipient_network(&plain, "main"), Ok(()));
+        assert_eq!(check_recipient_network(&plain, "test"), Ok(()));
+    }
+}
@@ -0,0 +1,78 @@
+//! Retention policies, which bound how much data the worker keeps in memory
+//! during long sessions.
+
+use crate::Config;
+use std::time::Duration;
+
+/// How much of a data set to keep in memory
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetentionPolicy {
+    /// The maximum number of entries to keep
+    pub max_entries: usize,
+    /// Entries older than this are dropped, if set
+    pub max_age: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    /// Whether to keep an entry, given its position counting from the newest
+    /// entry (starting at 0), and its age.
+    pub fn keeps(&self, idx_from_newest: usize, age: Duration) -> bool {
+        idx_from_newest < self.max_entries && self.max_age.map(|max| age <= max).unwrap_or(true)
+    }
+}
+
+/// The retention policies of each data set the worker keeps
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetentionPolicies {
+    /// The offers we submitted, which are no longer being submitted
+    pub offers: RetentionPolicy,
+    /// The quote books of pairs which the user is no longer looking at
+    pub quote_books: RetentionPolicy,
+}
+
+impl RetentionPolicies {
+    /// How often the worker applies the retention policies
+    pub const COMPACTION_INTERVAL: Duration = Duration::from_secs(10);
+}
+
+impl From<&Config> for RetentionPolicies {
+    fn from(config: &Config) -> Self {
+        Self {
+            offers: RetentionPolicy {
+                max_entries: config.offers_retention,
+                max_age: None,
+            },
+            quote_books: RetentionPolicy {
+                max_entries: config.quote_books_retention,
+                max_age: Some(Duration::from_secs(config.quote_books_retention_secs)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_keep_the_newest_entries() {
+        let policy = RetentionPolicy {
+            max_entries: 2,
+            max_age: None,
+        };
+        let old = Duration::from_secs(u64::MAX);
+        assert!(policy.keeps(0, old));
+        assert!(policy.keeps(1, old));
+        assert!(!policy.keeps(2, Duration::ZERO));
+    }
+
+    #[test]
+    fn policies_drop_entries_older_than_the_max_age() {
+        let policy = RetentionPolicy {
+            max_entries: usize::MAX,
+            max_age: Some(Duration::from_secs(60)),
+        };
+        assert!(policy.keeps(0, Duration::from_secs(60)));
+        assert!(!policy.keeps(0, Duration::from_secs(61)));
+    }
+}
@@ -0,0 +1,86 @@
+//! Retrying of operations which fail with errors that resolve themselves, like
+//! mobilecoind building a payment from a balance which is still catching up
+//! with the ledger.
+
+use crate::Config;
+use std::time::Duration;
+use tracing::{event, Level};
+
+/// How many times to attempt an operation which fails transiently, and how long
+/// to wait between attempts
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. The wait doubles with every retry.
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(200),
+        }
+    }
+}
+
+impl From<&Config> for RetryPolicy {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_attempts: config.send_attempts.max(1),
+            ..Default::default()
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Errors from mobilecoind which go away once the balance or the ledger catch
+    /// up, or the connection recovers (lowercase)
+    const TRANSIENT_ERRORS: &'static [&'static str] = &[
+        "contains spent key image",
+        "insufficient funds",
+        "unavailable",
+        "deadline exceeded",
+        "timed out",
+    ];
+
+    /// Whether an error is transient, so that the operation is worth retrying.
+    /// Anything else, like a bad address or amount, fails the same way every time.
+    pub fn is_transient(err: &str) -> bool {
+        let err = err.to_lowercase();
+        Self::TRANSIENT_ERRORS
+            .iter()
+            .any(|transient| err.contains(transient))
+    }
+
+    /// The waits before each of the retries
+    pub fn backoffs(&self) -> impl Iterator<Item = Duration> {
+        let initial_backoff = self.initial_backoff;
+        (0..self.max_attempts.saturating_sub(1))
+            .map(move |retry| initial_backoff.saturating_mul(1 << retry.min(16)))
+    }
+
+    /// Run an operation, retrying it while it fails with a transient error.
+    /// Returns the error of the last attempt if none of them succeeds.
+    pub fn run<T>(
+        &self,
+        what: &str,
+        mut op: impl FnMut() -> Result<T, String>,
+    ) -> Result<T, String> {
+        let mut backoffs = self.backoffs();
+        loop {
+            match op() {
+                Ok(result) => return Ok(result),
+                Err(err) if Self::is_transient(&err) => match backoffs.next() {
+                    Some(backoff) => {
+                        event!(Level::WARN, "{} will retry in {:?}: {}", what, backoff, err);
+                        std::thread::sleep(backoff);
+                    }
+                    None => return Err(err),
+                },
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
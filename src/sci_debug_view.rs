@@ -0,0 +1,77 @@
+//! A view of the decoded contents of a signed contingent input, so that the user
+//! can see exactly what mobilecoind signed.
+
+use crate::TokenInfo;
+use egui::{Grid, RichText, Ui};
+use mc_transaction_extra::{SignedContingentInput, SignedContingentInputAmounts};
+
+/// Render the decoded contents of an sci as a table.
+///
+/// Arguments:
+/// * ui which we are rendering into
+/// * sci, the decoded sci
+/// * amounts, the amounts of the sci as determined by validation
+/// * token_infos, obtained from worker.get_token_infos, used to format amounts
+pub fn sci_debug_view(
+    ui: &mut Ui,
+    sci: &SignedContingentInput,
+    amounts: &SignedContingentInputAmounts,
+    token_infos: &[TokenInfo],
+) {
+    let format_amount = |amount| TokenInfo::format_amount(token_infos, amount);
+    let rules = sci.tx_in.input_rules.as_ref();
+
+    Grid::new("sci_debug_view").striped(true).show(ui, |ui| {
+        ui.label("Key image");
+        ui.label(
+            RichText::new(format!("{:?}", sci.key_image()))
+                .monospace()
+                .small(),
+        );
+        ui.end_row();
+
+        ui.label("Block version");
+        ui.label(sci.block_version.to_string());
+        ui.end_row();
+
+        ui.label("Ring size");
+        ui.label(sci.tx_in.ring.len().to_string());
+        ui.end_row();
+
+        ui.label("Offered input");
+        ui.label(format_amount(amounts.pseudo_output));
+        ui.end_row();
+
+        for amount in amounts.required_outputs.iter() {
+            ui.label("Required output");
+            ui.label(format_amount(*amount));
+            ui.end_row();
+        }
+
+        for amount in amounts.partial_fill_outputs.iter() {
+            ui.label("Partial fill output");
+            ui.label(format_amount(*amount));
+            ui.end_row();
+        }
+
+        if let Some(amount) = amounts.partial_fill_change.as_ref() {
+            ui.label("Partial fill change");
+            ui.label(format_amount(*amount));
+            ui.end_row();
+        }
+
+        if let Some(rules) = rules {
+            ui.label("Min partial fill value");
+            ui.label(rules.min_partial_fill_value.to_string());
+            ui.end_row();
+
+            ui.label("Max tombstone block");
+            ui.label(if rules.max_tombstone_block == 0 {
+                "none".to_owned()
+            } else {
+                rules.max_tombstone_block.to_string()
+            });
+            ui.end_row();
+        }
+    });
+}
@@ -0,0 +1,211 @@
+//! A simple append-only file of length-prefixed records, which older entries
+//! of a data set are spilled to when they no longer fit in memory.
+//!
+//! Each record is a little-endian u32 length, followed by that many bytes.
+
+use displaydoc::Display;
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+/// Records larger than this are assumed to be corruption
+const MAX_RECORD_LEN: usize = 1 << 20;
+
+/// An error reading or writing a spill file
+#[derive(Clone, Debug, Display, Eq, PartialEq)]
+pub enum SpillError {
+    /// Spill file io: {0}
+    Io(String),
+    /// Spill file is corrupt at offset {0}
+    Corrupt(usize),
+}
+
+/// A spill file in the data dir
+#[derive(Clone, Debug)]
+pub struct SpillFile {
+    /// The path of the file
+    path: PathBuf,
+}
+
+impl SpillFile {
+    /// A spill file at the given path. The file is created on the first append.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    /// The path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append records to the file, oldest first
+    pub fn append(&self, records: &[Vec<u8>]) -> Result<(), SpillError> {
+        let mut bytes = Vec::default();
+        for record in records {
+            let len = u32::try_from(record.len())
+                .ok()
+                .filter(|len| (*len as usize) <= MAX_RECORD_LEN)
+                .ok_or_else(|| SpillError::Io("record too large".to_owned()))?;
+            bytes.extend_from_slice(&len.to_le_bytes());
+            bytes.extend_from_slice(record);
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|err| SpillError::Io(err.to_string()))?;
+        file.write_all(&bytes)
+            .map_err(|err| SpillError::Io(err.to_string()))
+    }
+
+    /// Read a page of records, newest first: skip the `skip` newest records,
+    /// and return at most `count` of the records older than those.
+    ///
+    /// A missing file holds no records.
+    pub fn read_page(&self, skip: usize, count: usize) -> Result<Vec<Vec<u8>>, SpillError> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::default()),
+            Err(err) => return Err(SpillError::Io(err.to_string())),
+        };
+        let records = Self::parse(&bytes)?;
+        Ok(records
+            .into_iter()
+            .rev()
+            .skip(skip)
+            .take(count)
+            .map(<[u8]>::to_vec)
+            .collect())
+    }
+
+    /// The number of records in the file
+    pub fn len(&self) -> Result<usize, SpillError> {
+        match std::fs::read(&self.path) {
+            Ok(bytes) => Ok(Self::parse(&bytes)?.len()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(SpillError::Io(err.to_string())),
+        }
+    }
+
+    /// Whether the file holds no records
+    pub fn is_empty(&self) -> Result<bool, SpillError> {
+        Ok(self.len()? == 0)
+    }
+
+    // Split the contents of a spill file into records, oldest first
+    fn parse(bytes: &[u8]) -> Result<Vec<&[u8]>, SpillError> {
+        let mut records = Vec::default();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let len_bytes: [u8; 4] = bytes
+                .get(offset..offset + 4)
+                .and_then(|slice| slice.try_into().ok())
+                .ok_or(SpillError::Corrupt(offset))?;
+            let len = u32::from_le_bytes(len_bytes) as usize;
+            if len > MAX_RECORD_LEN {
+                return Err(SpillError::Corrupt(offset));
+            }
+            let record = bytes
+                .get(offset + 4..offset + 4 + len)
+                .ok_or(SpillError::Corrupt(offset))?;
+            records.push(record);
+            offset += 4 + len;
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A spill file in a fresh temp dir, removed when the guard is dropped
+    struct TempSpill(SpillFile);
+
+    impl TempSpill {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("buddy-spill-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(name);
+            let _ = std::fs::remove_file(&path);
+            Self(SpillFile::new(path))
+        }
+    }
+
+    impl Drop for TempSpill {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(self.0.path());
+        }
+    }
+
+    fn records(range: std::ops::Range<u8>) -> Vec<Vec<u8>> {
+        range.map(|idx| vec![idx; idx as usize]).collect()
+    }
+
+    #[test]
+    fn records_are_length_prefixed() {
+        let spill = TempSpill::new("format.spill");
+        spill.0.append(&[vec![7, 8, 9], vec![]]).unwrap();
+        assert_eq!(
+            std::fs::read(spill.0.path()).unwrap(),
+            vec![3, 0, 0, 0, 7, 8, 9, 0, 0, 0, 0]
+        );
+        assert_eq!(spill.0.len(), Ok(2));
+    }
+
+    #[test]
+    fn a_missing_file_holds_no_records() {
+        let spill = TempSpill::new("missing.spill");
+        assert_eq!(spill.0.len(), Ok(0));
+        assert_eq!(spill.0.is_empty(), Ok(true));
+        assert_eq!(spill.0.read_page(0, 10), Ok(vec![]));
+    }
+
+    #[test]
+    fn pages_are_read_newest_first() {
+        let spill = TempSpill::new("pages.spill");
+        spill.0.append(&records(0..5)).unwrap();
+        spill.0.append(&records(5..10)).unwrap();
+        assert_eq!(spill.0.len(), Ok(10));
+
+        let mut expected = records(0..10);
+        expected.reverse();
+        assert_eq!(spill.0.read_page(0, 4), Ok(expected[0..4].to_vec()));
+        assert_eq!(spill.0.read_page(4, 4), Ok(expected[4..8].to_vec()));
+        assert_eq!(spill.0.read_page(8, 4), Ok(expected[8..10].to_vec()));
+        assert_eq!(spill.0.read_page(10, 4), Ok(vec![]));
+        assert_eq!(spill.0.read_page(0, usize::MAX), Ok(expected));
+    }
+
+    #[test]
+    fn oversized_records_are_not_written() {
+        let spill = TempSpill::new("oversized.spill");
+        assert!(spill.0.append(&[vec![0; MAX_RECORD_LEN + 1]]).is_err());
+        assert_eq!(spill.0.len(), Ok(0));
+    }
+
+    #[test]
+    fn corrupt_files_are_an_error() {
+        let spill = TempSpill::new("corrupt.spill");
+        spill.0.append(&records(1..3)).unwrap();
+
+        // A truncated record
+        let mut bytes = std::fs::read(spill.0.path()).unwrap();
+        bytes.pop();
+        std::fs::write(spill.0.path(), &bytes).unwrap();
+        assert_eq!(spill.0.len(), Err(SpillError::Corrupt(5)));
+        assert_eq!(spill.0.read_page(0, 1), Err(SpillError::Corrupt(5)));
+
+        // A truncated length
+        std::fs::write(spill.0.path(), [1, 0]).unwrap();
+        assert_eq!(spill.0.len(), Err(SpillError::Corrupt(0)));
+
+        // A length which can't be a record
+        std::fs::write(spill.0.path(), u32::MAX.to_le_bytes()).unwrap();
+        assert_eq!(spill.0.read_page(0, 1), Err(SpillError::Corrupt(0)));
+    }
+}
@@ -0,0 +1,127 @@
+//! The balances of the monitored account, broken down by subaddress.
+//!
+//! The monitor covers many subaddresses, and asking mobilecoind for the balance of
+//! each of them on every poll would multiply the number of rpcs. Instead, every poll
+//! asks about the subaddresses known to have received funds, plus the next chunk of
+//! the rest of the range, so that the whole range is swept every few polls.
+
+use crate::TokenId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::Path,
+};
+
+/// The balances per subaddress, for the subaddresses which are known to have received funds
+#[derive(Default)]
+pub struct SubaddressBalances {
+    /// The balance per token id, per subaddress index which received funds
+    balances: BTreeMap<u64, HashMap<TokenId, u64>>,
+    /// The offset into the subaddress range where the sweep continues
+    sweep_cursor: u64,
+    /// Whether the sweep covered the entire range at least once
+    swept: bool,
+}
+
+impl SubaddressBalances {
+    /// How many subaddresses outside of the known ones are swept per poll
+    pub const SWEEP_CHUNK: u64 = 20;
+
+    /// The subaddresses to poll next: those known to have received funds, those in
+    /// `always`, and the next chunk of the range [first, first + num) which is swept.
+    pub fn subaddresses_to_poll(
+        &mut self,
+        first: u64,
+        num: u64,
+        always: impl IntoIterator<Item = u64>,
+    ) -> Vec<u64> {
+        let mut result: BTreeSet<u64> = self.balances.keys().cloned().collect();
+        result.extend(always);
+        if num > 0 {
+            if self.sweep_cursor >= num {
+                self.sweep_cursor = 0;
+            }
+            let end = num.min(self.sweep_cursor + Self::SWEEP_CHUNK);
+            result.extend((self.sweep_cursor..end).map(|offset| first + offset));
+            self.sweep_cursor = end;
+            if end == num {
+                self.swept = true;
+            }
+        } else {
+            self.swept = true;
+        }
+        result.into_iter().collect()
+    }
+
+    /// Record the balance of a subaddress. Subaddresses which never held anything
+    /// aren't remembered, so they are only polled by the sweep.
+    pub fn record(&mut self, subaddress_index: u64, token_id: TokenId, balance: u64) {
+        if balance > 0 || self.balances.contains_key(&subaddress_index) {
+            self.balances
+                .entry(subaddress_index)
+                .or_default()
+                .insert(token_id, balance);
+        }
+    }
+
+    /// Whether the sweep covered the entire range, so that the totals include every subaddress
+    pub fn is_swept(&self) -> bool {
+        self.swept
+    }
+
+    /// The balances per token id, summed over all subaddresses
+    pub fn totals(&self) -> HashMap<TokenId, u64> {
+        let mut result = HashMap::<TokenId, u64>::default();
+        for balances in self.balances.values() {
+            for (token_id, balance) in balances {
+                let total = result.entry(*token_id).or_default();
+                *total = total.saturating_add(*balance);
+            }
+        }
+        result
+    }
+
+    /// The balances per token id of a subaddress, empty if it never received funds
+    pub fn balances_of(&self, subaddress_index: u64) -> HashMap<TokenId, u64> {
+        self.balances
+            .get(&subaddress_index)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The balances per token id of each subaddress which received funds, by index
+    pub fn by_subaddress(&self) -> Vec<(u64, HashMap<TokenId, u64>)> {
+        self.balances
+            .iter()
+            .map(|(index, balances)| (*index, balances.clone()))
+            .collect()
+    }
+}
+
+/// A subaddress issued for receiving payments. They are kept across restarts, so
+/// that an index which was handed out is never issued again, and payments to it
+/// keep being polled.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct IssuedSubaddress {
+    /// The subaddress index
+    pub index: u64,
+    /// The b58 address of the subaddress
+    pub b58_address: String,
+}
+
+impl IssuedSubaddress {
+    /// Read the issued subaddresses from a file. A missing file means none were issued.
+    pub fn load(path: impl AsRef<Path>) -> Result<Vec<Self>, String> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|err| err.to_string()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    /// Write the issued subaddresses to a file, replacing its contents
+    pub fn store(path: impl AsRef<Path>, issued: &[Self]) -> Result<(), String> {
+        let bytes = serde_json::to_vec_pretty(issued).map_err(|err| err.to_string())?;
+        std::fs::write(path, bytes).map_err(|err| err.to_string())
+    }
+}
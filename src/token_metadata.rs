@@ -0,0 +1,234 @@
+//! The symbols and decimals of tokens, which the network doesn't tell us about.
+//!
+//! A few well-known tokens are built in, and more can be described in a json file
+//! passed with --token-metadata. Tokens which the network has a minimum fee for,
+//! but which are described nowhere, are still listed with a placeholder symbol, so
+//! that their balances are visible.
+
+use crate::{TokenId, TokenInfo};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+/// The symbol and decimals of a token
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TokenMetadata {
+    /// The symbol shown for the token
+    pub symbol: String,
+    /// How many decimal places a whole unit of the token has
+    pub decimals: u32,
+}
+
+/// An entry of a token metadata file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenMetadataEntry {
+    token_id: u64,
+    symbol: String,
+    decimals: u32,
+}
+
+/// The metadata of every token we know about
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TokenRegistry {
+    /// The metadata, by token id
+    metadata: BTreeMap<TokenId, TokenMetadata>,
+}
+
+impl Default for TokenRegistry {
+    fn default() -> Self {
+        let builtin = [(0, "MOB", 12), (1, "EUSD", 6), (8192, "FauxUSD", 6)];
+        Self {
+            metadata: builtin
+                .into_iter()
+                .map(|(token_id, symbol, decimals)| {
+                    (
+                        TokenId::from(token_id),
+                        TokenMetadata {
+                            symbol: symbol.to_string(),
+                            decimals,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TokenRegistry {
+    /// The most decimals a token may have, which is the most a Decimal can scale by
+    pub const MAX_DECIMALS: u32 = 28;
+
+    /// The built-in metadata, with that in the file at `path` merged over it
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|err| format!("reading {}: {err}", path.display()))?;
+        let mut registry = Self::default();
+        registry
+            .merge_json(&json)
+            .map_err(|err| format!("{}: {err}", path.display()))?;
+        Ok(registry)
+    }
+
+    /// Merge the metadata in a json file over ours.
+    ///
+    /// The file holds a list of entries like
+    /// `{"token_id": 4096, "symbol": "XYZ", "decimals": 6}`. A token id may only
+    /// appear once, and nothing is merged if any entry is invalid.
+    pub fn merge_json(&mut self, json: &str) -> Result<(), String> {
+        let entries: Vec<TokenMetadataEntry> =
+            serde_json::from_str(json).map_err(|err| format!("invalid token metadata: {err}"))?;
+        let mut parsed = BTreeMap::default();
+        for entry in entries {
+            let symbol = entry.symbol.trim();
+            if symbol.is_empty() {
+                return Err(format!("token id {} has an empty symbol", entry.token_id));
+            }
+            if entry.decimals > Self::MAX_DECIMALS {
+                return Err(format!(
+                    "token id {} has {} decimals, but at most {} are supported",
+                    entry.token_id,
+                    entry.decimals,
+                    Self::MAX_DECIMALS
+                ));
+            }
+            let metadata = TokenMetadata {
+                symbol: symbol.to_string(),
+                decimals: entry.decimals,
+            };
+            if parsed
+                .insert(TokenId::from(entry.token_id), metadata)
+                .is_some()
+            {
+                return Err(format!(
+                    "token id {} appears more than once",
+                    entry.token_id
+                ));
+            }
+        }
+        self.metadata.extend(parsed);
+        Ok(())
+    }
+
+    /// The metadata of a token. Tokens we know nothing about get a placeholder
+    /// symbol like "TokenId(4096)", and no decimals.
+    pub fn metadata(&self, token_id: TokenId) -> TokenMetadata {
+        self.metadata
+            .get(&token_id)
+            .cloned()
+            .unwrap_or_else(|| TokenMetadata {
+                symbol: format!("TokenId({})", *token_id),
+                decimals: 0,
+            })
+    }
+
+    /// The token info of every token with a minimum fee on the network, in order of
+    /// token id
+    pub fn token_infos(&self, minimum_fees: &HashMap<TokenId, u64>) -> Vec<TokenInfo> {
+        let mut result: Vec<TokenInfo> = minimum_fees
+            .iter()
+            .map(|(token_id, fee)| {
+                let TokenMetadata { symbol, decimals } = self.metadata(*token_id);
+                TokenInfo {
+                    token_id: *token_id,
+                    symbol,
+                    fee: *fee,
+                    decimals,
+                }
+            })
+            .collect();
+        result.sort_by_key(|info| info.token_id);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(symbol: &str, decimals: u32) -> TokenMetadata {
+        TokenMetadata {
+            symbol: symbol.to_owned(),
+            decimals,
+        }
+    }
+
+    #[test]
+    fn files_are_merged_over_the_builtin_tokens() {
+        let mut registry = TokenRegistry::default();
+        registry
+            .merge_json(
+                r#"[
+                    {"token_id": 1, "symbol": "eUSD", "decimals": 6},
+                    {"token_id": 4096, "symbol": " XYZ ", "decimals": 3}
+                ]"#,
+            )
+            .unwrap();
+        assert_eq!(registry.metadata(TokenId::from(0)), metadata("MOB", 12));
+        assert_eq!(registry.metadata(TokenId::from(1)), metadata("eUSD", 6));
+        assert_eq!(registry.metadata(TokenId::from(4096)), metadata("XYZ", 3));
+    }
+
+    #[test]
+    fn malformed_files_are_rejected_whole() {
+        let files = [
+            "",
+            "{}",
+            r#"[{"token_id": 4096, "symbol": "XYZ"}]"#,
+            r#"[{"token_id": -1, "symbol": "XYZ", "decimals": 6}]"#,
+            r#"[{"token_id": 4096, "symbol": "XYZ", "decimals": 6, "color": "red"}]"#,
+            r#"[{"token_id": 4096, "symbol": "XYZ", "decimals": 6},
+                {"token_id": 4097, "symbol": "  ", "decimals": 6}]"#,
+            r#"[{"token_id": 4096, "symbol": "XYZ", "decimals": 29}]"#,
+        ];
+        for file in files {
+            let mut registry = TokenRegistry::default();
+            assert!(registry.merge_json(file).is_err(), "{file}");
+            assert_eq!(registry, TokenRegistry::default(), "{file}");
+        }
+    }
+
+    #[test]
+    fn duplicate_token_ids_are_rejected() {
+        let mut registry = TokenRegistry::default();
+        let err = registry
+            .merge_json(
+                r#"[
+                    {"token_id": 4096, "symbol": "XYZ", "decimals": 6},
+                    {"token_id": 4096, "symbol": "ABC", "decimals": 2}
+                ]"#,
+            )
+            .unwrap_err();
+        assert_eq!(err, "token id 4096 appears more than once");
+        assert_eq!(registry, TokenRegistry::default());
+    }
+
+    #[test]
+    fn unknown_tokens_get_a_placeholder() {
+        let registry = TokenRegistry::default();
+        assert_eq!(
+            registry.metadata(TokenId::from(4096)),
+            metadata("TokenId(4096)", 0)
+        );
+        let minimum_fees =
+            HashMap::from([(TokenId::from(4096), 7), (TokenId::from(0), 400_000_000)]);
+        let infos = registry.token_infos(&minimum_fees);
+        assert_eq!(
+            infos
+                .iter()
+                .map(|info| (info.symbol.as_str(), info.decimals, info.fee))
+                .collect::<Vec<_>>(),
+            vec![("MOB", 12, 400_000_000), ("TokenId(4096)", 0, 7)]
+        );
+    }
+
+    #[test]
+    fn missing_files_are_an_error() {
+        let path = std::env::temp_dir().join("buddy-no-such-token-metadata.json");
+        assert!(TokenRegistry::load(&path)
+            .unwrap_err()
+            .starts_with("reading "));
+    }
+}
@@ -1,8 +1,16 @@
 pub use mc_transaction_types::{Amount, TokenId};
 
+use displaydoc::Display;
+use mc_crypto_ring_signature::KeyImage;
 use mc_transaction_extra::{SignedContingentInput, SignedContingentInputAmounts};
 use rust_decimal::{prelude::*, Decimal};
-use std::str::FromStr;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Deref,
+    str::FromStr,
+    sync::Arc,
+};
 use tracing::{event, Level};
 
 /// Info available about a particular token id, which can be used to display it,
@@ -16,14 +24,40 @@ pub struct TokenInfo {
 
 impl TokenInfo {
     /// Try parsing a user-specified, scaled value, and modify decimals to make it
-    /// a u64 in the smallest representable units
+    /// a u64 in the smallest representable units.
+    ///
+    /// Fails if the value is negative, or has more decimal places than the token.
     pub fn try_scaled_to_u64(&self, scaled_value_str: &str) -> Result<u64, String> {
-        let parsed_decimal = Decimal::from_str(scaled_value_str).map_err(|err| err.to_string())?;
-        self.try_decimal_to_u64(parsed_decimal)
+        self.try_decimal_to_u64(Self::parse_scaled(scaled_value_str)?)
+    }
+
+    /// Like try_scaled_to_u64, but rounds a value with more decimal places than the
+    /// token to the nearest unit
+    pub fn try_scaled_to_u64_rounded(&self, scaled_value_str: &str) -> Result<u64, String> {
+        self.try_decimal_to_u64_rounded(Self::parse_scaled(scaled_value_str)?)
     }
 
-    /// Try converting a scaled decimal value to a u64 value in the smallest representable units
+    /// Try converting a scaled decimal value to a u64 value in the smallest representable units.
+    ///
+    /// Fails if the value is negative, or has more decimal places than the token, rather
+    /// than silently converting a different value than the one given.
     pub fn try_decimal_to_u64(&self, scaled_decimal: Decimal) -> Result<u64, String> {
+        if scaled_decimal.normalize().scale() > self.decimals {
+            return Err(format!(
+                "{} has at most {} decimal places",
+                self.symbol, self.decimals
+            ));
+        }
+        self.try_decimal_to_u64_rounded(scaled_decimal)
+    }
+
+    /// Like try_decimal_to_u64, but rounds a value with more decimal places than the
+    /// token to the nearest unit. This is for values computed from others, like the
+    /// volume times the price.
+    pub fn try_decimal_to_u64_rounded(&self, scaled_decimal: Decimal) -> Result<u64, String> {
+        if scaled_decimal.is_sign_negative() && !scaled_decimal.is_zero() {
+            return Err("the value can't be negative".to_string());
+        }
         let scale = Decimal::new(1, self.decimals);
         // Divide scaled_decimal by scaled to cancel out the scaling
         let unscaled_value = scaled_decimal
@@ -35,35 +69,574 @@ impl TokenInfo {
             .ok_or("u64 overflow".to_string())?;
         Ok(u64_value)
     }
+
+    /// Convert a u64 value in the smallest representable units to a scaled decimal.
+    ///
+    /// Every u64 value fits in the 96 bits of a Decimal, so this only fails if the
+    /// token has more decimals than a Decimal can scale by.
+    pub fn try_u64_to_decimal(&self, value: u64) -> Result<Decimal, String> {
+        Decimal::try_from_i128_with_scale(i128::from(value), self.decimals)
+            .map_err(|err| format!("{} can't be represented: {err}", self.symbol))
+    }
+
+    // Parse a user-specified, scaled value as a decimal
+    fn parse_scaled(scaled_value_str: &str) -> Result<Decimal, String> {
+        let normalized = normalize_amount_input(scaled_value_str).map_err(|err| err.to_string())?;
+        Decimal::from_str(&normalized).map_err(|_| AmountError::NotANumber.to_string())
+    }
+
+    /// How many decimal places the compact form of a value keeps
+    pub const COMPACT_DECIMALS: usize = 4;
+
+    /// Format a value in the smallest representable units as a scaled decimal, with
+    /// its digits grouped by thousands and the symbol, like "1,234,567.891011 MOB".
+    ///
+    /// Every u64 value is formatted exactly. This is for showing a value, a value to
+    /// be typed back in should use ScaledAmount::display.
+    pub fn format_u64(&self, value: u64) -> String {
+        self.format_scaled(value, self.decimals as usize)
+    }
+
+    /// Like format_u64, but with the fraction cut down to COMPACT_DECIMALS places,
+    /// for table cells. A nonzero value too small to show is shown as "<0.0001".
+    pub fn format_u64_compact(&self, value: u64) -> String {
+        let result = self.format_scaled(value, Self::COMPACT_DECIMALS);
+        if value > 0 && result.starts_with("0 ") {
+            return format!(
+                "<0.{}1 {}",
+                "0".repeat(Self::COMPACT_DECIMALS - 1),
+                self.symbol
+            );
+        }
+        result
+    }
+
+    /// Format an amount with the symbol of its token, looking up the token in token_infos
+    pub fn format_amount(token_infos: &[TokenInfo], amount: Amount) -> String {
+        match token_infos
+            .iter()
+            .find(|info| info.token_id == amount.token_id)
+        {
+            Some(info) => info.format_u64(amount.value),
+            None => format!("{} (token id {})", amount.value, amount.token_id),
+        }
+    }
+
+    // Format a value with the fraction truncated to `max_decimals` places, and its
+    // trailing zeros dropped. The digits are split with string operations, so that
+    // neither the value nor the decimals can overflow.
+    fn format_scaled(&self, value: u64, max_decimals: usize) -> String {
+        let decimals = self.decimals as usize;
+        let digits = format!("{value:0>width$}", width = decimals + 1);
+        let (integer, fraction) = digits.split_at(digits.len() - decimals);
+        let fraction = fraction[..fraction.len().min(max_decimals)].trim_end_matches('0');
+
+        let mut result = String::with_capacity(digits.len() + digits.len() / 3 + 2);
+        for (idx, digit) in integer.chars().enumerate() {
+            if idx > 0 && (integer.len() - idx) % 3 == 0 {
+                result.push(',');
+            }
+            result.push(digit);
+        }
+        if !fraction.is_empty() {
+            result.push('.');
+            result.push_str(fraction);
+        }
+        format!("{result} {}", self.symbol)
+    }
+}
+
+/// An unspent txo of the monitored account
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UtxoInfo {
+    /// The value of the txo
+    pub value: u64,
+    /// The token id of the txo
+    pub token_id: TokenId,
+    /// The subaddress which owns the txo
+    pub subaddress_index: u64,
+    /// The key image of the txo
+    pub key_image: KeyImage,
+}
+
+impl UtxoInfo {
+    /// How many unspent txos of a token make input selection likely to fail, so
+    /// that the user should defragment them
+    pub const FRAGMENTED_COUNT: usize = 50;
+    /// The most txos a transaction may spend
+    pub const MAX_INPUTS: usize = 16;
+}
+
+/// Why a scaled value entered by the user isn't a valid amount of a token
+#[derive(Clone, Debug, Display, Eq, PartialEq)]
+pub enum AmountError {
+    /// Not a number: use '.' or ',' for decimals and no thousands separators, like 1.5
+    NotANumber,
+    /// Ambiguous comma in {0}: write it without the comma, or with '.' for decimals
+    AmbiguousSeparator(String),
+    /// The amount can't be negative
+    Negative,
+    /// {symbol} has at most {decimals} decimal places
+    TooManyDecimals { symbol: String, decimals: u32 },
+    /// The amount is too large
+    TooLarge,
+    /// The amount and the {fee} fee exceed the balance of {balance}
+    ExceedsBalance { fee: String, balance: String },
+    /// The amount is less than the {0} fee, so it isn't worth sending
+    Dust(String),
+}
+
+/// Normalize a scaled value typed by the user, so that it parses as a Decimal.
+///
+/// A single comma is taken as the decimal separator, as in "1,5", unless three
+/// digits follow it, since "1,000" may well mean a thousand.
+pub fn normalize_amount_input(input: &str) -> Result<String, AmountError> {
+    let input = input.trim();
+    match (input.matches(',').count(), input.contains('.')) {
+        (0, _) => Ok(input.to_owned()),
+        (1, false) => match input.split_once(',') {
+            Some((_, fraction)) if fraction.len() == 3 => {
+                Err(AmountError::AmbiguousSeparator(input.to_owned()))
+            }
+            Some((integer, fraction)) => Ok(format!("{integer}.{fraction}")),
+            None => Err(AmountError::NotANumber),
+        },
+        _ => Err(AmountError::NotANumber),
+    }
+}
+
+/// A u64 value of a particular token, in the smallest representable units.
+///
+/// Arithmetic is checked, and fails rather than mixing values of different tokens.
+/// Conversion to and from scaled decimal values goes through the TokenInfo of the token.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScaledAmount {
+    /// The value in the smallest representable units
+    pub raw: u64,
+    /// The token id of the value
+    pub token_id: TokenId,
+}
+
+impl ScaledAmount {
+    /// Make a new amount from a u64 value in the smallest representable units
+    pub fn new(raw: u64, token_id: TokenId) -> Self {
+        Self { raw, token_id }
+    }
+
+    /// The network fee for a token
+    pub fn fee(info: &TokenInfo) -> Self {
+        Self::new(info.fee, info.token_id)
+    }
+
+    /// Parse a user-specified, scaled value of a token
+    pub fn parse(scaled_value_str: &str, info: &TokenInfo) -> Result<Self, String> {
+        Self::validate(scaled_value_str, info, None).map_err(|err| err.to_string())
+    }
+
+    /// Parse a user-specified, scaled value of a token, explaining exactly what is
+    /// wrong with it if it isn't a valid amount.
+    ///
+    /// If the amount is spent from a `balance`, it must also leave room for the
+    /// network fee, and not be smaller than the fee.
+    pub fn validate(
+        scaled_value_str: &str,
+        info: &TokenInfo,
+        balance: Option<u64>,
+    ) -> Result<Self, AmountError> {
+        let normalized = normalize_amount_input(scaled_value_str)?;
+        let decimal = Decimal::from_str(&normalized).map_err(|_| AmountError::NotANumber)?;
+        if decimal.is_sign_negative() && !decimal.is_zero() {
+            return Err(AmountError::Negative);
+        }
+        if decimal.normalize().scale() > info.decimals {
+            return Err(AmountError::TooManyDecimals {
+                symbol: info.symbol.clone(),
+                decimals: info.decimals,
+            });
+        }
+        let amount = Self::from_decimal(decimal, info).map_err(|_| AmountError::TooLarge)?;
+
+        if let Some(balance) = balance {
+            let fee = Self::fee(info);
+            if amount
+                .with_fee(info)
+                .map_or(true, |total| total.raw > balance)
+            {
+                return Err(AmountError::ExceedsBalance {
+                    fee: info.format_u64(fee.raw),
+                    balance: info.format_u64(balance),
+                });
+            }
+            if amount.raw > 0 && amount.raw < fee.raw {
+                return Err(AmountError::Dust(info.format_u64(fee.raw)));
+            }
+        }
+        Ok(amount)
+    }
+
+    /// Convert a scaled decimal value of a token. Fails if it is negative, or has more
+    /// decimal places than the token.
+    pub fn from_decimal(scaled_decimal: Decimal, info: &TokenInfo) -> Result<Self, String> {
+        Ok(Self::new(
+            info.try_decimal_to_u64(scaled_decimal)?,
+            info.token_id,
+        ))
+    }
+
+    /// Convert a scaled decimal value of a token, rounding it to the nearest unit
+    pub fn from_decimal_rounded(scaled_decimal: Decimal, info: &TokenInfo) -> Result<Self, String> {
+        Ok(Self::new(
+            info.try_decimal_to_u64_rounded(scaled_decimal)?,
+            info.token_id,
+        ))
+    }
+
+    /// Convert to a scaled decimal value, using the decimals of the token
+    pub fn to_decimal(&self, info: &TokenInfo) -> Result<Decimal, String> {
+        self.check_token_id(info.token_id)?;
+        info.try_u64_to_decimal(self.raw)
+    }
+
+    /// Display as a scaled decimal value, or "(overflow)" if that isn't possible
+    pub fn display(&self, info: &TokenInfo) -> String {
+        self.to_decimal(info)
+            .map(|value| value.to_string())
+            .unwrap_or_else(|_| "(overflow)".to_owned())
+    }
+
+    /// Add another amount of the same token
+    pub fn try_add(self, other: Self) -> Result<Self, String> {
+        self.check_token_id(other.token_id)?;
+        let raw = self
+            .raw
+            .checked_add(other.raw)
+            .ok_or("u64 overflow".to_string())?;
+        Ok(Self::new(raw, self.token_id))
+    }
+
+    /// Subtract another amount of the same token
+    pub fn try_sub(self, other: Self) -> Result<Self, String> {
+        self.check_token_id(other.token_id)?;
+        let raw = self
+            .raw
+            .checked_sub(other.raw)
+            .ok_or("u64 underflow".to_string())?;
+        Ok(Self::new(raw, self.token_id))
+    }
+
+    /// Add the network fee of this token
+    pub fn with_fee(self, info: &TokenInfo) -> Result<Self, String> {
+        self.try_add(Self::fee(info))
+            .map_err(|_| "u64 overflow with fee".to_string())
+    }
+
+    /// The largest amount which can be sent from a balance, paying the network fee
+    /// of the same token. Fails if the balance doesn't exceed the fee.
+    pub fn max_sendable(balance: u64, info: &TokenInfo) -> Result<Self, String> {
+        if balance <= info.fee {
+            return Err(format!("balance doesn't exceed the {} fee", info.symbol));
+        }
+        Ok(Self::new(balance - info.fee, info.token_id))
+    }
+
+    /// Check that the given balances cover this amount
+    pub fn require_covered_by(&self, balances: &HashMap<TokenId, u64>) -> Result<(), String> {
+        if self.raw > balances.get(&self.token_id).cloned().unwrap_or(0) {
+            return Err("insufficient funds".to_string());
+        }
+        Ok(())
+    }
+
+    fn check_token_id(&self, token_id: TokenId) -> Result<(), String> {
+        if self.token_id != token_id {
+            return Err(format!(
+                "token id mismatch: {} != {}",
+                *self.token_id, *token_id
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl From<ScaledAmount> for Amount {
+    fn from(src: ScaledAmount) -> Amount {
+        Amount::new(src.raw, src.token_id)
+    }
+}
+
+/// The balances of the monitored account, together with how far the monitor
+/// had scanned the ledger when they were read.
+#[derive(Clone, Debug, Default)]
+pub struct BalanceSnapshot {
+    /// The balance per token id
+    pub balances: HashMap<TokenId, u64>,
+    /// Whether the balances have been fetched from mobilecoind at all yet
+    pub fetched: bool,
+    /// Synced blocks on the monitor
+    pub synced_blocks: u64,
+    /// Total blocks in the ledger
+    pub total_blocks: u64,
+}
+
+impl BalanceSnapshot {
+    /// Balances are trusted once the monitor is within this many blocks of the ledger tip
+    pub const COMPLETE_WITHIN_BLOCKS: u64 = 2;
+
+    /// Whether a monitor that synced `synced_blocks` out of `total_blocks` has
+    /// scanned enough of the ledger for its balances to be trusted
+    pub fn is_sync_complete(synced_blocks: u64, total_blocks: u64) -> bool {
+        synced_blocks.saturating_add(Self::COMPLETE_WITHIN_BLOCKS) >= total_blocks
+    }
+
+    /// Whether these balances can be trusted, or may still be missing funds
+    pub fn is_complete(&self) -> bool {
+        self.fetched && Self::is_sync_complete(self.synced_blocks, self.total_blocks)
+    }
+
+    /// Explain that the balances may be incomplete, if they are.
+    ///
+    /// Validation should check this before trusting the balances in an insufficient funds check.
+    pub fn require_complete(&self) -> Result<(), String> {
+        if self.is_complete() {
+            return Ok(());
+        }
+        let percent = if self.total_blocks == 0 {
+            0
+        } else {
+            (self.synced_blocks.min(self.total_blocks) * 100) / self.total_blocks
+        };
+        Err(format!(
+            "still scanning the ledger ({percent}%) — balances may be incomplete"
+        ))
+    }
+}
+
+/// The balance of a token, along with what our own submitted transactions will add
+/// to and take from it, which the synced balance doesn't reflect yet
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DetailedBalance {
+    /// The synced balance, which payments may spend
+    pub spendable: u64,
+    /// The change coming back to us from our own transactions
+    pub pending_incoming: u64,
+    /// The value and fees paid by our own transactions
+    pub pending_outgoing: u64,
+}
+
+/// Identifies a quote in a merged quote book.
+///
+/// The same sci (key image) may be served by several deqs, so we also record
+/// which of the configured deqs served it to us.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct QuoteId {
+    /// The index of the deqs (in the order they were configured) which served this quote
+    pub source: usize,
+    /// The key image of the sci's input
+    pub key_image: KeyImage,
 }
 
 /// A validated quote that we got from the deqs
 #[derive(Clone, Debug)]
 pub struct ValidatedQuote {
+    /// The id of the quote, including which deqs it came from
+    pub id: QuoteId,
     /// The sci, needed when we match against the quote
     pub sci: SignedContingentInput,
     /// The sci amounts, produced by sci.validate(). Needed to help with partial fill arithmetic.
     pub amounts: SignedContingentInputAmounts,
     /// u64 timestamp
     pub timestamp: u64,
+    /// Whether the sci spends one of our own txos, so that this is our own offer
+    pub is_mine: bool,
+    /// The last tombstone block the sci accepts, if its input rules limit it
+    pub max_tombstone_block: Option<u64>,
+}
+
+/// The last tombstone block a transaction filling the sci may have, if the input
+/// rules of the sci limit it (0 means no limit)
+fn max_tombstone_block(sci: &SignedContingentInput) -> Option<u64> {
+    sci.tx_in
+        .input_rules
+        .as_ref()
+        .map(|rules| rules.max_tombstone_block)
+        .filter(|block| *block != 0)
 }
 
-impl TryFrom<&deqs_api::deqs::Quote> for ValidatedQuote {
-    type Error = String;
-    fn try_from(src: &deqs_api::deqs::Quote) -> Result<Self, Self::Error> {
+impl ValidatedQuote {
+    /// Validate a quote that was served by the deqs at index `source`
+    pub fn try_from_quote(src: &deqs_api::deqs::Quote, source: usize) -> Result<Self, String> {
         let sci = SignedContingentInput::try_from(src.get_sci()).map_err(|err| err.to_string())?;
         let amounts = sci.validate().map_err(|err| err.to_string())?;
         let timestamp = src.timestamp;
+        let max_tombstone_block = max_tombstone_block(&sci);
+        let id = QuoteId {
+            source,
+            key_image: sci.key_image(),
+        };
 
         Ok(Self {
+            id,
             sci,
             amounts,
             timestamp,
+            is_mine: false,
+            max_tombstone_block,
         })
     }
-}
 
-impl ValidatedQuote {
+    /// Whether the quote can no longer be filled in a ledger of `num_blocks` blocks,
+    /// since any transaction landing in it would be past the tombstone block the
+    /// sci accepts
+    pub fn is_expired(&self, num_blocks: u64) -> bool {
+        self.max_tombstone_block
+            .map_or(false, |max_tombstone_block| {
+                max_tombstone_block <= num_blocks
+            })
+    }
+
+    /// Drop the quotes of a book which expired in a ledger of `num_blocks` blocks
+    pub fn drop_expired(book: &mut Vec<ValidatedQuote>, num_blocks: u64) {
+        book.retain(|quote| !quote.is_expired(num_blocks));
+    }
+
+    /// Merge the quote books obtained from several deqs for the same pair.
+    ///
+    /// Quotes are deduplicated by key image, the copy from the deqs configured
+    /// first is the one that is kept.
+    pub fn merge_books(books: Vec<Vec<ValidatedQuote>>) -> Vec<ValidatedQuote> {
+        let mut seen = HashSet::<KeyImage>::default();
+        let mut result = Vec::default();
+        for book in books {
+            for quote in book {
+                if seen.insert(quote.id.key_image) {
+                    result.push(quote);
+                }
+            }
+        }
+        result
+    }
+
+    /// Merge the books polled from each deqs, given the errors of the deqs which
+    /// didn't answer. A deqs which failed doesn't blank the book, its error is
+    /// returned alongside the merged book, to be reported. Fails with every error if
+    /// no deqs answered.
+    pub fn merge_polled_books(
+        books: Vec<Vec<ValidatedQuote>>,
+        errors: Vec<String>,
+    ) -> Result<(Vec<ValidatedQuote>, Vec<String>), String> {
+        if books.is_empty() {
+            return Err(errors.join(", "));
+        }
+        Ok((Self::merge_books(books), errors))
+    }
+
+    /// Sort a book from the best price to the worst, that is by how much of the
+    /// other token the quotes ask for each unit of the token they offer, with older
+    /// quotes first among equal prices. Whichever token is the base, this sorts
+    /// bids by descending price and asks by ascending price.
+    ///
+    /// The prices are compared exactly, from the u64 amounts of the scis. Quotes
+    /// which aren't simple swaps go last.
+    pub fn sort_book(book: &mut [ValidatedQuote]) {
+        book.sort_by(|a, b| a.cmp_price(b).then(a.timestamp.cmp(&b.timestamp)));
+    }
+
+    /// Whether a page of the book returned by a deqs shows that the quote is no
+    /// longer listed. The page must leave the quote out, and either hold the
+    /// whole book (`is_complete`), or hold a quote priced worse than this one,
+    /// which this one would have been listed before. A quote left out of a full
+    /// page of better or equal prices may only have been pushed past the limit.
+    pub fn is_unlisted_in(&self, page: &[ValidatedQuote], is_complete: bool) -> bool {
+        if page
+            .iter()
+            .any(|quote| quote.id.key_image == self.id.key_image)
+        {
+            return false;
+        }
+        is_complete
+            || page
+                .iter()
+                .any(|quote| self.cmp_price(quote) == Ordering::Less)
+    }
+
+    // Compare prices exactly, from the u64 amounts of the scis, lower first.
+    // Quotes which aren't simple swaps compare greater than the rest.
+    fn cmp_price(&self, other: &Self) -> Ordering {
+        match (self.swap_values(), other.swap_values()) {
+            (Some((a_offered, a_asked)), Some((b_offered, b_asked))) => {
+                let a_price = a_asked as u128 * b_offered as u128;
+                let b_price = b_asked as u128 * a_offered as u128;
+                a_price.cmp(&b_price)
+            }
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+
+    /// The value of the token the sci offers, and the value of the token it asks
+    /// for in return, if it is a simple swap
+    fn swap_values(&self) -> Option<(u64, u64)> {
+        let terms = self.swap_terms().ok()?;
+        Some((terms.offered, terms.asked.value))
+    }
+
+    /// What the sci offers and asks for in return, if it is a swap this
+    /// implementation can fill.
+    ///
+    /// Besides the plain shapes, where the offerer gets only what they ask for, a
+    /// change output back to the offerer is accepted: a required output of the
+    /// offered token, which doesn't count as offered. For a quote which can't be
+    /// filled partially, pseudo_output = required_output + change. For a partial
+    /// fill quote, pseudo_output = change + partial_fill_change.
+    fn swap_terms(&self) -> Result<SwapTerms, &'static str> {
+        let amounts = &self.amounts;
+        let pseudo_output = &amounts.pseudo_output;
+        let (change, required): (Vec<&Amount>, Vec<&Amount>) = amounts
+            .required_outputs
+            .iter()
+            .partition(|amount| amount.token_id == pseudo_output.token_id);
+        if change.len() > 1 {
+            return Err("expected at most one change output");
+        }
+        let change_value = change.first().map_or(0, |change| change.value);
+
+        let terms = if let Some(partial_fill_change) = amounts.partial_fill_change.as_ref() {
+            if partial_fill_change.token_id != pseudo_output.token_id
+                || partial_fill_change.value.checked_add(change_value) != Some(pseudo_output.value)
+            {
+                return Err("partial fill change and change output don't add up to pseudo output");
+            }
+            if !required.is_empty() {
+                return Err("mixing partial fill and required outputs");
+            }
+            if amounts.partial_fill_outputs.len() != 1 {
+                return Err("expected one partial fill output");
+            }
+            SwapTerms {
+                offered: partial_fill_change.value,
+                asked: amounts.partial_fill_outputs[0],
+                is_partial_fill: true,
+            }
+        } else {
+            if !amounts.partial_fill_outputs.is_empty() {
+                return Err("partial fill outputs without partial fill change");
+            }
+            if required.len() != 1 {
+                return Err("expected one required output");
+            }
+            SwapTerms {
+                offered: pseudo_output.value.saturating_sub(change_value),
+                asked: *required[0],
+                is_partial_fill: false,
+            }
+        };
+        if terms.offered == 0 || terms.asked.value == 0 {
+            return Err("nothing offered or nothing asked");
+        }
+        Ok(terms)
+    }
+
     /// Get information to render this quote as part of a quote book.
     /// Depending on which is the base and which is the counter, this ends up on the bid or ask side.
     /// TokenInfo are used to scale the token amounts appropriately for display.
@@ -83,137 +656,121 @@ impl ValidatedQuote {
             .find(|info| info.token_id == counter_token_id)
             .ok_or("missing counter token info".to_owned())?;
 
-        if self.amounts.pseudo_output.token_id == base_token_id {
-            // Quote is offering the base token, so this should be an ask
-            let quote_side = QuoteSide::Ask;
+        // A quote offering the base token is an ask, and one offering the counter
+        // token is a bid
+        let offered_token_id = self.amounts.pseudo_output.token_id;
+        let (quote_side, side_name, asked_token_id) = if offered_token_id == base_token_id {
+            (QuoteSide::Ask, "Ask", counter_token_id)
+        } else if offered_token_id == counter_token_id {
+            (QuoteSide::Bid, "Bid", base_token_id)
+        } else {
+            return Err("SCI does not belong to this book (pseudo-output)".to_owned());
+        };
 
-            if let Some(partial_fill_change) = self.amounts.partial_fill_change.as_ref() {
-                if &self.amounts.pseudo_output != partial_fill_change {
-                    return Err("Ask SCI is too complicated for this implementation (partial fill change not equal to pseudo output)".to_owned());
-                }
-                if !self.amounts.required_outputs.is_empty() {
-                    return Err("Ask SCI is too complicated for this implementation (mixing partial fill and required outputs)".to_owned());
-                }
-                if self.amounts.partial_fill_outputs.len() != 1 {
-                    return Err("Ask SCI is too complicated for this implementation (expected one partial fill output)".to_owned());
-                }
-                if self.amounts.partial_fill_outputs[0].token_id != counter_token_id {
-                    return Err(
-                        "Ask SCI does not belong to this book (partial fill output)".to_owned()
-                    );
-                }
-                // TODO: should handle overflow at i64 conversion
-                let volume = Decimal::new(
-                    self.amounts.pseudo_output.value as i64,
-                    base_token_info.decimals,
-                );
-                let counter_volume = Decimal::new(
-                    self.amounts.partial_fill_outputs[0].value as i64,
-                    counter_token_info.decimals,
-                );
-                let price = counter_volume / volume;
-                Ok(QuoteInfo {
-                    quote_side,
-                    price,
-                    volume,
-                    is_partial_fill: true,
-                    timestamp: self.timestamp,
-                })
-            } else {
-                if !self.amounts.partial_fill_outputs.is_empty() {
-                    return Err("Invalid Ask SCI".to_owned());
-                }
-                if self.amounts.required_outputs.len() != 1 {
-                    return Err("Ask SCI is too complicated for this implementation (expected one required output)".to_owned());
-                }
-                if self.amounts.required_outputs[0].token_id != counter_token_id {
-                    return Err("Ask SCI does not belong to this book (required_output)".to_owned());
-                }
-                // TODO: should handle overflow at i64 conversion
-                let volume = Decimal::new(
-                    self.amounts.pseudo_output.value as i64,
-                    base_token_info.decimals,
-                );
-                let counter_volume = Decimal::new(
-                    self.amounts.required_outputs[0].value as i64,
-                    counter_token_info.decimals,
-                );
-                let price = counter_volume / volume;
-                Ok(QuoteInfo {
-                    quote_side,
-                    price,
-                    volume,
-                    is_partial_fill: false,
-                    timestamp: self.timestamp,
-                })
-            }
-        } else if self.amounts.pseudo_output.token_id == counter_token_id {
-            // Quote is offering the counter token, so this should be an bid
-            let quote_side = QuoteSide::Bid;
+        let terms = self.swap_terms().map_err(|err| {
+            format!("{side_name} SCI is too complicated for this implementation ({err})")
+        })?;
+        if terms.asked.token_id != asked_token_id {
+            return Err(format!(
+                "{side_name} SCI does not belong to this book (asked token) {} != {}",
+                terms.asked.token_id, asked_token_id
+            ));
+        }
+        let (base_value, counter_value) = match quote_side {
+            QuoteSide::Ask => (terms.offered, terms.asked.value),
+            QuoteSide::Bid => (terms.asked.value, terms.offered),
+        };
 
-            if let Some(partial_fill_change) = self.amounts.partial_fill_change.as_ref() {
-                if &self.amounts.pseudo_output != partial_fill_change {
-                    return Err("Bid SCI is too complicated for this implementation (partial fill change not equal to pseudo output)".to_owned());
-                }
-                if !self.amounts.required_outputs.is_empty() {
-                    return Err("Bid SCI is too complicated for this implementation (mixing partial fill and required outputs)".to_owned());
-                }
-                if self.amounts.partial_fill_outputs.len() != 1 {
-                    return Err("Bid SCI is too complicated for this implementation (expected one partial fill output)".to_owned());
-                }
-                if self.amounts.partial_fill_outputs[0].token_id != base_token_id {
-                    return Err(format!(
-                        "Bid SCI does not belong to this book (partial fill output) {} != {}",
-                        self.amounts.partial_fill_outputs[0].token_id, base_token_id
-                    ));
-                }
-                // TODO: should handle overflow at i64 conversion
-                let counter_volume = Decimal::new(
-                    self.amounts.pseudo_output.value as i64,
-                    counter_token_info.decimals,
-                );
-                let volume = Decimal::new(
-                    self.amounts.partial_fill_outputs[0].value as i64,
-                    base_token_info.decimals,
-                );
-                let price = counter_volume / volume;
-                Ok(QuoteInfo {
-                    quote_side,
-                    price,
-                    volume,
-                    is_partial_fill: true,
-                    timestamp: self.timestamp,
-                })
-            } else {
-                if !self.amounts.partial_fill_outputs.is_empty() {
-                    return Err("Invalid Bid SCI".to_owned());
-                }
-                if self.amounts.required_outputs.len() != 1 {
-                    return Err("Bid SCI is too complicated for this implementation (expected one required output)".to_owned());
-                }
-                if self.amounts.required_outputs[0].token_id != base_token_id {
-                    return Err("Bid SCI does not belong to this book (required_output)".to_owned());
-                }
-                // TODO: should handle overflow at i64 conversion
-                let counter_volume = Decimal::new(
-                    self.amounts.pseudo_output.value as i64,
-                    counter_token_info.decimals,
-                );
-                let volume = Decimal::new(
-                    self.amounts.required_outputs[0].value as i64,
-                    base_token_info.decimals,
-                );
-                let price = counter_volume / volume;
-                Ok(QuoteInfo {
-                    quote_side,
-                    price,
-                    volume,
-                    is_partial_fill: false,
-                    timestamp: self.timestamp,
-                })
-            }
-        } else {
-            Err("SCI does not belong to this book (pseudo-output)".to_owned())
+        let volume = base_token_info.try_u64_to_decimal(base_value)?;
+        let counter_volume = counter_token_info.try_u64_to_decimal(counter_value)?;
+        let price = counter_volume
+            .checked_div(volume)
+            .ok_or_else(|| format!("{side_name} SCI has no base volume to price"))?;
+        Ok(QuoteInfo {
+            quote_side,
+            price,
+            volume,
+            is_partial_fill: terms.is_partial_fill,
+            timestamp: self.timestamp,
+            is_mine: self.is_mine,
+        })
+    }
+}
+
+/// What a quote offers, and asks for in return
+#[derive(Clone, Copy, Debug)]
+struct SwapTerms {
+    /// The value of the offered token which the quote gives, not counting change
+    /// back to the offerer. For a partial fill quote, the most it gives.
+    offered: u64,
+    /// The amount asked for in return, if the quote is filled completely
+    asked: Amount,
+    /// Whether the quote can be filled partially
+    is_partial_fill: bool,
+}
+
+/// A quote book as the worker stores it, which is shared with the ui without
+/// copying the quotes
+#[derive(Clone, Debug, Default)]
+pub struct QuoteBook {
+    /// The quotes, best price first
+    pub quotes: Arc<Vec<ValidatedQuote>>,
+    /// Changes whenever the quotes change, so that anything computed from them can
+    /// be kept until then. Generations are never reused, not even across pairs, and
+    /// 0 is the empty book of a pair which was never polled.
+    pub generation: u64,
+}
+
+impl QuoteBook {
+    /// Whether freshly polled quotes are the ones already in this book. A quote's
+    /// sci is identified by its key image, so comparing the ids is enough.
+    pub fn has_same_quotes(&self, quotes: &[ValidatedQuote]) -> bool {
+        self.quotes.len() == quotes.len()
+            && self.quotes.iter().zip(quotes).all(|(old, new)| {
+                old.id == new.id && old.timestamp == new.timestamp && old.is_mine == new.is_mine
+            })
+    }
+}
+
+impl Deref for QuoteBook {
+    type Target = [ValidatedQuote];
+
+    fn deref(&self) -> &[ValidatedQuote] {
+        &self.quotes
+    }
+}
+
+/// Which quotes of the books of a pair to show, out of the ones the worker has.
+/// Quote selection always uses whole books.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QuoteFilter {
+    /// Only show the quotes whose price is within this fraction of the mid-price
+    /// (0.1 is 10%), if set
+    pub price_band: Option<Decimal>,
+}
+
+impl QuoteFilter {
+    /// Parse a user-specified price band, in percent. An empty string means no band.
+    pub fn parse_price_band(src: &str) -> Result<Self, String> {
+        let src = src.trim();
+        if src.is_empty() {
+            return Ok(Self::default());
+        }
+        let percent = Decimal::from_str(src)
+            .ok()
+            .filter(|percent| percent.is_sign_positive() && !percent.is_zero())
+            .ok_or("The price band must be a percentage larger than 0")?;
+        Ok(Self {
+            price_band: Some(percent / Decimal::ONE_HUNDRED),
+        })
+    }
+
+    /// Whether to show a quote with `price`, in a book whose mid-price is `mid`.
+    /// Without a mid-price, every quote is shown.
+    pub fn accepts(&self, price: Decimal, mid: Option<Decimal>) -> bool {
+        match (self.price_band, mid) {
+            (Some(band), Some(mid)) => (price - mid).abs() <= mid * band,
+            _ => true,
         }
     }
 }
@@ -225,6 +782,7 @@ pub enum QuoteSide {
 }
 
 /// Information about a quote that we render in the ui
+#[derive(Clone, Debug)]
 pub struct QuoteInfo {
     /// Which side of the book this quote is on.
     /// This is relative to a particular pair being displayed
@@ -241,118 +799,1546 @@ pub struct QuoteInfo {
 
     /// Timestamp of the quote
     pub timestamp: u64,
+
+    /// Whether this is our own quote
+    pub is_mine: bool,
 }
 
-/// The output of a quote selection algorithm that tries to find the best quote to obtain one amount.
-#[derive(Clone, Debug)]
-pub struct QuoteSelection {
-    // The sci we selected
+/// The quotes of one side of a book at one price level
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DepthLevel {
+    /// The price of the level, a multiple of the tick size
+    pub price: Decimal,
+    /// The volume of base token of this level and every better one
+    pub cumulative_volume: Decimal,
+    /// The indices of the quotes at this level, in the quotes they were aggregated from
+    pub quotes: Vec<usize>,
+}
+
+impl DepthLevel {
+    /// Parse a user-specified tick size. An empty string means levels at exact prices.
+    pub fn parse_tick(src: &str) -> Result<Decimal, String> {
+        let src = src.trim();
+        if src.is_empty() {
+            return Ok(Decimal::ZERO);
+        }
+        Decimal::from_str(src)
+            .ok()
+            .filter(|tick| tick.is_sign_positive() && !tick.is_zero())
+            .ok_or_else(|| "The tick size must be a number larger than 0".to_string())
+    }
+}
+
+/// Bucket the quotes of one side of a book by price, and accumulate their volume
+/// from the best price outwards.
+///
+/// Prices are rounded to a multiple of `tick`, away from the market: bids down
+/// and asks up, so that a level never looks better than its quotes. A zero tick
+/// buckets exact prices only. The volumes of the quotes are already scaled by the
+/// decimals of the base token, so they add up as they are.
+pub fn aggregate_book(quotes: &[QuoteInfo], tick: Decimal) -> Result<Vec<DepthLevel>, String> {
+    // The quotes are all on the same side
+    let is_bid = quotes
+        .first()
+        .map_or(true, |quote| matches!(quote.quote_side, QuoteSide::Bid));
+    let mut buckets = BTreeMap::<Decimal, Vec<usize>>::new();
+    for (idx, quote) in quotes.iter().enumerate() {
+        let price = if tick.is_zero() {
+            quote.price
+        } else {
+            let overflow = || format!("price level overflow at {}", quote.price);
+            let ticks = quote.price.checked_div(tick).ok_or_else(overflow)?;
+            let ticks = if is_bid { ticks.floor() } else { ticks.ceil() };
+            ticks.checked_mul(tick).ok_or_else(overflow)?
+        };
+        buckets.entry(price.normalize()).or_default().push(idx);
+    }
+
+    // Bids are best at the highest price, asks at the lowest
+    let buckets: Vec<_> = if is_bid {
+        buckets.into_iter().rev().collect()
+    } else {
+        buckets.into_iter().collect()
+    };
+    let mut cumulative_volume = Decimal::ZERO;
+    buckets
+        .into_iter()
+        .map(|(price, indices)| {
+            for idx in indices.iter() {
+                cumulative_volume = cumulative_volume
+                    .checked_add(quotes[*idx].volume)
+                    .ok_or_else(|| format!("cumulative volume overflow at price {price}"))?;
+            }
+            Ok(DepthLevel {
+                price,
+                cumulative_volume,
+                quotes: indices,
+            })
+        })
+        .collect()
+}
+
+/// An sci to add to a transaction, and how much of it to fill
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SciFill {
+    /// The sci
     pub sci: SignedContingentInput,
-    // The partial fill value to use when adding this to a Tx
+    /// The partial fill value to use when adding this to a Tx, or 0 if the sci
+    /// can't be filled partially
     pub partial_fill_value: u64,
-    // The u64 value which must be supplied to fulfill this quote
+    /// The index of the deqs which served the quote. That deqs is asked whether it
+    /// still lists the quote right before the fill is submitted.
+    pub source: usize,
+}
+
+impl SciFill {
+    /// The last tombstone block a transaction filling the sci may have, if any
+    pub fn max_tombstone_block(&self) -> Option<u64> {
+        max_tombstone_block(&self.sci)
+    }
+
+    /// The id of the quote this fills, in the merged quote book
+    pub fn quote_id(&self) -> QuoteId {
+        QuoteId {
+            source: self.source,
+            key_image: self.sci.key_image(),
+        }
+    }
+
+    /// Route fills to the deqs which served their quotes: the key images of the
+    /// scis to check with each deqs, by index of the deqs
+    pub fn route(fills: &[SciFill]) -> BTreeMap<usize, Vec<KeyImage>> {
+        let mut routes = BTreeMap::<usize, Vec<KeyImage>>::default();
+        for fill in fills {
+            routes
+                .entry(fill.source)
+                .or_default()
+                .push(fill.sci.key_image());
+        }
+        routes
+    }
+}
+
+/// A quote which could be filled to obtain a token, and the fill values it accepts
+struct FillableQuote<'a> {
+    /// The quote
+    quote: &'a ValidatedQuote,
+    /// Whether the quote can be filled partially
+    is_partial_fill: bool,
+    /// The least of the token the quote can be filled for
+    min_fill_value: u64,
+    /// The most of the token the quote can be filled for
+    max_fill_value: u64,
+}
+
+impl<'a> FillableQuote<'a> {
+    /// The quotes of a book which give `to_token_id`, and are simple enough to fill.
+    /// Our own quotes are skipped unless `include_mine` is set.
+    fn filter(
+        quote_book: &'a [ValidatedQuote],
+        to_token_id: TokenId,
+        include_mine: bool,
+    ) -> Vec<Self> {
+        let mut result = Vec::new();
+        for quote in quote_book {
+            if quote.is_mine && !include_mine {
+                continue;
+            }
+            if quote.amounts.pseudo_output.token_id != to_token_id {
+                event!(Level::WARN, "unexpected token id mismatch");
+                continue;
+            }
+
+            let terms = match quote.swap_terms() {
+                Ok(terms) => terms,
+                Err(err) => {
+                    event!(Level::WARN, "SCI too complicated: {}", err);
+                    continue;
+                }
+            };
+
+            if terms.is_partial_fill {
+                let min_fill_value = quote
+                    .sci
+                    .tx_in
+                    .input_rules
+                    .as_ref()
+                    .map(|rules| rules.min_partial_fill_value)
+                    .unwrap_or(0);
+                result.push(Self {
+                    quote,
+                    is_partial_fill: true,
+                    min_fill_value,
+                    max_fill_value: terms.offered,
+                });
+            } else {
+                result.push(Self {
+                    quote,
+                    is_partial_fill: false,
+                    min_fill_value: terms.offered,
+                    max_fill_value: terms.offered,
+                });
+            }
+        }
+        result
+    }
+
+    /// Whether the quote can be filled for `fill_value` of the token
+    fn accepts(&self, fill_value: u64) -> bool {
+        self.min_fill_value <= fill_value && fill_value <= self.max_fill_value
+    }
+
+    /// How much of `from_token_id` filling the quote for `fill_value` costs, or None
+    /// if the quote isn't a simple swap for `from_token_id`
+    fn cost(&self, from_token_id: TokenId, fill_value: u64) -> Option<u64> {
+        let partial_fill_value = if self.is_partial_fill { fill_value } else { 0 };
+        let balance_sheet = match self.quote.amounts.compute_balance_sheet(partial_fill_value) {
+            Ok(balance_sheet) => balance_sheet,
+            Err(err) => {
+                event!(Level::WARN, "Could not compute balances of SCI: {}", err);
+                return None;
+            }
+        };
+
+        if balance_sheet.len() != 2 {
+            event!(Level::WARN, "SCI too complicated: {:?}", balance_sheet);
+            return None;
+        }
+
+        match balance_sheet.get(&from_token_id) {
+            Some(val) => Some(*val as u64),
+            None => {
+                event!(Level::WARN, "unexpected token id mismatch");
+                None
+            }
+        }
+    }
+
+    /// The sci fill which fills the quote for `fill_value` of the token
+    fn sci_fill(&self, fill_value: u64) -> SciFill {
+        SciFill {
+            sci: self.quote.sci.clone(),
+            partial_fill_value: if self.is_partial_fill { fill_value } else { 0 },
+            source: self.quote.id.source,
+        }
+    }
+}
+
+/// The output of a quote selection algorithm that tries to find the best quotes to obtain one amount.
+#[derive(Clone, Debug)]
+pub struct QuoteSelection {
+    // The scis we selected, and the degree to fill each of them to. Usually just one.
+    pub fills: Vec<SciFill>,
+    // The u64 value which must be supplied to fulfill these quotes
     pub from_u64_value: u64,
+    // The u64 value these quotes provide
+    pub to_u64_value: u64,
     // The from value as a scaled Decimal
     pub from_value_decimal: Decimal,
+    // The price of the to token in units of the from token, implied by these quotes
+    pub price: Decimal,
 }
 
 impl QuoteSelection {
+    /// The most scis to fill in one transaction. Every sci spends an input and adds
+    /// up to two outputs, and a transaction has room for 16 of each, which must also
+    /// fit our own inputs, change and the swapped output.
+    pub const MAX_SCIS: usize = 6;
+    /// The error when only our own quotes could provide a selection
+    pub const ONLY_MINE: &'static str = "only your own offers match";
+
+    /// The error when no selection could be made. If our own quotes were skipped,
+    /// and `with_mine` shows that they would have matched, says so, since filling
+    /// them only pays fees to swap with ourselves.
+    fn no_liquidity(
+        include_mine: bool,
+        with_mine: impl FnOnce() -> Result<Self, String>,
+    ) -> String {
+        if !include_mine && with_mine().is_ok() {
+            Self::ONLY_MINE.to_owned()
+        } else {
+            "insufficient liquidity".to_owned()
+        }
+    }
+
     /// Try to select the best quote to obtain `to_amount`, paying `from_token_id`.
     /// These should all be quotes from the right book type, or warnings will be logged.
     ///
-    /// If there is no appropriate quote, returns "insufficient liquidity".
+    /// Partial fill quotes whose minimum fill value exceeds `to_amount` are skipped,
+    /// since the counterparty would reject the fill.
     ///
-    /// TODO: We should probably allow to incorporate several quotes, like two or three,
-    /// if that would give a better execution, since mobilecoind supports that.
+    /// Our own quotes are skipped unless `include_mine` is set, since filling them
+    /// only pays a fee to swap with ourselves.
+    ///
+    /// If there is no appropriate quote, returns "insufficient liquidity", or
+    /// ONLY_MINE if there would be one among our own quotes.
     pub fn new(
         quote_book: &[ValidatedQuote],
         from_token_id: TokenId,
         from_token_info: &TokenInfo,
         to_amount: Amount,
+        to_token_info: &TokenInfo,
+        include_mine: bool,
     ) -> Result<QuoteSelection, String> {
         let mut candidates: Vec<QuoteSelection> = Default::default();
-        for quote in quote_book {
-            if quote.amounts.pseudo_output.token_id != to_amount.token_id {
-                event!(Level::WARN, "unexpected token id mismatch");
+        for fillable in FillableQuote::filter(quote_book, to_amount.token_id, include_mine) {
+            if !fillable.accepts(to_amount.value) {
+                // There isn't enough liquidity in this SCI, or it can't be filled for this little
                 continue;
             }
 
-            if let Some(partial_fill_change) = quote.amounts.partial_fill_change.as_ref() {
-                if &quote.amounts.pseudo_output != partial_fill_change {
-                    event!(Level::WARN, "SCI too complicated");
-                    continue;
-                }
-
-                if quote.amounts.pseudo_output.value < to_amount.value {
-                    // This just means there isn't enough liquidity in this SCI
-                    continue;
-                }
+            if let Some(from_u64_value) = fillable.cost(from_token_id, to_amount.value) {
+                candidates.push(QuoteSelection::from_fills(
+                    vec![fillable.sci_fill(to_amount.value)],
+                    from_u64_value,
+                    to_amount.value,
+                    from_token_info,
+                    to_token_info,
+                )?);
+            }
+        }
+        candidates.sort_by_key(|qs| qs.from_u64_value);
+        candidates.get(0).cloned().ok_or_else(|| {
+            Self::no_liquidity(include_mine, || {
+                Self::new(
+                    quote_book,
+                    from_token_id,
+                    from_token_info,
+                    to_amount,
+                    to_token_info,
+                    true,
+                )
+            })
+        })
+    }
 
-                let balance_sheet = match quote.amounts.compute_balance_sheet(to_amount.value) {
-                    Ok(balance_sheet) => balance_sheet,
-                    Err(err) => {
-                        event!(Level::WARN, "Could not compute balances of SCI: {}", err);
-                        continue;
-                    }
-                };
+    /// Like new, but if no single quote can provide `to_amount`, fill several of them
+    /// in one transaction, greedily taking the ones with the best price first.
+    ///
+    /// At most MAX_SCIS quotes are used. If they can't provide `to_amount` together,
+    /// returns "insufficient liquidity", or ONLY_MINE if our own quotes could.
+    pub fn new_multi(
+        quote_book: &[ValidatedQuote],
+        from_token_id: TokenId,
+        from_token_info: &TokenInfo,
+        to_amount: Amount,
+        to_token_info: &TokenInfo,
+        include_mine: bool,
+    ) -> Result<QuoteSelection, String> {
+        if let Ok(qs) = Self::new(
+            quote_book,
+            from_token_id,
+            from_token_info,
+            to_amount,
+            to_token_info,
+            include_mine,
+        ) {
+            return Ok(qs);
+        }
 
-                if balance_sheet.len() != 2 {
-                    event!(Level::WARN, "SCI too complicated: {:?}", balance_sheet);
-                    continue;
-                }
+        // Rank the quotes by the price of filling them entirely, comparing
+        // cost_a / value_a with cost_b / value_b without dividing
+        let mut candidates: Vec<(FillableQuote, u64)> =
+            FillableQuote::filter(quote_book, to_amount.token_id, include_mine)
+                .into_iter()
+                .filter(|fillable| fillable.max_fill_value > 0)
+                .filter_map(|fillable| {
+                    let cost = fillable.cost(from_token_id, fillable.max_fill_value)?;
+                    Some((fillable, cost))
+                })
+                .collect();
+        candidates.sort_by(|(a, a_cost), (b, b_cost)| {
+            (*a_cost as u128 * b.max_fill_value as u128)
+                .cmp(&(*b_cost as u128 * a.max_fill_value as u128))
+        });
 
-                if let Some(val) = balance_sheet.get(&from_token_id) {
-                    let from_u64_value = *val as u64;
-                    // FIXME: check for overflow
-                    let from_value_decimal =
-                        Decimal::new(from_u64_value as i64, from_token_info.decimals);
-                    candidates.push(QuoteSelection {
-                        sci: quote.sci.clone(),
-                        partial_fill_value: to_amount.value,
-                        from_u64_value,
-                        from_value_decimal,
-                    });
-                } else {
-                    event!(Level::WARN, "unexpected token id mismatch");
-                }
+        let mut remaining = to_amount.value;
+        let mut fills = Vec::new();
+        let mut from_u64_value: u64 = 0;
+        for (fillable, _) in candidates {
+            if remaining == 0 || fills.len() == Self::MAX_SCIS {
+                break;
+            }
+            // Take as much of a partial fill quote as we still need, and all of any
+            // other quote if we need all of it
+            let fill_value = if fillable.is_partial_fill {
+                remaining.min(fillable.max_fill_value)
             } else {
-                if quote.amounts.pseudo_output.value != to_amount.value {
-                    continue;
-                }
+                fillable.max_fill_value
+            };
+            if fill_value > remaining || !fillable.accepts(fill_value) {
+                continue;
+            }
+            let cost = match fillable.cost(from_token_id, fill_value) {
+                Some(cost) => cost,
+                None => continue,
+            };
+            from_u64_value = from_u64_value
+                .checked_add(cost)
+                .ok_or("u64 overflow".to_string())?;
+            remaining -= fill_value;
+            fills.push(fillable.sci_fill(fill_value));
+        }
+        if remaining > 0 {
+            return Err(Self::no_liquidity(include_mine, || {
+                Self::new_multi(
+                    quote_book,
+                    from_token_id,
+                    from_token_info,
+                    to_amount,
+                    to_token_info,
+                    true,
+                )
+            }));
+        }
 
-                let balance_sheet = match quote.amounts.compute_balance_sheet(0) {
-                    Ok(balance_sheet) => balance_sheet,
-                    Err(err) => {
-                        event!(Level::WARN, "Could not compute balances of SCI: {}", err);
-                        continue;
-                    }
-                };
+        QuoteSelection::from_fills(
+            fills,
+            from_u64_value,
+            to_amount.value,
+            from_token_info,
+            to_token_info,
+        )
+    }
 
-                if balance_sheet.len() != 2 {
-                    event!(Level::WARN, "SCI too complicated: {:?}", balance_sheet);
-                    continue;
-                }
+    /// Try to select the quote which provides the most of the to token for at most
+    /// `from_amount`. This is the reverse of new, for when the user enters the amount
+    /// they pay rather than the amount they get.
+    ///
+    /// A partial fill quote is filled to the largest value whose cost fits in
+    /// `from_amount`, which is found with a binary search, since the cost grows with
+    /// the fill value.
+    ///
+    /// If there is no appropriate quote, returns "insufficient liquidity", or
+    /// ONLY_MINE if there would be one among our own quotes.
+    pub fn for_from_amount(
+        quote_book: &[ValidatedQuote],
+        from_amount: Amount,
+        from_token_info: &TokenInfo,
+        to_token_info: &TokenInfo,
+        include_mine: bool,
+    ) -> Result<QuoteSelection, String> {
+        let to_token_id = to_token_info.token_id;
+        let from_token_id = from_amount.token_id;
+        let affordable = |fillable: &FillableQuote, fill_value: u64| {
+            fillable
+                .cost(from_token_id, fill_value)
+                .filter(|cost| *cost <= from_amount.value)
+        };
 
-                if let Some(val) = balance_sheet.get(&from_token_id) {
-                    let from_u64_value = *val as u64;
-                    // FIXME: check for overflow
-                    let from_value_decimal =
-                        Decimal::new(from_u64_value as i64, from_token_info.decimals);
-                    candidates.push(QuoteSelection {
-                        sci: quote.sci.clone(),
-                        partial_fill_value: 0,
-                        from_u64_value,
-                        from_value_decimal,
-                    });
-                } else {
-                    event!(Level::WARN, "unexpected token id mismatch");
+        let mut candidates: Vec<QuoteSelection> = Default::default();
+        for fillable in FillableQuote::filter(quote_book, to_token_id, include_mine) {
+            let mut fill_value = fillable.min_fill_value.max(1);
+            if fill_value > fillable.max_fill_value || affordable(&fillable, fill_value).is_none() {
+                continue;
+            }
+            if fillable.is_partial_fill {
+                let mut too_much = fillable.max_fill_value.saturating_add(1);
+                while too_much - fill_value > 1 {
+                    let mid = fill_value + (too_much - fill_value) / 2;
+                    if affordable(&fillable, mid).is_some() {
+                        fill_value = mid;
+                    } else {
+                        too_much = mid;
+                    }
                 }
             }
+
+            if let Some(from_u64_value) = affordable(&fillable, fill_value) {
+                candidates.push(QuoteSelection::from_fills(
+                    vec![fillable.sci_fill(fill_value)],
+                    from_u64_value,
+                    fill_value,
+                    from_token_info,
+                    to_token_info,
+                )?);
+            }
         }
-        candidates.sort_by_key(|qs| qs.from_u64_value);
-        candidates
-            .get(0)
-            .cloned()
-            .ok_or("insufficient liquidity".to_owned())
+        candidates.sort_by_key(|qs| (std::cmp::Reverse(qs.to_u64_value), qs.from_u64_value));
+        candidates.get(0).cloned().ok_or_else(|| {
+            Self::no_liquidity(include_mine, || {
+                Self::for_from_amount(
+                    quote_book,
+                    from_amount,
+                    from_token_info,
+                    to_token_info,
+                    true,
+                )
+            })
+        })
+    }
+
+    /// The best price in a book of the to token in units of the from token, that of
+    /// filling the cheapest quote entirely, or None if no quote can be filled.
+    pub fn best_price(
+        quote_book: &[ValidatedQuote],
+        from_token_info: &TokenInfo,
+        to_token_info: &TokenInfo,
+        include_mine: bool,
+    ) -> Option<Decimal> {
+        FillableQuote::filter(quote_book, to_token_info.token_id, include_mine)
+            .into_iter()
+            .filter_map(|fillable| {
+                let from_u64_value =
+                    fillable.cost(from_token_info.token_id, fillable.max_fill_value)?;
+                QuoteSelection::from_fills(
+                    vec![],
+                    from_u64_value,
+                    fillable.max_fill_value,
+                    from_token_info,
+                    to_token_info,
+                )
+                .ok()
+            })
+            .filter(|qs| qs.to_u64_value > 0)
+            .map(|qs| qs.price)
+            .min()
+    }
+
+    /// The key images of the selected scis
+    pub fn key_images(&self) -> Vec<KeyImage> {
+        self.fills.iter().map(|fill| fill.sci.key_image()).collect()
+    }
+
+    // Make a selection of fills, computing the scaled from value and the price
+    fn from_fills(
+        fills: Vec<SciFill>,
+        from_u64_value: u64,
+        to_u64_value: u64,
+        from_token_info: &TokenInfo,
+        to_token_info: &TokenInfo,
+    ) -> Result<Self, String> {
+        let from_value_decimal = from_token_info.try_u64_to_decimal(from_u64_value)?;
+        let to_value_decimal = to_token_info.try_u64_to_decimal(to_u64_value)?;
+        // The price is zero if nothing is obtained
+        let price = from_value_decimal
+            .checked_div(to_value_decimal)
+            .unwrap_or_default();
+        Ok(Self {
+            fills,
+            from_u64_value,
+            to_u64_value,
+            from_value_decimal,
+            price,
+        })
+    }
+}
+
+#[cfg(test)]
+impl ValidatedQuote {
+    /// A quote served by the deqs at index `source`, with the given amounts. Its sci
+    /// is empty except for its key image, so only its id and amounts are meaningful.
+    pub fn fixture(source: usize, key_image: u64, amounts: SignedContingentInputAmounts) -> Self {
+        let key_image = KeyImage::from(key_image);
+        let mut sci = SignedContingentInput::default();
+        sci.mlsag.key_image = key_image;
+        Self {
+            id: QuoteId { source, key_image },
+            sci,
+            amounts,
+            timestamp: 0,
+            is_mine: false,
+            max_tombstone_block: None,
+        }
+    }
+
+    /// A quote offering `offered` for `asked`, which may be filled partially if
+    /// `is_partial_fill` is set
+    pub fn swap_fixture(
+        source: usize,
+        key_image: u64,
+        offered: Amount,
+        asked: Amount,
+        is_partial_fill: bool,
+    ) -> Self {
+        let amounts = if is_partial_fill {
+            SignedContingentInputAmounts {
+                pseudo_output: offered,
+                required_outputs: vec![],
+                partial_fill_outputs: vec![asked],
+                partial_fill_change: Some(offered),
+            }
+        } else {
+            SignedContingentInputAmounts {
+                pseudo_output: offered,
+                required_outputs: vec![asked],
+                partial_fill_outputs: vec![],
+                partial_fill_change: None,
+            }
+        };
+        Self::fixture(source, key_image, amounts)
+    }
+
+    /// The quote, with input rules asking for fills of at least `min_fill_value`
+    pub fn with_min_partial_fill_value(mut self, min_fill_value: u64) -> Self {
+        let mut rules = self.sci.tx_in.input_rules.take().unwrap_or_default();
+        rules.min_partial_fill_value = min_fill_value;
+        self.sci.tx_in.input_rules = Some(rules);
+        self
+    }
+
+    /// The quote, with input rules which expire it at `max_tombstone_block`
+    pub fn with_max_tombstone_block(mut self, max_tombstone_block: u64) -> Self {
+        let mut rules = self.sci.tx_in.input_rules.take().unwrap_or_default();
+        rules.max_tombstone_block = max_tombstone_block;
+        self.sci.tx_in.input_rules = Some(rules);
+        self.max_tombstone_block = Some(max_tombstone_block);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOB: TokenId = TokenId::MOB;
+
+    fn eusd() -> TokenId {
+        TokenId::from(1)
+    }
+
+    fn token_info(token_id: TokenId, symbol: &str, decimals: u32, fee: u64) -> TokenInfo {
+        TokenInfo {
+            token_id,
+            symbol: symbol.to_owned(),
+            fee,
+            decimals,
+        }
+    }
+
+    fn mob_info() -> TokenInfo {
+        token_info(MOB, "MOB", 12, 400_000_000)
+    }
+
+    fn eusd_info() -> TokenInfo {
+        token_info(eusd(), "EUSD", 6, 2_560)
+    }
+
+    fn quote(source: usize, key_image: u64) -> ValidatedQuote {
+        ValidatedQuote::swap_fixture(
+            source,
+            key_image,
+            Amount::new(1_000, MOB),
+            Amount::new(2_000, eusd()),
+            false,
+        )
+    }
+
+    fn ids(book: &[ValidatedQuote]) -> Vec<(usize, KeyImage)> {
+        book.iter()
+            .map(|quote| (quote.id.source, quote.id.key_image))
+            .collect()
+    }
+
+    #[test]
+    fn merge_books_dedups_by_key_image_keeping_the_first_deqs() {
+        let merged = ValidatedQuote::merge_books(vec![
+            vec![quote(0, 1), quote(0, 2)],
+            vec![quote(1, 2), quote(1, 3)],
+            vec![quote(2, 3), quote(2, 1)],
+        ]);
+        assert_eq!(
+            ids(&merged),
+            vec![
+                (0, KeyImage::from(1)),
+                (0, KeyImage::from(2)),
+                (1, KeyImage::from(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_polled_books_survives_a_dead_deqs() {
+        let (merged, errors) = ValidatedQuote::merge_polled_books(
+            vec![vec![quote(1, 7)]],
+            vec!["deqs://a: unavailable".to_owned()],
+        )
+        .unwrap();
+        assert_eq!(ids(&merged), vec![(1, KeyImage::from(7))]);
+        assert_eq!(errors, vec!["deqs://a: unavailable".to_owned()]);
+    }
+
+    #[test]
+    fn merge_polled_books_keeps_an_empty_book_from_a_live_deqs() {
+        let (merged, errors) =
+            ValidatedQuote::merge_polled_books(vec![vec![]], vec!["deqs://a: down".to_owned()])
+                .unwrap();
+        assert!(merged.is_empty());
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn merge_polled_books_reports_every_error_when_all_deqs_fail() {
+        let errors: Vec<String> = (0..5).map(|idx| format!("deqs://{idx}: down")).collect();
+        let err = ValidatedQuote::merge_polled_books(vec![], errors.clone()).unwrap_err();
+        for error in errors {
+            assert!(err.contains(&error), "{err} lacks {error}");
+        }
+    }
+
+    #[test]
+    fn fills_route_to_the_deqs_which_served_their_quote() {
+        let fill = |source, key_image| SciFill {
+            sci: quote(source, key_image).sci,
+            partial_fill_value: 0,
+            source,
+        };
+        let fills = vec![fill(1, 10), fill(0, 11), fill(1, 12)];
+        let routes = SciFill::route(&fills);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[&0], vec![KeyImage::from(11)]);
+        assert_eq!(routes[&1], vec![KeyImage::from(10), KeyImage::from(12)]);
+        assert_eq!(
+            fills[0].quote_id(),
+            QuoteId {
+                source: 1,
+                key_image: KeyImage::from(10)
+            }
+        );
+    }
+
+    #[test]
+    fn selected_fills_carry_the_source_of_their_quote() {
+        let mob = token_info(MOB, "MOB", 0, 0);
+        let eusd = token_info(eusd(), "EUSD", 0, 0);
+        let book = vec![quote(2, 5)];
+        let qs = QuoteSelection::new(
+            &book,
+            eusd.token_id,
+            &eusd,
+            Amount::new(1_000, MOB),
+            &mob,
+            false,
+        )
+        .unwrap();
+        assert_eq!(qs.fills.len(), 1);
+        assert_eq!(qs.fills[0].quote_id(), book[0].id);
+    }
+
+    fn snapshot(synced_blocks: u64, total_blocks: u64) -> BalanceSnapshot {
+        BalanceSnapshot {
+            balances: HashMap::from([(MOB, 1_000)]),
+            fetched: true,
+            synced_blocks,
+            total_blocks,
+        }
+    }
+
+    #[test]
+    fn sync_is_complete_within_the_threshold() {
+        let within = BalanceSnapshot::COMPLETE_WITHIN_BLOCKS;
+        assert!(BalanceSnapshot::is_sync_complete(100, 100));
+        assert!(BalanceSnapshot::is_sync_complete(100 - within, 100));
+        assert!(!BalanceSnapshot::is_sync_complete(100 - within - 1, 100));
+        // A monitor ahead of a reorged ledger is synced
+        assert!(BalanceSnapshot::is_sync_complete(101, 100));
+        assert!(BalanceSnapshot::is_sync_complete(u64::MAX, u64::MAX));
+        assert!(BalanceSnapshot::is_sync_complete(0, 0));
+    }
+
+    #[test]
+    fn balances_which_were_never_fetched_are_incomplete() {
+        let mut snapshot = snapshot(100, 100);
+        assert!(snapshot.is_complete());
+        snapshot.fetched = false;
+        assert!(!snapshot.is_complete());
+    }
+
+    #[test]
+    fn incomplete_balances_explain_how_far_the_scan_got() {
+        let within = BalanceSnapshot::COMPLETE_WITHIN_BLOCKS;
+        assert_eq!(snapshot(200 - within, 200).require_complete(), Ok(()));
+        assert_eq!(
+            snapshot(124, 200).require_complete(),
+            Err("still scanning the ledger (62%) — balances may be incomplete".to_owned())
+        );
+        let mut never_fetched = snapshot(0, 0);
+        never_fetched.fetched = false;
+        assert_eq!(
+            never_fetched.require_complete(),
+            Err("still scanning the ledger (0%) — balances may be incomplete".to_owned())
+        );
+    }
+
+    #[test]
+    fn scaled_amounts_add_and_subtract_within_u64() {
+        let a = ScaledAmount::new(u64::MAX - 1, MOB);
+        assert_eq!(
+            a.try_add(ScaledAmount::new(1, MOB)),
+            Ok(ScaledAmount::new(u64::MAX, MOB))
+        );
+        assert_eq!(
+            a.try_add(ScaledAmount::new(2, MOB)),
+            Err("u64 overflow".to_owned())
+        );
+        assert_eq!(
+            ScaledAmount::new(1, MOB).try_sub(ScaledAmount::new(1, MOB)),
+            Ok(ScaledAmount::new(0, MOB))
+        );
+        assert_eq!(
+            ScaledAmount::new(0, MOB).try_sub(ScaledAmount::new(1, MOB)),
+            Err("u64 underflow".to_owned())
+        );
+    }
+
+    #[test]
+    fn scaled_amounts_of_different_tokens_dont_mix() {
+        let mob = ScaledAmount::new(1, MOB);
+        let eusd = ScaledAmount::new(1, eusd());
+        assert!(mob.try_add(eusd).unwrap_err().contains("token id mismatch"));
+        assert!(mob.try_sub(eusd).unwrap_err().contains("token id mismatch"));
+        assert!(mob.to_decimal(&eusd_info()).is_err());
+        assert_eq!(mob.display(&eusd_info()), "(overflow)");
+    }
+
+    #[test]
+    fn scaled_amounts_convert_to_and_from_decimals() {
+        let mob = mob_info();
+        let amount = ScaledAmount::parse("1.5", &mob).unwrap();
+        assert_eq!(amount, ScaledAmount::new(1_500_000_000_000, MOB));
+        assert_eq!(amount.to_decimal(&mob), Ok(Decimal::new(15, 1)));
+        assert_eq!(amount.display(&mob), "1.500000000000");
+        let max = ScaledAmount::new(u64::MAX, MOB);
+        assert_eq!(
+            max.to_decimal(&mob),
+            Ok(Decimal::from_str("18446744.073709551615").unwrap())
+        );
+        assert_eq!(
+            ScaledAmount::from_decimal(max.to_decimal(&mob).unwrap(), &mob),
+            Ok(max)
+        );
+        assert!(ScaledAmount::from_decimal(Decimal::from(18_446_745), &mob).is_err());
+        assert!(ScaledAmount::from_decimal(Decimal::new(-1, 0), &mob).is_err());
+        assert_eq!(
+            ScaledAmount::from_decimal_rounded(Decimal::new(15, 13), &mob),
+            Ok(ScaledAmount::new(2, MOB))
+        );
+    }
+
+    #[test]
+    fn scaled_amounts_pay_fees_from_balances() {
+        let mob = mob_info();
+        assert_eq!(
+            ScaledAmount::new(1, MOB).with_fee(&mob),
+            Ok(ScaledAmount::new(400_000_001, MOB))
+        );
+        assert!(ScaledAmount::new(u64::MAX, MOB).with_fee(&mob).is_err());
+        assert_eq!(
+            ScaledAmount::max_sendable(400_000_001, &mob),
+            Ok(ScaledAmount::new(1, MOB))
+        );
+        assert!(ScaledAmount::max_sendable(400_000_000, &mob).is_err());
+
+        let balances = HashMap::from([(MOB, 10)]);
+        assert_eq!(
+            ScaledAmount::new(10, MOB).require_covered_by(&balances),
+            Ok(())
+        );
+        assert!(ScaledAmount::new(11, MOB)
+            .require_covered_by(&balances)
+            .is_err());
+        assert!(ScaledAmount::new(1, eusd())
+            .require_covered_by(&balances)
+            .is_err());
+    }
+
+    // Token infos without decimals, so that values and prices read directly
+    fn whole_mob() -> TokenInfo {
+        token_info(MOB, "MOB", 0, 0)
+    }
+
+    fn whole_eusd() -> TokenInfo {
+        token_info(eusd(), "EUSD", 0, 0)
+    }
+
+    // A quote which may be partially filled, offering `offered` MOB for `asked` EUSD
+    fn partial_quote(key_image: u64, offered: u64, asked: u64) -> ValidatedQuote {
+        ValidatedQuote::swap_fixture(
+            0,
+            key_image,
+            Amount::new(offered, MOB),
+            Amount::new(asked, eusd()),
+            true,
+        )
+    }
+
+    fn select(book: &[ValidatedQuote], to_value: u64) -> Result<QuoteSelection, String> {
+        QuoteSelection::new(
+            book,
+            eusd(),
+            &whole_eusd(),
+            Amount::new(to_value, MOB),
+            &whole_mob(),
+            false,
+        )
+    }
+
+    #[test]
+    fn quotes_are_filled_at_or_above_their_minimum() {
+        let book = vec![partial_quote(1, 1_000, 2_000).with_min_partial_fill_value(500)];
+        assert_eq!(
+            select(&book, 400).unwrap_err(),
+            "insufficient liquidity".to_owned()
+        );
+
+        let qs = select(&book, 500).unwrap();
+        assert_eq!(qs.fills[0].partial_fill_value, 500);
+        assert_eq!(qs.from_u64_value, 1_000);
+
+        let qs = select(&book, 600).unwrap();
+        assert_eq!(qs.fills[0].partial_fill_value, 600);
+        assert_eq!(qs.from_u64_value, 1_200);
+        assert_eq!(qs.price, Decimal::TWO);
+    }
+
+    #[test]
+    fn quotes_whose_minimum_is_too_high_leave_room_for_pricier_ones() {
+        let book = vec![
+            partial_quote(1, 1_000, 2_000).with_min_partial_fill_value(500),
+            partial_quote(2, 1_000, 3_000).with_min_partial_fill_value(100),
+        ];
+        let qs = select(&book, 400).unwrap();
+        assert_eq!(qs.key_images(), vec![KeyImage::from(2)]);
+        assert_eq!(qs.from_u64_value, 1_200);
+
+        let qs = select(&book, 500).unwrap();
+        assert_eq!(qs.key_images(), vec![KeyImage::from(1)]);
+    }
+
+    #[test]
+    fn paying_a_set_amount_fills_at_least_the_minimum() {
+        let book = vec![partial_quote(1, 1_000, 2_000).with_min_partial_fill_value(500)];
+        let pay = |value| {
+            QuoteSelection::for_from_amount(
+                &book,
+                Amount::new(value, eusd()),
+                &whole_eusd(),
+                &whole_mob(),
+                false,
+            )
+        };
+        assert!(pay(999).is_err());
+        let qs = pay(1_000).unwrap();
+        assert_eq!(qs.to_u64_value, 500);
+        let qs = pay(1_500).unwrap();
+        assert_eq!(qs.to_u64_value, 750);
+        assert_eq!(qs.fills[0].partial_fill_value, 750);
+    }
+
+    // A quote which must be filled entirely, offering `offered` MOB for `asked` EUSD
+    fn full_quote(key_image: u64, offered: u64, asked: u64) -> ValidatedQuote {
+        ValidatedQuote::swap_fixture(
+            0,
+            key_image,
+            Amount::new(offered, MOB),
+            Amount::new(asked, eusd()),
+            false,
+        )
+    }
+
+    fn select_multi(book: &[ValidatedQuote], to_value: u64) -> Result<QuoteSelection, String> {
+        QuoteSelection::new_multi(
+            book,
+            eusd(),
+            &whole_eusd(),
+            Amount::new(to_value, MOB),
+            &whole_mob(),
+            false,
+        )
+    }
+
+    #[test]
+    fn one_quote_is_enough_when_it_covers_the_amount() {
+        let book = vec![full_quote(1, 300, 600), partial_quote(2, 1_000, 3_000)];
+        let qs = select_multi(&book, 600).unwrap();
+        assert_eq!(qs.key_images(), vec![KeyImage::from(2)]);
+        assert_eq!(qs.from_u64_value, 1_800);
+    }
+
+    #[test]
+    fn several_quotes_are_filled_best_price_first() {
+        let book = vec![
+            full_quote(1, 300, 600),
+            full_quote(2, 300, 900),
+            full_quote(3, 300, 450),
+            partial_quote(4, 500, 1_250),
+        ];
+        let qs = select_multi(&book, 1_000).unwrap();
+        // 300 at 1.5, 300 at 2, and the rest of the partial fill quote at 2.5
+        assert_eq!(
+            qs.key_images(),
+            vec![KeyImage::from(3), KeyImage::from(1), KeyImage::from(4)]
+        );
+        assert_eq!(
+            qs.fills
+                .iter()
+                .map(|fill| fill.partial_fill_value)
+                .collect::<Vec<_>>(),
+            vec![0, 0, 400]
+        );
+        assert_eq!(qs.from_u64_value, 450 + 600 + 1_000);
+        assert_eq!(qs.to_u64_value, 1_000);
+    }
+
+    #[test]
+    fn quotes_larger_than_the_rest_are_passed_over() {
+        // The cheapest quote would overshoot what is still needed
+        let book = vec![
+            full_quote(1, 300, 600),
+            full_quote(2, 700, 700),
+            full_quote(3, 300, 900),
+        ];
+        let qs = select_multi(&book, 600).unwrap();
+        assert_eq!(qs.key_images(), vec![KeyImage::from(1), KeyImage::from(3)]);
+        assert_eq!(qs.from_u64_value, 1_500);
+    }
+
+    #[test]
+    fn at_most_max_scis_quotes_are_filled() {
+        let book: Vec<ValidatedQuote> = (0..=QuoteSelection::MAX_SCIS as u64)
+            .map(|key_image| full_quote(key_image, 100, 100 + key_image))
+            .collect();
+        let most = 100 * QuoteSelection::MAX_SCIS as u64;
+        let qs = select_multi(&book, most).unwrap();
+        assert_eq!(qs.fills.len(), QuoteSelection::MAX_SCIS);
+        assert!(!qs
+            .key_images()
+            .contains(&KeyImage::from(QuoteSelection::MAX_SCIS as u64)));
+        assert_eq!(
+            select_multi(&book, most + 100).unwrap_err(),
+            "insufficient liquidity".to_owned()
+        );
+    }
+
+    #[test]
+    fn quotes_expire_once_the_ledger_reaches_their_tombstone_block() {
+        let expiring = quote(0, 1).with_max_tombstone_block(100);
+        assert_eq!(expiring.max_tombstone_block, Some(100));
+        assert!(!expiring.is_expired(99));
+        assert!(expiring.is_expired(100));
+        assert!(expiring.is_expired(101));
+        // Quotes without input rules, or without a tombstone rule, never expire
+        assert!(!quote(0, 2).is_expired(u64::MAX));
+        assert!(!quote(0, 3)
+            .with_min_partial_fill_value(1)
+            .is_expired(u64::MAX));
+    }
+
+    #[test]
+    fn expired_quotes_leave_the_book_before_selection() {
+        let mut book = vec![
+            quote(0, 1).with_max_tombstone_block(100),
+            quote(0, 2).with_max_tombstone_block(101),
+        ];
+        ValidatedQuote::drop_expired(&mut book, 100);
+        assert_eq!(ids(&book), vec![(0, KeyImage::from(2))]);
+        let qs = select(&book, 1_000).unwrap();
+        assert_eq!(qs.key_images(), vec![KeyImage::from(2)]);
+        assert_eq!(qs.fills[0].max_tombstone_block(), Some(101));
+
+        ValidatedQuote::drop_expired(&mut book, 101);
+        assert!(select(&book, 1_000).is_err());
+    }
+
+    fn dec(src: &str) -> Decimal {
+        Decimal::from_str(src).unwrap()
+    }
+
+    fn quote_info(quote_side: QuoteSide, price: &str, volume: &str) -> QuoteInfo {
+        QuoteInfo {
+            quote_side,
+            price: dec(price),
+            volume: dec(volume),
+            is_partial_fill: false,
+            timestamp: 0,
+            is_mine: false,
+        }
+    }
+
+    fn levels(levels: &[DepthLevel]) -> Vec<(Decimal, Decimal, Vec<usize>)> {
+        levels
+            .iter()
+            .map(|level| (level.price, level.cumulative_volume, level.quotes.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn bids_round_down_to_the_tick_best_first() {
+        let bids = [
+            quote_info(QuoteSide::Bid, "1.234", "1"),
+            quote_info(QuoteSide::Bid, "1.2399", "2"),
+            quote_info(QuoteSide::Bid, "1.24", "4"),
+            quote_info(QuoteSide::Bid, "1.2301", "8"),
+        ];
+        assert_eq!(
+            levels(&aggregate_book(&bids, dec("0.01")).unwrap()),
+            vec![
+                (dec("1.24"), dec("4"), vec![2]),
+                (dec("1.23"), dec("15"), vec![0, 1, 3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn asks_round_up_to_the_tick_best_first() {
+        let asks = [
+            quote_info(QuoteSide::Ask, "1.2401", "1"),
+            quote_info(QuoteSide::Ask, "1.231", "2"),
+            quote_info(QuoteSide::Ask, "1.24", "4"),
+        ];
+        assert_eq!(
+            levels(&aggregate_book(&asks, dec("0.01")).unwrap()),
+            vec![
+                (dec("1.24"), dec("6"), vec![1, 2]),
+                (dec("1.25"), dec("7"), vec![0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_zero_tick_buckets_exact_prices() {
+        let asks = [
+            quote_info(QuoteSide::Ask, "1.230", "1"),
+            quote_info(QuoteSide::Ask, "1.2300001", "2"),
+            quote_info(QuoteSide::Ask, "1.23", "4"),
+        ];
+        assert_eq!(
+            levels(&aggregate_book(&asks, Decimal::ZERO).unwrap()),
+            vec![
+                (dec("1.23"), dec("5"), vec![0, 2]),
+                (dec("1.2300001"), dec("7"), vec![1]),
+            ]
+        );
+        assert!(aggregate_book(&[], dec("0.01")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn overflowing_levels_are_an_error() {
+        let mut huge = quote_info(QuoteSide::Ask, "1", "1");
+        huge.volume = Decimal::MAX;
+        let asks = [huge.clone(), huge];
+        assert!(aggregate_book(&asks, Decimal::ZERO)
+            .unwrap_err()
+            .starts_with("cumulative volume overflow"));
+
+        let mut far = quote_info(QuoteSide::Ask, "1", "1");
+        far.price = Decimal::MAX;
+        assert!(aggregate_book(&[far], dec("0.1"))
+            .unwrap_err()
+            .starts_with("price level overflow"));
+    }
+
+    #[test]
+    fn tick_sizes_must_be_positive() {
+        assert_eq!(DepthLevel::parse_tick(" "), Ok(Decimal::ZERO));
+        assert_eq!(DepthLevel::parse_tick("0.05"), Ok(dec("0.05")));
+        for tick in ["0", "-1", "abc"] {
+            assert!(DepthLevel::parse_tick(tick).is_err(), "{tick}");
+        }
+    }
+
+    fn amounts(
+        pseudo_output: u64,
+        required_outputs: &[Amount],
+        partial_fill_outputs: &[Amount],
+        partial_fill_change: Option<u64>,
+    ) -> SignedContingentInputAmounts {
+        SignedContingentInputAmounts {
+            pseudo_output: Amount::new(pseudo_output, MOB),
+            required_outputs: required_outputs.to_vec(),
+            partial_fill_outputs: partial_fill_outputs.to_vec(),
+            partial_fill_change: partial_fill_change.map(|value| Amount::new(value, MOB)),
+        }
+    }
+
+    // The terms of a quote with the given amounts, as (offered, asked, is_partial_fill)
+    fn terms(amounts: SignedContingentInputAmounts) -> Result<(u64, Amount, bool), &'static str> {
+        ValidatedQuote::fixture(0, 1, amounts)
+            .swap_terms()
+            .map(|terms| (terms.offered, terms.asked, terms.is_partial_fill))
+    }
+
+    #[test]
+    fn simple_swaps_offer_their_whole_input() {
+        let asked = Amount::new(2_000, eusd());
+        assert_eq!(
+            terms(amounts(1_000, &[asked], &[], None)),
+            Ok((1_000, asked, false))
+        );
+        assert_eq!(
+            terms(amounts(1_000, &[], &[asked], Some(1_000))),
+            Ok((1_000, asked, true))
+        );
+    }
+
+    #[test]
+    fn change_back_to_the_offerer_is_not_offered() {
+        let asked = Amount::new(2_000, eusd());
+        let change = Amount::new(500, MOB);
+        assert_eq!(
+            terms(amounts(1_500, &[asked, change], &[], None)),
+            Ok((1_000, asked, false))
+        );
+        // The order of the required outputs doesn't matter
+        assert_eq!(
+            terms(amounts(1_500, &[change, asked], &[], None)),
+            Ok((1_000, asked, false))
+        );
+        assert_eq!(
+            terms(amounts(1_500, &[change], &[asked], Some(1_000))),
+            Ok((1_000, asked, true))
+        );
+    }
+
+    #[test]
+    fn swaps_of_other_shapes_are_too_complicated() {
+        let asked = Amount::new(2_000, eusd());
+        let change = Amount::new(500, MOB);
+        let shapes = [
+            amounts(2_000, &[asked, change, change], &[], None),
+            amounts(1_500, &[asked, Amount::new(1, TokenId::from(2))], &[], None),
+            amounts(1_000, &[asked], &[asked], Some(1_000)),
+            amounts(1_500, &[change], &[asked], Some(900)),
+            amounts(1_000, &[], &[asked, asked], Some(1_000)),
+            amounts(1_000, &[asked], &[asked], None),
+            // Nothing left to offer after the change
+            amounts(500, &[asked, change], &[], None),
+            amounts(1_000, &[Amount::new(0, eusd())], &[], None),
+        ];
+        for shape in shapes {
+            assert!(terms(shape.clone()).is_err(), "{shape:?}");
+        }
+    }
+
+    #[test]
+    fn quotes_with_change_are_shown_and_selected_by_what_they_offer() {
+        let quote = ValidatedQuote::fixture(
+            0,
+            1,
+            amounts(
+                1_500,
+                &[Amount::new(2_000, eusd()), Amount::new(500, MOB)],
+                &[],
+                None,
+            ),
+        );
+        let info = quote
+            .get_quote_info(MOB, eusd(), &[whole_mob(), whole_eusd()])
+            .unwrap();
+        assert!(matches!(info.quote_side, QuoteSide::Ask));
+        assert_eq!(info.volume, Decimal::from(1_000));
+        assert_eq!(info.price, Decimal::TWO);
+
+        let qs = select(&[quote.clone()], 1_000).unwrap();
+        assert_eq!(qs.from_u64_value, 2_000);
+        // The change can't be bought
+        assert!(select(&[quote], 1_500).is_err());
+    }
+
+    fn mine(mut quote: ValidatedQuote) -> ValidatedQuote {
+        quote.is_mine = true;
+        quote
+    }
+
+    #[test]
+    fn books_without_our_quotes_are_selected_from_as_usual() {
+        let book = vec![full_quote(1, 1_000, 2_000), full_quote(2, 1_000, 1_500)];
+        assert_eq!(
+            select(&book, 1_000).unwrap().key_images(),
+            vec![KeyImage::from(2)]
+        );
+        assert_eq!(
+            select(&book, 2_000).unwrap_err(),
+            "insufficient liquidity".to_owned()
+        );
+    }
+
+    #[test]
+    fn our_own_quotes_are_skipped_even_when_they_are_best() {
+        let book = vec![
+            full_quote(1, 1_000, 2_000),
+            mine(full_quote(2, 1_000, 1_500)),
+        ];
+        assert_eq!(
+            select(&book, 1_000).unwrap().key_images(),
+            vec![KeyImage::from(1)]
+        );
+        let qs = select_multi(&book, 1_000).unwrap();
+        assert_eq!(qs.key_images(), vec![KeyImage::from(1)]);
+    }
+
+    #[test]
+    fn books_of_only_our_quotes_say_so() {
+        let book = vec![mine(full_quote(1, 1_000, 2_000))];
+        assert_eq!(select(&book, 1_000).unwrap_err(), QuoteSelection::ONLY_MINE);
+        assert_eq!(
+            select_multi(&book, 1_000).unwrap_err(),
+            QuoteSelection::ONLY_MINE
+        );
+        // Unless our quotes couldn't provide the amount either
+        assert_eq!(
+            select(&book, 2_000).unwrap_err(),
+            "insufficient liquidity".to_owned()
+        );
+
+        // Self-matching can be allowed, for testing
+        let qs = QuoteSelection::new(
+            &book,
+            eusd(),
+            &whole_eusd(),
+            Amount::new(1_000, MOB),
+            &whole_mob(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(qs.key_images(), vec![KeyImage::from(1)]);
+    }
+
+    #[test]
+    fn paying_a_set_amount_skips_our_quotes_too() {
+        let book = vec![mine(partial_quote(1, 1_000, 2_000))];
+        let pay = |include_mine| {
+            QuoteSelection::for_from_amount(
+                &book,
+                Amount::new(1_000, eusd()),
+                &whole_eusd(),
+                &whole_mob(),
+                include_mine,
+            )
+        };
+        assert_eq!(pay(false).unwrap_err(), QuoteSelection::ONLY_MINE);
+        assert_eq!(pay(true).unwrap().to_u64_value, 500);
+    }
+
+    #[test]
+    fn amount_input_takes_a_lone_comma_as_the_decimal_separator() {
+        for (input, normalized) in [
+            ("1.5", "1.5"),
+            ("  2 ", "2"),
+            ("1,5", "1.5"),
+            ("0,25", "0.25"),
+            (",5", ".5"),
+            ("1,0000", "1.0000"),
+        ] {
+            assert_eq!(normalize_amount_input(input).as_deref(), Ok(normalized));
+        }
+    }
+
+    #[test]
+    fn amount_input_with_a_thousands_comma_or_mixed_separators_is_refused() {
+        assert_eq!(
+            normalize_amount_input("1,000"),
+            Err(AmountError::AmbiguousSeparator("1,000".to_owned()))
+        );
+        for input in ["1,000.5", "1.000,5", "1,000,000", "1,2,3"] {
+            assert_eq!(normalize_amount_input(input), Err(AmountError::NotANumber));
+        }
+    }
+
+    #[test]
+    fn validation_says_exactly_what_is_wrong() {
+        let info = eusd_info();
+        assert_eq!(
+            ScaledAmount::validate("1,5", &info, None),
+            Ok(ScaledAmount::new(1_500_000, eusd()))
+        );
+        assert_eq!(
+            ScaledAmount::validate("1.5 EUSD", &info, None),
+            Err(AmountError::NotANumber)
+        );
+        assert_eq!(
+            ScaledAmount::validate("", &info, None),
+            Err(AmountError::NotANumber)
+        );
+        assert_eq!(
+            ScaledAmount::validate("-1", &info, None),
+            Err(AmountError::Negative)
+        );
+        assert_eq!(
+            ScaledAmount::validate("1.2345678", &info, None),
+            Err(AmountError::TooManyDecimals {
+                symbol: "EUSD".to_owned(),
+                decimals: 6
+            })
+        );
+        // Trailing zeros are not extra decimal places
+        assert_eq!(
+            ScaledAmount::validate("1.50000000", &info, None),
+            Ok(ScaledAmount::new(1_500_000, eusd()))
+        );
+        assert_eq!(
+            ScaledAmount::validate("18446744073709.551616", &info, None),
+            Err(AmountError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn validation_against_a_balance_leaves_room_for_the_fee() {
+        let info = eusd_info();
+        // 1 EUSD plus the 0.00256 fee
+        let balance = Some(1_002_560);
+        assert_eq!(
+            ScaledAmount::validate("1", &info, balance),
+            Ok(ScaledAmount::new(1_000_000, eusd()))
+        );
+        assert_eq!(
+            ScaledAmount::validate("1.000001", &info, balance),
+            Err(AmountError::ExceedsBalance {
+                fee: "0.00256 EUSD".to_owned(),
+                balance: "1.00256 EUSD".to_owned(),
+            })
+        );
+        assert_eq!(
+            ScaledAmount::validate("0.001", &info, balance),
+            Err(AmountError::Dust("0.00256 EUSD".to_owned()))
+        );
+        assert_eq!(
+            ScaledAmount::validate("0.00256", &info, balance),
+            Ok(ScaledAmount::new(2_560, eusd()))
+        );
+        // Without a balance, any amount is fine
+        assert_eq!(
+            ScaledAmount::validate("0.001", &info, None),
+            Ok(ScaledAmount::new(1_000, eusd()))
+        );
+    }
+
+    #[test]
+    fn decimals_convert_exactly_or_not_at_all() {
+        let too_precise = Err("EUSD has at most 6 decimal places".to_owned());
+        let negative = Err("the value can't be negative".to_owned());
+        let overflow = Err("u64 overflow".to_owned());
+        let info = eusd_info();
+        for (scaled, expected) in [
+            ("0", Ok(0)),
+            ("0.000001", Ok(1)),
+            ("1.234567", Ok(1_234_567)),
+            ("1.2345670000", Ok(1_234_567)),
+            ("1.2345678", too_precise.clone()),
+            ("0.0000005", too_precise),
+            ("-1", negative.clone()),
+            ("-0.000001", negative),
+            ("18446744073709.551615", Ok(u64::MAX)),
+            ("18446744073709.551616", overflow),
+        ] {
+            assert_eq!(info.try_decimal_to_u64(dec(scaled)), expected, "{scaled}");
+        }
+    }
+
+    #[test]
+    fn computed_decimals_round_to_the_nearest_unit() {
+        let info = eusd_info();
+        for (scaled, expected) in [
+            ("1.2345674", Ok(1_234_567)),
+            ("1.2345676", Ok(1_234_568)),
+            ("0.0000004", Ok(0)),
+            ("-1", Err("the value can't be negative".to_owned())),
+        ] {
+            assert_eq!(
+                info.try_decimal_to_u64_rounded(dec(scaled)),
+                expected,
+                "{scaled}"
+            );
+        }
+        assert_eq!(info.try_scaled_to_u64("1,5"), Ok(1_500_000));
+        assert!(info.try_scaled_to_u64("1.2345678").is_err());
+        assert_eq!(info.try_scaled_to_u64_rounded("1.2345678"), Ok(1_234_568));
+    }
+
+    #[test]
+    fn values_are_formatted_exactly_with_grouped_digits() {
+        let mob = mob_info();
+        assert_eq!(mob.format_u64(u64::MAX), "18,446,744.073709551615 MOB");
+        assert_eq!(mob.format_u64(0), "0 MOB");
+        assert_eq!(mob.format_u64(1), "0.000000000001 MOB");
+        assert_eq!(mob.format_u64(1_500_000_000_000), "1.5 MOB");
+        assert_eq!(
+            eusd_info().format_u64(1_234_567_891_011),
+            "1,234,567.891011 EUSD"
+        );
+        assert_eq!(whole_eusd().format_u64(1_000), "1,000 EUSD");
+        assert_eq!(whole_eusd().format_u64(999), "999 EUSD");
+    }
+
+    #[test]
+    fn compact_values_cut_the_fraction() {
+        let mob = mob_info();
+        assert_eq!(mob.format_u64_compact(u64::MAX), "18,446,744.0737 MOB");
+        assert_eq!(mob.format_u64_compact(0), "0 MOB");
+        assert_eq!(mob.format_u64_compact(1), "<0.0001 MOB");
+        assert_eq!(mob.format_u64_compact(99_999_999), "<0.0001 MOB");
+        assert_eq!(mob.format_u64_compact(100_000_000), "0.0001 MOB");
+        assert_eq!(
+            eusd_info().format_u64_compact(1_234_567_891_011),
+            "1,234,567.891 EUSD"
+        );
+        assert_eq!(whole_eusd().format_u64_compact(1_000), "1,000 EUSD");
+    }
+
+    #[test]
+    fn amounts_of_unknown_tokens_are_shown_raw() {
+        let infos = [mob_info(), eusd_info()];
+        assert_eq!(
+            TokenInfo::format_amount(&infos, Amount::new(2_500_000, eusd())),
+            "2.5 EUSD"
+        );
+        assert_eq!(
+            TokenInfo::format_amount(&infos, Amount::new(7, TokenId::from(9))),
+            "7 (token id 9)"
+        );
+    }
+
+    #[test]
+    fn values_above_i64_max_convert_exactly() {
+        let above_i64 = i64::MAX as u64 + 1;
+        assert_eq!(
+            whole_mob().try_u64_to_decimal(above_i64),
+            Ok(Decimal::from(above_i64))
+        );
+        assert_eq!(
+            mob_info().try_u64_to_decimal(u64::MAX),
+            Ok(dec("18446744.073709551615"))
+        );
+        let max = ScaledAmount::new(u64::MAX, MOB);
+        assert_eq!(max.display(&mob_info()), "18446744.073709551615");
+        assert_eq!(
+            ScaledAmount::from_decimal(max.to_decimal(&mob_info()).unwrap(), &mob_info()),
+            Ok(max)
+        );
+    }
+
+    #[test]
+    fn quotes_above_i64_max_are_priced_exactly() {
+        let above_i64 = i64::MAX as u64 + 1;
+        let quote = ValidatedQuote::swap_fixture(
+            0,
+            1,
+            Amount::new(above_i64, MOB),
+            Amount::new(above_i64 + above_i64 / 2, eusd()),
+            false,
+        );
+        let info = quote
+            .get_quote_info(MOB, eusd(), &[whole_mob(), whole_eusd()])
+            .unwrap();
+        assert!(matches!(info.quote_side, QuoteSide::Ask));
+        assert_eq!(info.volume, Decimal::from(above_i64));
+        assert_eq!(info.price, dec("1.5"));
+    }
+
+    #[test]
+    fn quotes_are_unlisted_only_if_the_page_would_show_them() {
+        let ours = full_quote(1, 1_000, 2_000);
+        let cheaper = full_quote(2, 1_000, 1_000);
+        let pricier = full_quote(3, 1_000, 3_000);
+        // Listed, whatever else the page holds
+        assert!(!ours.is_unlisted_in(&[cheaper.clone(), ours.clone()], true));
+        assert!(!ours.is_unlisted_in(&[ours.clone(), pricier.clone()], false));
+        // Left out of the whole book
+        assert!(ours.is_unlisted_in(&[], true));
+        assert!(ours.is_unlisted_in(&[cheaper.clone()], true));
+        // Left out of a full page which holds a worse price
+        assert!(ours.is_unlisted_in(&[cheaper.clone(), pricier], false));
+        // Left out of a full page of better or equal prices, maybe past the limit
+        assert!(!ours.is_unlisted_in(&[cheaper, full_quote(4, 1_000, 2_000)], false));
     }
 }
@@ -0,0 +1,180 @@
+//! View-only account files, for --watch-only. They hold the view private key and
+//! the spend public key of an account, which are enough to see its balances and
+//! incoming payments, but not its spend private key, so nothing can be spent with
+//! them even if the machine running the buddy is compromised.
+//!
+//! A view-only account file is json with both keys hex encoded:
+//! `{"view_private_key": "...", "spend_public_key": "..."}`.
+
+use crate::is_encrypted_keyfile;
+use displaydoc::Display;
+use mc_account_keys::ViewAccountKey;
+use mc_crypto_keys::{RistrettoPrivate, RistrettoPublic};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// The fields of the keyfiles which hold the spend key, which a view-only account
+/// file must not have
+const SPEND_KEY_FIELDS: &[&str] = &["mnemonic", "root_entropy", "spend_private_key"];
+
+/// An error reading a view-only account file. None of them include the contents
+/// of the file.
+#[derive(Clone, Debug, Display, Eq, PartialEq)]
+pub enum ViewOnlyKeyfileError {
+    /// Keyfile io: {0}
+    Io(String),
+    /// The keyfile holds the spend key, --watch-only needs a view-only account file
+    SpendKey,
+    /// Invalid view-only account file: {0}
+    Invalid(String),
+}
+
+/// The json of a view-only account file
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ViewOnlyKeyfile {
+    /// The hex view private key
+    view_private_key: String,
+    /// The hex spend public key
+    spend_public_key: String,
+}
+
+/// Read the view-only account file at `path`. A keyfile which holds the spend key,
+/// in plaintext or encrypted, is refused rather than loaded.
+pub fn read_view_only_keyfile(path: &Path) -> Result<ViewAccountKey, ViewOnlyKeyfileError> {
+    if is_encrypted_keyfile(path) {
+        return Err(ViewOnlyKeyfileError::SpendKey);
+    }
+    let bytes = Zeroizing::new(
+        std::fs::read(path)
+            .map_err(|err| ViewOnlyKeyfileError::Io(format!("{}: {err}", path.display())))?,
+    );
+    view_account_key_from_json(&bytes)
+}
+
+/// Parse the json of a view-only account file
+pub fn view_account_key_from_json(json: &[u8]) -> Result<ViewAccountKey, ViewOnlyKeyfileError> {
+    // The parse errors could quote the keys, so they aren't passed on
+    let value: serde_json::Value = serde_json::from_slice(json)
+        .map_err(|_| ViewOnlyKeyfileError::Invalid("not json".to_owned()))?;
+    if SPEND_KEY_FIELDS
+        .iter()
+        .any(|field| value.get(field).is_some())
+    {
+        return Err(ViewOnlyKeyfileError::SpendKey);
+    }
+    let keyfile: ViewOnlyKeyfile = serde_json::from_value(value).map_err(|_| {
+        ViewOnlyKeyfileError::Invalid(
+            "expected view_private_key and spend_public_key only".to_owned(),
+        )
+    })?;
+
+    let decode = |field: &str, hex_str: &str| {
+        hex::decode(hex_str)
+            .map(Zeroizing::new)
+            .map_err(|_| ViewOnlyKeyfileError::Invalid(format!("{field} is not hex")))
+    };
+    let view_private_key = RistrettoPrivate::try_from(
+        decode("view_private_key", &keyfile.view_private_key)?.as_slice(),
+    )
+    .map_err(|_| ViewOnlyKeyfileError::Invalid("view_private_key is not a key".to_owned()))?;
+    let spend_public_key = RistrettoPublic::try_from(
+        decode("spend_public_key", &keyfile.spend_public_key)?.as_slice(),
+    )
+    .map_err(|_| ViewOnlyKeyfileError::Invalid("spend_public_key is not a key".to_owned()))?;
+    Ok(ViewAccountKey::new(view_private_key, spend_public_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A canonical scalar, and the compressed ristretto basepoint
+    const VIEW_PRIVATE_KEY: &str =
+        "0101010101010101010101010101010101010101010101010101010101010101";
+    const SPEND_PUBLIC_KEY: &str =
+        "e2f2ae0a6abc4e71a884a961c500515f58e30b6aa582dd8db6a65945e08d2d76";
+
+    fn parse(json: &str) -> Result<ViewAccountKey, ViewOnlyKeyfileError> {
+        view_account_key_from_json(json.as_bytes())
+    }
+
+    #[test]
+    fn view_only_files_hold_the_view_private_and_spend_public_keys() {
+        let key = parse(&keys(VIEW_PRIVATE_KEY, SPEND_PUBLIC_KEY).to_string()).unwrap();
+        let spend_public_key = hex::decode(SPEND_PUBLIC_KEY).unwrap();
+        assert_eq!(
+            key.spend_public_key(),
+            &RistrettoPublic::try_from(spend_public_key.as_slice()).unwrap()
+        );
+    }
+
+    #[test]
+    fn keyfiles_with_the_spend_key_are_refused() {
+        for field in SPEND_KEY_FIELDS {
+            let mut json = keys(VIEW_PRIVATE_KEY, SPEND_PUBLIC_KEY);
+            json[*field] = "secret".into();
+            assert_eq!(
+                parse(&json.to_string()),
+                Err(ViewOnlyKeyfileError::SpendKey)
+            );
+        }
+        assert_eq!(
+            parse(r#"{"mnemonic": "legal winner thank year wave sausage"}"#),
+            Err(ViewOnlyKeyfileError::SpendKey)
+        );
+    }
+
+    #[test]
+    fn encrypted_keyfiles_are_refused_unread() {
+        let path = std::env::temp_dir().join(format!(
+            "buddy-encrypted-view-only-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"{"format": "mobilecoind-buddy-encrypted-keyfile", "version": 1,
+                "salt": "00", "nonce": "00", "ciphertext": "00"}"#,
+        )
+        .unwrap();
+        let result = read_view_only_keyfile(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result, Err(ViewOnlyKeyfileError::SpendKey));
+    }
+
+    #[test]
+    fn invalid_view_only_files_are_invalid() {
+        let with = |view: &str, spend: &str| parse(&keys(view, spend).to_string());
+        assert!(matches!(
+            with("not hex", SPEND_PUBLIC_KEY),
+            Err(ViewOnlyKeyfileError::Invalid(_))
+        ));
+        assert!(matches!(
+            with(VIEW_PRIVATE_KEY, &"ff".repeat(32)),
+            Err(ViewOnlyKeyfileError::Invalid(_))
+        ));
+        assert!(matches!(
+            with(VIEW_PRIVATE_KEY, "e2f2"),
+            Err(ViewOnlyKeyfileError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse(&format!(r#"{{"view_private_key": "{VIEW_PRIVATE_KEY}"}}"#)),
+            Err(ViewOnlyKeyfileError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse("[1, 2]"),
+            Err(ViewOnlyKeyfileError::Invalid(_))
+        ));
+        assert!(matches!(parse("{"), Err(ViewOnlyKeyfileError::Invalid(_))));
+    }
+
+    #[test]
+    fn missing_view_only_files_are_io_errors() {
+        let path = std::env::temp_dir().join("buddy-no-such-view-only.json");
+        assert!(matches!(
+            read_view_only_keyfile(&path),
+            Err(ViewOnlyKeyfileError::Io(err)) if err.contains("buddy-no-such-view-only")
+        ));
+    }
+}
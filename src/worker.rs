@@ -1,78 +1,647 @@
-use crate::{Amount, Config, ConnectionUriGrpcioChannel, TokenId, TokenInfo, ValidatedQuote};
-use deqs_api::{deqs as d_api, deqs_grpc::DeqsClientApiClient as DeqsClient};
+use crate::{
+    check_recipient_network, describe_redeem_error, describe_rpc_error, gift_account_key,
+    is_encrypted_keyfile, is_spent_key_image_error, mid_price, preflight, read_view_only_keyfile,
+    AccessDenied, AccessPolicy, AccessToken, Amount, AuthToken, BalanceSnapshot, CommandId,
+    CommandInfo, CommandLog, CommandStatus, Config, ConnectionHealth, ConnectionUriGrpcioChannel,
+    DeqsSubmitMode, DetailedBalance, Direction, ErrorEntry, ErrorQueue, FillMode, GiftCode,
+    GrpcProxy, GrpcTuning, HealthStatus, HeldOffer, HeldOffers, History, HistoryEntry,
+    HistoryFilter, HoldHandle, IndicativePrices, InputMatch, InputPreparationError,
+    IssuedSubaddress, OfferId, OfferStatus, OfferTracker, OperationProgress, Payment,
+    PendingTransaction, PreconditionFailed, PriceSample, PriceSampler, QuoteBook, QuoteFilter,
+    QuoteId, QuoteSide, QuoteStream, QuoteUpdate, Recipient, RetentionPolicies, RetryPolicy,
+    RpcTimeouts, SavedOffer, SavedOfferStatus, ScaledAmount, SciFill, Severity, StreamEvent,
+    SubaddressBalances, SubmitOutcome, TlsIdentity, TokenId, TokenInfo, TokenRegistry,
+    TrackedOffer, TwoSidedQuote, TxOptions, UtxoInfo, ValidatedQuote, ViewOnlyKeyfileError,
+    WorkerCommand,
+};
+use deqs_api::{deqs as d_api, deqs_grpc::DeqsClientApiClient as DeqsClient, DeqsClientUri};
 use displaydoc::Display;
-use grpcio::ChannelBuilder;
-use mc_account_keys::AccountKey;
-use mc_api::{external, printable::PrintableWrapper};
+use grpcio::{CallOption, ChannelBuilder, RpcStatusCode};
+use mc_account_keys::{AccountKey, ViewAccountKey};
+use mc_api::{
+    external,
+    printable::{PaymentRequest, PrintableWrapper},
+};
+use mc_crypto_ring_signature::KeyImage;
 use mc_mobilecoind_api::{self as mcd_api, mobilecoind_api_grpc::MobilecoindApiClient, TxStatus};
-use mc_transaction_extra::SignedContingentInput;
+use mc_transaction_extra::{SignedContingentInput, SignedContingentInputAmounts};
 use mc_util_keyfile::read_keyfile;
-use std::collections::{HashMap, VecDeque};
+use mc_util_uri::ConnectionUri;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Mutex,
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    mpsc::{channel, Receiver, Sender},
+    Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
 };
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::{event, span, Level};
 
-const QUOTES_LIMIT: u64 = 10;
+// The first subaddress index handed out for receiving payments.
+// Subaddresses 0 and 1 were part of the monitor before receive subaddresses existed.
+const FIRST_RECEIVE_SUBADDRESS: u64 = 2;
+// How long preparing the input of an offer may take, including waiting for a
+// self-payment to land and sync.
+const INPUT_PREPARATION_TIMEOUT: Duration = Duration::from_secs(90);
+// The number of subaddresses the monitor covers, starting at 0.
+// Receive subaddresses are issued from this range.
+const NUM_SUBADDRESSES: u64 = FIRST_RECEIVE_SUBADDRESS + 1000;
+// How many times to try registering the account with mobilecoind, one second
+// apart, before reporting the failure
+const INIT_ATTEMPTS: u32 = 10;
+// Errors from mobilecoind which mean it no longer knows our monitor, as after it
+// was restarted with a fresh database (lowercase, without spaces)
+const MONITOR_LOST_ERRORS: &[&str] = &["monitoridnotfound", "monitornotfound"];
+
+// What the user sees when a deqs rejects our token, or that we sent none
+const DEQS_AUTH_FAILED: &str = "deqs authentication failed, check --deqs-auth-token";
+
+// Lock a mutex, also after a thread panicked holding it. The worker thread recovers
+// from panics, so the state it left is used rather than taking the app down.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Lock a RwLock for reading, also after a thread panicked holding it
+fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Lock a RwLock for writing, also after a thread panicked holding it
+fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Whether a deqs rejected the rpc for lack of valid credentials
+fn is_unauthenticated(err: &grpcio::Error) -> bool {
+    match err {
+        grpcio::Error::RpcFailure(status) => status.code() == RpcStatusCode::UNAUTHENTICATED,
+        _ => false,
+    }
+}
+
+// Whether an error from mobilecoind means it lost our monitor
+fn is_monitor_lost_error(err: &str) -> bool {
+    let normalized: String = err
+        .to_lowercase()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    MONITOR_LOST_ERRORS
+        .iter()
+        .any(|lost| normalized.contains(lost))
+}
+
+// An sci generated by mobilecoind: the proto sci, the decoded sci, and its validated amounts
+type GeneratedSci = (
+    external::SignedContingentInput,
+    SignedContingentInput,
+    SignedContingentInputAmounts,
+);
+
+/// How actively the user is looking at the app. This determines how often the
+/// worker polls the servers.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ActivityLevel {
+    /// The window is focused
+    #[default]
+    Active,
+    /// The window is visible but not focused
+    Background,
+    /// The window is minimized
+    Hidden,
+}
+
+/// The state of the deqs polling for a particular pair
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DeqsStatus {
+    /// No deqs was configured
+    NotConfigured,
+    /// We haven't successfully polled the quote book for this pair yet
+    Loading,
+    /// The most recent poll failed to reach any deqs
+    Unreachable(String),
+    /// We have a quote book for this pair
+    Ready,
+}
+
+/// How often the worker should poll each of the servers
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PollIntervals {
+    /// How often to poll mobilecoind for sync status and balances
+    pub mobilecoind: Duration,
+    /// How often to poll the deqs for quote books, or None if we shouldn't. Our own
+    /// key images are polled along with them.
+    pub deqs: Option<Duration>,
+    /// How often to refresh the unspent txos
+    pub utxos: Duration,
+    /// How often to check whether our live offers were filled or expired
+    pub offers: Duration,
+    /// How often to ask for the status of our pending transactions
+    pub pending: Duration,
+}
+
+impl PollIntervals {
+    /// How often to refresh the unspent txos while the user is active
+    pub const UTXOS: Duration = Duration::from_secs(2);
+}
+
+impl Default for PollIntervals {
+    fn default() -> Self {
+        Self {
+            mobilecoind: Duration::from_millis(20),
+            deqs: Some(Duration::from_millis(20)),
+            utxos: Self::UTXOS,
+            offers: OfferTracker::POLL_INTERVAL,
+            pending: PendingTransaction::POLL_INTERVAL,
+        }
+    }
+}
+
+impl From<&Config> for PollIntervals {
+    fn from(config: &Config) -> Self {
+        Self {
+            mobilecoind: Duration::from_millis(config.poll_interval_ms.max(1)),
+            deqs: Some(Duration::from_millis(config.quote_poll_interval_ms.max(1))),
+            ..Default::default()
+        }
+    }
+}
+
+/// A polling interval which lengthens while the polls find nothing new, up to
+/// MAX_IDLE, and snaps back once they do, or once the user does something
+#[derive(Clone, Copy, Debug, Default)]
+struct AdaptiveInterval {
+    /// How many polls in a row found nothing new
+    idle_polls: u32,
+}
+
+impl AdaptiveInterval {
+    /// The longest the interval gets while nothing changes
+    const MAX_IDLE: Duration = Duration::from_secs(2);
+
+    // The interval to use instead of `base`. It doubles with every idle poll, but
+    // never exceeds MAX_IDLE, unless `base` already does.
+    fn interval(&self, base: Duration) -> Duration {
+        if base >= Self::MAX_IDLE {
+            return base;
+        }
+        base.saturating_mul(1 << self.idle_polls.min(16))
+            .min(Self::MAX_IDLE)
+    }
+
+    // Record whether a poll found anything new
+    fn record(&mut self, changed: bool) {
+        self.idle_polls = if changed {
+            0
+        } else {
+            self.idle_polls.saturating_add(1)
+        };
+    }
+
+    // Go back to the base interval
+    fn reset(&mut self) {
+        self.idle_polls = 0;
+    }
+}
+
+impl ActivityLevel {
+    /// Get the polling intervals appropriate for this activity level, given those
+    /// to use while the user is active.
+    ///
+    /// If automated features which depend on fresh data are armed, we poll as if
+    /// the user is active regardless of what the window is doing.
+    pub fn poll_intervals(&self, automation_armed: bool, active: PollIntervals) -> PollIntervals {
+        let level = if automation_armed {
+            ActivityLevel::Active
+        } else {
+            *self
+        };
+        match level {
+            ActivityLevel::Active => active,
+            ActivityLevel::Background => PollIntervals {
+                mobilecoind: active.mobilecoind.max(Duration::from_secs(2)),
+                deqs: active.deqs.map(|deqs| deqs.max(Duration::from_secs(2))),
+                utxos: active.utxos.max(Duration::from_secs(2)),
+                ..active
+            },
+            ActivityLevel::Hidden => PollIntervals {
+                mobilecoind: active.mobilecoind.max(Duration::from_secs(30)),
+                deqs: None,
+                utxos: active.utxos.max(Duration::from_secs(30)),
+                offers: active.offers.max(Duration::from_secs(30)),
+                pending: active.pending.max(Duration::from_secs(30)),
+            },
+        }
+    }
+}
 
 /// The state and handle to the background worker, which owns the server connections.
 /// This object exposes various getters to help the UI render the correct data without
 /// blocking the UI thread, and allows for things like submitting a transaction.
 pub struct Worker {
     /// Our startup parameters
-    #[allow(unused)]
     config: Config,
     /// The connection to mobilecoind
-    mobilecoind_api_client: MobilecoindApiClient,
-    /// The connection to deqs (if any)
-    deqs_client: Option<DeqsClient>,
-    /// The account key holding our funds
+    mobilecoind_api_client: Supervised<MobilecoindApiClient>,
+    /// The connections to deqs (if any), in the order they were configured
+    deqs_connections: Vec<DeqsConnection>,
+    /// The keys of the account holding our funds
     #[allow(unused)]
-    account_key: AccountKey,
-    /// The monitor id we registered account with in mobilecoind
-    monitor_id: Vec<u8>,
-    /// The proto public address of this account
-    monitor_public_address: external::PublicAddress,
-    /// The b58 public address of this account
-    monitor_b58_address: String,
-    /// The minimum fees for this network
-    minimum_fees: HashMap<TokenId, u64>,
-    /// The chain id of the network
-    chain_id: String,
+    account_key: MonitorKey,
+    /// The symbols and decimals of tokens
+    token_registry: TokenRegistry,
     /// The state that is mutable after initialization (updated by worker thread)
     state: Arc<Mutex<WorkerState>>,
-    /// The worker thread handle
-    join_handle: Option<JoinHandle<()>>,
+    /// The parts of the state the UI reads every frame, as of the worker's last loop
+    published: Arc<RwLock<Published>>,
+    /// The queue of commands for the worker thread to execute
+    command_sender: Mutex<Sender<(CommandId, WorkerCommand)>>,
+    /// The worker thread handle, until the worker is shut down
+    join_handle: Mutex<Option<JoinHandle<()>>>,
     /// The stop requested flag to stop the worker
     stop_requested: Arc<AtomicBool>,
 }
 
+/// The payment a fee is estimated for
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct FeeEstimateKey {
+    value: u64,
+    token_id: TokenId,
+    recipient: String,
+    sender_subaddress: u64,
+    /// The coins the user selected to spend, if any
+    inputs: HashSet<KeyImage>,
+}
+
+/// A fee estimate for a payment. The result is None until the worker thread
+/// computed it, which it does once the payment stopped changing for DEBOUNCE.
+struct FeeEstimate {
+    key: FeeEstimateKey,
+    /// When the payment last changed
+    requested_at: Instant,
+    result: Option<Result<u64, String>>,
+}
+
+impl FeeEstimate {
+    /// How long the payment must stay the same before its fee is estimated, so
+    /// that typing an amount doesn't estimate the fee of every prefix
+    const DEBOUNCE: Duration = Duration::from_millis(300);
+
+    // How long until the estimate is due, None if it was already computed
+    fn due_in(&self, now: Instant) -> Option<Duration> {
+        if self.result.is_some() {
+            return None;
+        }
+        Some(Self::DEBOUNCE.saturating_sub(now.saturating_duration_since(self.requested_at)))
+    }
+}
+
+/// The pages of history the UI has loaded, for its current filter
+#[derive(Default)]
+struct HistoryView {
+    /// The filter the pages were loaded with, None before the UI asked for any
+    filter: Option<HistoryFilter>,
+    /// The entries loaded so far, newest first
+    entries: Vec<HistoryEntry>,
+    /// The number of records to skip to read the next page
+    next_skip: usize,
+    /// Whether all entries which pass the filter were loaded
+    exhausted: bool,
+    /// Set when the UI wants the next page
+    page_requested: bool,
+}
+
+/// A grpc client whose channel is rebuilt after RECONNECT_AFTER transport failures
+/// in a row. Channels sometimes stay broken after the computer slept, even once
+/// the server is reachable again.
+#[derive(Clone)]
+struct Supervised<C> {
+    /// The client, which is swapped for a new one when the channel is rebuilt
+    client: Arc<RwLock<C>>,
+    /// Makes a client on a new channel
+    connect: Arc<dyn Fn() -> C + Send + Sync>,
+    /// How many rpcs in a row failed at the transport level
+    transport_failures: Arc<AtomicU32>,
+    /// Whether the channel was rebuilt, and no rpc succeeded on it yet
+    reconnecting: Arc<AtomicBool>,
+}
+
+impl<C: Clone> Supervised<C> {
+    /// How many transport failures in a row make us rebuild the channel
+    const RECONNECT_AFTER: u32 = 5;
+
+    fn new(connect: impl Fn() -> C + Send + Sync + 'static) -> Self {
+        Self {
+            client: Arc::new(RwLock::new(connect())),
+            connect: Arc::new(connect),
+            transport_failures: Default::default(),
+            reconnecting: Default::default(),
+        }
+    }
+
+    // The client on the current channel
+    fn get(&self) -> C {
+        read_lock(&self.client).clone()
+    }
+
+    // Record a failed rpc. Returns whether the channel was rebuilt.
+    fn record_failure(&self, err: &grpcio::Error) -> bool {
+        let transport = match err {
+            grpcio::Error::RpcFailure(status) => matches!(
+                status.code(),
+                RpcStatusCode::UNAVAILABLE | RpcStatusCode::DEADLINE_EXCEEDED
+            ),
+            _ => false,
+        };
+        // Any other error means the server answered
+        if !transport {
+            self.transport_failures.store(0, Ordering::SeqCst);
+            return false;
+        }
+        let failures = self.transport_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures < Self::RECONNECT_AFTER {
+            return false;
+        }
+        self.transport_failures.store(0, Ordering::SeqCst);
+        *write_lock(&self.client) = (self.connect)();
+        self.reconnecting.store(true, Ordering::SeqCst);
+        true
+    }
+
+    // Record a successful rpc. Returns whether it is the first since the channel
+    // was rebuilt.
+    fn record_success(&self) -> bool {
+        self.transport_failures.store(0, Ordering::SeqCst);
+        self.reconnecting.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// A connection to one of the configured deqs
+#[derive(Clone)]
+struct DeqsConnection {
+    /// The uri we connected to
+    uri: DeqsClientUri,
+    /// The grpc client
+    client: Supervised<DeqsClient>,
+    /// The proxy the client connects through, if any
+    proxy: Option<GrpcProxy>,
+    /// The bearer token the deqs requires, if any
+    auth_token: Option<AuthToken>,
+}
+
+impl DeqsConnection {
+    // The call options of an rpc to this deqs, with the token if there is one
+    fn call_options(&self, options: CallOption) -> CallOption {
+        match self.auth_token.as_ref() {
+            Some(auth_token) => auth_token.authorize(options),
+            None => options,
+        }
+    }
+
+    // Describe an rpc error from this deqs for the user, without the token
+    fn describe_error(&self, err: &grpcio::Error) -> String {
+        let message = if is_unauthenticated(err) {
+            DEQS_AUTH_FAILED.to_string()
+        } else {
+            describe_rpc_error("the deqs", err)
+        };
+        let message = match self.proxy.as_ref() {
+            Some(proxy) => proxy.annotate(message, err),
+            None => message,
+        };
+        match self.auth_token.as_ref() {
+            Some(auth_token) => auth_token.redact(&message),
+            None => message,
+        }
+    }
+}
+
+/// What the worker thread executes commands with: the connections, and the state
+/// the commands update
+#[derive(Clone)]
+struct CommandContext {
+    /// The keys of the account, to register it with mobilecoind again if it loses
+    /// our monitor
+    account_key: MonitorKey,
+    /// The connection to mobilecoind
+    mobilecoind_api_client: Supervised<MobilecoindApiClient>,
+    /// The connections to deqs (if any), in the order they were configured
+    deqs_connections: Vec<DeqsConnection>,
+    /// Which of the deqs new quotes are submitted to
+    deqs_submit_mode: DeqsSubmitMode,
+    /// The chain id mobilecoind must report, if one was given
+    expected_chain_id: Option<String>,
+    /// The proxy the connections go through, if any
+    grpc_proxy: Option<GrpcProxy>,
+    /// The directory the history and saved offers are kept in
+    data_dir: PathBuf,
+    /// The state that is mutable after initialization
+    state: Arc<Mutex<WorkerState>>,
+    /// The parts of the state the UI reads every frame, as of the worker's last loop
+    published: Arc<RwLock<Published>>,
+    /// The metadata of the tokens, to read the prices of their books
+    token_registry: TokenRegistry,
+}
+
+/// Copies of the parts of WorkerState which the UI reads every frame. The worker
+/// thread publishes them once per loop, so that the UI doesn't wait for WorkerState
+/// while the worker updates balances or replaces quote books.
+#[derive(Default)]
+struct Published {
+    /// Synced blocks on this monitor id, and total blocks in the ledger
+    sync_progress: (u64, u64),
+    /// The balances of the monitored account
+    balances: BalanceSnapshot,
+    /// The quote books, which share their quotes with those in WorkerState
+    quote_books: HashMap<(TokenId, TokenId), QuoteBook>,
+}
+
+impl Published {
+    // Copy the parts of the state which are published
+    fn publish(state: &Mutex<WorkerState>, published: &RwLock<Published>) {
+        let fresh = {
+            let st = lock(&state);
+            Published {
+                sync_progress: (st.synced_blocks, st.total_blocks),
+                balances: st.balance_snapshot(),
+                quote_books: st.quote_books.clone(),
+            }
+        };
+        *write_lock(&published) = fresh;
+    }
+}
+
 #[derive(Default)]
 struct WorkerState {
+    /// Whether the account was registered with mobilecoind yet
+    pub init_status: InitStatus,
+    /// The monitor id we registered account with in mobilecoind, which changes if
+    /// mobilecoind loses our monitor and we register the account again
+    pub monitor_id: Vec<u8>,
+    /// The proto public address of this account
+    pub monitor_public_address: external::PublicAddress,
+    /// The b58 public address of this account
+    pub monitor_b58_address: String,
+    /// The chain id of the network
+    pub chain_id: String,
     /// Synced blocks on this monitor id
     pub synced_blocks: u64,
     /// Total blocks in the ledger
     pub total_blocks: u64,
     /// The current balance of this account
     pub balance: HashMap<TokenId, u64>,
+    /// Whether the balance has been fetched at least once
+    pub balance_fetched: bool,
+    /// The most recently known minimum fees for this network
+    pub minimum_fees: HashMap<TokenId, u64>,
     /// The current token ids to poll for deqs
     /// Empty if the user is not trying to swap right now
     pub get_quotes_token_ids: Option<(TokenId, TokenId)>,
-    /// The quotes we currently know about in the quote books
-    pub quote_books: HashMap<(TokenId, TokenId), Vec<ValidatedQuote>>,
+    /// Which quotes of those books the user wants to see
+    pub quote_filter: QuoteFilter,
+    /// The quotes we currently know about in the quote books, merged across all deqs
+    pub quote_books: HashMap<(TokenId, TokenId), QuoteBook>,
+    /// The generation of the most recently stored quote book
+    pub last_book_generation: u64,
+    /// When each of the quote books was last polled successfully
+    pub quote_books_polled: HashMap<(TokenId, TokenId), Instant>,
+    /// The key images of our unspent txos of the selected pair, refreshed before
+    /// every deqs poll, to recognize our own quotes in the books
+    pub my_key_images: HashSet<KeyImage>,
+    /// Whether the input of each quote the deqs serve for a book was already spent,
+    /// for the quotes we checked with mobilecoind
+    pub quote_inputs_spent: HashMap<(TokenId, TokenId), HashMap<KeyImage, bool>>,
     /// A buffer of errors
-    pub errors: VecDeque<String>,
+    pub errors: ErrorQueue,
+    /// How actively the user is looking at the app
+    pub activity_level: ActivityLevel,
+    /// Set when the worker should poll everything right away, regardless of intervals
+    pub refresh_requested: bool,
+    /// The next block of the monitor when the balances were last fetched, None if
+    /// they should be fetched at the next poll whether or not it moved
+    pub balances_block: Option<u64>,
+    /// The error from the most recent deqs poll, if it failed to reach any deqs
+    pub deqs_error: Option<String>,
+    /// How the recent polls of mobilecoind went
+    pub mobilecoind_health: ConnectionHealth,
+    /// How the recent polls of the deqs went
+    pub deqs_health: ConnectionHealth,
+    /// The offers we submitted during this session
+    pub offers: OfferTracker,
+    /// The offers which are held for preview before submission
+    pub held_offers: HeldOffers,
+    /// The key images of inputs used by an offer which is being generated, held or tracked,
+    /// so that no other offer uses the same input
+    pub reserved_inputs: HashSet<KeyImage>,
+    /// The commands queued with the worker thread, and their status
+    pub commands: CommandLog,
+    /// The payments we submitted, until a little while after they resolved
+    pub pending_transactions: Vec<PendingTransaction>,
+    /// The most recently requested fee estimate
+    pub fee_estimate: Option<FeeEstimate>,
+    /// The balances broken down by subaddress
+    pub subaddress_balances: SubaddressBalances,
+    /// The history pages loaded for the UI
+    pub history_view: HistoryView,
+    /// The unspent txos on subaddress 0, for the tokens in utxos_requested
+    pub utxos: HashMap<TokenId, Vec<UtxoInfo>>,
+    /// The tokens whose unspent txos the UI asked for
+    pub utxos_requested: HashSet<TokenId>,
+    /// The subaddresses issued for receiving payments, by index, with their b58 address
+    pub issued_subaddresses: BTreeMap<u64, String>,
+    /// The b58 address of the subaddress issued last, with the command which issued it
+    pub last_issued_subaddress: Option<(CommandId, String)>,
+    /// How payments, swaps and offers which fail transiently are retried
+    pub retry_policy: RetryPolicy,
+    /// How many quotes to request from each deqs, for each side of the book
+    pub quotes_limit: u64,
+    /// How often to poll the servers while the user is active
+    pub poll_intervals: PollIntervals,
+    /// How long rpcs may take before they are given up on
+    pub rpc_timeouts: RpcTimeouts,
+    /// The deqs, by index, which rejected our credentials at the last poll. The
+    /// user is told when a deqs is added, rather than at every poll.
+    pub deqs_auth_failed: HashSet<usize>,
+    /// How old a quote book may be before swaps against it are refused
+    pub max_quote_age: Duration,
+    /// The gift codes created during this session
+    pub gift_codes: Vec<GiftCode>,
+    /// The phase of the offer the worker thread is generating or submitting, if any
+    pub active_operation: Option<OperationProgress>,
+    /// The mid-price of each token in USD, kept up to date whichever pair is active
+    pub indicative_prices: IndicativePrices,
+    /// The mid-prices of the active pairs sampled during the session
+    pub price_history: PriceSampler,
+    /// A notice for the user about something the worker recovered from, and when
+    /// it was raised
+    pub notice: Option<(String, Instant)>,
+}
+
+impl WorkerState {
+    // How long before `now` the quote book of a pair was last polled successfully,
+    // or None if it never was
+    fn quote_book_age(&self, pair: (TokenId, TokenId), now: Instant) -> Option<Duration> {
+        self.quote_books_polled
+            .get(&pair)
+            .map(|polled| now.saturating_duration_since(*polled))
+    }
+
+    // Fetch the balances at the next poll, and poll right away, since something we
+    // did changed them
+    fn request_balance_refresh(&mut self) {
+        self.balances_block = None;
+        self.refresh_requested = true;
+    }
+
+    // Drop quotes from the merged books right away, rather than waiting for the deqs
+    // which served them to stop listing them
+    fn drop_quotes(&mut self, ids: &[QuoteId]) {
+        for book in self.quote_books.values_mut() {
+            if !book.iter().any(|quote| ids.contains(&quote.id)) {
+                continue;
+            }
+            let quotes = book
+                .iter()
+                .filter(|quote| !ids.contains(&quote.id))
+                .cloned()
+                .collect();
+            self.last_book_generation += 1;
+            *book = QuoteBook {
+                quotes: Arc::new(quotes),
+                generation: self.last_book_generation,
+            };
+        }
+    }
+
+    // The current balances, for the worker's own checks
+    fn balance_snapshot(&self) -> BalanceSnapshot {
+        BalanceSnapshot {
+            balances: self.balance.clone(),
+            fetched: self.balance_fetched,
+            synced_blocks: self.synced_blocks,
+            total_blocks: self.total_blocks,
+        }
+    }
+
+    // The current balances of a single subaddress, which is what a payment from
+    // that subaddress can spend
+    fn balance_snapshot_of(&self, subaddress_index: u64) -> BalanceSnapshot {
+        BalanceSnapshot {
+            balances: self.subaddress_balances.balances_of(subaddress_index),
+            fetched: self.balance_fetched,
+            synced_blocks: self.synced_blocks,
+            total_blocks: self.total_blocks,
+        }
+    }
 }
 
 impl Drop for Worker {
     fn drop(&mut self) {
-        if let Some(join_handle) = self.join_handle.take() {
-            self.stop_requested.store(true, Ordering::SeqCst);
-            join_handle.join().expect("worker thread panicked");
-        }
+        self.shutdown();
     }
 }
 
@@ -91,743 +660,5491 @@ struct MobilecoindSetupData {
 }
 
 impl Worker {
-    /// Initialize a new worker from config
+    /// How long a notice from get_notice is shown
+    pub const NOTICE_DURATION: Duration = Duration::from_secs(10);
+    /// How long shutdown waits for the command being executed, like a submission,
+    /// before leaving the worker thread behind
+    pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+    // How long to wait before restarting the polling loop after it panicked
+    const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+    /// Initialize a new worker from config.
+    ///
+    /// This returns right away, and the account is registered with mobilecoind on the
+    /// worker thread; see init_status.
     pub fn new(config: Config) -> Result<Arc<Self>, WorkerInitError> {
-        // Search for keyfile and load it
-        let account_key = read_keyfile(config.keyfile.clone()).expect("Could not load keyfile");
+        // A watch-only wallet never loads the spend key
+        if config.watch_only {
+            let view_account_key = read_view_only_keyfile(&config.keyfile).map_err(|err| {
+                WorkerInitError::ViewOnlyKeyfile(format!("{}: {err}", config.keyfile.display()))
+            })?;
+            return Self::start(config, MonitorKey::ViewOnly(view_account_key));
+        }
 
-        // Set up the gRPC connection to the mobilecoind client
-        // Note: choice of 2 completion queues here is not very deliberate
-        let grpc_env = Arc::new(grpcio::EnvBuilder::new().cq_count(2).build());
-        let ch = ChannelBuilder::default_channel_builder(grpc_env.clone())
-            .connect_to_uri(&config.mobilecoind_uri);
+        // Search for keyfile and load it. An encrypted one is decrypted by the caller,
+        // see with_account_key.
+        if is_encrypted_keyfile(&config.keyfile) {
+            return Err(WorkerInitError::Keyfile(format!(
+                "{} is encrypted, its passphrase is needed",
+                config.keyfile.display()
+            )));
+        }
+        let account_key = read_keyfile(config.keyfile.clone()).map_err(|err| {
+            WorkerInitError::Keyfile(format!("{}: {err}", config.keyfile.display()))
+        })?;
+        Self::with_account_key(config, account_key)
+    }
 
-        let mobilecoind_api_client = MobilecoindApiClient::new(ch);
+    /// Initialize a new worker from config, with an account key which was already
+    /// loaded, like one decrypted from an encrypted keyfile.
+    ///
+    /// Like new, this returns right away. A watch-only wallet refuses the account
+    /// key, since it holds the spend key.
+    pub fn with_account_key(
+        config: Config,
+        account_key: AccountKey,
+    ) -> Result<Arc<Self>, WorkerInitError> {
+        if config.watch_only {
+            return Err(WorkerInitError::ViewOnlyKeyfile(
+                ViewOnlyKeyfileError::SpendKey.to_string(),
+            ));
+        }
+        Self::start(config, MonitorKey::Full(account_key))
+    }
 
-        let mut retries = 10;
-        let MobilecoindSetupData {
-            monitor_id,
-            monitor_public_address,
-            monitor_b58_address,
-            minimum_fees,
-            chain_id,
-        } = loop {
-            match Self::try_new_mobilecoind(&mobilecoind_api_client, &account_key) {
-                Ok(result) => break result,
-                Err(err) => event!(Level::ERROR, "Initialization failed, will retry: {}", err),
-            }
-            if retries == 0 {
-                return Err(WorkerInitError::Mobilecoind);
-            }
-            retries -= 1;
-            std::thread::sleep(Duration::from_millis(1000));
+    // Start the worker thread, which registers the account with mobilecoind
+    fn start(config: Config, account_key: MonitorKey) -> Result<Arc<Self>, WorkerInitError> {
+        let token_registry = match config.token_metadata.as_ref() {
+            Some(path) => TokenRegistry::load(path).map_err(WorkerInitError::TokenMetadata)?,
+            None => TokenRegistry::default(),
         };
 
-        let deqs_client = config.deqs_uri.as_ref().map(|uri| {
-            let ch = ChannelBuilder::default_channel_builder(grpc_env).connect_to_uri(uri);
+        // Client certificates are only presented over TLS
+        let mobilecoind_identity = Self::load_tls_identity(
+            config.mobilecoind_tls_identity.as_deref(),
+            config.mobilecoind_uri.use_tls(),
+            "--mobilecoind-tls-identity",
+        )?;
+        let deqs_identity = Self::load_tls_identity(
+            config.deqs_tls_identity.as_deref(),
+            config.deqs_uri.iter().all(|uri| uri.use_tls()),
+            "--deqs-tls-identity",
+        )?;
 
-            DeqsClient::new(ch)
-        });
+        // Set up the gRPC connection to the mobilecoind client
+        // Note: choice of 2 completion queues here is not very deliberate
+        let grpc_proxy = GrpcProxy::from_flag_or_env(config.grpc_proxy.as_deref())
+            .map_err(WorkerInitError::Proxy)?;
+        if let Some(proxy) = grpc_proxy.as_ref() {
+            event!(Level::INFO, "connecting through proxy {}", proxy);
+        }
+        let grpc_tuning = GrpcTuning::from(&config);
+        let grpc_env = Arc::new(grpcio::EnvBuilder::new().cq_count(2).build());
+        let mobilecoind_api_client = {
+            let grpc_env = grpc_env.clone();
+            let grpc_proxy = grpc_proxy.clone();
+            let uri = config.mobilecoind_uri.clone();
+            Supervised::new(move || {
+                let ch = ChannelBuilder::default_channel_builder(
+                    grpc_env.clone(),
+                    &grpc_tuning,
+                    grpc_proxy.as_ref(),
+                )
+                .connect_to_uri_with_identity(&uri, mobilecoind_identity.as_ref());
+                MobilecoindApiClient::new(ch)
+            })
+        };
+
+        let deqs_connections: Vec<DeqsConnection> = config
+            .deqs_uri
+            .iter()
+            .map(|uri| {
+                let grpc_env = grpc_env.clone();
+                let channel_proxy = grpc_proxy.clone();
+                let deqs_identity = deqs_identity.clone();
+                let channel_uri = uri.clone();
+                DeqsConnection {
+                    uri: uri.clone(),
+                    proxy: grpc_proxy.clone(),
+                    client: Supervised::new(move || {
+                        let ch = ChannelBuilder::default_channel_builder(
+                            grpc_env.clone(),
+                            &grpc_tuning,
+                            channel_proxy.as_ref(),
+                        )
+                        .connect_to_uri_with_identity(&channel_uri, deqs_identity.as_ref());
+                        DeqsClient::new(ch)
+                    }),
+                    auth_token: config.deqs_auth_token.clone(),
+                }
+            })
+            .collect();
 
+        // The account is registered with mobilecoind on the worker thread, so that
+        // the UI can show the progress
         let state = Arc::new(Mutex::new(WorkerState {
-            total_blocks: 1,
+            // Unknown until mobilecoind is polled
+            total_blocks: 0,
+            retry_policy: RetryPolicy::from(&config),
+            quotes_limit: config.deqs_quotes_limit.max(1),
+            rpc_timeouts: RpcTimeouts::from(&config),
+            poll_intervals: PollIntervals::from(&config),
+            max_quote_age: Duration::from_secs(config.max_quote_age_secs),
+            price_history: PriceSampler::new(
+                Duration::from_secs(config.price_sample_interval_secs),
+                config.price_history_samples,
+            ),
             ..Default::default()
         }));
 
+        let (command_sender, command_receiver) = channel();
+
+        let published = Arc::new(RwLock::new(Published::default()));
+        Published::publish(&state, &published);
+
         let stop_requested = Arc::new(AtomicBool::default());
         let thread_stop_requested = stop_requested.clone();
-        let thread_monitor_id = monitor_id.clone();
-        let thread_mcd_client = mobilecoind_api_client.clone();
-        let thread_deqs_client = deqs_client.clone();
-        let thread_minimum_fees = minimum_fees.clone();
-        let thread_state = state.clone();
+        let thread_context = CommandContext {
+            account_key: account_key.clone(),
+            mobilecoind_api_client: mobilecoind_api_client.clone(),
+            deqs_connections: deqs_connections.clone(),
+            deqs_submit_mode: config.deqs_submit_mode,
+            expected_chain_id: config.expected_chain_id.clone(),
+            grpc_proxy: grpc_proxy.clone(),
+            data_dir: config.data_dir(),
+            state: state.clone(),
+            published: published.clone(),
+            token_registry: token_registry.clone(),
+        };
+        let thread_retention = RetentionPolicies::from(&config);
 
-        let join_handle = Some(std::thread::spawn(move || {
+        let join_handle = Mutex::new(Some(std::thread::spawn(move || {
             Self::worker_thread_entrypoint(
-                thread_monitor_id,
-                thread_mcd_client,
-                thread_deqs_client,
-                thread_minimum_fees,
-                thread_state,
+                thread_context,
+                thread_retention,
+                command_receiver,
                 thread_stop_requested,
             )
-        }));
+        })));
 
         Ok(Arc::new(Worker {
             config,
             mobilecoind_api_client,
-            deqs_client,
+            deqs_connections,
             account_key,
-            monitor_id,
-            monitor_public_address,
-            monitor_b58_address,
-            minimum_fees,
-            chain_id,
+            token_registry,
             state,
+            published,
+            command_sender: Mutex::new(command_sender),
             join_handle,
             stop_requested,
         }))
     }
 
-    /// Get the b58 address of the monitored account.
-    pub fn get_b58_address(&self) -> String {
-        self.monitor_b58_address.clone()
-    }
+    /// Stop the worker thread. The command being executed, if any, gets up to
+    /// SHUTDOWN_TIMEOUT to finish, and the commands queued behind it are reported as
+    /// dropped. If the thread doesn't stop in time, the commands it still had are
+    /// reported as abandoned. Does nothing if the worker was already shut down.
+    pub fn shutdown(&self) {
+        let join_handle = match lock(&self.join_handle).take() {
+            Some(join_handle) => join_handle,
+            None => return,
+        };
+        self.stop_requested.store(true, Ordering::SeqCst);
+        // Hanging up the command queue wakes the worker thread, if it is waiting
+        *lock(&self.command_sender) = channel().0;
 
-    /// Get the sync progress of the monitored account
-    pub fn get_sync_progress(&self) -> (u64, u64) {
-        let st = self.state.lock().unwrap();
-        (st.synced_blocks, st.total_blocks)
+        let deadline = Instant::now() + Self::SHUTDOWN_TIMEOUT;
+        while !join_handle.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        if !join_handle.is_finished() {
+            event!(
+                Level::WARN,
+                "worker thread didn't stop within {:?}, not waiting for it",
+                Self::SHUTDOWN_TIMEOUT
+            );
+            // Whatever the worker thread still had, it won't get to finish
+            let err = "worker is shutting down, command abandoned";
+            let mut st = lock(&self.state);
+            let abandoned = st.commands.fail_unfinished(err);
+            if abandoned > 0 {
+                event!(Level::WARN, "abandoned {} commands on shutdown", abandoned);
+                st.errors.push(Severity::Error, err.to_string());
+            }
+            return;
+        }
+        if join_handle.join().is_err() {
+            event!(Level::ERROR, "worker thread panicked");
+        }
     }
 
-    /// Get the token info of tokens known to us, and configured on this network
-    pub fn get_token_info(&self) -> Vec<TokenInfo> {
-        // Hard-coded symbol and decimals per token id
-        let result = vec![
-            TokenInfo {
-                token_id: 0.into(),
-                symbol: "MOB".to_string(),
-                fee: 9999,
-                decimals: 12,
-            },
-            TokenInfo {
-                token_id: 1.into(),
-                symbol: "EUSD".to_string(),
-                fee: 9999,
-                decimals: 6,
-            },
-            TokenInfo {
-                token_id: 8192.into(),
-                symbol: "FauxUSD".to_string(),
-                fee: 9999,
-                decimals: 6,
-            },
-        ];
-        // Filter by which of these are actually defined on the given network
-        result
-            .into_iter()
-            .filter_map(|mut info| {
-                if let Some(fee) = self.minimum_fees.get(&info.token_id) {
-                    info.fee = *fee;
-                    Some(info)
-                } else {
-                    None
-                }
-            })
-            .collect()
+    // Load the client certificate given with `flag`, if any, which needs every uri it
+    // is presented to to use TLS
+    fn load_tls_identity(
+        path: Option<&Path>,
+        use_tls: bool,
+        flag: &str,
+    ) -> Result<Option<TlsIdentity>, WorkerInitError> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        if !use_tls {
+            return Err(WorkerInitError::TlsIdentity(format!(
+                "{flag} needs a uri which uses TLS"
+            )));
+        }
+        TlsIdentity::load(path)
+            .map(Some)
+            .map_err(WorkerInitError::TlsIdentity)
     }
 
-    /// Get the chain id of the network
-    pub fn get_chain_id(&self) -> String {
-        self.chain_id.clone()
+    /// Get whether the account was registered with mobilecoind yet. Until it is
+    /// Ready, the worker has no monitor, balances or network information.
+    pub fn init_status(&self) -> InitStatus {
+        lock(&self.state).init_status.clone()
     }
 
-    /// Get the balances of the monitored account.
-    pub fn get_balances(&self) -> HashMap<TokenId, u64> {
-        self.state.lock().unwrap().balance.clone()
+    /// Try registering the account with mobilecoind again, if it failed
+    pub fn retry_init(&self) {
+        let mut st = lock(&self.state);
+        if matches!(st.init_status, InitStatus::Failed(_)) {
+            st.init_status = InitStatus::Connecting;
+        }
     }
 
-    /// Check if the worker has a deqs connection
-    pub fn has_deqs(&self) -> bool {
-        self.deqs_client.is_some()
+    /// Get the uri of mobilecoind, as configured
+    pub fn get_mobilecoind_uri(&self) -> String {
+        self.config.mobilecoind_uri.to_string()
     }
 
-    /// Ask the worker to get quotes for given token ids
-    pub fn get_quotes_for_token_ids(&self, tok1: TokenId, tok2: TokenId) {
-        self.state.lock().unwrap().get_quotes_token_ids = Some((tok1, tok2));
+    /// Get the directory the app keeps its files in
+    pub fn data_dir(&self) -> PathBuf {
+        self.config.data_dir()
     }
 
-    /// Tell the worker it can stop getting quotes
-    pub fn stop_quotes(&self) {
-        self.state.lock().unwrap().get_quotes_token_ids = None;
+    /// Get the address of the proxy the connections go through, if any
+    pub fn get_grpc_proxy(&self) -> Option<String> {
+        GrpcProxy::from_flag_or_env(self.config.grpc_proxy.as_deref())
+            .ok()
+            .flatten()
+            .map(|proxy| proxy.address())
     }
 
-    /// Get the quote book for a given pair
-    pub fn get_quote_book(&self, tok1: TokenId, tok2: TokenId) -> Vec<ValidatedQuote> {
-        self.state
-            .lock()
-            .unwrap()
-            .quote_books
-            .get(&(tok1, tok2))
-            .cloned()
-            .unwrap_or(Default::default())
+    /// Get how the recent polls of mobilecoind and the deqs went. The deqs health is
+    /// None if no deqs is configured.
+    pub fn get_connection_health(&self) -> (ConnectionHealth, Option<ConnectionHealth>) {
+        let st = lock(&self.state);
+        let deqs_health = if self.deqs_connections.is_empty() {
+            None
+        } else {
+            Some(st.deqs_health.clone())
+        };
+        (st.mobilecoind_health.clone(), deqs_health)
     }
 
-    /// Decode a b58 address
-    pub fn decode_b58_address(b58_address: &str) -> Result<external::PublicAddress, String> {
-        let printable_wrapper = PrintableWrapper::b58_decode(b58_address.to_owned())
-            .map_err(|err| format!("Invalid address: {err}"))?;
+    /// Get the b58 address of the monitored account.
+    pub fn get_b58_address(&self) -> String {
+        lock(&self.state).monitor_b58_address.clone()
+    }
 
-        if !printable_wrapper.has_public_address() {
-            return Err("not a public address".to_string());
-        };
+    // The monitor id we registered the account with in mobilecoind
+    fn monitor_id(&self) -> Vec<u8> {
+        lock(&self.state).monitor_id.clone()
+    }
 
-        Ok(printable_wrapper.get_public_address().clone())
+    /// Issue a fresh subaddress of the monitored account for receiving a payment,
+    /// so that payments to it can be told apart from other payments.
+    ///
+    /// The worker thread fetches its b58 address, which get_issued_subaddress returns
+    /// once the command was submitted. Funds received on it are included in
+    /// get_balances.
+    pub fn next_receive_subaddress(&self, caller: &AccessToken) -> Result<CommandId, AccessDenied> {
+        caller.require(AccessPolicy::READ_BALANCES)?;
+        Ok(self.queue_command(WorkerCommand::IssueReceiveSubaddress))
     }
 
-    /// Send money from the monitored account to the specified recipient
-    pub fn send(&self, value: u64, token_id: TokenId, recipient: String) {
-        span!(Level::INFO, "send payment");
-        event!(
-            Level::INFO,
-            "send: {} of {} to {}",
-            value,
-            *token_id,
-            recipient
-        );
+    /// Get the b58 address of the subaddress issued by a next_receive_subaddress
+    /// command, once it was issued, as long as no other was issued since
+    pub fn get_issued_subaddress(
+        &self,
+        caller: &AccessToken,
+        command_id: CommandId,
+    ) -> Result<Option<String>, AccessDenied> {
+        caller.require(AccessPolicy::READ_BALANCES)?;
+        Ok(lock(&self.state)
+            .last_issued_subaddress
+            .as_ref()
+            .filter(|(issued_by, _)| *issued_by == command_id)
+            .map(|(_, b58_address)| b58_address.clone()))
+    }
 
-        let receiver = match Self::decode_b58_address(&recipient) {
-            Ok(receiver) => receiver,
-            Err(err) => {
-                event!(Level::ERROR, "decoding b58: {}", err);
-                let mut st = self.state.lock().unwrap();
-                st.errors.push_back(err);
-                return;
-            }
-        };
+    /// Get the balances of each subaddress which received funds, as (index, balances)
+    pub fn get_balances_by_subaddress(
+        &self,
+        caller: &AccessToken,
+    ) -> Result<Vec<(u64, HashMap<TokenId, u64>)>, AccessDenied> {
+        caller.require(AccessPolicy::READ_BALANCES)?;
+        Ok(lock(&self.state).subaddress_balances.by_subaddress())
+    }
 
-        let mut outlay = mcd_api::Outlay::new();
-        outlay.value = value;
-        outlay.set_receiver(receiver);
+    /// Get the history entries which pass a filter, newest first, and whether there
+    /// may be more of them.
+    ///
+    /// Only the pages loaded so far are returned, call load_more_history to load the
+    /// next page. Changing the filter starts over from the first page. Pages are
+    /// loaded and filtered by the worker thread.
+    pub fn get_history(
+        &self,
+        caller: &AccessToken,
+        filter: HistoryFilter,
+    ) -> Result<(Vec<HistoryEntry>, bool), AccessDenied> {
+        caller.require(AccessPolicy::READ_BALANCES)?;
+        let mut st = lock(&self.state);
+        if st.history_view.filter.as_ref() != Some(&filter) {
+            st.history_view = HistoryView {
+                filter: Some(filter),
+                page_requested: true,
+                ..Default::default()
+            };
+        }
+        Ok((st.history_view.entries.clone(), !st.history_view.exhausted))
+    }
 
-        let mut req = mcd_api::SendPaymentRequest::new();
-        req.set_sender_monitor_id(self.monitor_id.clone());
-        req.set_outlay_list(vec![outlay].into());
-        req.token_id = *token_id;
+    /// Export the entire history as CSV, oldest first, returning the number of entries.
+    ///
+    /// Each row has the block index, timestamp, direction, token symbol, exact scaled
+    /// amount, fee and counterparty b58 address. mobilecoind doesn't report the timestamp,
+    /// fee or counterparty of a processed txo, so those columns are left empty for now.
+    ///
+    /// This reads the whole history file, so it should not be called from the UI thread.
+    pub fn export_history_csv(&self, caller: &AccessToken, path: &Path) -> Result<usize, String> {
+        caller
+            .require(AccessPolicy::READ_BALANCES)
+            .map_err(|err| err.to_string())?;
+        let entries = History::open(Self::history_path(
+            &self.config.data_dir(),
+            &self.monitor_id(),
+        ))
+        .and_then(|history| history.all())
+        .map_err(|err| format!("Reading history: {err}"))?;
+        let token_infos = self.get_token_info();
 
-        match self.mobilecoind_api_client.send_payment(&req) {
-            Ok(_) => {
-                event!(Level::INFO, "submitted payment successfully");
+        let mut csv = String::from("block,timestamp,direction,token,amount,fee,counterparty\n");
+        for entry in entries.iter() {
+            let (symbol, amount) = match token_infos
+                .iter()
+                .find(|info| info.token_id == entry.token_id)
+            {
+                Some(info) => (
+                    info.symbol.clone(),
+                    ScaledAmount::new(entry.value, entry.token_id).to_decimal(info)?,
+                ),
+                // Without decimals, the raw value is the only exact amount we can write
+                None => (
+                    format!("token {}", *entry.token_id),
+                    Decimal::from(entry.value),
+                ),
+            };
+            let direction = match entry.direction {
+                Direction::Sent => "sent",
+                Direction::Received => "received",
+            };
+            csv.push_str(&format!(
+                "{},,{},{},{},,\n",
+                entry.block, direction, symbol, amount
+            ));
+        }
+
+        std::fs::write(path, csv).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(entries.len())
+    }
+
+    /// Ask the worker to load the next page of history, see get_history
+    pub fn load_more_history(&self) {
+        let mut st = lock(&self.state);
+        if !st.history_view.exhausted {
+            st.history_view.page_requested = true;
+        }
+    }
+
+    /// Get the subaddresses issued for receiving payments, as (index, b58 address)
+    pub fn get_issued_subaddresses(&self) -> Vec<(u64, String)> {
+        lock(&self.state)
+            .issued_subaddresses
+            .iter()
+            .map(|(index, b58_address)| (*index, b58_address.clone()))
+            .collect()
+    }
+
+    /// Get the sync progress of the monitored account, as of the worker thread's last loop
+    pub fn get_sync_progress(&self) -> (u64, u64) {
+        read_lock(&self.published).sync_progress
+    }
+
+    /// Get the fraction of the ledger the monitored account synced, or None while the
+    /// number of blocks in the ledger is unknown
+    pub fn get_sync_progress_fraction(&self) -> Option<f32> {
+        let (synced_blocks, total_blocks) = self.get_sync_progress();
+        Self::sync_fraction(synced_blocks, total_blocks)
+    }
+
+    /// The fraction of `total_blocks` which `synced_blocks` is, clamped to [0, 1], since
+    /// the monitor may be ahead of a ledger which was just reorganized. None if there
+    /// are no blocks.
+    pub fn sync_fraction(synced_blocks: u64, total_blocks: u64) -> Option<f32> {
+        if total_blocks == 0 {
+            return None;
+        }
+        Some((synced_blocks as f64 / total_blocks as f64).clamp(0.0, 1.0) as f32)
+    }
+
+    /// Get the token info of the tokens configured on this network
+    pub fn get_token_info(&self) -> Vec<TokenInfo> {
+        let st = lock(&self.state);
+        self.token_registry.token_infos(&st.minimum_fees)
+    }
+
+    /// Get the chain id of the network
+    pub fn get_chain_id(&self) -> String {
+        lock(&self.state).chain_id.clone()
+    }
+
+    /// Get the balances of the monitored account, and whether they are complete, as of
+    /// the worker thread's last loop.
+    pub fn get_balances(&self, caller: &AccessToken) -> Result<BalanceSnapshot, AccessDenied> {
+        caller.require(AccessPolicy::READ_BALANCES)?;
+        Ok(read_lock(&self.published).balances.clone())
+    }
+
+    // The current balances, for the worker's own checks
+    fn balance_snapshot(&self) -> BalanceSnapshot {
+        lock(&self.state).balance_snapshot()
+    }
+
+    /// Get the balance of each token, along with the change and payments of our own
+    /// transactions which the monitor didn't sync yet.
+    ///
+    /// Right after a payment, its inputs can be gone from the balance before its change
+    /// shows up, so the pending change explains where the funds are. Only the
+    /// spendable balance can be spent.
+    pub fn get_balances_detailed(
+        &self,
+        caller: &AccessToken,
+    ) -> Result<HashMap<TokenId, DetailedBalance>, AccessDenied> {
+        caller.require(AccessPolicy::READ_BALANCES)?;
+        let st = lock(&self.state);
+        let mut detailed: HashMap<TokenId, DetailedBalance> = st
+            .balance
+            .iter()
+            .map(|(token_id, value)| {
+                let balance = DetailedBalance {
+                    spendable: *value,
+                    ..Default::default()
+                };
+                (*token_id, balance)
+            })
+            .collect();
+        for tx in st
+            .pending_transactions
+            .iter()
+            .filter(|tx| tx.is_balance_pending(st.synced_blocks))
+        {
+            let balance = detailed.entry(tx.token_id).or_default();
+            balance.pending_incoming = balance.pending_incoming.saturating_add(tx.change);
+            balance.pending_outgoing = balance
+                .pending_outgoing
+                .saturating_add(tx.value.saturating_add(tx.fee));
+        }
+        Ok(detailed)
+    }
+
+    // Ask mobilecoind for the current minimum fee of a token, and remember it.
+    // If mobilecoind can't be reached, the most recently known fee is used.
+    fn fetch_minimum_fee(
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+        token_id: TokenId,
+    ) -> u64 {
+        let timeouts = lock(&state).rpc_timeouts;
+        match client.get_network_status_opt(&Default::default(), timeouts.query_options()) {
+            Ok(resp) => {
+                let mut st = lock(&state);
+                for (k, v) in resp.get_last_block_info().minimum_fees.iter() {
+                    st.minimum_fees.insert(k.into(), *v);
+                }
+            }
+            Err(err) => event!(Level::WARN, "could not refresh minimum fees: {}", err),
+        }
+        lock(&state)
+            .minimum_fees
+            .get(&token_id)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// The token to hand to the web dashboard, which may do what
+    /// --dashboard-permissions allows
+    pub fn dashboard_token(&self) -> AccessToken {
+        AccessToken::dashboard(&self.config.dashboard_permissions)
+    }
+
+    /// Check if the worker has a deqs connection
+    pub fn has_deqs(&self) -> bool {
+        !self.deqs_connections.is_empty()
+    }
+
+    /// Get the uris of the configured deqs, in order. The index in this list is
+    /// the `source` of a QuoteId.
+    pub fn get_deqs_uris(&self) -> Vec<String> {
+        self.deqs_connections
+            .iter()
+            .map(|conn| conn.uri.to_string())
+            .collect()
+    }
+
+    /// Ask the worker to get quotes for given token ids
+    pub fn get_quotes_for_token_ids(&self, tok1: TokenId, tok2: TokenId) {
+        self.set_active_pair(Some((tok1, tok2)));
+    }
+
+    /// Set the pair the worker gets quotes for, or None to stop getting quotes.
+    /// Setting the pair it already has does nothing, so this can be called every frame.
+    pub fn set_active_pair(&self, pair: Option<(TokenId, TokenId)>) {
+        let mut st = lock(&self.state);
+        if st.get_quotes_token_ids != pair {
+            event!(Level::DEBUG, "active pair: {:?}", pair);
+            st.get_quotes_token_ids = pair;
+        }
+    }
+
+    /// Get the pair the worker gets quotes for, if any
+    pub fn get_active_pair(&self) -> Option<(TokenId, TokenId)> {
+        lock(&self.state).get_quotes_token_ids
+    }
+
+    /// Set which quotes of the books of the current pair the user wants to see
+    pub fn set_quote_filter(&self, filter: QuoteFilter) {
+        lock(&self.state).quote_filter = filter;
+    }
+
+    /// Get which quotes of the books of the current pair the user wants to see
+    pub fn get_quote_filter(&self) -> QuoteFilter {
+        lock(&self.state).quote_filter
+    }
+
+    /// Tell the worker how actively the user is looking at the app, so that it
+    /// can poll less often when nobody is looking. Becoming active again triggers
+    /// an immediate refresh.
+    pub fn set_activity_level(&self, level: ActivityLevel) {
+        let mut st = lock(&self.state);
+        if st.activity_level != level {
+            event!(Level::DEBUG, "activity level: {:?}", level);
+            if level == ActivityLevel::Active {
+                st.refresh_requested = true;
+            }
+            st.activity_level = level;
+        }
+    }
+
+    /// Tell the worker whether the window is in the foreground. This is a shorthand
+    /// for set_activity_level, for callers which can't tell whether a window in the
+    /// background is still visible.
+    pub fn set_foreground(&self, foreground: bool) {
+        self.set_activity_level(if foreground {
+            ActivityLevel::Active
+        } else {
+            ActivityLevel::Background
+        });
+    }
+
+    /// Ask the worker to fetch the balances right away, even if no block was
+    /// processed since they were last fetched
+    pub fn refresh_balances(&self) {
+        lock(&self.state).request_balance_refresh();
+    }
+
+    /// Ask the worker to poll everything right away
+    pub fn request_refresh(&self) {
+        lock(&self.state).refresh_requested = true;
+    }
+
+    /// Tell the worker it can stop getting quotes. This does nothing if it already
+    /// stopped.
+    pub fn stop_quotes(&self) {
+        self.set_active_pair(None);
+    }
+
+    /// Get the quote book for a given pair, as of the worker thread's last loop
+    pub fn get_quote_book(
+        &self,
+        caller: &AccessToken,
+        tok1: TokenId,
+        tok2: TokenId,
+    ) -> Result<QuoteBook, AccessDenied> {
+        caller.require(AccessPolicy::READ_BOOK)?;
+        Ok(read_lock(&self.published)
+            .quote_books
+            .get(&(tok1, tok2))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Get how long ago the quote book for a given pair was last polled
+    /// successfully, or None if it never was
+    pub fn get_quote_book_age(&self, tok1: TokenId, tok2: TokenId) -> Option<Duration> {
+        lock(&self.state).quote_book_age((tok1, tok2), Instant::now())
+    }
+
+    /// How old a quote book may be before swaps against it are refused
+    pub fn max_quote_age(&self) -> Duration {
+        lock(&self.state).max_quote_age
+    }
+
+    /// Get the best bid and the best ask of a pair, in units of the counter token,
+    /// if both sides of the book have quotes
+    pub fn get_best_prices(
+        &self,
+        caller: &AccessToken,
+        base_token_id: TokenId,
+        counter_token_id: TokenId,
+    ) -> Result<Option<(Decimal, Decimal)>, AccessDenied> {
+        let books = [
+            self.get_quote_book(caller, base_token_id, counter_token_id)?,
+            self.get_quote_book(caller, counter_token_id, base_token_id)?,
+        ];
+        let (best_bid, best_ask) = Self::best_bid_ask(
+            &books,
+            base_token_id,
+            counter_token_id,
+            &self.get_token_info(),
+        );
+        Ok(best_bid.zip(best_ask))
+    }
+
+    /// Get the approximate price of one unit of a token in USD, from the mid-price of
+    /// its books against EUSD. None if the books have no mid-price, or weren't
+    /// polled in a while.
+    pub fn get_indicative_price(&self, token_id: TokenId) -> Option<Decimal> {
+        lock(&self.state)
+            .indicative_prices
+            .get(token_id, Instant::now())
+    }
+
+    /// Get the mid-prices of a (base, counter) pair sampled while it was being polled
+    /// during this session, oldest first
+    pub fn get_price_history(&self, pair: (TokenId, TokenId)) -> Vec<PriceSample> {
+        lock(&self.state).price_history.history(pair)
+    }
+
+    // The best bid and the best ask in the books of both sides of a pair, in units of
+    // the counter token
+    fn best_bid_ask(
+        books: &[QuoteBook; 2],
+        base_token_id: TokenId,
+        counter_token_id: TokenId,
+        token_infos: &[TokenInfo],
+    ) -> (Option<Decimal>, Option<Decimal>) {
+        let mut best_bid: Option<Decimal> = None;
+        let mut best_ask: Option<Decimal> = None;
+        for book in books {
+            for info in book.iter().filter_map(|quote| {
+                quote
+                    .get_quote_info(base_token_id, counter_token_id, token_infos)
+                    .ok()
+            }) {
+                match info.quote_side {
+                    QuoteSide::Bid => {
+                        best_bid = Some(best_bid.map_or(info.price, |best| best.max(info.price)))
+                    }
+                    QuoteSide::Ask => {
+                        best_ask = Some(best_ask.map_or(info.price, |best| best.min(info.price)))
+                    }
+                }
+            }
+        }
+        (best_bid, best_ask)
+    }
+
+    /// Get the state of the deqs polling for a given pair
+    pub fn get_deqs_status(&self, tok1: TokenId, tok2: TokenId) -> DeqsStatus {
+        if self.deqs_connections.is_empty() {
+            return DeqsStatus::NotConfigured;
+        }
+        let st = lock(&self.state);
+        if let Some(err) = st.deqs_error.as_ref() {
+            DeqsStatus::Unreachable(err.clone())
+        } else if st.quote_books.contains_key(&(tok1, tok2)) {
+            DeqsStatus::Ready
+        } else {
+            DeqsStatus::Loading
+        }
+    }
+
+    /// Decode a b58 address
+    pub fn decode_b58_address(b58_address: &str) -> Result<Recipient, String> {
+        let printable_wrapper = PrintableWrapper::b58_decode(b58_address.to_owned())
+            .map_err(|err| format!("Invalid address: {err}"))?;
+
+        if !printable_wrapper.has_public_address() {
+            return Err("not a public address".to_string());
+        };
+
+        Ok(Recipient {
+            address: printable_wrapper.get_public_address().clone(),
+        })
+    }
+
+    /// Decode a b58 address, and check that it is for the network mobilecoind is
+    /// connected to, as far as can be told (see check_recipient_network)
+    pub fn validate_recipient(&self, b58_address: &str) -> Result<Recipient, String> {
+        let recipient = Self::decode_b58_address(b58_address)?;
+        check_recipient_network(&recipient, &self.get_chain_id())?;
+        Ok(recipient)
+    }
+
+    /// Create a b58 payment request code, asking for a payment of `value` of a token to
+    /// the monitored account.
+    ///
+    /// The code is decoded again before it is returned, to make sure that it carries
+    /// exactly the requested amount.
+    pub fn create_payment_request_b58(
+        &self,
+        value: u64,
+        token_id: TokenId,
+        memo: &str,
+    ) -> Result<String, String> {
+        let public_address = lock(&self.state).monitor_public_address.clone();
+        let mut payment_request = PaymentRequest::new();
+        payment_request.set_public_address(public_address.clone());
+        payment_request.set_value(value);
+        payment_request.set_token_id(*token_id);
+        payment_request.set_memo(memo.to_owned());
+
+        let mut printable_wrapper = PrintableWrapper::new();
+        printable_wrapper.set_payment_request(payment_request);
+        let b58_code = printable_wrapper
+            .b58_encode()
+            .map_err(|err| format!("Could not encode payment request: {err}"))?;
+
+        // Round trip, so that we never hand out a code for the wrong amount
+        let decoded = PrintableWrapper::b58_decode(b58_code.clone())
+            .map_err(|err| format!("Could not decode payment request: {err}"))?;
+        let decoded = decoded.get_payment_request();
+        if decoded.get_value() != value
+            || decoded.get_token_id() != *token_id
+            || decoded.get_memo() != memo
+            || decoded.get_public_address() != &public_address
+        {
+            return Err("Payment request did not survive a round trip".to_owned());
+        }
+
+        Ok(b58_code)
+    }
+
+    /// Queue a payment from the monitored account to the specified recipient.
+    ///
+    /// The payment is checked against the balances of its sender subaddress (or the
+    /// txos it spends) that we know about right away, and then executed by the worker
+    /// thread, which checks the current fee and balances again before sending it.
+    /// Its progress can be followed with get_commands.
+    pub fn send(&self, caller: &AccessToken, payment: Payment) -> Result<CommandId, CommandError> {
+        caller.require(AccessPolicy::SEND)?;
+        self.require_spending()?;
+        self.validate_recipient(&payment.recipient)
+            .map_err(CommandError::InvalidRecipient)?;
+        preflight::check_tx_options(&payment.options, NUM_SUBADDRESSES)?;
+        let amount = ScaledAmount::new(payment.value, payment.token_id);
+        {
+            let st = lock(&self.state);
+            if payment.inputs.is_empty() {
+                preflight::check_send(
+                    &st.balance_snapshot_of(payment.options.sender_subaddress),
+                    amount,
+                    payment.expected_fee,
+                )?;
+            } else {
+                let selected = preflight::check_inputs_available(
+                    st.utxos
+                        .get(&payment.token_id)
+                        .map(Vec::as_slice)
+                        .unwrap_or_default(),
+                    &st.reserved_inputs,
+                    &payment.inputs,
+                )?;
+                preflight::check_inputs(&selected, amount, payment.expected_fee)?;
+            }
+        }
+
+        Ok(self.queue_command(WorkerCommand::Send(payment)))
+    }
+
+    /// Queue a payment which spends specific unspent txos of subaddress 0, rather than
+    /// letting mobilecoind select the inputs. The change goes back to subaddress 0.
+    ///
+    /// The txos are checked against the ones last fetched with get_utxos right away,
+    /// and against the current ones again by the worker thread.
+    pub fn send_from_utxos(
+        &self,
+        caller: &AccessToken,
+        inputs: Vec<KeyImage>,
+        value: u64,
+        token_id: TokenId,
+        recipient: String,
+        expected_fee: u64,
+    ) -> Result<CommandId, CommandError> {
+        self.send(
+            caller,
+            Payment {
+                value,
+                token_id,
+                recipient,
+                expected_fee,
+                payment_request_id: None,
+                inputs,
+                options: TxOptions::default(),
+            },
+        )
+    }
+
+    /// The number of subaddresses the monitor covers, starting at 0
+    pub fn num_subaddresses(&self) -> u64 {
+        NUM_SUBADDRESSES
+    }
+
+    /// Get the balances of a single subaddress, which is what a payment from that
+    /// subaddress can spend
+    pub fn get_subaddress_balances(
+        &self,
+        caller: &AccessToken,
+        subaddress_index: u64,
+    ) -> Result<BalanceSnapshot, AccessDenied> {
+        caller.require(AccessPolicy::READ_BALANCES)?;
+        Ok(lock(&self.state).balance_snapshot_of(subaddress_index))
+    }
+
+    /// Get the commands queued with the worker during this session, and their status
+    pub fn get_commands(&self, caller: &AccessToken) -> Result<Vec<CommandInfo>, AccessDenied> {
+        caller.require(AccessPolicy::READ_BALANCES)?;
+        Ok(lock(&self.state).commands.commands().to_vec())
+    }
+
+    /// Queue a payment of the entire balance of a token on subaddress 0, less the
+    /// network fee, to the specified recipient.
+    ///
+    /// Fails if the balance doesn't exceed the fee.
+    pub fn send_all(
+        &self,
+        caller: &AccessToken,
+        token_id: TokenId,
+        recipient: String,
+    ) -> Result<CommandId, CommandError> {
+        caller.require(AccessPolicy::SEND)?;
+        self.require_spending()?;
+        let snapshot = lock(&self.state).balance_snapshot_of(0);
+        preflight::check_sync(&snapshot)?;
+
+        let balance = snapshot.balances.get(&token_id).cloned().unwrap_or(0);
+        let fee = self
+            .get_token_info()
+            .iter()
+            .find(|info| info.token_id == token_id)
+            .map(|info| info.fee)
+            .unwrap_or(0);
+        if balance <= fee {
+            return Err(PreconditionFailed::InsufficientFunds {
+                token_id,
+                needed: fee.saturating_add(1),
+                available: balance,
+            }
+            .into());
+        }
+
+        self.send(
+            caller,
+            Payment {
+                value: balance - fee,
+                token_id,
+                recipient,
+                expected_fee: fee,
+                payment_request_id: None,
+                inputs: Vec::new(),
+                options: TxOptions::default(),
+            },
+        )
+    }
+
+    /// Get the unspent txos of a token on subaddress 0, which is where payments are sent from.
+    ///
+    /// The list is cached, and refreshed by the worker thread every few seconds once it
+    /// has been asked for, so this is cheap to call every frame.
+    pub fn get_utxos(
+        &self,
+        caller: &AccessToken,
+        token_id: TokenId,
+    ) -> Result<Vec<UtxoInfo>, AccessDenied> {
+        caller.require(AccessPolicy::READ_BALANCES)?;
+        let mut st = lock(&self.state);
+        if st.utxos_requested.insert(token_id) {
+            st.refresh_requested = true;
+        }
+        Ok(st.utxos.get(&token_id).cloned().unwrap_or_default())
+    }
+
+    /// Queue a self-payment which merges the smallest unspent txos of a token into one,
+    /// so that later payments and swaps don't fail input selection.
+    pub fn defragment(
+        &self,
+        caller: &AccessToken,
+        token_id: TokenId,
+    ) -> Result<CommandId, CommandError> {
+        caller.require(AccessPolicy::SEND)?;
+        self.require_spending()?;
+        Ok(self.queue_command(WorkerCommand::Defragment { token_id }))
+    }
+
+    /// Queue the creation of a gift code holding `value` of a token, funded from
+    /// subaddress 0.
+    ///
+    /// The balance must cover the value plus the fee. The code shows up in
+    /// get_gift_codes as soon as it is generated, and is funded once its command
+    /// is submitted.
+    pub fn create_transfer_code(
+        &self,
+        caller: &AccessToken,
+        value: u64,
+        token_id: TokenId,
+        expected_fee: u64,
+    ) -> Result<CommandId, CommandError> {
+        caller.require(AccessPolicy::SEND)?;
+        self.require_spending()?;
+        let snapshot = lock(&self.state).balance_snapshot_of(0);
+        preflight::check_send(&snapshot, ScaledAmount::new(value, token_id), expected_fee)?;
+        Ok(self.queue_command(WorkerCommand::CreateGiftCode {
+            value,
+            token_id,
+            expected_fee,
+        }))
+    }
+
+    /// Queue the redemption of a gift code, which sweeps its value less the fee into
+    /// subaddress 0. Its progress can be followed with get_commands.
+    pub fn redeem_transfer_code(
+        &self,
+        caller: &AccessToken,
+        b58_code: &str,
+    ) -> Result<CommandId, CommandError> {
+        caller.require(AccessPolicy::SEND)?;
+        self.require_spending()?;
+        Self::decode_transfer_code(b58_code).map_err(CommandError::InvalidGiftCode)?;
+        Ok(self.queue_command(WorkerCommand::RedeemGiftCode {
+            b58_code: b58_code.trim().to_owned(),
+        }))
+    }
+
+    /// Check that a string is a b58 gift code, without asking mobilecoind about it
+    pub fn decode_transfer_code(b58_code: &str) -> Result<(), String> {
+        let printable_wrapper = PrintableWrapper::b58_decode(b58_code.trim().to_owned())
+            .map_err(|err| format!("Invalid gift code: {err}"))?;
+
+        if !printable_wrapper.has_transfer_payload() {
+            return Err("not a gift code".to_string());
+        }
+        Ok(())
+    }
+
+    /// Get the gift codes created during this session
+    pub fn get_gift_codes(&self, caller: &AccessToken) -> Result<Vec<GiftCode>, AccessDenied> {
+        caller.require(AccessPolicy::SEND)?;
+        Ok(lock(&self.state).gift_codes.clone())
+    }
+
+    /// Get the fee mobilecoind would use for a payment, if it has been estimated.
+    ///
+    /// The estimate is cached, and recomputed by the worker thread once the
+    /// arguments stop changing, so this is cheap to call every frame. Returns None
+    /// while the estimate is pending.
+    pub fn get_fee_estimate(
+        &self,
+        value: u64,
+        token_id: TokenId,
+        recipient: String,
+        sender_subaddress: u64,
+        inputs: &HashSet<KeyImage>,
+    ) -> Option<Result<u64, String>> {
+        let key = FeeEstimateKey {
+            value,
+            token_id,
+            recipient,
+            sender_subaddress,
+            inputs: inputs.clone(),
+        };
+        let mut st = lock(&self.state);
+        if let Some(estimate) = st.fee_estimate.as_ref() {
+            if estimate.key == key {
+                return estimate.result.clone();
+            }
+        }
+        st.fee_estimate = Some(FeeEstimate {
+            key,
+            requested_at: Instant::now(),
+            result: None,
+        });
+        None
+    }
+
+    // Estimate the fee of the payment the UI asked about, once it is due. This runs
+    // on the worker thread, so there is at most one estimate in flight, and its
+    // result is dropped if the payment changed meanwhile.
+    fn estimate_pending_fee(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+    ) {
+        let key = match lock(&state).fee_estimate.as_ref() {
+            Some(estimate) if estimate.due_in(Instant::now()) == Some(Duration::ZERO) => {
+                estimate.key.clone()
+            }
+            _ => return,
+        };
+        let result = Self::estimate_fee(monitor_id, client, state, &key);
+        if let Some(estimate) = lock(&state).fee_estimate.as_mut() {
+            if estimate.key == key {
+                estimate.result = Some(result);
+            }
+        }
+    }
+
+    // Estimate the fee of a payment, by asking mobilecoind to generate the
+    // transaction without submitting it. The transaction spends what the payment
+    // would: the selected coins, or else the txos execute_send checks for.
+    fn estimate_fee(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+        key: &FeeEstimateKey,
+    ) -> Result<u64, String> {
+        let timeouts = lock(&state).rpc_timeouts;
+        let receiver = Self::decode_b58_address(&key.recipient)?.address;
+
+        let fee = Self::fetch_minimum_fee(client, state, key.token_id);
+        let unspent = Self::fetch_unspent(
+            monitor_id,
+            client,
+            key.sender_subaddress,
+            key.token_id,
+            timeouts,
+        )?;
+        let selected: Vec<KeyImage> = key.inputs.iter().cloned().collect();
+        let input_list = Self::payment_inputs(
+            unspent,
+            &lock(&state).reserved_inputs,
+            ScaledAmount::new(key.value, key.token_id),
+            fee,
+            &selected,
+        )
+        .map_err(|err| err.to_string())?;
+
+        let mut outlay = mcd_api::Outlay::new();
+        outlay.value = key.value;
+        outlay.set_receiver(receiver);
+
+        let mut req = mcd_api::GenerateTxRequest::new();
+        req.set_sender_monitor_id(monitor_id.to_vec());
+        req.set_change_subaddress(key.sender_subaddress);
+        req.set_input_list(input_list.into());
+        req.set_outlay_list(vec![outlay].into());
+        req.token_id = *key.token_id;
+
+        let resp = client
+            .generate_tx_opt(&req, timeouts.submit_options())
+            .map_err(|err| {
+                format!(
+                    "failed generating tx: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?;
+        Ok(resp.get_tx_proposal().fee)
+    }
+
+    /// Get the payments we submitted which are pending, or resolved very recently
+    pub fn get_pending_transactions(
+        &self,
+        caller: &AccessToken,
+    ) -> Result<Vec<PendingTransaction>, AccessDenied> {
+        caller.require(AccessPolicy::READ_BALANCES)?;
+        Ok(lock(&self.state).pending_transactions.clone())
+    }
+
+    /// Ask the worker thread to confirm, using its receiver receipts, that a payment
+    /// we sent is visible to the recipient. The outcome is recorded in the
+    /// receiver_status of the pending transaction.
+    pub fn verify_receipt(
+        &self,
+        caller: &AccessToken,
+        receipt: &mcd_api::ReceiverTxReceipt,
+    ) -> Result<(), AccessDenied> {
+        caller.require(AccessPolicy::READ_BALANCES)?;
+        let tx_public_key = receipt.get_tx_public_key().get_data();
+        let mut st = lock(&self.state);
+        if let Some(tx) = st
+            .pending_transactions
+            .iter_mut()
+            .find(|tx| tx.has_receiver_receipt(tx_public_key))
+        {
+            tx.request_verification();
+            st.refresh_requested = true;
+        }
+        Ok(())
+    }
+
+    /// Get the status of a command queued during this session, or None once it is
+    /// forgotten
+    pub fn get_command_status(&self, command_id: CommandId) -> Option<CommandStatus> {
+        lock(&self.state).commands.status(command_id)
+    }
+
+    /// Whether a command matching `filter` is queued or being executed, so that the UI
+    /// doesn't submit the same operation again before the worker is done with it
+    pub fn command_in_flight(&self, filter: impl Fn(&WorkerCommand) -> bool) -> bool {
+        lock(&self.state)
+            .commands
+            .commands()
+            .iter()
+            .any(|info| !info.status.is_finished() && filter(&info.command))
+    }
+
+    /// Forget about a finished command
+    pub fn dismiss_command(&self, command_id: CommandId) {
+        lock(&self.state).commands.dismiss(command_id);
+    }
+
+    /// Whether the wallet is watch-only, so that nothing may be spent from it
+    pub fn is_watch_only(&self) -> bool {
+        self.config.watch_only
+    }
+
+    // Check that the wallet may spend, which a watch-only wallet may not
+    fn require_spending(&self) -> Result<(), CommandError> {
+        if self.config.watch_only {
+            return Err(CommandError::WatchOnly);
+        }
+        Ok(())
+    }
+
+    // Record a command and hand it to the worker thread
+    fn queue_command(&self, command: WorkerCommand) -> CommandId {
+        let command_id = lock(&self.state).commands.begin(command.clone());
+        if lock(&self.command_sender)
+            .send((command_id, command))
+            .is_err()
+        {
+            // The worker thread is gone, so nobody is going to execute this
+            let err = "worker is shutting down, command dropped".to_string();
+            let mut st = lock(&self.state);
+            st.commands
+                .set_status(command_id, CommandStatus::Failed(err.clone()));
+            st.errors.push(Severity::Error, err);
+        }
+        command_id
+    }
+
+    // Execute a command on the worker thread, recording the outcome
+    fn execute_command(command_id: CommandId, command: WorkerCommand, context: &CommandContext) {
+        let CommandContext { state, .. } = context;
+        lock(&state)
+            .commands
+            .set_status(command_id, CommandStatus::Submitting);
+        let client = &context.mobilecoind_api_client.get();
+        let monitor_id = &context.monitor_id();
+        let name = command.name();
+        // Payments, swaps and cancellations change the balances as soon as they are
+        // submitted
+        let spends = !matches!(
+            command,
+            WorkerCommand::IssueReceiveSubaddress | WorkerCommand::PreviewOffer { .. }
+        );
+        let result = match command {
+            WorkerCommand::Send(payment) => Self::execute_send(monitor_id, client, state, &payment),
+            WorkerCommand::Defragment { token_id } => {
+                Self::execute_defragment(monitor_id, client, state, token_id)
+            }
+            WorkerCommand::CreateGiftCode {
+                value,
+                token_id,
+                expected_fee,
+            } => Self::execute_create_gift_code(
+                command_id,
+                monitor_id,
+                client,
+                state,
+                ScaledAmount::new(value, token_id),
+                expected_fee,
+            ),
+            WorkerCommand::RedeemGiftCode { b58_code } => {
+                Self::execute_redeem_gift_code(monitor_id, client, state, &b58_code)
+            }
+            WorkerCommand::IssueReceiveSubaddress => Self::execute_issue_receive_subaddress(
+                command_id,
+                monitor_id,
+                client,
+                state,
+                &Self::subaddresses_path(&context.data_dir, monitor_id),
+            ),
+            WorkerCommand::OfferSwap {
+                offer_id,
+                from_amount,
+                to_amount,
+                sender_subaddress,
+                fill_mode,
+            } => context.execute_offer_swap(
+                offer_id,
+                from_amount,
+                to_amount,
+                sender_subaddress,
+                fill_mode,
+            ),
+            WorkerCommand::OfferBothSides {
+                ask_offer_id,
+                bid_offer_id,
+                quote,
+                sender_subaddress,
+                fill_mode,
+            } => context.execute_offer_both_sides(
+                ask_offer_id,
+                bid_offer_id,
+                quote,
+                sender_subaddress,
+                fill_mode,
+            ),
+            WorkerCommand::PreviewOffer {
+                handle,
+                from_amount,
+                to_amount,
+                sender_subaddress,
+                fill_mode,
+            } => context.execute_preview_offer(
+                handle,
+                from_amount,
+                to_amount,
+                sender_subaddress,
+                fill_mode,
+            ),
+            WorkerCommand::SubmitHeldOffer { offer_id, sci } => {
+                context.execute_submit_held_offer(offer_id, &sci)
+            }
+            WorkerCommand::CancelOffer { offer_id } => context.execute_cancel_offer(offer_id),
+            WorkerCommand::PerformSwap {
+                fills,
+                from_amount,
+                fee_token_id,
+                options,
+                max_slippage,
+            } => context.execute_perform_swap(
+                &fills,
+                from_amount,
+                fee_token_id,
+                options,
+                max_slippage,
+            ),
+        };
+        if let Err(err) = result.as_ref() {
+            context.recover_monitor(err);
+        }
+        let mut st = lock(&state);
+        match result {
+            Ok(()) => {
+                st.commands.set_status(command_id, CommandStatus::Submitted);
+                st.errors.note(Severity::Info, format!("{name} submitted"));
+                if spends {
+                    st.request_balance_refresh();
+                }
+            }
+            Err(err) => {
+                st.commands
+                    .set_status(command_id, CommandStatus::Failed(err.clone()));
+                st.errors.push(Severity::Error, err);
+            }
+        }
+    }
+
+    // Issue the next receive subaddress, and fetch its b58 address. It is saved with
+    // the subaddresses issued before it, before the UI can hand it out, so that its
+    // index is never issued again.
+    fn execute_issue_receive_subaddress(
+        command_id: CommandId,
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+        subaddresses_path: &Path,
+    ) -> Result<(), String> {
+        let timeouts = lock(&state).rpc_timeouts;
+        // Commands execute one at a time, so nobody else claims the index meanwhile
+        let index = lock(&state)
+            .issued_subaddresses
+            .keys()
+            .next_back()
+            .map(|index| index + 1)
+            .unwrap_or(FIRST_RECEIVE_SUBADDRESS);
+        if index >= NUM_SUBADDRESSES {
+            return Err(format!(
+                "All {} receive subaddresses were issued",
+                NUM_SUBADDRESSES - FIRST_RECEIVE_SUBADDRESS
+            ));
+        }
+
+        let mut req = mcd_api::GetPublicAddressRequest::new();
+        req.set_monitor_id(monitor_id.to_vec());
+        req.set_subaddress_index(index);
+        let resp = client
+            .get_public_address_opt(&req, timeouts.query_options())
+            .map_err(|err| {
+                format!(
+                    "Failed getting public address: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?;
+
+        let mut st = lock(&state);
+        st.issued_subaddresses.insert(index, resp.b58_code.clone());
+        let issued: Vec<IssuedSubaddress> = st
+            .issued_subaddresses
+            .iter()
+            .map(|(index, b58_address)| IssuedSubaddress {
+                index: *index,
+                b58_address: b58_address.clone(),
+            })
+            .collect();
+        if let Err(err) = IssuedSubaddress::store(subaddresses_path, &issued) {
+            st.issued_subaddresses.remove(&index);
+            return Err(format!(
+                "Could not save the issued subaddress: {}: {err}",
+                subaddresses_path.display()
+            ));
+        }
+        st.last_issued_subaddress = Some((command_id, resp.b58_code));
+        Ok(())
+    }
+
+    // Merge the smallest unspent txos of a token on subaddress 0 into one, by paying
+    // their value less the fee back to ourselves
+    fn execute_defragment(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+        token_id: TokenId,
+    ) -> Result<(), String> {
+        let timeouts = lock(&state).rpc_timeouts;
+        span!(Level::INFO, "defragment");
+
+        let fee = Self::fetch_minimum_fee(client, state, token_id);
+
+        let mut req = mcd_api::GetUnspentTxOutListRequest::new();
+        req.set_monitor_id(monitor_id.to_vec());
+        req.set_subaddress_index(0);
+        req.set_token_id(*token_id);
+        let mut resp = client
+            .get_unspent_tx_out_list_opt(&req, timeouts.query_options())
+            .map_err(|err| {
+                format!(
+                    "failed getting unspent tx out list: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?;
+
+        // Inputs reserved by offers must not be spent
+        let mut utxos: Vec<mcd_api::UnspentTxOut> = {
+            let st = lock(&state);
+            resp.take_output_list()
+                .into_iter()
+                .filter(|utxo| {
+                    utxo.token_id == *token_id
+                        && KeyImage::try_from(utxo.get_key_image())
+                            .map(|key_image| !st.reserved_inputs.contains(&key_image))
+                            .unwrap_or(false)
+                })
+                .collect()
+        };
+        utxos.sort_by_key(|utxo| utxo.value);
+        utxos.truncate(UtxoInfo::MAX_INPUTS);
+        if utxos.len() < 2 {
+            return Err("Nothing to defragment, there are fewer than 2 coins".to_owned());
+        }
+        let total = utxos
+            .iter()
+            .try_fold(0u64, |total, utxo| total.checked_add(utxo.value))
+            .ok_or("u64 overflow")?;
+        if total <= fee {
+            return Err("Nothing to defragment, the smallest coins don't cover the fee".to_owned());
+        }
+
+        let mut req = mcd_api::GetPublicAddressRequest::new();
+        req.set_monitor_id(monitor_id.to_vec());
+        req.set_subaddress_index(0);
+        let self_address = client
+            .get_public_address_opt(&req, timeouts.query_options())
+            .map_err(|err| {
+                format!(
+                    "failed getting public address: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?
+            .take_public_address();
+
+        event!(
+            Level::INFO,
+            "defragment: merging {} coins of {} worth {}",
+            utxos.len(),
+            *token_id,
+            total
+        );
+        let mut outlay = mcd_api::Outlay::new();
+        outlay.set_value(total - fee);
+        outlay.set_receiver(self_address);
+
+        let mut req = mcd_api::GenerateTxRequest::new();
+        req.set_sender_monitor_id(monitor_id.to_vec());
+        req.set_change_subaddress(0);
+        req.set_input_list(utxos.into());
+        req.set_outlay_list(vec![outlay].into());
+        req.set_fee(fee);
+        req.token_id = *token_id;
+        let mut resp = client
+            .generate_tx_opt(&req, timeouts.submit_options())
+            .map_err(|err| {
+                format!(
+                    "failed generating defragment tx: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?;
+
+        let mut req = mcd_api::SubmitTxRequest::new();
+        req.set_tx_proposal(resp.take_tx_proposal());
+        let receipts = client
+            .submit_tx_opt(&req, timeouts.submit_options())
+            .map_err(|err| {
+                format!(
+                    "failed submitting defragment tx: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?;
+        event!(Level::INFO, "submitted defragment tx successfully");
+
+        lock(&state).pending_transactions.push(
+            PendingTransaction::new(
+                token_id,
+                total - fee,
+                "(merge own coins)".to_owned(),
+                receipts,
+            )
+            .with_proposal(req.get_tx_proposal(), true),
+        );
+        Ok(())
+    }
+
+    // Send a payment, after checking the current fee and balances
+    fn execute_send(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+        payment: &Payment,
+    ) -> Result<(), String> {
+        let timeouts = lock(&state).rpc_timeouts;
+        span!(Level::INFO, "send payment");
+        let Payment {
+            value,
+            token_id,
+            ref recipient,
+            expected_fee,
+            payment_request_id,
+            ref inputs,
+            options,
+        } = *payment;
+        if !inputs.is_empty() {
+            return Self::execute_send_from_utxos(monitor_id, client, state, payment);
+        }
+        let sender_subaddress = options.sender_subaddress;
+
+        // Revalidate against the current fee and balances, they may have changed
+        // since the payment was queued
+        let fee = Self::fetch_minimum_fee(client, state, token_id);
+        let snapshot = lock(&state).balance_snapshot_of(sender_subaddress);
+        preflight::check_fee(token_id, expected_fee, fee)
+            .and_then(|_| preflight::check_send(&snapshot, ScaledAmount::new(value, token_id), fee))
+            .map_err(|err| format!("Payment not sent: {err}"))?;
+
+        // mobilecoind selects the inputs itself, but fails obscurely when the
+        // balance is spread over more txos than a transaction may spend
+        let unspent =
+            Self::fetch_unspent(monitor_id, client, sender_subaddress, token_id, timeouts)?;
+        Self::payment_inputs(
+            unspent,
+            &lock(&state).reserved_inputs,
+            ScaledAmount::new(value, token_id),
+            fee,
+            &[],
+        )
+        .map_err(|err| format!("Payment not sent: {err}"))?;
+
+        event!(
+            Level::INFO,
+            "send: {} of {} to {}",
+            value,
+            *token_id,
+            recipient
+        );
+
+        let receiver = Self::decode_b58_address(recipient)
+            .map_err(|err| {
+                event!(Level::ERROR, "decoding b58: {}", err);
+                err
+            })?
+            .address;
+
+        let mut outlay = mcd_api::Outlay::new();
+        outlay.value = value;
+        outlay.set_receiver(receiver);
+
+        let mut req = mcd_api::SendPaymentRequest::new();
+        req.set_sender_monitor_id(monitor_id.to_vec());
+        req.set_sender_subaddress(sender_subaddress);
+        req.set_override_change_subaddress(true);
+        req.set_change_subaddress(sender_subaddress);
+        req.set_outlay_list(vec![outlay].into());
+        req.set_tombstone(options.tombstone_block(snapshot.total_blocks));
+        req.token_id = *token_id;
+
+        // Let the recipient attribute the payment to us, and to their request
+        if let Some(payment_request_id) = payment_request_id {
+            let mut rth = mcd_api::TransactionMemo_RTH::new();
+            rth.set_payment_request_id(payment_request_id);
+            let mut memo = mcd_api::TransactionMemo::new();
+            memo.set_rth(rth);
+            req.set_transaction_memo(memo);
+        }
+
+        let retry_policy = lock(&state).retry_policy;
+        match retry_policy.run("send_payment", || {
+            client
+                .send_payment_opt(&req, timeouts.submit_options())
+                .map_err(|err| describe_rpc_error("mobilecoind", &err))
+        }) {
+            Ok(mut resp) => {
+                event!(Level::INFO, "submitted payment successfully");
+
+                // Coerce this into a SubmitTxResponse, so that we can use it with get_tx_status_as_sender
+                let mut receipts = mcd_api::SubmitTxResponse::new();
+                receipts.set_sender_tx_receipt(resp.take_sender_tx_receipt());
+                receipts.set_receiver_tx_receipt_list(resp.take_receiver_tx_receipt_list());
+                lock(&state).pending_transactions.push(
+                    PendingTransaction::new(token_id, value, recipient.to_owned(), receipts)
+                        .with_proposal(resp.get_tx_proposal(), false),
+                );
+                Ok(())
+            }
+            Err(err) => {
+                event!(Level::ERROR, "failed to submit payment: {}", err);
+                Err(err)
+            }
+        }
+    }
+
+    // Get the unspent txos of a token owned by a subaddress, with their info
+    fn fetch_unspent(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        subaddress_index: u64,
+        token_id: TokenId,
+        timeouts: RpcTimeouts,
+    ) -> Result<Vec<(UtxoInfo, mcd_api::UnspentTxOut)>, String> {
+        let mut req = mcd_api::GetUnspentTxOutListRequest::new();
+        req.set_monitor_id(monitor_id.to_vec());
+        req.set_subaddress_index(subaddress_index);
+        req.set_token_id(*token_id);
+        let mut resp = client
+            .get_unspent_tx_out_list_opt(&req, timeouts.query_options())
+            .map_err(|err| {
+                format!(
+                    "failed getting unspent tx out list: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?;
+        Ok(resp
+            .take_output_list()
+            .into_iter()
+            .filter_map(|utxo| {
+                let info = UtxoInfo {
+                    value: utxo.value,
+                    token_id: TokenId::from(utxo.token_id),
+                    subaddress_index: utxo.subaddress_index,
+                    key_image: KeyImage::try_from(utxo.get_key_image()).ok()?,
+                };
+                Some((info, utxo))
+            })
+            .collect())
+    }
+
+    // The txos a payment of `amount` and `fee` spends: the coins the user selected,
+    // once checked to be unspent, unreserved and enough, or else the txos
+    // select_inputs picks among the unreserved ones
+    fn payment_inputs(
+        unspent: Vec<(UtxoInfo, mcd_api::UnspentTxOut)>,
+        reserved: &HashSet<KeyImage>,
+        amount: ScaledAmount,
+        fee: u64,
+        selected: &[KeyImage],
+    ) -> Result<Vec<mcd_api::UnspentTxOut>, PreconditionFailed> {
+        let infos: Vec<UtxoInfo> = unspent.iter().map(|(info, _)| info.clone()).collect();
+        let inputs = if selected.is_empty() {
+            let required = amount
+                .raw
+                .checked_add(fee)
+                .ok_or(PreconditionFailed::Overflow)?;
+            preflight::select_inputs(
+                &infos,
+                reserved,
+                ScaledAmount::new(required, amount.token_id),
+                UtxoInfo::MAX_INPUTS,
+            )?
+        } else {
+            let inputs = preflight::check_inputs_available(&infos, reserved, selected)?;
+            preflight::check_inputs(&inputs, amount, fee)?;
+            inputs
+        };
+        Ok(unspent
+            .into_iter()
+            .filter(|(info, _)| inputs.iter().any(|input| input.key_image == info.key_image))
+            .map(|(_, utxo)| utxo)
+            .collect())
+    }
+
+    // Send a payment which spends the txos the user picked, after checking the
+    // current fee and that the txos are still unspent
+    fn execute_send_from_utxos(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+        payment: &Payment,
+    ) -> Result<(), String> {
+        let timeouts = lock(&state).rpc_timeouts;
+        let Payment {
+            value,
+            token_id,
+            ref recipient,
+            expected_fee,
+            ref inputs,
+            options,
+            ..
+        } = *payment;
+        let sender_subaddress = options.sender_subaddress;
+
+        let fee = Self::fetch_minimum_fee(client, state, token_id);
+        preflight::check_fee(token_id, expected_fee, fee)
+            .map_err(|err| format!("Payment not sent: {err}"))?;
+
+        let unspent =
+            Self::fetch_unspent(monitor_id, client, sender_subaddress, token_id, timeouts)?;
+
+        // Revalidate against the current txos, they may have been spent since the
+        // payment was queued
+        let infos: Vec<UtxoInfo> = unspent.iter().map(|(info, _)| info.clone()).collect();
+        let change = {
+            let st = lock(&state);
+            preflight::check_inputs_available(&infos, &st.reserved_inputs, inputs)
+                .and_then(|selected| {
+                    preflight::check_inputs(&selected, ScaledAmount::new(value, token_id), fee)
+                })
+                .map_err(|err| format!("Payment not sent: {err}"))?
+        };
+        let input_list: Vec<mcd_api::UnspentTxOut> = unspent
+            .into_iter()
+            .filter(|(info, _)| inputs.contains(&info.key_image))
+            .map(|(_, utxo)| utxo)
+            .collect();
+
+        event!(
+            Level::INFO,
+            "send: {} of {} to {} from {} selected coins, {} change",
+            value,
+            *token_id,
+            recipient,
+            input_list.len(),
+            change
+        );
+
+        let receiver = Self::decode_b58_address(recipient)?.address;
+        let mut outlay = mcd_api::Outlay::new();
+        outlay.set_value(value);
+        outlay.set_receiver(receiver);
+
+        let num_blocks = lock(&state).total_blocks;
+        let mut req = mcd_api::GenerateTxRequest::new();
+        req.set_sender_monitor_id(monitor_id.to_vec());
+        req.set_change_subaddress(sender_subaddress);
+        req.set_input_list(input_list.into());
+        req.set_outlay_list(vec![outlay].into());
+        req.set_fee(fee);
+        req.set_tombstone(options.tombstone_block(num_blocks));
+        req.token_id = *token_id;
+        let mut resp = client
+            .generate_tx_opt(&req, timeouts.submit_options())
+            .map_err(|err| {
+                format!(
+                    "failed generating tx: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?;
+
+        let mut req = mcd_api::SubmitTxRequest::new();
+        req.set_tx_proposal(resp.take_tx_proposal());
+        let retry_policy = lock(&state).retry_policy;
+        let receipts = retry_policy
+            .run("submit_tx", || {
+                client
+                    .submit_tx_opt(&req, timeouts.submit_options())
+                    .map_err(|err| describe_rpc_error("mobilecoind", &err))
+            })
+            .map_err(|err| format!("failed submitting tx: {err}"))?;
+        event!(Level::INFO, "submitted payment successfully");
+
+        lock(&state).pending_transactions.push(
+            PendingTransaction::new(token_id, value, recipient.to_owned(), receipts)
+                .with_proposal(req.get_tx_proposal(), false),
+        );
+        Ok(())
+    }
+
+    // Fund a new gift code from subaddress 0, after checking the current fee and balance.
+    //
+    // The code is recorded before its transaction is submitted, so that it isn't lost
+    // if the submission fails ambiguously and the transaction lands anyway.
+    fn execute_create_gift_code(
+        command_id: CommandId,
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+        amount: ScaledAmount,
+        expected_fee: u64,
+    ) -> Result<(), String> {
+        let timeouts = lock(&state).rpc_timeouts;
+        span!(Level::INFO, "create gift code");
+        let ScaledAmount {
+            raw: value,
+            token_id,
+        } = amount;
+
+        let fee = Self::fetch_minimum_fee(client, state, token_id);
+        let snapshot = lock(&state).balance_snapshot_of(0);
+        preflight::check_fee(token_id, expected_fee, fee)
+            .and_then(|_| preflight::check_send(&snapshot, amount, fee))
+            .map_err(|err| format!("Gift code not created: {err}"))?;
+
+        // The inputs of open offers must not be spent on the code
+        let unspent = Self::fetch_unspent(monitor_id, client, 0, token_id, timeouts)?;
+        let input_list =
+            Self::payment_inputs(unspent, &lock(&state).reserved_inputs, amount, fee, &[])
+                .map_err(|err| format!("Gift code not created: {err}"))?;
+
+        let mut req = mcd_api::GenerateTransferCodeTxRequest::new();
+        req.set_sender_monitor_id(monitor_id.to_vec());
+        req.set_change_subaddress(0);
+        req.set_input_list(input_list.into());
+        req.set_value(value);
+        req.set_fee(fee);
+        req.set_tombstone(TxOptions::default().tombstone_block(snapshot.total_blocks));
+        req.token_id = *token_id;
+        let mut resp = client
+            .generate_transfer_code_tx_opt(&req, timeouts.submit_options())
+            .map_err(|err| {
+                format!(
+                    "failed generating gift code tx: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?;
+
+        lock(&state).gift_codes.push(GiftCode {
+            b58_code: resp.take_b58_code(),
+            value,
+            token_id,
+            command_id,
+        });
+
+        let mut req = mcd_api::SubmitTxRequest::new();
+        req.set_tx_proposal(resp.take_tx_proposal());
+        let retry_policy = lock(&state).retry_policy;
+        let receipts = retry_policy
+            .run("submit_tx", || {
+                client
+                    .submit_tx_opt(&req, timeouts.submit_options())
+                    .map_err(|err| describe_rpc_error("mobilecoind", &err))
+            })
+            .map_err(|err| format!("failed submitting gift code tx: {err}"))?;
+        event!(
+            Level::INFO,
+            "funded gift code of {} of {}",
+            value,
+            *token_id
+        );
+
+        lock(&state).pending_transactions.push(
+            PendingTransaction::new(token_id, value, "gift code".to_owned(), receipts)
+                .with_proposal(req.get_tx_proposal(), false),
+        );
+        Ok(())
+    }
+
+    // Sweep the txo of a gift code into subaddress 0.
+    //
+    // This isn't retried: a spent key image means the code was already redeemed,
+    // which won't go away.
+    fn execute_redeem_gift_code(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+        b58_code: &str,
+    ) -> Result<(), String> {
+        let timeouts = lock(&state).rpc_timeouts;
+        span!(Level::INFO, "redeem gift code");
+        let mut req = mcd_api::ParseTransferCodeRequest::new();
+        req.set_b58_code(b58_code.to_owned());
+        let mut code = client
+            .parse_transfer_code_opt(&req, timeouts.query_options())
+            .map_err(|err| describe_redeem_error(&describe_rpc_error("mobilecoind", &err)))?;
+        let utxo = code.take_utxo();
+        let value = utxo.value;
+        let token_id = TokenId::from(utxo.token_id);
+
+        // The fee comes out of the gift, so it must leave something to sweep
+        let fee = Self::fetch_minimum_fee(client, state, token_id);
+        if value <= fee {
+            return Err(format!(
+                "Gift code not redeemed: its {value} of token id {token_id} doesn't cover the fee of {fee}"
+            ));
+        }
+        let account_key = gift_account_key(code.get_bip39_entropy())
+            .map_err(|err| describe_redeem_error(&err))?;
+
+        let mut req = mcd_api::GetPublicAddressRequest::new();
+        req.set_monitor_id(monitor_id.to_vec());
+        req.set_subaddress_index(0);
+        let receiver = client
+            .get_public_address_opt(&req, timeouts.query_options())
+            .map_err(|err| {
+                format!(
+                    "Failed getting public address: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?
+            .take_public_address();
+
+        let mut req = mcd_api::GenerateTxFromTxOutListRequest::new();
+        req.set_account_key((&account_key).into());
+        req.set_input_list(vec![utxo].into());
+        req.set_receiver(receiver);
+        req.set_fee(fee);
+        req.token_id = *token_id;
+        let mut resp = client
+            .generate_tx_from_tx_out_list_opt(&req, timeouts.submit_options())
+            .map_err(|err| describe_redeem_error(&describe_rpc_error("mobilecoind", &err)))?;
+
+        let mut req = mcd_api::SubmitTxRequest::new();
+        req.set_tx_proposal(resp.take_tx_proposal());
+        client
+            .submit_tx_opt(&req, timeouts.submit_options())
+            .map_err(|err| describe_redeem_error(&describe_rpc_error("mobilecoind", &err)))?;
+        event!(
+            Level::INFO,
+            "redeemed gift code of {} of {}",
+            value - fee,
+            *token_id
+        );
+        Ok(())
+    }
+
+    /// Create and submit a swap offer.
+    ///
+    /// The offer is tracked as submitting right away, and the submission is queued
+    /// for the worker thread, so this doesn't block the UI. The outcome is recorded
+    /// in the tracked offer (see get_offers).
+    pub fn offer_swap(
+        &self,
+        caller: &AccessToken,
+        from_amount: Amount,
+        to_amount: Amount,
+        sender_subaddress: u64,
+        fill_mode: FillMode,
+        keep_alive: bool,
+    ) -> Result<OfferId, CommandError> {
+        caller.require(AccessPolicy::OFFER)?;
+        self.require_spending()?;
+        preflight::check_subaddress(sender_subaddress, NUM_SUBADDRESSES)?;
+        let offer_id = {
+            let mut st = lock(&self.state);
+            preflight::check_offer(
+                &st.balance_snapshot_of(sender_subaddress),
+                ScaledAmount::new(from_amount.value, from_amount.token_id),
+            )?;
+            st.offers.begin(
+                from_amount,
+                to_amount,
+                sender_subaddress,
+                fill_mode,
+                keep_alive,
+            )
+        };
+        self.queue_command(WorkerCommand::OfferSwap {
+            offer_id,
+            from_amount,
+            to_amount,
+            sender_subaddress,
+            fill_mode,
+        });
+        Ok(offer_id)
+    }
+
+    /// Post offers on both sides of the book at once: an ask selling base, and a bid
+    /// buying it. The offers are generated and submitted one after the other, since
+    /// each may need a self-payment to prepare its input. They show up in
+    /// get_offers paired with each other, and the command fails with what went
+    /// wrong if either of them failed.
+    pub fn offer_both_sides(
+        &self,
+        caller: &AccessToken,
+        quote: TwoSidedQuote,
+        sender_subaddress: u64,
+        fill_mode: FillMode,
+        keep_alive: bool,
+    ) -> Result<(OfferId, OfferId), CommandError> {
+        caller.require(AccessPolicy::OFFER)?;
+        self.require_spending()?;
+        preflight::check_subaddress(sender_subaddress, NUM_SUBADDRESSES)?;
+        let (ask_offer_id, bid_offer_id) = {
+            let mut st = lock(&self.state);
+            let snapshot = st.balance_snapshot_of(sender_subaddress);
+            for (from_amount, _) in [quote.ask, quote.bid] {
+                preflight::check_offer(
+                    &snapshot,
+                    ScaledAmount::new(from_amount.value, from_amount.token_id),
+                )?;
+            }
+            let [ask_offer_id, bid_offer_id] = [quote.ask, quote.bid].map(|(from, to)| {
+                st.offers
+                    .begin(from, to, sender_subaddress, fill_mode, keep_alive)
+            });
+            st.offers.pair(ask_offer_id, bid_offer_id);
+            (ask_offer_id, bid_offer_id)
+        };
+        self.queue_command(WorkerCommand::OfferBothSides {
+            ask_offer_id,
+            bid_offer_id,
+            quote,
+            sender_subaddress,
+            fill_mode,
+        });
+        Ok((ask_offer_id, bid_offer_id))
+    }
+
+    /// Generate the sci for a swap offer, and hold it so that the user can review
+    /// it before it is submitted (see submit_held_offer and discard_held_offer).
+    ///
+    /// The sci is generated by the worker thread. Held offers are discarded
+    /// automatically after HeldOffers::HOLD_TIMEOUT, so that we don't hold a stale sci.
+    pub fn preview_offer(
+        &self,
+        caller: &AccessToken,
+        from_amount: Amount,
+        to_amount: Amount,
+        sender_subaddress: u64,
+        fill_mode: FillMode,
+    ) -> Result<HoldHandle, CommandError> {
+        caller.require(AccessPolicy::OFFER)?;
+        self.require_spending()?;
+        preflight::check_subaddress(sender_subaddress, NUM_SUBADDRESSES)?;
+        let handle = {
+            let mut st = lock(&self.state);
+            preflight::check_offer(
+                &st.balance_snapshot_of(sender_subaddress),
+                ScaledAmount::new(from_amount.value, from_amount.token_id),
+            )?;
+            st.held_offers.begin()
+        };
+        self.queue_command(WorkerCommand::PreviewOffer {
+            handle,
+            from_amount,
+            to_amount,
+            sender_subaddress,
+            fill_mode,
+        });
+        Ok(handle)
+    }
+
+    /// Get the offers which are held for preview, and whether any offer is still
+    /// being generated for preview
+    pub fn get_held_offers(
+        &self,
+        caller: &AccessToken,
+    ) -> Result<(Vec<HeldOffer>, bool), AccessDenied> {
+        caller.require(AccessPolicy::READ_BOOK)?;
+        let st = lock(&self.state);
+        Ok((st.held_offers.held().to_vec(), st.held_offers.has_pending()))
+    }
+
+    /// Submit a held offer, with exactly the sci that was previewed.
+    ///
+    /// Returns None if the offer is no longer held, for instance because it expired.
+    pub fn submit_held_offer(
+        &self,
+        caller: &AccessToken,
+        handle: HoldHandle,
+        keep_alive: bool,
+    ) -> Result<Option<OfferId>, CommandError> {
+        caller.require(AccessPolicy::OFFER)?;
+        self.require_spending()?;
+        let (held, offer_id) = {
+            let mut st = lock(&self.state);
+            let held = match st.held_offers.take(handle) {
+                Some(held) => held,
+                None => return Ok(None),
+            };
+            let snapshot = st.balance_snapshot_of(held.sender_subaddress);
+            let from_amount = ScaledAmount::new(held.from_amount.value, held.from_amount.token_id);
+            if let Err(err) = preflight::check_offer(&snapshot, from_amount) {
+                // Keep holding it, the user may decide to discard it
+                st.held_offers.hold_again(held);
+                return Err(err.into());
+            }
+            let offer_id = st.offers.begin(
+                held.from_amount,
+                held.to_amount,
+                held.sender_subaddress,
+                held.fill_mode,
+                keep_alive,
+            );
+            st.offers.set_key_image(offer_id, held.sci.key_image());
+            (held, offer_id)
+        };
+        self.queue_command(WorkerCommand::SubmitHeldOffer {
+            offer_id,
+            sci: held.sci,
+        });
+        Ok(Some(offer_id))
+    }
+
+    /// Discard a held offer, releasing its input for other offers
+    pub fn discard_held_offer(
+        &self,
+        caller: &AccessToken,
+        handle: HoldHandle,
+    ) -> Result<(), AccessDenied> {
+        caller.require(AccessPolicy::OFFER)?;
+        let mut st = lock(&self.state);
+        if let Some(held) = st.held_offers.take(handle) {
+            st.reserved_inputs.remove(&held.sci.key_image());
+        }
+        Ok(())
+    }
+
+    /// Get the phase of the offer the worker thread is generating or submitting, if any.
+    ///
+    /// Generating an offer can take a while, when we first need to pay ourselves
+    /// to make an input of the offered value.
+    pub fn get_operation_progress(&self) -> Option<OperationProgress> {
+        lock(&self.state).active_operation
+    }
+
+    /// Get the offers we submitted during this session
+    pub fn get_offers(&self, caller: &AccessToken) -> Result<Vec<TrackedOffer>, AccessDenied> {
+        caller.require(AccessPolicy::READ_BOOK)?;
+        Ok(lock(&self.state).offers.offers().to_vec())
+    }
+
+    /// Submit a failed offer again, with the same amounts. The failed offer is
+    /// replaced by the new one once it is queued, and kept if it can't be.
+    pub fn retry_offer(&self, caller: &AccessToken, offer_id: OfferId) -> Result<(), CommandError> {
+        caller.require(AccessPolicy::OFFER)?;
+        self.require_spending()?;
+        let offer = lock(&self.state)
+            .offers
+            .offers()
+            .iter()
+            .find(|offer| offer.id == offer_id && matches!(offer.status, OfferStatus::Failed(_)))
+            .cloned()
+            .ok_or(PreconditionFailed::OfferNotFailed)?;
+        self.offer_swap(
+            caller,
+            offer.from_amount,
+            offer.to_amount,
+            offer.sender_subaddress,
+            offer.fill_mode,
+            offer.keep_alive,
+        )?;
+        lock(&self.state).offers.remove(offer_id);
+        Ok(())
+    }
+
+    /// Set whether to submit an offer again, with a fresh sci, whenever it expires.
+    ///
+    /// Renewal stops by itself when the offer is cancelled, or when the balance no
+    /// longer covers it.
+    pub fn set_offer_keep_alive(
+        &self,
+        caller: &AccessToken,
+        offer_id: OfferId,
+        keep_alive: bool,
+    ) -> Result<(), AccessDenied> {
+        caller.require(AccessPolicy::OFFER)?;
+        lock(&self.state)
+            .offers
+            .set_keep_alive(offer_id, keep_alive);
+        Ok(())
+    }
+
+    /// Cancel an open or expired offer, by spending its input in a self-payment, so
+    /// that its sci becomes invalid. The offer is cancelled once the spend lands,
+    /// unless someone fills it first. Its progress can be followed with get_offers.
+    ///
+    /// The cancellation goes to every deqs which accepted or listed the offer, see
+    /// TrackedOffer::cancel_targets, whatever the submit mode is now.
+    pub fn cancel_offer(
+        &self,
+        caller: &AccessToken,
+        offer_id: OfferId,
+    ) -> Result<CommandId, CommandError> {
+        caller.require(AccessPolicy::CANCEL_OFFERS)?;
+        self.require_spending()?;
+        if !lock(&self.state).offers.mark_cancelling(offer_id) {
+            return Err(PreconditionFailed::OfferNotOpen.into());
+        }
+        Ok(self.queue_command(WorkerCommand::CancelOffer { offer_id }))
+    }
+
+    /// Stop tracking an offer, releasing its input for other offers.
+    ///
+    /// This doesn't cancel the offer at the deqs, see cancel_offer.
+    pub fn dismiss_offer(&self, offer_id: OfferId) {
+        let mut st = lock(&self.state);
+        if let Some(key_image) = st.offers.remove(offer_id).and_then(|offer| offer.key_image) {
+            st.reserved_inputs.remove(&key_image);
+        }
+    }
+
+    /// Act as the counterparty to given swaps, filling them in one transaction
+    ///
+    /// The fill goes through mobilecoind and lands on the ledger directly, but each
+    /// fill is routed to the deqs which served its quote: right before the
+    /// transaction is built, that deqs is asked whether it still lists the quote,
+    /// and the swap is aborted if it doesn't. Once the transaction is submitted, the
+    /// filled quotes are dropped from the books of those deqs.
+    ///
+    /// The swap is checked against the quote books and balances we know about right
+    /// away, and then executed by the worker thread, which checks them again against
+    /// the current fee. Both times, the scis are validated again, and the swap is
+    /// aborted if its cost moved by more than max_slippage from from_amount.
+    /// Its progress can be followed with get_commands.
+    ///
+    /// Arguments:
+    /// fills - scis to fulfill, and the degree to fill each of them to
+    /// from_amount - the amount we need to pay in order to fulfill the scis
+    /// fee_token_id - the token id to pay the fee in
+    /// options - the subaddress to pay from, which also receives the change, and the tombstone offset
+    /// max_slippage - how far the cost may move from from_amount, relative to it (0.005 is 0.5%)
+    pub fn perform_swap(
+        &self,
+        caller: &AccessToken,
+        fills: Vec<SciFill>,
+        from_amount: Amount,
+        fee_token_id: TokenId,
+        options: TxOptions,
+        max_slippage: Decimal,
+    ) -> Result<CommandId, CommandError> {
+        caller.require(AccessPolicy::SWAP)?;
+        self.require_spending()?;
+        preflight::check_tx_options(&options, NUM_SUBADDRESSES)?;
+        {
+            let st = lock(&self.state);
+            let fee = st.minimum_fees.get(&fee_token_id).cloned().unwrap_or(0);
+            Self::check_swap_preconditions(
+                &st,
+                &fills,
+                from_amount,
+                fee_token_id,
+                fee,
+                options,
+                max_slippage,
+            )?;
+        }
+        Ok(self.queue_command(WorkerCommand::PerformSwap {
+            fills,
+            from_amount,
+            fee_token_id,
+            options,
+            max_slippage,
+        }))
+    }
+
+    // Check that the scis are still valid, unexpired and in quote books which are
+    // fresh enough, that their cost didn't move too far, and that the balances of the
+    // sender subaddress cover the swap and the fee
+    fn check_swap_preconditions(
+        st: &WorkerState,
+        fills: &[SciFill],
+        from_amount: Amount,
+        fee_token_id: TokenId,
+        fee: u64,
+        options: TxOptions,
+        max_slippage: Decimal,
+    ) -> Result<(), PreconditionFailed> {
+        let from_token_id = from_amount.token_id;
+        preflight::check_quotes_unexpired(fills, st.total_blocks)?;
+        preflight::check_swap_price(fills, from_token_id, from_amount.value, max_slippage)?;
+        let snapshot = st.balance_snapshot_of(options.sender_subaddress);
+        let book = st.quote_books.values().flat_map(|book| book.iter());
+        let key_images: Vec<KeyImage> = fills.iter().map(|fill| fill.sci.key_image()).collect();
+        let now = Instant::now();
+        for fill in fills {
+            let quote_id = fill.quote_id();
+            let age = st
+                .quote_books
+                .iter()
+                .find(|(_, book)| book.iter().any(|quote| quote.id == quote_id))
+                .and_then(|(pair, _)| st.quote_book_age(*pair, now));
+            if let Some(age) = age {
+                preflight::check_quotes_fresh(age, st.max_quote_age)?;
+            }
+        }
+        preflight::check_swap_with_fee(
+            &snapshot,
+            book,
+            &key_images,
+            ScaledAmount::new(from_amount.value, from_token_id),
+            ScaledAmount::new(fee, fee_token_id),
+        )
+    }
+
+    /// Get the notice about something the worker recovered from, if it was raised
+    /// less than NOTICE_DURATION ago.
+    pub fn get_notice(&self) -> Option<String> {
+        match lock(&self.state).notice.as_ref() {
+            Some((notice, raised)) if raised.elapsed() < Self::NOTICE_DURATION => {
+                Some(notice.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the most severe error in the error queue, if any, the oldest of them if
+    /// several.
+    pub fn top_error(&self) -> Option<ErrorEntry> {
+        lock(&self.state).errors.top().cloned()
+    }
+
+    /// Report an error which came from the UI, so that it is displayed alongside
+    /// errors from the worker.
+    pub fn push_error(&self, err: String) {
+        lock(&self.state).errors.push(Severity::Error, err);
+    }
+
+    /// Report a notice which came from the UI, like where a file was exported to
+    pub fn push_notice(&self, notice: String) {
+        lock(&self.state).errors.push(Severity::Info, notice);
+    }
+
+    /// Get the most recent events, including dismissed errors, newest first. At
+    /// most ErrorQueue::MAX_RECENT are kept.
+    pub fn get_recent_events(&self) -> Vec<ErrorEntry> {
+        lock(&self.state).errors.recent()
+    }
+
+    /// Pop the error returned by top_error, if any. It isn't queued again
+    /// while it keeps being reported, see ErrorQueue::SUPPRESSION_WINDOW.
+    pub fn pop_error(&self) {
+        lock(&self.state).errors.pop();
+    }
+
+    // Check that mobilecoind is on the network given with --expected-chain-id, if any
+    fn check_chain_id(expected: Option<&str>, actual: &str) -> Result<(), WorkerInitError> {
+        match expected {
+            Some(expected) if expected != actual => Err(WorkerInitError::ChainIdMismatch {
+                expected: expected.to_string(),
+                actual: actual.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Try to issue commands to mobilecoind to set up a new account, returning an
+    // error if any of them fail
+    //
+    // Returns monitor id, monitor public address, monitor b58 address, and the
+    // current network minimum fees
+    fn try_new_mobilecoind(
+        mobilecoind_api_client: &MobilecoindApiClient,
+        account_key: &MonitorKey,
+        timeouts: RpcTimeouts,
+        expected_chain_id: Option<&str>,
+    ) -> Result<MobilecoindSetupData, WorkerInitError> {
+        // Get the network minimum fees and chain id. The account isn't registered
+        // with a mobilecoind on another network than expected.
+        let (minimum_fees, chain_id) = {
+            let mut minimum_fees = HashMap::<TokenId, u64>::default();
+
+            let mut resp = mobilecoind_api_client
+                .get_network_status_opt(&Default::default(), timeouts.query_options())
+                .map_err(|err| WorkerInitError::from_rpc(err, WorkerInitError::NetworkStatus))?;
+            event!(
+                Level::INFO,
+                "mobilecoind is on network {:?} at block {}, block version {}",
+                resp.chain_id,
+                resp.network_highest_block_index,
+                resp.get_last_block_info().network_block_version
+            );
+            Self::check_chain_id(expected_chain_id, &resp.chain_id)?;
+
+            for (k, v) in resp.get_last_block_info().minimum_fees.iter() {
+                minimum_fees.insert(k.into(), *v);
+            }
+
+            (minimum_fees, resp.take_chain_id())
+        };
+
+        // Create a monitor using our account key, or only its view key when watching
+        let monitor_id = {
+            let mut req = mcd_api::AddMonitorRequest::new();
+            match account_key {
+                MonitorKey::Full(account_key) => req.set_account_key(account_key.into()),
+                MonitorKey::ViewOnly(view_account_key) => {
+                    req.set_view_account_key(view_account_key.into())
+                }
+            }
+            req.set_num_subaddresses(NUM_SUBADDRESSES);
+            req.set_name("mobilecoind-buddy".to_string());
+
+            let resp = mobilecoind_api_client
+                .add_monitor_opt(&req, timeouts.query_options())
+                .map_err(|err| WorkerInitError::from_rpc(err, WorkerInitError::AddMonitor))?;
+
+            resp.monitor_id
+        };
+
+        // Get the b58 public address for monitor
+        let monitor_b58_address = {
+            let mut req = mcd_api::GetPublicAddressRequest::new();
+            req.set_monitor_id(monitor_id.clone());
+
+            let resp = mobilecoind_api_client
+                .get_public_address_opt(&req, timeouts.query_options())
+                .map_err(|err| WorkerInitError::from_rpc(err, WorkerInitError::AddMonitor))?;
+
+            resp.b58_code
+        };
+
+        let monitor_public_address = Self::decode_b58_address(&monitor_b58_address)
+            .map_err(|err| {
+                WorkerInitError::AddMonitor(format!(
+                    "mobilecoind returned a bad address for the monitor: {err}"
+                ))
+            })?
+            .address;
+
+        Ok(MobilecoindSetupData {
+            monitor_id,
+            monitor_public_address,
+            monitor_b58_address,
+            minimum_fees,
+            chain_id,
+        })
+    }
+
+    // Register the account with mobilecoind, retrying a few times in case it is
+    // still starting up, and again whenever the user asks to (see retry_init).
+    //
+    // Returns false if the worker was stopped before the account was registered.
+    fn connect_mobilecoind(context: &CommandContext, stop_requested: &AtomicBool) -> bool {
+        let mut attempts_left = INIT_ATTEMPTS;
+        loop {
+            if stop_requested.load(Ordering::SeqCst) {
+                return false;
+            }
+            let connecting = matches!(lock(&context.state).init_status, InitStatus::Connecting);
+            if !connecting {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            let timeouts = lock(&context.state).rpc_timeouts;
+            match Self::try_new_mobilecoind(
+                &context.mobilecoind_api_client.get(),
+                &context.account_key,
+                timeouts,
+                context.expected_chain_id.as_deref(),
+            ) {
+                Ok(data) => {
+                    let mut st = lock(&context.state);
+                    st.monitor_id = data.monitor_id;
+                    st.monitor_public_address = data.monitor_public_address;
+                    st.monitor_b58_address = data.monitor_b58_address;
+                    st.minimum_fees = data.minimum_fees;
+                    st.chain_id = data.chain_id;
+                    st.init_status = InitStatus::Ready;
+                    return true;
+                }
+                Err(err) => {
+                    event!(Level::ERROR, "Initialization failed: {}", err);
+                    attempts_left -= 1;
+                    // Waiting doesn't move mobilecoind to another network
+                    let wrong_network = matches!(err, WorkerInitError::ChainIdMismatch { .. });
+                    if attempts_left == 0 || wrong_network {
+                        lock(&context.state).init_status = InitStatus::Failed(err);
+                        attempts_left = INIT_ATTEMPTS;
+                    } else {
+                        std::thread::sleep(Duration::from_millis(1000));
+                    }
+                }
+            }
+        }
+    }
+
+    fn worker_thread_entrypoint(
+        context: CommandContext,
+        retention: RetentionPolicies,
+        commands: Receiver<(CommandId, WorkerCommand)>,
+        stop_requested: Arc<AtomicBool>,
+    ) {
+        if !Self::connect_mobilecoind(&context, &stop_requested) {
+            return;
+        }
+        let state = context.state.clone();
+        // The history and saved offers stay with the monitor id we started with
+        let initial_monitor_id = context.monitor_id();
+
+        // The history of this monitor, which is kept across restarts
+        let history_path = Self::history_path(&context.data_dir, &initial_monitor_id);
+        let mut history = match std::fs::create_dir_all(&context.data_dir)
+            .map_err(|err| err.to_string())
+            .and_then(|_| History::open(&history_path).map_err(|err| err.to_string()))
+        {
+            Ok(history) => Some(history),
+            Err(err) => {
+                let err = format!("History unavailable: {}: {err}", history_path.display());
+                event!(Level::ERROR, "{}", err);
+                lock(&state).errors.push(Severity::Warning, err);
+                None
+            }
+        };
+
+        // The offers of this monitor which made it to the deqs, which are kept across restarts
+        let offers_path = Self::offers_path(&context.data_dir, &initial_monitor_id);
+        let mut saved_offers = match SavedOffer::load(&offers_path) {
+            Ok(saved_offers) => saved_offers,
+            Err(err) => {
+                let err = format!("Saved offers unavailable: {}: {err}", offers_path.display());
+                event!(Level::ERROR, "{}", err);
+                lock(&state).errors.push(Severity::Warning, err);
+                Vec::new()
+            }
+        };
+        {
+            let mut st = lock(&state);
+            // The inputs of open offers must not be offered or spent again
+            for offer in saved_offers.iter() {
+                if offer.status == SavedOfferStatus::Open {
+                    st.reserved_inputs.insert(offer.key_image);
+                }
+            }
+            st.offers.restore(saved_offers.clone());
+        }
+
+        // The receive subaddresses issued to this monitor, which are never issued again
+        let subaddresses_path = Self::subaddresses_path(&context.data_dir, &initial_monitor_id);
+        match IssuedSubaddress::load(&subaddresses_path) {
+            Ok(issued) => lock(&state).issued_subaddresses.extend(
+                issued
+                    .into_iter()
+                    .map(|issued| (issued.index, issued.b58_address)),
+            ),
+            Err(err) => {
+                let err = format!(
+                    "Issued subaddresses unavailable: {}: {err}",
+                    subaddresses_path.display()
+                );
+                event!(Level::ERROR, "{}", err);
+                lock(&state).errors.push(Severity::Warning, err);
+            }
+        }
+
+        // A panic in the polling loop is reported, and the loop started over, so
+        // that a bug doesn't take the whole app down, now or when it exits
+        loop {
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                Self::polling_loop(
+                    &context,
+                    &retention,
+                    &commands,
+                    &stop_requested,
+                    &mut history,
+                    &offers_path,
+                    &mut saved_offers,
+                )
+            }));
+            let panic = match result {
+                Ok(()) => break,
+                Err(panic) => panic,
+            };
+            let msg = panic
+                .downcast_ref::<&str>()
+                .map(|msg| msg.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            event!(Level::ERROR, "worker thread panicked, restarting: {}", msg);
+            // The command being executed, if any, was cut short
+            let mut st = lock(&state);
+            st.commands
+                .fail_submitting(&format!("The worker crashed executing the command: {msg}"));
+            st.errors.push(
+                Severity::Error,
+                format!("The worker crashed and was restarted: {msg}"),
+            );
+            drop(st);
+            if stop_requested.load(Ordering::SeqCst) {
+                break;
+            }
+            std::thread::sleep(Self::RESTART_DELAY);
+        }
+    }
+
+    // Poll the servers and execute the commands queued by the UI, until stop is
+    // requested
+    fn polling_loop(
+        context: &CommandContext,
+        retention: &RetentionPolicies,
+        commands: &Receiver<(CommandId, WorkerCommand)>,
+        stop_requested: &AtomicBool,
+        history: &mut Option<History>,
+        offers_path: &Path,
+        saved_offers: &mut Vec<SavedOffer>,
+    ) {
+        let CommandContext {
+            deqs_connections,
+            state,
+            published,
+            ..
+        } = context.clone();
+
+        // When we last polled each server, None if we should poll right away
+        let mut last_mobilecoind_poll: Option<Instant> = None;
+        let mut last_deqs_poll: Option<Instant> = None;
+        let mut last_price_poll: Option<Instant> = None;
+        // When we last applied the retention policies
+        let mut last_compaction: Option<Instant> = None;
+        // When we last asked for the status of pending transactions
+        let mut last_pending_poll: Option<Instant> = None;
+        // When we last refreshed the unspent txos
+        let mut last_utxo_poll: Option<Instant> = None;
+        // When we last checked whether live offers were filled or expired
+        let mut last_offer_poll: Option<Instant> = None;
+        // How much to slow down the polls of each server, while they find nothing new
+        let mut mobilecoind_idle = AdaptiveInterval::default();
+        let mut deqs_idle = AdaptiveInterval::default();
+
+        // The live update streams from the deqs, for the pair being looked at. They
+        // are closed when dropped, also when this thread exits.
+        let (stream_events_sender, stream_events) = channel::<StreamEvent>();
+        let mut quote_streams = Vec::<QuoteStream>::default();
+        let mut streamed_pair: Option<(TokenId, TokenId)> = None;
+        // The deqs which don't support live updates, and are only polled
+        let mut streaming_unsupported = HashSet::<usize>::default();
+        // When the stream of each deqs last ended, so that it isn't reopened right away
+        let mut streams_ended = HashMap::<usize, Instant>::default();
+
+        loop {
+            if stop_requested.load(Ordering::SeqCst) {
+                // Commands which were queued but not executed are dropped, tell the user
+                let mut st = lock(&state);
+                for (command_id, command) in commands.try_iter() {
+                    event!(Level::WARN, "dropping command on shutdown: {:?}", command);
+                    let err = "worker is shutting down, command dropped".to_string();
+                    st.commands
+                        .set_status(command_id, CommandStatus::Failed(err.clone()));
+                    st.errors.push(Severity::Error, err);
+                }
+                break;
+            }
+
+            event!(Level::TRACE, "worker: polling loop");
+            Published::publish(&state, &published);
+            let monitor_id = context.monitor_id();
+            // The channel may have been rebuilt since the last iteration
+            let mobilecoind_api_client = context.mobilecoind_api_client.get();
+
+            // Execute the commands queued by the UI
+            while let Ok((command_id, command)) = commands.try_recv() {
+                Self::execute_command(command_id, command, context);
+                mobilecoind_idle.reset();
+                deqs_idle.reset();
+                if stop_requested.load(Ordering::SeqCst) {
+                    break;
+                }
+            }
+            Self::estimate_pending_fee(&monitor_id, &mobilecoind_api_client, &state);
+
+            let (activity_level, refresh_requested, active_intervals) = {
+                let mut st = lock(&state);
+                (
+                    st.activity_level,
+                    std::mem::take(&mut st.refresh_requested),
+                    st.poll_intervals,
+                )
+            };
+            if refresh_requested {
+                last_mobilecoind_poll = None;
+                last_deqs_poll = None;
+                last_utxo_poll = None;
+                mobilecoind_idle.reset();
+                deqs_idle.reset();
+            }
+            // Offers which are renewed or hedged by the worker need fresh data even
+            // while nobody is looking
+            let automation_armed = lock(&state).offers.automation_armed();
+            let intervals = activity_level.poll_intervals(automation_armed, active_intervals);
+
+            if is_due(last_compaction, RetentionPolicies::COMPACTION_INTERVAL) {
+                last_compaction = Some(Instant::now());
+                Self::compact(retention, &state);
+            }
+
+            // Discard held offers which expired, releasing their inputs
+            {
+                let mut st = lock(&state);
+                for held in st.held_offers.expire(Instant::now()) {
+                    event!(Level::INFO, "held offer {} expired", held.handle);
+                    st.reserved_inputs.remove(&held.sci.key_image());
+                }
+            }
+            if is_due(last_utxo_poll, intervals.utxos) {
+                last_utxo_poll = Some(Instant::now());
+                if let Err(err) = Self::poll_utxos(&monitor_id, &mobilecoind_api_client, &state) {
+                    event!(Level::WARN, "polling utxos: {}", err);
+                    context.recover_monitor(&err.to_string());
+                }
+            }
+
+            if is_due(last_offer_poll, intervals.offers) {
+                last_offer_poll = Some(Instant::now());
+                if let Err(err) = Self::poll_offers(&monitor_id, &mobilecoind_api_client, &state) {
+                    event!(Level::WARN, "polling offers: {}", err);
+                }
+                context.renew_expired_offers();
+                let offers = lock(&state).offers.saved();
+                if offers != *saved_offers {
+                    if let Err(err) = SavedOffer::store(offers_path, &offers) {
+                        event!(Level::ERROR, "saving offers: {}", err);
+                    }
+                    *saved_offers = offers;
+                }
+            }
+
+            if is_due(last_pending_poll, intervals.pending) {
+                last_pending_poll = Some(Instant::now());
+                Self::poll_pending_transactions(&mobilecoind_api_client, &state);
+            }
+
+            if let Some(history) = history.as_ref() {
+                Self::load_history_page(history, &state);
+            }
+
+            let mobilecoind_interval = mobilecoind_idle.interval(intervals.mobilecoind);
+
+            if is_due(last_mobilecoind_poll, mobilecoind_interval) {
+                last_mobilecoind_poll = Some(Instant::now());
+                let progress_before = Self::sync_progress(&state);
+                if let Err(err) =
+                    Self::poll_mobilecoind(&monitor_id, &mobilecoind_api_client, &state)
+                {
+                    event!(Level::ERROR, "polling mobilecoind: {}", err);
+                    if context.recover_monitor(&err.to_string()) {
+                        continue;
+                    }
+                    if context.mobilecoind_api_client.record_failure(&err) {
+                        event!(Level::WARN, "rebuilt the channel to mobilecoind");
+                    }
+                    let err = match context.grpc_proxy.as_ref() {
+                        Some(proxy) => {
+                            proxy.annotate(describe_rpc_error("mobilecoind", &err), &err)
+                        }
+                        None => describe_rpc_error("mobilecoind", &err),
+                    };
+                    let backoff = {
+                        let mut st = lock(&state);
+                        let went_down = st.mobilecoind_health.record_failure(err.clone());
+                        let down = st.mobilecoind_health.status() == HealthStatus::Down;
+                        Self::report_poll_error(&mut st, err, down && !went_down);
+                        st.mobilecoind_health.backoff()
+                    };
+                    // Back off when there is an error
+                    Self::wait_for_command(commands, backoff, context);
+                    continue;
+                }
+                {
+                    let mut st = lock(&state);
+                    st.mobilecoind_health.record_success();
+                    if context.mobilecoind_api_client.record_success() {
+                        event!(Level::INFO, "reconnected to mobilecoind");
+                        st.errors
+                            .push(Severity::Info, "Reconnected to mobilecoind".to_string());
+                    }
+                }
+                mobilecoind_idle.record(Self::sync_progress(&state) != progress_before);
+                Self::drop_expired_quotes(&state);
+                if let Some(history) = history.as_mut() {
+                    if let Err(err) =
+                        Self::poll_history(&monitor_id, &mobilecoind_api_client, history, &state)
+                    {
+                        event!(Level::ERROR, "polling history: {}", err);
+                    }
+                }
+            }
+
+            // Streams follow the selected pair, and their updates apply to its books
+            let selected_pair = lock(&state).get_quotes_token_ids;
+            if selected_pair != streamed_pair {
+                quote_streams.clear();
+                streamed_pair = selected_pair;
+                deqs_idle.reset();
+            }
+            while let Ok(stream_event) = stream_events.try_recv() {
+                match stream_event {
+                    StreamEvent::Unsupported { source } => {
+                        event!(
+                            Level::INFO,
+                            "deqs #{} has no live updates, polling it",
+                            source
+                        );
+                        streaming_unsupported.insert(source);
+                        quote_streams.retain(|stream| stream.source != source);
+                    }
+                    StreamEvent::Ended { source, err } => {
+                        event!(
+                            Level::WARN,
+                            "live updates of deqs #{} ended: {:?}",
+                            source,
+                            err
+                        );
+                        quote_streams.retain(|stream| stream.source != source);
+                        streams_ended.insert(source, Instant::now());
+                    }
+                    StreamEvent::Update(update) => Self::apply_quote_update(update, &state),
+                }
+            }
+
+            if let Some(deqs_interval) = intervals.deqs {
+                // While every deqs streams its updates, the books only need an
+                // occasional poll to catch anything the streams missed
+                let deqs_interval = if quote_streams.len() == deqs_connections.len() {
+                    deqs_interval.max(QuoteStream::RESYNC_INTERVAL)
+                } else {
+                    deqs_idle.interval(deqs_interval)
+                };
+                if !deqs_connections.is_empty() && is_due(last_deqs_poll, deqs_interval) {
+                    last_deqs_poll = Some(Instant::now());
+                    let generation_before = lock(&state).last_book_generation;
+                    if let Err(err) =
+                        Self::poll_my_key_images(&monitor_id, &mobilecoind_api_client, &state)
+                    {
+                        event!(Level::WARN, "polling own key images: {}", err);
+                    }
+                    let active_pair = lock(&state).get_quotes_token_ids;
+                    if let Err(err) = Self::poll_deqs(
+                        &deqs_connections,
+                        &mobilecoind_api_client,
+                        &state,
+                        active_pair,
+                    ) {
+                        event!(Level::ERROR, "polling deqs: {}", err);
+                        let backoff = {
+                            let mut st = lock(&state);
+                            st.deqs_error = Some(err.clone());
+                            let went_down = st.deqs_health.record_failure(err.clone());
+                            let down = st.deqs_health.status() == HealthStatus::Down;
+                            Self::report_poll_error(&mut st, err, down && !went_down);
+                            st.deqs_health.backoff()
+                        };
+                        // Back off when there is an error
+                        Self::wait_for_command(commands, backoff, context);
+                        continue;
+                    }
+                    let generation_after = {
+                        let mut st = lock(&state);
+                        st.deqs_health.record_success();
+                        st.last_book_generation
+                    };
+                    deqs_idle.record(generation_after != generation_before);
+
+                    // Once we have the books, follow them with the live updates of
+                    // the deqs which support them
+                    if streamed_pair.is_some() {
+                        for (source, conn) in deqs_connections.iter().enumerate() {
+                            let ended = streams_ended.get(&source).copied();
+                            if streaming_unsupported.contains(&source)
+                                || quote_streams.iter().any(|stream| stream.source == source)
+                                || !is_due(ended, QuoteStream::RESYNC_INTERVAL)
+                            {
+                                continue;
+                            }
+                            quote_streams.push(QuoteStream::open(
+                                source,
+                                conn.client.get(),
+                                conn.auth_token.clone(),
+                                stream_events_sender.clone(),
+                            ));
+                        }
+                    }
+                }
+            }
+
+            // Sample the mid-price of the active pair, for its chart
+            if !deqs_connections.is_empty() && lock(&state).price_history.is_due(Instant::now()) {
+                context.sample_active_price();
+            }
+
+            // The indicative prices are kept up to date at a slow pace, whichever pair
+            // the user is looking at
+            if intervals.deqs.is_some()
+                && !deqs_connections.is_empty()
+                && is_due(last_price_poll, IndicativePrices::POLL_INTERVAL)
+            {
+                last_price_poll = Some(Instant::now());
+                context.poll_indicative_prices(&mobilecoind_api_client);
+            }
+
+            // Wait until the next poll is due, or a command is queued. Live updates
+            // are applied at the rate the deqs are polled while the user is active.
+            let mut wait = mobilecoind_interval.min(AdaptiveInterval::MAX_IDLE);
+            if let Some(deqs_interval) = intervals.deqs.filter(|_| !deqs_connections.is_empty()) {
+                wait = wait.min(deqs_idle.interval(deqs_interval));
+            }
+            if !quote_streams.is_empty() {
+                wait = wait.min(active_intervals.deqs.unwrap_or(wait));
+            }
+            if let Some(due_in) = lock(&state)
+                .fee_estimate
+                .as_ref()
+                .and_then(|estimate| estimate.due_in(Instant::now()))
+            {
+                wait = wait.min(due_in);
+            }
+            if Self::wait_for_command(commands, wait, context) {
+                mobilecoind_idle.reset();
+                deqs_idle.reset();
+            }
+        }
+    }
+
+    // Wait up to `timeout` for the UI to queue a command, and execute it if it does.
+    // Returns right away if the command queue was hung up, which happens when the
+    // worker is stopped. Returns whether a command was executed.
+    fn wait_for_command(
+        commands: &Receiver<(CommandId, WorkerCommand)>,
+        timeout: Duration,
+        context: &CommandContext,
+    ) -> bool {
+        match commands.recv_timeout(timeout) {
+            Ok((command_id, command)) => {
+                Self::execute_command(command_id, command, context);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // The sync progress, to tell whether a poll of mobilecoind found anything new
+    fn sync_progress(state: &Mutex<WorkerState>) -> (u64, u64) {
+        let st = lock(&state);
+        (st.synced_blocks, st.total_blocks)
+    }
+
+    // Queue the error of a failed poll. While the backend stays down, the health
+    // indicator tells the user, so its errors are not queued at all.
+    fn report_poll_error(st: &mut WorkerState, err: String, still_down: bool) {
+        if !still_down {
+            st.errors.push(Severity::Warning, err);
+        }
+    }
+
+    fn poll_mobilecoind(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Arc<Mutex<WorkerState>>,
+    ) -> Result<(), grpcio::Error> {
+        let timeouts = lock(&state).rpc_timeouts;
+        span!(Level::TRACE, "poll mobilecoind");
+        // The tokens with a minimum fee are the ones configured on this network
+        let token_ids: Vec<TokenId> = lock(&state).minimum_fees.keys().cloned().collect();
+        let mut num_rpcs = 0;
+        // Check ledger status
+        {
+            event!(Level::TRACE, "worker: check ledger status");
+            let info = client.get_ledger_info_opt(&Default::default(), timeouts.query_options())?;
+            num_rpcs += 1;
+            let mut st = lock(&state);
+            st.total_blocks = info.block_count;
+        }
+
+        // Check monitor status, and the range of subaddresses it covers
+        let (first_subaddress, num_subaddresses, next_block) = {
+            event!(Level::TRACE, "worker: check monitor status");
+            let mut req = mcd_api::GetMonitorStatusRequest::new();
+            req.set_monitor_id(monitor_id.to_owned());
+            let resp = client.get_monitor_status_opt(&req, timeouts.query_options())?;
+            num_rpcs += 1;
+            let status = resp.get_status();
+
+            let mut st = lock(&state);
+            st.synced_blocks = status.next_block;
+            (
+                status.first_subaddress,
+                status.num_subaddresses,
+                status.next_block,
+            )
+        };
+
+        // The balances only change when the monitor processes a block, unless we
+        // spent from them. Until the whole subaddress range was swept once, every
+        // poll sweeps another chunk of it.
+        let balances_current = {
+            let st = lock(&state);
+            st.balances_block == Some(next_block) && st.subaddress_balances.is_swept()
+        };
+        if balances_current {
+            event!(Level::DEBUG, "polled mobilecoind with {} rpcs", num_rpcs);
+            return Ok(());
+        }
+
+        // Get balance, per subaddress. This polls the subaddresses which received funds
+        // and the issued receive subaddresses, and sweeps a chunk of the rest of the range.
+        {
+            let subaddresses: Vec<u64> = {
+                let mut st = lock(&state);
+                let always: Vec<u64> = std::iter::once(0)
+                    .chain(st.issued_subaddresses.keys().cloned())
+                    .collect();
+                st.subaddress_balances.subaddresses_to_poll(
+                    first_subaddress,
+                    num_subaddresses,
+                    always,
+                )
+            };
+            for token_id in token_ids.iter() {
+                event!(Level::TRACE, "worker: check balance: {}", *token_id);
+                for subaddress_index in subaddresses.iter() {
+                    let mut req = mcd_api::GetBalanceRequest::new();
+                    req.set_monitor_id(monitor_id.to_owned());
+                    req.set_subaddress_index(*subaddress_index);
+                    req.set_token_id(**token_id);
+                    let resp = client.get_balance_opt(&req, timeouts.query_options())?;
+                    num_rpcs += 1;
+
+                    let mut st = lock(&state);
+                    st.subaddress_balances
+                        .record(*subaddress_index, *token_id, resp.balance);
+                }
+            }
+
+            let mut st = lock(&state);
+            let mut totals = st.subaddress_balances.totals();
+            for token_id in token_ids.iter() {
+                totals.entry(*token_id).or_default();
+            }
+            st.balance = totals;
+            st.balances_block = Some(next_block);
+            // The balances can't be trusted until every subaddress was looked at
+            if st.subaddress_balances.is_swept() {
+                st.balance_fetched = true;
+            }
+        }
+        event!(Level::DEBUG, "polled mobilecoind with {} rpcs", num_rpcs);
+        Ok(())
+    }
+
+    // The path of the history spill file of a monitor
+    fn history_path(data_dir: &Path, monitor_id: &[u8]) -> PathBuf {
+        data_dir.join(format!("history-{}.spill", Self::monitor_hex(monitor_id)))
+    }
+
+    // The path of the file with the saved offers of a monitor
+    fn offers_path(data_dir: &Path, monitor_id: &[u8]) -> PathBuf {
+        data_dir.join(format!("offers-{}.json", Self::monitor_hex(monitor_id)))
+    }
+
+    // The path of the file with the receive subaddresses issued to a monitor
+    fn subaddresses_path(data_dir: &Path, monitor_id: &[u8]) -> PathBuf {
+        data_dir.join(format!(
+            "subaddresses-{}.json",
+            Self::monitor_hex(monitor_id)
+        ))
+    }
+
+    // The monitor id in hex, which names the files of a monitor
+    fn monitor_hex(monitor_id: &[u8]) -> String {
+        monitor_id
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    // Record the txos of the blocks the monitor processed since the last poll in
+    // the history. A bounded number of blocks is processed per poll, so that the
+    // first scan of a long ledger doesn't hold up the other polls.
+    fn poll_history(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        history: &mut History,
+        state: &Mutex<WorkerState>,
+    ) -> Result<(), String> {
+        let timeouts = lock(&state).rpc_timeouts;
+        const BLOCKS_PER_POLL: u64 = 200;
+
+        let synced_blocks = lock(&state).synced_blocks;
+        let end = synced_blocks.min(history.next_block() + BLOCKS_PER_POLL);
+        for block in history.next_block()..end {
+            let mut req = mcd_api::GetProcessedBlockRequest::new();
+            req.set_monitor_id(monitor_id.to_vec());
+            req.set_block(block);
+            let resp = client
+                .get_processed_block_opt(&req, timeouts.query_options())
+                .map_err(|err| {
+                    format!(
+                        "get processed block {block}: {}",
+                        describe_rpc_error("mobilecoind", &err)
+                    )
+                })?;
+
+            let entries: Vec<HistoryEntry> = resp
+                .get_tx_outs()
+                .iter()
+                .filter_map(|txo| {
+                    let direction = match txo.direction {
+                        mcd_api::ProcessedTxOutDirection::Received => Direction::Received,
+                        mcd_api::ProcessedTxOutDirection::Spent => Direction::Sent,
+                        _ => return None,
+                    };
+                    Some(HistoryEntry {
+                        block,
+                        token_id: TokenId::from(txo.token_id),
+                        value: txo.value,
+                        direction,
+                        subaddress_index: txo.subaddress_index,
+                    })
+                })
+                .collect();
+            history
+                .record_block(block, &entries)
+                .map_err(|err| err.to_string())?;
+
+            // The new entries are the newest, so the loaded pages now start further in
+            if !entries.is_empty() {
+                let mut st = lock(&state);
+                let view = &mut st.history_view;
+                view.next_skip += entries.len();
+                for entry in entries {
+                    if view
+                        .filter
+                        .as_ref()
+                        .map_or(false, |filter| filter.matches(&entry))
+                    {
+                        view.entries.insert(0, entry);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Load the next page of history for the UI, if it asked for one
+    fn load_history_page(history: &History, state: &Mutex<WorkerState>) {
+        let (filter, skip) = {
+            let st = lock(&state);
+            match st.history_view.filter.as_ref() {
+                Some(filter) if st.history_view.page_requested => {
+                    (filter.clone(), st.history_view.next_skip)
+                }
+                _ => return,
+            }
+        };
+
+        // Don't hold the lock during the file io
+        let result = history.query(&filter, skip);
+
+        let mut st = lock(&state);
+        let view = &mut st.history_view;
+        view.page_requested = false;
+        // The UI may have changed the filter, or new entries arrived, meanwhile
+        if view.filter.as_ref() != Some(&filter) || view.next_skip != skip {
+            return;
+        }
+        match result {
+            Ok(page) => {
+                view.entries.extend(page.entries);
+                view.next_skip = page.next_skip;
+                view.exhausted = page.exhausted;
+            }
+            Err(err) => {
+                view.exhausted = true;
+                st.errors
+                    .push(Severity::Warning, format!("Reading history: {err}"));
+            }
+        }
+    }
+
+    // Refresh the unspent txos of the tokens the UI asked for
+    fn poll_utxos(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+    ) -> Result<(), grpcio::Error> {
+        let timeouts = lock(&state).rpc_timeouts;
+        let token_ids: Vec<TokenId> = lock(&state).utxos_requested.iter().cloned().collect();
+        for token_id in token_ids {
+            let mut req = mcd_api::GetUnspentTxOutListRequest::new();
+            req.set_monitor_id(monitor_id.to_vec());
+            req.set_subaddress_index(0);
+            req.set_token_id(*token_id);
+            let resp = client.get_unspent_tx_out_list_opt(&req, timeouts.query_options())?;
+
+            let mut utxos: Vec<UtxoInfo> = resp
+                .get_output_list()
+                .iter()
+                .filter(|utxo| utxo.token_id == *token_id)
+                .filter_map(|utxo| {
+                    Some(UtxoInfo {
+                        value: utxo.value,
+                        token_id,
+                        subaddress_index: utxo.subaddress_index,
+                        key_image: KeyImage::try_from(utxo.get_key_image()).ok()?,
+                    })
+                })
+                .collect();
+            utxos.sort_by_key(|utxo| utxo.value);
+            lock(&state).utxos.insert(token_id, utxos);
+        }
+        Ok(())
+    }
+
+    // Mark the live offers whose input was spent as filled, and the ones which the
+    // deqs stopped listing although their input is unspent as expired. The inputs
+    // of both are no longer reserved. Offers we are cancelling are cancelled once
+    // their input was spent.
+    fn poll_offers(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+    ) -> Result<(), grpcio::Error> {
+        let timeouts = lock(&state).rpc_timeouts;
+        let live: Vec<TrackedOffer> = lock(&state)
+            .offers
+            .offers()
+            .iter()
+            .filter(|offer| {
+                (offer.status == OfferStatus::Live || offer.status == OfferStatus::Cancelling)
+                    && offer.key_image.is_some()
+            })
+            .cloned()
+            .collect();
+
+        // The unspent key images of each subaddress and token which has live offers
+        let mut unspent = HashMap::<(u64, TokenId), HashSet<KeyImage>>::default();
+        for offer in live.iter() {
+            let from_token_id = offer.from_amount.token_id;
+            if unspent.contains_key(&(offer.sender_subaddress, from_token_id)) {
+                continue;
+            }
+            let mut req = mcd_api::GetUnspentTxOutListRequest::new();
+            req.set_monitor_id(monitor_id.to_vec());
+            req.set_subaddress_index(offer.sender_subaddress);
+            req.set_token_id(*from_token_id);
+            let resp = client.get_unspent_tx_out_list_opt(&req, timeouts.query_options())?;
+            let key_images = resp
+                .get_output_list()
+                .iter()
+                .filter_map(|utxo| KeyImage::try_from(utxo.get_key_image()).ok())
+                .collect();
+            unspent.insert((offer.sender_subaddress, from_token_id), key_images);
+        }
+
+        let mut st = lock(&state);
+        for offer in live {
+            let key_image = offer.key_image.unwrap();
+            let pair = (offer.from_amount.token_id, offer.to_amount.token_id);
+            if !unspent[&(offer.sender_subaddress, pair.0)].contains(&key_image) {
+                if offer.status == OfferStatus::Cancelling {
+                    event!(Level::INFO, "offer {} was cancelled", offer.id);
+                    st.offers.mark_cancelled(offer.id);
+                    st.drop_quotes(&offer.quote_ids);
+                } else {
+                    event!(Level::INFO, "offer {} was filled", offer.id);
+                    st.offers.mark_filled(offer.id);
+                }
+                st.reserved_inputs.remove(&key_image);
+                continue;
+            }
+            if offer.status != OfferStatus::Live {
+                continue;
+            }
+            // Only a book polled well after the offer was submitted tells it expired
+            let listed_by = offer.submitted_at + OfferTracker::LISTING_GRACE;
+            let book_is_recent = st
+                .quote_books_polled
+                .get(&pair)
+                .map(|polled| *polled > listed_by)
+                .unwrap_or(false);
+            let listed = st
+                .quote_books
+                .get(&pair)
+                .map(|book| book.iter().any(|quote| quote.id.key_image == key_image))
+                .unwrap_or(false);
+            if book_is_recent && !listed {
+                event!(Level::INFO, "offer {} expired", offer.id);
+                st.offers.mark_expired(offer.id);
+                st.reserved_inputs.remove(&key_image);
+            }
+        }
+        Ok(())
+    }
+
+    // Ask mobilecoind for the status of the pending transactions, and of the receiver
+    // receipts the user asked to verify, and forget about the ones which resolved a
+    // while ago
+    fn poll_pending_transactions(client: &MobilecoindApiClient, state: &Mutex<WorkerState>) {
+        let timeouts = lock(&state).rpc_timeouts;
+        let (unresolved, to_verify) = {
+            let mut st = lock(&state);
+            let now = Instant::now();
+            st.pending_transactions.retain(|tx| !tx.is_expired(now));
+            let receipts_of = |filter: fn(&PendingTransaction) -> bool| {
+                st.pending_transactions
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, tx)| filter(tx))
+                    .map(|(idx, tx)| (idx, tx.receipts.clone()))
+                    .collect::<Vec<_>>()
+            };
+            (
+                receipts_of(|tx| !tx.is_resolved()),
+                receipts_of(PendingTransaction::needs_verification),
+            )
+        };
+
+        // Don't hold the lock during the rpcs. Only this thread adds or removes
+        // entries, so the indices stay valid.
+        for (idx, receipts) in unresolved {
+            let status = client
+                .get_tx_status_as_sender_opt(&receipts, timeouts.query_options())
+                .map_err(|err| event!(Level::WARN, "get tx status: {}", err))
+                .ok()
+                .map(|resp| resp.status);
+            let mut st = lock(&state);
+            let num_blocks = st.total_blocks;
+            if let Some(tx) = st.pending_transactions.get_mut(idx) {
+                // Give up once the ledger moved past the tombstone block, the
+                // transaction can't land anymore
+                let status = match status {
+                    Some(TxStatus::Unknown) | None if tx.is_past_tombstone(num_blocks) => {
+                        TxStatus::TombstoneBlockExceeded
+                    }
+                    Some(status) => status,
+                    None => continue,
+                };
+                if status != TxStatus::Unknown {
+                    event!(Level::INFO, "payment resolved: {:?}", status);
+                }
+                tx.set_status(status, num_blocks, Instant::now());
+            }
+        }
+
+        for (idx, receipts) in to_verify {
+            let result = Self::receiver_status(client, &receipts, timeouts);
+            event!(Level::INFO, "receiver receipts: {:?}", result);
+            let mut st = lock(&state);
+            if let Some(tx) = st.pending_transactions.get_mut(idx) {
+                tx.set_receiver_status(result, Instant::now());
+            }
+        }
+    }
+
+    // Get the status of a transaction from the point of view of its recipients: Verified
+    // if every receiver receipt landed, TombstoneBlockExceeded if any of them can't
+    // anymore, and Unknown otherwise.
+    //
+    // No monitor id is passed, so mobilecoind only checks the ledger and doesn't
+    // require the receipts to belong to one of its monitors. Errors are reported
+    // rather than retried, since they won't go away on their own.
+    fn receiver_status(
+        client: &MobilecoindApiClient,
+        receipts: &mcd_api::SubmitTxResponse,
+        timeouts: RpcTimeouts,
+    ) -> Result<TxStatus, String> {
+        let mut result = TxStatus::Verified;
+        for receipt in receipts.get_receiver_tx_receipt_list() {
+            let mut req = mcd_api::GetTxStatusAsReceiverRequest::new();
+            req.set_receipt(receipt.clone());
+            let status = client
+                .get_tx_status_as_receiver_opt(&req, timeouts.query_options())
+                .map_err(|err| {
+                    format!(
+                        "mobilecoind could not check the receipt: {}",
+                        describe_rpc_error("mobilecoind", &err)
+                    )
+                })?
+                .status;
+            match status {
+                TxStatus::Verified => {}
+                TxStatus::Unknown => result = TxStatus::Unknown,
+                other => return Ok(other),
+            }
+        }
+        Ok(result)
+    }
+
+    // Drop the data which the retention policies don't keep
+    fn compact(retention: &RetentionPolicies, state: &Arc<Mutex<WorkerState>>) {
+        let now = Instant::now();
+        let mut st = lock(&state);
+
+        // Quote books, newest first, except for the pair we are polling right now
+        let current = st.get_quotes_token_ids;
+        let mut books: Vec<((TokenId, TokenId), Instant)> = st
+            .quote_books_polled
+            .iter()
+            .map(|(pair, polled)| (*pair, *polled))
+            .filter(|((tok1, tok2), _)| {
+                current != Some((*tok1, *tok2)) && current != Some((*tok2, *tok1))
+            })
+            .collect();
+        books.sort_by_key(|(_, polled)| std::cmp::Reverse(*polled));
+        for (idx, (pair, polled)) in books.into_iter().enumerate() {
+            if !retention
+                .quote_books
+                .keeps(idx, now.saturating_duration_since(polled))
+            {
+                event!(Level::DEBUG, "dropping quote book {:?}", pair);
+                st.quote_books.remove(&pair);
+                st.quote_books_polled.remove(&pair);
+                st.quote_inputs_spent.remove(&pair);
+            }
+        }
+
+        // Offers we no longer track don't reserve their inputs
+        for offer in st.offers.compact(&retention.offers, now) {
+            if let Some(key_image) = offer.key_image {
+                st.reserved_inputs.remove(&key_image);
+            }
+        }
+    }
+
+    // Apply a change from a live update stream to the stored book of the selected
+    // pair, giving the book a new generation if it changed. Books which weren't
+    // polled yet are left alone, the first poll fetches them whole.
+    fn apply_quote_update(update: QuoteUpdate, state: &Mutex<WorkerState>) {
+        let st = &mut *lock(&state);
+        let is_selected = |(tok1, tok2): (TokenId, TokenId)| {
+            st.get_quotes_token_ids == Some((tok1, tok2))
+                || st.get_quotes_token_ids == Some((tok2, tok1))
+        };
+        let (pair, quotes) = match update {
+            QuoteUpdate::Added { pair, mut quote } => {
+                let book = match st.quote_books.get(&pair) {
+                    Some(book) if is_selected(pair) => book,
+                    _ => return,
+                };
+                let key_image = quote.id.key_image;
+                if quote.is_expired(st.total_blocks)
+                    || book.iter().any(|quote| quote.id.key_image == key_image)
+                {
+                    return;
+                }
+                Self::mark_my_quotes(std::slice::from_mut(&mut quote), st);
+                let mut quotes = book.to_vec();
+                quotes.push(quote);
+                ValidatedQuote::sort_book(&mut quotes);
+                (pair, quotes)
+            }
+            QuoteUpdate::Removed { pair, key_image } => {
+                let book = match st.quote_books.get(&pair) {
+                    Some(book) if is_selected(pair) => book,
+                    _ => return,
+                };
+                if !book.iter().any(|quote| quote.id.key_image == key_image) {
+                    return;
+                }
+                let quotes: Vec<ValidatedQuote> = book
+                    .iter()
+                    .filter(|quote| quote.id.key_image != key_image)
+                    .cloned()
+                    .collect();
+                (pair, quotes)
+            }
+        };
+        st.offers.observe_book(&quotes);
+        st.last_book_generation += 1;
+        let book = QuoteBook {
+            quotes: Arc::new(quotes),
+            generation: st.last_book_generation,
+        };
+        st.quote_books.insert(pair, book);
+    }
+
+    // Drop the quotes which expired as the ledger grew from the stored books, giving
+    // the books which changed a new generation
+    fn drop_expired_quotes(state: &Mutex<WorkerState>) {
+        let st = &mut *lock(&state);
+        let num_blocks = st.total_blocks;
+        for book in st.quote_books.values_mut() {
+            if book.iter().any(|quote| quote.is_expired(num_blocks)) {
+                let mut quotes = book.to_vec();
+                ValidatedQuote::drop_expired(&mut quotes, num_blocks);
+                st.last_book_generation += 1;
+                *book = QuoteBook {
+                    quotes: Arc::new(quotes),
+                    generation: st.last_book_generation,
+                };
+            }
+        }
+    }
+
+    // Refresh the key images of our unspent txos of the selected pair, from the
+    // subaddresses which hold any of those tokens, so that a deqs poll can flag
+    // our own quotes without an rpc per quote
+    fn poll_my_key_images(
+        monitor_id: &[u8],
+        client: &MobilecoindApiClient,
+        state: &Mutex<WorkerState>,
+    ) -> Result<(), grpcio::Error> {
+        let timeouts = lock(&state).rpc_timeouts;
+        let (token_ids, subaddresses) = {
+            let st = lock(&state);
+            let token_ids = match st.get_quotes_token_ids {
+                Some((token1, token2)) if token1 != token2 => [token1, token2],
+                _ => return Ok(()),
+            };
+            (token_ids, st.subaddress_balances.by_subaddress())
+        };
+
+        let mut key_images = HashSet::<KeyImage>::default();
+        for (subaddress_index, balances) in subaddresses {
+            for token_id in token_ids {
+                if balances.get(&token_id).copied().unwrap_or(0) == 0 {
+                    continue;
+                }
+                let mut req = mcd_api::GetUnspentTxOutListRequest::new();
+                req.set_monitor_id(monitor_id.to_vec());
+                req.set_subaddress_index(subaddress_index);
+                req.set_token_id(*token_id);
+                let resp = client.get_unspent_tx_out_list_opt(&req, timeouts.query_options())?;
+                key_images.extend(
+                    resp.get_output_list()
+                        .iter()
+                        .filter_map(|utxo| KeyImage::try_from(utxo.get_key_image()).ok()),
+                );
+            }
+        }
+        lock(&state).my_key_images = key_images;
+        Ok(())
+    }
+
+    // Flag the quotes whose input is one of our unspent txos, or the input of one
+    // of our offers
+    fn mark_my_quotes(quotes: &mut [ValidatedQuote], st: &WorkerState) {
+        for quote in quotes {
+            let key_image = quote.id.key_image;
+            quote.is_mine =
+                st.my_key_images.contains(&key_image) || st.reserved_inputs.contains(&key_image);
+        }
+    }
+
+    // Drop the quotes whose input was already spent on chain, which the deqs
+    // sometimes still serve. Each key image is checked once, and the result is
+    // remembered for as long as the deqs keep serving the quote.
+    fn drop_spent_quotes(
+        client: &MobilecoindApiClient,
+        pair: (TokenId, TokenId),
+        quotes: &mut Vec<ValidatedQuote>,
+        state: &Mutex<WorkerState>,
+    ) {
+        let timeouts = lock(&state).rpc_timeouts;
+        let mut spent = lock(&state)
+            .quote_inputs_spent
+            .remove(&pair)
+            .unwrap_or_default();
+
+        // mobilecoind only reports a sender receipt as verified once all of its key
+        // images are in the ledger, so each key image gets a receipt of its own.
+        // Start every check before waiting on any of them.
+        Self::filter_spent_quotes(quotes, &mut spent, |unchecked| {
+            let receivers: Vec<_> = unchecked
+                .iter()
+                .map(|key_image| {
+                    let mut receipt = mcd_api::SenderTxReceipt::new();
+                    receipt.set_key_image_list(vec![external::KeyImage::from(key_image)].into());
+                    receipt.set_tombstone(u64::MAX);
+                    let mut req = mcd_api::SubmitTxResponse::new();
+                    req.set_sender_tx_receipt(receipt);
+                    client.get_tx_status_as_sender_async_opt(&req, timeouts.query_options())
+                })
+                .collect();
+            unchecked
+                .iter()
+                .zip(receivers)
+                .filter_map(|(key_image, receiver)| {
+                    match receiver.and_then(futures::executor::block_on) {
+                        Ok(resp) => Some((*key_image, resp.status == TxStatus::Verified)),
+                        // Check it again on the next poll
+                        Err(err) => {
+                            event!(Level::WARN, "checking quote input: {}", err);
+                            None
+                        }
+                    }
+                })
+                .collect()
+        });
+        lock(&state).quote_inputs_spent.insert(pair, spent);
+    }
+
+    // Drop the quotes whose input was spent, according to `spent`, which remembers
+    // the key images checked so far and forgets those no longer served. The key
+    // images which weren't checked yet are passed to `check`, which returns whether
+    // each of them was spent, leaving out those it couldn't check.
+    fn filter_spent_quotes(
+        quotes: &mut Vec<ValidatedQuote>,
+        spent: &mut HashMap<KeyImage, bool>,
+        check: impl FnOnce(&[KeyImage]) -> Vec<(KeyImage, bool)>,
+    ) {
+        let served: HashSet<KeyImage> = quotes.iter().map(|quote| quote.id.key_image).collect();
+        spent.retain(|key_image, _| served.contains(key_image));
+        let unchecked: Vec<KeyImage> = served
+            .into_iter()
+            .filter(|key_image| !spent.contains_key(key_image))
+            .collect();
+        if !unchecked.is_empty() {
+            spent.extend(check(&unchecked));
+        }
+
+        quotes.retain(|quote| {
+            let is_spent = spent.get(&quote.id.key_image).copied().unwrap_or(false);
+            if is_spent {
+                event!(
+                    Level::DEBUG,
+                    "dropping quote with spent input {:?}",
+                    quote.id
+                );
+            }
+            !is_spent
+        });
+    }
+
+    // Poll every deqs for the quote books of the selected pair, and merge them.
+    // Quotes whose input was already spent are dropped.
+    //
+    // A deqs which fails to respond is reported, but doesn't prevent the books
+    // from the other deqs from being stored. This only returns an error if every
+    // deqs failed.
+    fn poll_deqs(
+        connections: &[DeqsConnection],
+        client: &MobilecoindApiClient,
+        state: &Arc<Mutex<WorkerState>>,
+        maybe_tokens: Option<(TokenId, TokenId)>,
+    ) -> Result<(), String> {
+        let (timeouts, quotes_limit) = {
+            let st = lock(&state);
+            (st.rpc_timeouts, st.quotes_limit)
+        };
+        // Only do the poll if we were given two particular tokens, and then only if
+        // they are different tokens.
+        if let Some((token1, token2)) = maybe_tokens {
+            if token1 == token2 {
+                return Ok(());
+            }
+            span!(Level::TRACE, "poll deqs");
+
+            for (base_token_id, counter_token_id) in
+                vec![(token1, token2), (token2, token1)].into_iter()
+            {
+                let mut pair = d_api::Pair::new();
+                pair.set_base_token_id(*base_token_id);
+                pair.set_counter_token_id(*counter_token_id);
+
+                let mut req = d_api::GetQuotesRequest::new();
+                req.set_pair(pair);
+                req.set_limit(quotes_limit);
+
+                event!(
+                    Level::TRACE,
+                    "getting quotes for pair {} / {}",
+                    *base_token_id,
+                    *counter_token_id
+                );
+                // Start the request to every deqs before waiting on any of them,
+                // so that they are in flight concurrently.
+                let receivers: Vec<_> = connections
+                    .iter()
+                    .map(|conn| {
+                        conn.client
+                            .get()
+                            .get_quotes_async_opt(&req, conn.call_options(timeouts.query_options()))
+                    })
+                    .collect();
+
+                let mut books = Vec::<Vec<ValidatedQuote>>::default();
+                let mut errors = Vec::<String>::default();
+                // The deqs which rejected our credentials, or accepted them
+                let mut unauthenticated = HashSet::<usize>::default();
+                let mut authenticated = HashSet::<usize>::default();
+                // The deqs which answered for the first time since their channel was rebuilt
+                let mut reconnected = Vec::<usize>::default();
+                for (source, (conn, receiver)) in connections.iter().zip(receivers).enumerate() {
+                    let response = receiver.and_then(futures::executor::block_on);
+                    match &response {
+                        Ok(_) => {
+                            authenticated.insert(source);
+                            if conn.client.record_success() {
+                                reconnected.push(source);
+                            }
+                        }
+                        Err(err) => {
+                            if conn.client.record_failure(err) {
+                                event!(Level::WARN, "rebuilt the channel to {}", conn.uri);
+                            }
+                        }
+                    }
+                    match response {
+                        Err(err) if is_unauthenticated(&err) => {
+                            unauthenticated.insert(source);
+                        }
+                        Ok(resp) => books.push(
+                            resp.get_quotes()
+                                .iter()
+                                .filter_map(|quote| {
+                                    match ValidatedQuote::try_from_quote(quote, source) {
+                                        Ok(validated_quote) => Some(validated_quote),
+                                        Err(err) => {
+                                            event!(Level::ERROR, "validating quote: {}", err);
+                                            None
+                                        }
+                                    }
+                                })
+                                .collect(),
+                        ),
+                        Err(err) => {
+                            let err = conn.describe_error(&err);
+                            event!(Level::ERROR, "getting quotes from {}: {}", conn.uri, err);
+                            errors.push(format!("{}: {}", conn.uri, err));
+                        }
+                    }
+                }
+
+                // A rejected token is reported once, rather than at every poll, until
+                // the deqs accepts it again
+                {
+                    let mut st = lock(&state);
+                    for source in reconnected {
+                        event!(Level::INFO, "reconnected to {}", connections[source].uri);
+                        st.errors.push(
+                            Severity::Info,
+                            format!("Reconnected to {}", connections[source].uri),
+                        );
+                    }
+                    st.deqs_auth_failed
+                        .retain(|source| !authenticated.contains(source));
+                    for source in unauthenticated {
+                        let err = format!("{}: {}", connections[source].uri, DEQS_AUTH_FAILED);
+                        if st.deqs_auth_failed.insert(source) {
+                            event!(Level::ERROR, "{}", err);
+                            st.errors.push(Severity::Error, err.clone());
+                        }
+                        // Only the health of the deqs still tells, if none answered
+                        if books.is_empty() {
+                            errors.push(err);
+                        }
+                    }
+                }
+
+                // Every deqs which failed is reported, unless none answered
+                let (mut validated_quotes, errors) =
+                    ValidatedQuote::merge_polled_books(books, errors)?;
+                if !errors.is_empty() {
+                    let mut st = lock(&state);
+                    for err in errors {
+                        st.errors.push(Severity::Warning, err);
+                    }
+                }
+
+                Self::drop_spent_quotes(
+                    client,
+                    (base_token_id, counter_token_id),
+                    &mut validated_quotes,
+                    state,
+                );
+                {
+                    let mut st = lock(&state);
+                    ValidatedQuote::drop_expired(&mut validated_quotes, st.total_blocks);
+                    ValidatedQuote::sort_book(&mut validated_quotes);
+                    Self::mark_my_quotes(&mut validated_quotes, &st);
+                    st.deqs_error = None;
+                    st.offers.observe_book(&validated_quotes);
+                    let pair = (base_token_id, counter_token_id);
+                    st.quote_books_polled.insert(pair, Instant::now());
+                    // Keep the stored book, and its generation, if nothing changed
+                    let unchanged = st
+                        .quote_books
+                        .get(&pair)
+                        .map_or(false, |book| book.has_same_quotes(&validated_quotes));
+                    if !unchanged {
+                        st.last_book_generation += 1;
+                        let book = QuoteBook {
+                            quotes: Arc::new(validated_quotes),
+                            generation: st.last_book_generation,
+                        };
+                        st.quote_books.insert(pair, book);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CommandContext {
+    // Sample the mid-price of the active pair from its books. A book which is stale,
+    // or lacks quotes on a side, is sampled as a gap.
+    fn sample_active_price(&self) {
+        let mut st = lock(&self.state);
+        let (base_token_id, counter_token_id) = match st.get_quotes_token_ids {
+            Some(pair) if pair.0 != pair.1 => pair,
+            _ => return,
+        };
+        let token_infos = self.token_registry.token_infos(&st.minimum_fees);
+        let pairs = [
+            (base_token_id, counter_token_id),
+            (counter_token_id, base_token_id),
+        ];
+        let now = Instant::now();
+        let fresh = pairs.iter().all(|pair| {
+            st.quote_book_age(*pair, now)
+                .map_or(false, |age| age <= st.max_quote_age)
+        });
+        let price = if fresh {
+            let books = pairs.map(|pair| st.quote_books.get(&pair).cloned().unwrap_or_default());
+            let (best_bid, best_ask) =
+                Worker::best_bid_ask(&books, base_token_id, counter_token_id, &token_infos);
+            mid_price(best_bid, best_ask)
+        } else {
+            None
+        };
+        st.price_history
+            .record((base_token_id, counter_token_id), price, Instant::now());
+    }
+
+    // Poll the books of every token against EUSD, and record their mid-prices. A
+    // book which can't be polled keeps its price until it gets too old to be shown.
+    fn poll_indicative_prices(&self, client: &MobilecoindApiClient) {
+        let token_infos = {
+            let st = lock(&self.state);
+            self.token_registry.token_infos(&st.minimum_fees)
+        };
+        let reference = match token_infos
+            .iter()
+            .find(|info| info.symbol == IndicativePrices::REFERENCE_SYMBOL)
+        {
+            Some(info) => info.token_id,
+            None => return,
+        };
+        lock(&self.state).indicative_prices.set_reference(reference);
+
+        for info in token_infos.iter().filter(|info| info.token_id != reference) {
+            let pair = (info.token_id, reference);
+            if let Err(err) =
+                Worker::poll_deqs(&self.deqs_connections, client, &self.state, Some(pair))
+            {
+                event!(Level::WARN, "polling the price of {}: {}", info.symbol, err);
+                continue;
+            }
+            let mut st = lock(&self.state);
+            let books = [pair, (reference, info.token_id)]
+                .map(|pair| st.quote_books.get(&pair).cloned().unwrap_or_default());
+            let (best_bid, best_ask) =
+                Worker::best_bid_ask(&books, info.token_id, reference, &token_infos);
+            st.indicative_prices.record(
+                info.token_id,
+                mid_price(best_bid, best_ask),
+                Instant::now(),
+            );
+        }
+    }
+
+    // The monitor id we registered the account with in mobilecoind
+    fn monitor_id(&self) -> Vec<u8> {
+        lock(&self.state).monitor_id.clone()
+    }
+
+    // If `err` means that mobilecoind lost our monitor, register the account with
+    // it again, and swap in the new monitor id. The errors caused by the lost
+    // monitor are cleared, and the user is told what happened.
+    //
+    // Returns whether the account was registered again.
+    fn recover_monitor(&self, err: &str) -> bool {
+        if !is_monitor_lost_error(err) {
+            return false;
+        }
+        // A mobilecoind which came back on another network doesn't get the account
+        let (timeouts, chain_id) = {
+            let st = lock(&self.state);
+            (st.rpc_timeouts, st.chain_id.clone())
+        };
+        match Worker::try_new_mobilecoind(
+            &self.mobilecoind_api_client.get(),
+            &self.account_key,
+            timeouts,
+            Some(&chain_id),
+        ) {
+            Ok(data) => {
+                event!(
+                    Level::WARN,
+                    "mobilecoind lost our monitor, re-registered the account"
+                );
+                let mut st = lock(&self.state);
+                st.monitor_id = data.monitor_id;
+                st.monitor_public_address = data.monitor_public_address;
+                st.monitor_b58_address = data.monitor_b58_address;
+                st.minimum_fees = data.minimum_fees;
+                st.errors.retain(|err| !is_monitor_lost_error(err));
+                let notice = "Re-registered account with mobilecoind".to_string();
+                st.errors.note(Severity::Info, notice.clone());
+                st.notice = Some((notice, Instant::now()));
+                true
+            }
+            Err(err) => {
+                event!(Level::ERROR, "re-registering the account: {}", err);
+                false
+            }
+        }
+    }
+
+    // Generate and submit a swap offer, recording the outcome in the tracked offer
+    fn execute_offer_swap(
+        &self,
+        offer_id: OfferId,
+        from_amount: Amount,
+        to_amount: Amount,
+        sender_subaddress: u64,
+        fill_mode: FillMode,
+    ) -> Result<(), String> {
+        span!(Level::INFO, "offer_swap");
+        let result = self.track_progress(OperationProgress::SelectingInput, || {
+            self.try_offer_swap(
+                offer_id,
+                from_amount,
+                to_amount,
+                sender_subaddress,
+                fill_mode,
+            )
+        });
+        self.record_offer_result(offer_id, result)
+    }
+
+    // Generate and submit both offers of a two-sided quote, the ask first. Reports
+    // which side failed, if any did.
+    fn execute_offer_both_sides(
+        &self,
+        ask_offer_id: OfferId,
+        bid_offer_id: OfferId,
+        quote: TwoSidedQuote,
+        sender_subaddress: u64,
+        fill_mode: FillMode,
+    ) -> Result<(), String> {
+        span!(Level::INFO, "offer_both_sides");
+        let [ask_result, bid_result] = [(ask_offer_id, quote.ask), (bid_offer_id, quote.bid)].map(
+            |(offer_id, (from_amount, to_amount))| {
+                self.execute_offer_swap(
+                    offer_id,
+                    from_amount,
+                    to_amount,
+                    sender_subaddress,
+                    fill_mode,
+                )
+            },
+        );
+        match (ask_result, bid_result) {
+            (Ok(()), Ok(())) => Ok(()),
+            (Ok(()), Err(err)) => Err(format!("ask posted, bid failed: {err}")),
+            (Err(err), Ok(())) => Err(format!("bid posted, ask failed: {err}")),
+            (Err(ask_err), Err(bid_err)) => {
+                Err(format!("ask failed: {ask_err}; bid failed: {bid_err}"))
+            }
+        }
+    }
+
+    // Submit the expired offers which the user wants kept alive again, with fresh
+    // scis. An offer which the balance no longer covers stops being renewed.
+    fn renew_expired_offers(&self) {
+        let renewals: Vec<TrackedOffer> = {
+            let mut st = lock(&self.state);
+            let expired: Vec<TrackedOffer> = st
+                .offers
+                .offers()
+                .iter()
+                .filter(|offer| offer.status == OfferStatus::Expired && offer.keep_alive)
+                .cloned()
+                .collect();
+            let mut renewals = Vec::new();
+            for offer in expired {
+                let snapshot = st.balance_snapshot_of(offer.sender_subaddress);
+                let from_amount =
+                    ScaledAmount::new(offer.from_amount.value, offer.from_amount.token_id);
+                if let Err(err) = preflight::check_offer(&snapshot, from_amount) {
+                    event!(Level::WARN, "not renewing offer {}: {}", offer.id, err);
+                    st.offers.set_keep_alive(offer.id, false);
+                    st.errors.push(
+                        Severity::Warning,
+                        format!("Offer no longer kept alive: {err}"),
+                    );
+                    continue;
+                }
+                if st.offers.begin_renewal(offer.id) {
+                    renewals.push(offer);
+                }
+            }
+            renewals
+        };
+        for offer in renewals {
+            event!(
+                Level::INFO,
+                "renewing offer {} (renewal {})",
+                offer.id,
+                offer.renewals + 1
+            );
+            if let Err(err) = self.execute_offer_swap(
+                offer.id,
+                offer.from_amount,
+                offer.to_amount,
+                offer.sender_subaddress,
+                offer.fill_mode,
+            ) {
+                let err = format!("Offer not renewed: {err}");
+                lock(&self.state).errors.push(Severity::Error, err);
+            }
+        }
+    }
+
+    // Generate the sci for a swap offer, and hold it for preview
+    fn execute_preview_offer(
+        &self,
+        handle: HoldHandle,
+        from_amount: Amount,
+        to_amount: Amount,
+        sender_subaddress: u64,
+        fill_mode: FillMode,
+    ) -> Result<(), String> {
+        span!(Level::INFO, "preview_offer");
+        let result = self.track_progress(OperationProgress::SelectingInput, || {
+            self.generate_offer_sci(from_amount, to_amount, sender_subaddress, fill_mode)
+        });
+        let mut st = lock(&self.state);
+        match result {
+            Ok((proto_sci, sci, amounts)) => {
+                let key_image = sci.key_image();
+                let held = HeldOffer {
+                    handle,
+                    from_amount,
+                    to_amount,
+                    sender_subaddress,
+                    fill_mode,
+                    proto_sci,
+                    sci,
+                    amounts,
+                    expires_at: Instant::now() + HeldOffers::HOLD_TIMEOUT,
+                };
+                // If it was discarded while we generated it, release the input right away
+                if !st.held_offers.hold(held) {
+                    st.reserved_inputs.remove(&key_image);
+                }
+                Ok(())
             }
             Err(err) => {
-                event!(Level::ERROR, "failed to submit payment: {}", err);
-                let mut st = self.state.lock().unwrap();
-                st.errors.push_back(err.to_string());
+                event!(Level::ERROR, "failed to generate swap offer: {}", err);
+                st.held_offers.take(handle);
+                Err(err)
             }
         }
     }
 
-    /// Create and submit a swap offer
-    pub fn offer_swap(&self, from_amount: Amount, to_amount: Amount) {
-        span!(Level::INFO, "offer_swap");
-        // FIXME: There should not be any unwraps, we should split this out into a helper function probably
-        let selected_utxo = match self.get_specific_utxo(from_amount) {
-            Ok(utxo) => utxo,
+    // Spend the input of an offer in a self-payment, to cancel the offer. If the input
+    // was already spent, someone filled the offer, which isn't an error.
+    fn execute_cancel_offer(&self, offer_id: OfferId) -> Result<(), String> {
+        span!(Level::INFO, "cancel_offer");
+        let result = self.try_cancel_offer(offer_id);
+        let mut st = lock(&self.state);
+        match result {
+            Ok(true) => Ok(()),
+            Ok(false) => {
+                event!(Level::INFO, "offer {} was already filled", offer_id);
+                st.offers.mark_filled(offer_id);
+                Ok(())
+            }
+            Err(err) if is_spent_key_image_error(&err) => {
+                event!(Level::INFO, "offer {} was already filled", offer_id);
+                st.offers.mark_filled(offer_id);
+                Ok(())
+            }
             Err(err) => {
-                event!(
-                    Level::ERROR,
-                    "failed to obtain required utxo for swap: {}",
-                    err
-                );
-                let mut st = self.state.lock().unwrap();
-                st.errors.push_back(err);
-                return;
+                event!(Level::ERROR, "failed to cancel offer: {}", err);
+                st.offers.cancel_failed(offer_id);
+                Err(format!("Offer not cancelled: {err}"))
+            }
+        }
+    }
+
+    // Helper for execute_cancel_offer.
+    //
+    // Finds the input of the offer, and submits a self-payment which spends it.
+    // Returns false if the input is already gone.
+    fn try_cancel_offer(&self, offer_id: OfferId) -> Result<bool, String> {
+        let timeouts = lock(&self.state).rpc_timeouts;
+        let offer = lock(&self.state)
+            .offers
+            .get(offer_id)
+            .cloned()
+            .ok_or("the offer is no longer tracked")?;
+        let key_image = offer.key_image.ok_or("the offer has no input")?;
+        let token_id = offer.from_amount.token_id;
+        let fee =
+            Worker::fetch_minimum_fee(&self.mobilecoind_api_client.get(), &self.state, token_id);
+
+        let mut req = mcd_api::GetUnspentTxOutListRequest::new();
+        req.set_monitor_id(self.monitor_id());
+        req.set_subaddress_index(offer.sender_subaddress);
+        req.set_token_id(*token_id);
+        let utxo = match self
+            .mobilecoind_api_client
+            .get()
+            .get_unspent_tx_out_list_opt(&req, timeouts.query_options())
+            .map_err(|err| {
+                format!(
+                    "failed getting unspent tx out list: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?
+            .take_output_list()
+            .into_iter()
+            .find(|utxo| KeyImage::try_from(utxo.get_key_image()).ok() == Some(key_image))
+        {
+            Some(utxo) => utxo,
+            None => return Ok(false),
+        };
+        if utxo.value <= fee {
+            return Err("the input of the offer doesn't cover the fee".to_owned());
+        }
+
+        let mut req = mcd_api::GetPublicAddressRequest::new();
+        req.set_monitor_id(self.monitor_id());
+        req.set_subaddress_index(offer.sender_subaddress);
+        let self_address = self
+            .mobilecoind_api_client
+            .get()
+            .get_public_address_opt(&req, timeouts.query_options())
+            .map_err(|err| {
+                format!(
+                    "failed getting public address: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?
+            .take_public_address();
+
+        let value = utxo.value - fee;
+        let mut outlay = mcd_api::Outlay::new();
+        outlay.set_value(value);
+        outlay.set_receiver(self_address);
+
+        let mut req = mcd_api::GenerateTxRequest::new();
+        req.set_sender_monitor_id(self.monitor_id());
+        req.set_change_subaddress(offer.sender_subaddress);
+        req.set_input_list(vec![utxo].into());
+        req.set_outlay_list(vec![outlay].into());
+        req.set_fee(fee);
+        req.token_id = *token_id;
+        let mut resp = self
+            .mobilecoind_api_client
+            .get()
+            .generate_tx_opt(&req, timeouts.submit_options())
+            .map_err(|err| {
+                format!(
+                    "failed generating cancel tx: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?;
+
+        let mut req = mcd_api::SubmitTxRequest::new();
+        req.set_tx_proposal(resp.take_tx_proposal());
+        let receipts = self
+            .mobilecoind_api_client
+            .get()
+            .submit_tx_opt(&req, timeouts.submit_options())
+            .map_err(|err| {
+                format!(
+                    "failed submitting cancel tx: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?;
+        let targets: Vec<String> = offer
+            .cancel_targets()
+            .into_iter()
+            .filter_map(|source| self.deqs_connections.get(source))
+            .map(|conn| conn.uri.to_string())
+            .collect();
+        event!(
+            Level::INFO,
+            "submitted cancel tx for offer {}, listed by [{}]",
+            offer_id,
+            targets.join(", ")
+        );
+
+        lock(&self.state).pending_transactions.push(
+            PendingTransaction::new(token_id, value, "(cancel offer)".to_owned(), receipts)
+                .with_proposal(req.get_tx_proposal(), true),
+        );
+        Ok(true)
+    }
+
+    // Submit the sci of a held offer, recording the outcome in the tracked offer
+    fn execute_submit_held_offer(
+        &self,
+        offer_id: OfferId,
+        sci: &SignedContingentInput,
+    ) -> Result<(), String> {
+        span!(Level::INFO, "submit_held_offer");
+        let result = self.track_progress(OperationProgress::SubmittingQuote, || {
+            self.submit_offer_sci(sci.into(), sci.key_image())
+        });
+        self.record_offer_result(offer_id, result)
+    }
+
+    // Run an offer operation, starting in the given phase, and clear the progress
+    // when it is done. If it fails, the error names the phase it failed in.
+    fn track_progress<T>(
+        &self,
+        phase: OperationProgress,
+        op: impl FnOnce() -> Result<T, String>,
+    ) -> Result<T, String> {
+        self.set_progress(phase);
+        let result = op();
+        let phase = lock(&self.state).active_operation.take();
+        result.map_err(|err| match phase {
+            Some(phase) => format!("failed while {phase}: {err}"),
+            None => err,
+        })
+    }
+
+    // Record the phase of the offer operation in progress
+    fn set_progress(&self, phase: OperationProgress) {
+        event!(Level::DEBUG, "offer: {}", phase);
+        lock(&self.state).active_operation = Some(phase);
+    }
+
+    // Fill quotes, after checking the current fee, quote books and balances, which
+    // may have changed since the swap was queued
+    fn execute_perform_swap(
+        &self,
+        fills: &[SciFill],
+        from_amount: Amount,
+        fee_token_id: TokenId,
+        options: TxOptions,
+        max_slippage: Decimal,
+    ) -> Result<(), String> {
+        let timeouts = lock(&self.state).rpc_timeouts;
+        span!(Level::INFO, "perform_swap");
+        let from_token_id = from_amount.token_id;
+        let sender_subaddress = options.sender_subaddress;
+
+        let fee = Worker::fetch_minimum_fee(
+            &self.mobilecoind_api_client.get(),
+            &self.state,
+            fee_token_id,
+        );
+        let num_blocks = {
+            let st = lock(&self.state);
+            Worker::check_swap_preconditions(
+                &st,
+                fills,
+                from_amount,
+                fee_token_id,
+                fee,
+                options,
+                max_slippage,
+            )
+            .map_err(|err| format!("Swap not submitted: {err}"))?;
+            st.total_blocks
+        };
+        // The tx can't outlive any of the scis it fills
+        let tombstone = fills
+            .iter()
+            .filter_map(SciFill::max_tombstone_block)
+            .fold(options.tombstone_block(num_blocks), u64::min);
+        self.check_fills_listed(fills)
+            .map_err(|err| format!("Swap not submitted: {err}"))?;
+        // Build the tx from a fresh utxo list on every attempt, since a transient
+        // failure usually means the utxos we used are stale
+        let retry_policy = lock(&self.state).retry_policy;
+        let result = retry_policy.run("swap", || {
+            let input_list = self.select_swap_inputs(from_amount, fee_token_id, fee, options)?;
+
+            let scis_for_tx: Vec<mcd_api::SciForTx> = fills
+                .iter()
+                .map(|fill| {
+                    let mut sci_for_tx = mcd_api::SciForTx::new();
+                    sci_for_tx.set_sci((&fill.sci).into());
+                    sci_for_tx.set_partial_fill_value(fill.partial_fill_value);
+                    sci_for_tx
+                })
+                .collect();
+
+            let mut req = mcd_api::GenerateMixedTxRequest::new();
+            req.set_sender_monitor_id(self.monitor_id());
+            req.set_change_subaddress(sender_subaddress);
+            req.set_input_list(input_list.into());
+            req.set_scis(scis_for_tx.into());
+            req.set_fee_token_id(*fee_token_id);
+            req.set_tombstone(tombstone);
+
+            let mut resp = self
+                .mobilecoind_api_client
+                .get()
+                .generate_mixed_tx_opt(&req, timeouts.submit_options())
+                .map_err(|err| {
+                    format!(
+                        "failed to generate swap tx: {}",
+                        describe_rpc_error("mobilecoind", &err)
+                    )
+                })?;
+            event!(Level::DEBUG, "generated swap tx successfully");
+
+            let mut req = mcd_api::SubmitTxRequest::new();
+            req.set_tx_proposal(resp.take_tx_proposal());
+            self.mobilecoind_api_client
+                .get()
+                .submit_tx_opt(&req, timeouts.submit_options())
+                .map_err(|err| {
+                    format!(
+                        "failed to submit swap tx: {}",
+                        describe_rpc_error("mobilecoind", &err)
+                    )
+                })
+        });
+
+        match result {
+            Ok(_resp) => {
+                event!(Level::INFO, "submitted swap tx successfully");
+                let filled: Vec<QuoteId> = fills.iter().map(SciFill::quote_id).collect();
+                lock(&self.state).drop_quotes(&filled);
+                Ok(())
+            }
+            Err(err) => {
+                event!(Level::ERROR, "{}", err);
+                Err(err)
+            }
+        }
+    }
+
+    // Helper for execute_perform_swap.
+    //
+    // Asks each deqs which served a quote we are about to fill whether it still
+    // lists the quote, so that a quote which was filled or cancelled in the meantime
+    // aborts the swap, instead of the transaction failing on the ledger. A deqs which
+    // can't be reached doesn't hold up the swap, since the ledger decides anyway, and
+    // neither does a quote which may only have been pushed past the page limit.
+    fn check_fills_listed(&self, fills: &[SciFill]) -> Result<(), String> {
+        let (timeouts, quotes_limit, routes) = {
+            let st = lock(&self.state);
+            // The pair of the book each deqs served the quotes in, and the quotes
+            let routes: Vec<_> = SciFill::route(fills)
+                .into_iter()
+                .filter_map(|(source, key_images)| {
+                    st.quote_books.iter().find_map(|(pair, book)| {
+                        let quotes: Vec<ValidatedQuote> = book
+                            .iter()
+                            .filter(|quote| {
+                                quote.id.source == source
+                                    && key_images.contains(&quote.id.key_image)
+                            })
+                            .cloned()
+                            .collect();
+                        (!quotes.is_empty()).then_some((source, *pair, quotes))
+                    })
+                })
+                .collect();
+            (st.rpc_timeouts, st.quotes_limit, routes)
+        };
+        for (source, (base_token_id, counter_token_id), quotes) in routes {
+            let conn = match self.deqs_connections.get(source) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            let mut pair = d_api::Pair::new();
+            pair.set_base_token_id(*base_token_id);
+            pair.set_counter_token_id(*counter_token_id);
+            let mut req = d_api::GetQuotesRequest::new();
+            req.set_pair(pair);
+            req.set_limit(quotes_limit);
+            let resp = match conn
+                .client
+                .get()
+                .get_quotes_opt(&req, conn.call_options(timeouts.query_options()))
+            {
+                Ok(resp) => resp,
+                Err(err) => {
+                    event!(
+                        Level::WARN,
+                        "couldn't check the quotes to fill with {}: {}",
+                        conn.uri,
+                        conn.describe_error(&err)
+                    );
+                    continue;
+                }
+            };
+            let is_complete = (resp.get_quotes().len() as u64) < quotes_limit;
+            let page: Vec<ValidatedQuote> = resp
+                .get_quotes()
+                .iter()
+                .filter_map(|quote| ValidatedQuote::try_from_quote(quote, source).ok())
+                .collect();
+            if quotes
+                .iter()
+                .any(|quote| quote.is_unlisted_in(&page, is_complete))
+            {
+                return Err(format!(
+                    "a quote is no longer listed by {}, wait for the book to refresh",
+                    conn.uri
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Helper for execute_perform_swap.
+    //
+    // Picks the txos which pay for a swap and its fee, within the protocol's limit
+    // of inputs per transaction. When the fee is paid in another token, txos of
+    // that token are inputs as well.
+    fn select_swap_inputs(
+        &self,
+        from_amount: Amount,
+        fee_token_id: TokenId,
+        fee: u64,
+        options: TxOptions,
+    ) -> Result<Vec<mcd_api::UnspentTxOut>, String> {
+        let from_token_id = from_amount.token_id;
+        let timeouts = lock(&self.state).rpc_timeouts;
+        let fetch = |token_id| {
+            Worker::fetch_unspent(
+                &self.monitor_id(),
+                &self.mobilecoind_api_client.get(),
+                options.sender_subaddress,
+                token_id,
+                timeouts,
+            )
+        };
+        let mut unspent = fetch(from_token_id)?;
+        let (from_required, fee_required) = if fee_token_id == from_token_id {
+            let required = from_amount.value.checked_add(fee).ok_or("u64 overflow")?;
+            (required, None)
+        } else {
+            unspent.extend(fetch(fee_token_id)?);
+            (from_amount.value, Some(fee))
+        };
+        let infos: Vec<UtxoInfo> = unspent.iter().map(|(info, _)| info.clone()).collect();
+
+        let selected = {
+            let st = lock(&self.state);
+            let mut selected = match fee_required {
+                Some(fee) => preflight::select_inputs(
+                    &infos,
+                    &st.reserved_inputs,
+                    ScaledAmount::new(fee, fee_token_id),
+                    UtxoInfo::MAX_INPUTS - 1,
+                ),
+                None => Ok(Vec::new()),
             }
+            .map_err(|err| format!("Swap not submitted: {err}"))?;
+            selected.extend(
+                preflight::select_inputs(
+                    &infos,
+                    &st.reserved_inputs,
+                    ScaledAmount::new(from_required, from_token_id),
+                    UtxoInfo::MAX_INPUTS - selected.len(),
+                )
+                .map_err(|err| format!("Swap not submitted: {err}"))?,
+            );
+            selected
         };
+        Ok(unspent
+            .into_iter()
+            .filter(|(info, _)| selected.iter().any(|s| s.key_image == info.key_image))
+            .map(|(_, utxo)| utxo)
+            .collect())
+    }
+
+    // Helper for execute_offer_swap and execute_submit_held_offer.
+    //
+    // Records the outcome of submitting an offer
+    fn record_offer_result(
+        &self,
+        offer_id: OfferId,
+        result: Result<Vec<QuoteId>, String>,
+    ) -> Result<(), String> {
+        let mut st = lock(&self.state);
+        match result {
+            Ok(quote_ids) => {
+                st.offers.mark_live(offer_id, quote_ids);
+                Ok(())
+            }
+            Err(err) => {
+                event!(Level::ERROR, "failed to submit swap offer: {}", err);
+                st.offers.mark_failed(offer_id, err.clone());
+                // Release the input, unless we saw the offer in the book after all
+                let release = st
+                    .offers
+                    .get(offer_id)
+                    .filter(|offer| offer.status != OfferStatus::Live)
+                    .and_then(|offer| offer.key_image);
+                if let Some(key_image) = release {
+                    st.reserved_inputs.remove(&key_image);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // Helper for execute_offer_swap.
+    //
+    // Generates an sci and submits it to the deqs selected by the submit mode.
+    // Succeeds if at least one deqs accepted the quote, returning the quote ids.
+    fn try_offer_swap(
+        &self,
+        offer_id: OfferId,
+        from_amount: Amount,
+        to_amount: Amount,
+        sender_subaddress: u64,
+        fill_mode: FillMode,
+    ) -> Result<Vec<QuoteId>, String> {
+        let (proto_sci, sci, _amounts) =
+            self.generate_offer_sci(from_amount, to_amount, sender_subaddress, fill_mode)?;
+
+        // Record the key image, so that the offer can be recognized in the quote book
+        // even before the deqs responds to us
+        let key_image = sci.key_image();
+        lock(&self.state).offers.set_key_image(offer_id, key_image);
+
+        self.submit_offer_sci(proto_sci, key_image)
+    }
+
+    // Helper for execute_offer_swap and execute_preview_offer.
+    //
+    // Selects (and reserves) an input of the right value, and asks mobilecoind
+    // to sign an sci over it. If this fails, the input is released again.
+    fn generate_offer_sci(
+        &self,
+        from_amount: Amount,
+        to_amount: Amount,
+        sender_subaddress: u64,
+        fill_mode: FillMode,
+    ) -> Result<GeneratedSci, String> {
+        if self.deqs_connections.is_empty() {
+            return Err("No deqs uri was configured".to_owned());
+        }
 
-        // Ask mobilecoind to sign an SCI over this input
+        // The sci offers its whole input, so only an input of exactly the offered
+        // value offers what the user asked for
+        let (selected_utxo, input_key_image) = self
+            .get_specific_utxo(from_amount, sender_subaddress, InputMatch::Exact)
+            .map_err(|err| format!("failed to obtain required utxo for swap: {err}"))?;
+
+        let result = self.sign_offer_input(
+            selected_utxo,
+            from_amount,
+            to_amount,
+            sender_subaddress,
+            fill_mode,
+        );
+        if result.is_err() {
+            let mut st = lock(&self.state);
+            st.reserved_inputs.remove(&input_key_image);
+        }
+        result
+    }
+
+    // Helper for generate_offer_sci.
+    //
+    // Asks mobilecoind to sign an sci over the input, and validates it
+    fn sign_offer_input(
+        &self,
+        selected_utxo: mcd_api::UnspentTxOut,
+        from_amount: Amount,
+        to_amount: Amount,
+        sender_subaddress: u64,
+        fill_mode: FillMode,
+    ) -> Result<GeneratedSci, String> {
+        let timeouts = lock(&self.state).rpc_timeouts;
+        self.set_progress(OperationProgress::GeneratingSci);
         let mut request = mcd_api::GenerateSwapRequest::new();
-        request.set_sender_monitor_id(self.monitor_id.clone());
-        request.set_change_subaddress(0);
+        request.set_sender_monitor_id(self.monitor_id());
+        request.set_change_subaddress(sender_subaddress);
         request.set_input(selected_utxo);
-        request.set_allow_partial_fill(true);
         request.set_counter_value(to_amount.value);
         request.set_counter_token_id(*to_amount.token_id);
-        // Arbitrarily, minimum fill value is 10 * minimum_fee
-        let min_fill_value = self
+        let fee = lock(&self.state)
             .minimum_fees
             .get(&from_amount.token_id)
             .cloned()
-            .unwrap_or(0)
-            * 10;
-        request.set_minimum_fill_value(min_fill_value);
-        let mut response = match self.mobilecoind_api_client.generate_swap(&request) {
-            Ok(resp) => resp,
-            Err(err) => {
-                event!(Level::ERROR, "mobilecoind generate_swap rpc: {}", err);
-                let mut st = self.state.lock().unwrap();
-                st.errors.push_back(err.to_string());
-                return;
+            .unwrap_or(0);
+        if let Some(min_fill_value) = fill_mode.min_fill_value(fee, from_amount.value) {
+            request.set_allow_partial_fill(true);
+            request.set_minimum_fill_value(min_fill_value);
+        }
+        let mut response = self
+            .mobilecoind_api_client
+            .get()
+            .generate_swap_opt(&request, timeouts.submit_options())
+            .map_err(|err| {
+                format!(
+                    "mobilecoind generate_swap rpc: {}",
+                    describe_rpc_error("mobilecoind", &err)
+                )
+            })?;
+
+        let proto_sci = response.take_sci();
+
+        let sci = SignedContingentInput::try_from(&proto_sci)
+            .map_err(|err| format!("mobilecoind generated a malformed sci: {err}"))?;
+
+        let amounts = sci
+            .validate()
+            .map_err(|err| format!("mobilecoind generated an invalid sci: {err}"))?;
+
+        Ok((proto_sci, sci, amounts))
+    }
+
+    // Helper for execute_offer_swap and execute_submit_held_offer.
+    //
+    // Submits an sci to the deqs selected by the submit mode.
+    // Succeeds if at least one deqs accepted the quote, returning the quote ids.
+    fn submit_offer_sci(
+        &self,
+        proto_sci: external::SignedContingentInput,
+        key_image: KeyImage,
+    ) -> Result<Vec<QuoteId>, String> {
+        let timeouts = lock(&self.state).rpc_timeouts;
+        if self.deqs_connections.is_empty() {
+            return Err("no deqs is configured to submit the offer to".to_owned());
+        }
+        self.set_progress(OperationProgress::SubmittingQuote);
+        let retry_policy = lock(&self.state).retry_policy;
+        let mut request = d_api::SubmitQuotesRequest::new();
+        request.set_quotes(vec![proto_sci].into());
+        let mut quote_ids = Vec::default();
+        let mut errors = Vec::default();
+        for idx in self.deqs_submit_mode.targets(self.deqs_connections.len()) {
+            let conn = &self.deqs_connections[idx];
+            let mut backoffs = retry_policy.backoffs();
+            // Failures among extra status codes, from the last attempt
+            let mut extra_failures = Vec::new();
+            let outcome = loop {
+                let outcome = match conn
+                    .client
+                    .get()
+                    .submit_quotes_opt(&request, conn.call_options(timeouts.submit_options()))
+                {
+                    Ok(response) => {
+                        // We submit a single quote, so we expect a single outcome
+                        let (outcome, extra) = SubmitOutcome::from_single_response(&response, idx);
+                        extra_failures = extra;
+                        outcome
+                    }
+                    Err(err) if is_unauthenticated(&err) => {
+                        extra_failures.clear();
+                        SubmitOutcome::Rejected(conn.describe_error(&err))
+                    }
+                    Err(err) => {
+                        extra_failures.clear();
+                        SubmitOutcome::from_rpc_error(&err)
+                    }
+                };
+                if let SubmitOutcome::Retryable(err) = &outcome {
+                    if let Some(backoff) = backoffs.next() {
+                        event!(
+                            Level::WARN,
+                            "deqs submit_quotes ({}) will retry in {:?}: {}",
+                            conn.uri,
+                            backoff,
+                            err
+                        );
+                        std::thread::sleep(backoff);
+                        continue;
+                    }
+                }
+                break outcome;
+            };
+            for err_msg in extra_failures {
+                event!(Level::WARN, "deqs ({}): {}", conn.uri, err_msg);
+                errors.push(format!("{}: {}", conn.uri, err_msg));
+            }
+            match outcome {
+                SubmitOutcome::Created => {
+                    event!(
+                        Level::INFO,
+                        "submitted swap offer successfully to {}",
+                        conn.uri
+                    );
+                    quote_ids.push(QuoteId {
+                        source: idx,
+                        key_image,
+                    });
+                }
+                SubmitOutcome::AlreadyExists(existing_id) => {
+                    event!(Level::INFO, "swap offer was already live at {}", conn.uri);
+                    quote_ids.push(existing_id.unwrap_or(QuoteId {
+                        source: idx,
+                        key_image,
+                    }));
+                }
+                SubmitOutcome::Rejected(err_msg) | SubmitOutcome::Retryable(err_msg) => {
+                    event!(Level::ERROR, "deqs error ({}): {}", conn.uri, err_msg);
+                    errors.push(format!("{}: {}", conn.uri, err_msg));
+                }
+            }
+        }
+
+        if quote_ids.is_empty() {
+            return Err(errors.join(", "));
+        }
+        // Some deqs accepted the offer, so just report the others
+        if !errors.is_empty() {
+            let mut st = lock(&self.state);
+            for err in errors {
+                st.errors.push(Severity::Warning, err);
             }
+        }
+        Ok(quote_ids)
+    }
+
+    // Helper for generate_offer_sci.
+    //
+    // Finds a utxo for an offer which isn't used by another offer: one of exactly the
+    // offered value, or of at least that value if the caller permits it. If there is
+    // none, pays ourselves the offered value, and polls the unspent txos until the
+    // output shows up, giving up after INPUT_PREPARATION_TIMEOUT.
+    // The utxo is reserved, and returned with its key image.
+    fn get_specific_utxo(
+        &self,
+        from_amount: Amount,
+        sender_subaddress: u64,
+        input_match: InputMatch,
+    ) -> Result<(mcd_api::UnspentTxOut, KeyImage), InputPreparationError> {
+        let timeouts = lock(&self.state).rpc_timeouts;
+        let deadline = Instant::now() + INPUT_PREPARATION_TIMEOUT;
+        self.set_progress(OperationProgress::SelectingInput);
+        if let Some(found) =
+            self.reserve_matching_utxo(from_amount, sender_subaddress, input_match)?
+        {
+            return Ok(found);
+        }
+
+        // The self-payment which constructs the utxo goes to the sender subaddress
+        let self_address = if sender_subaddress == 0 {
+            lock(&self.state).monitor_public_address.clone()
+        } else {
+            let mut req = mcd_api::GetPublicAddressRequest::new();
+            req.set_monitor_id(self.monitor_id());
+            req.set_subaddress_index(sender_subaddress);
+            self.mobilecoind_api_client
+                .get()
+                .get_public_address_opt(&req, timeouts.query_options())
+                .map_err(|err| {
+                    InputPreparationError::Mobilecoind(format!(
+                        "failed getting public address: {err}"
+                    ))
+                })?
+                .take_public_address()
         };
 
-        let proto_sci = response.take_sci();
+        span!(Level::INFO, "self payment");
+        event!(Level::INFO, "attempting self payment before swap offer");
+        self.set_progress(OperationProgress::SelfPayment);
+        let mut outlay = mcd_api::Outlay::new();
+        outlay.set_value(from_amount.value);
+        outlay.set_receiver(self_address);
+        let mut request = mcd_api::SendPaymentRequest::new();
+        request.set_sender_monitor_id(self.monitor_id());
+        request.set_sender_subaddress(sender_subaddress);
+        request.set_token_id(*from_amount.token_id);
+        request.set_outlay_list(vec![outlay].into());
+        let retry_policy = lock(&self.state).retry_policy;
+        let mut response = retry_policy
+            .run("self-payment", || {
+                self.mobilecoind_api_client
+                    .get()
+                    .send_payment_opt(&request, timeouts.submit_options())
+                    .map_err(|err| describe_rpc_error("mobilecoind", &err))
+            })
+            .map_err(InputPreparationError::SelfPayment)?;
+
+        // Coerce this into a SubmitTxResponse, so that we can use it with get_tx_status_as_sender
+        let mut submit_tx_response = mcd_api::SubmitTxResponse::new();
+        submit_tx_response.set_sender_tx_receipt(response.take_sender_tx_receipt());
+        submit_tx_response.set_receiver_tx_receipt_list(response.take_receiver_tx_receipt_list());
+
+        // Wait for the output of the self-payment to show up in the unspent txos, rather
+        // than guessing how long the monitor takes to sync it
+        self.set_progress(OperationProgress::WaitingForConfirmation);
+        loop {
+            if Instant::now() >= deadline {
+                event!(Level::ERROR, "self payment did not land in time");
+                return Err(InputPreparationError::Timeout(INPUT_PREPARATION_TIMEOUT));
+            }
+            std::thread::sleep(Duration::from_millis(200));
+
+            match self.reserve_matching_utxo(from_amount, sender_subaddress, InputMatch::Exact) {
+                Ok(Some(found)) => return Ok(found),
+                Ok(None) => {}
+                Err(err) => event!(Level::WARN, "{}", err),
+            }
+
+            // Give up early if the self-payment can't land anymore
+            match self
+                .mobilecoind_api_client
+                .get()
+                .get_tx_status_as_sender_opt(&submit_tx_response, timeouts.query_options())
+            {
+                Ok(resp)
+                    if resp.status != TxStatus::Unknown && resp.status != TxStatus::Verified =>
+                {
+                    return Err(InputPreparationError::SelfPayment(format!(
+                        "self-payment resolved as {:?}",
+                        resp.status
+                    )));
+                }
+                Ok(_) => {}
+                Err(err) => event!(Level::WARN, "get tx status: {}", err),
+            }
+        }
+    }
+
+    // Helper for get_specific_utxo.
+    //
+    // Reserves an unspent txo of the sender subaddress which matches the offered
+    // amount and isn't used by another offer, if there is one. Of the txos of at
+    // least the offered value, the smallest is used.
+    fn reserve_matching_utxo(
+        &self,
+        from_amount: Amount,
+        sender_subaddress: u64,
+        input_match: InputMatch,
+    ) -> Result<Option<(mcd_api::UnspentTxOut, KeyImage)>, InputPreparationError> {
+        let timeouts = lock(&self.state).rpc_timeouts;
+        let mut request = mcd_api::GetUnspentTxOutListRequest::new();
+        request.set_monitor_id(self.monitor_id());
+        request.set_subaddress_index(sender_subaddress);
+        request.set_token_id(*from_amount.token_id);
+        let response = self
+            .mobilecoind_api_client
+            .get()
+            .get_unspent_tx_out_list_opt(&request, timeouts.query_options())
+            .map_err(|err| {
+                InputPreparationError::Mobilecoind(format!(
+                    "failed getting unspent tx out list: {err}"
+                ))
+            })?;
+
+        let mut st = lock(&self.state);
+        let found = response
+            .output_list
+            .iter()
+            .filter_map(|utxo| {
+                let key_image = KeyImage::try_from(utxo.get_key_image()).ok()?;
+                (utxo.token_id == *from_amount.token_id
+                    && input_match.accepts(utxo.value, from_amount.value)
+                    && !st.reserved_inputs.contains(&key_image))
+                .then_some((utxo, key_image))
+            })
+            .min_by_key(|(utxo, _)| utxo.value);
+        Ok(found.map(|(utxo, key_image)| {
+            st.reserved_inputs.insert(key_image);
+            (utxo.clone(), key_image)
+        }))
+    }
+}
+
+// Check if a poll which last happened at `last` is due again, given the interval
+fn is_due(last: Option<Instant>, interval: Duration) -> bool {
+    last.map(|last| last.elapsed() >= interval).unwrap_or(true)
+}
+
+/// An error which prevented the worker from executing a command
+#[derive(Clone, Debug, Display, Eq, PartialEq)]
+pub enum CommandError {
+    /// {0}
+    AccessDenied(AccessDenied),
+    /// Something changed since the command was reviewed: {0}
+    PreconditionChanged(PreconditionFailed),
+    /// Invalid recipient: {0}
+    InvalidRecipient(String),
+    /// Invalid gift code: {0}
+    InvalidGiftCode(String),
+    /// The wallet is watch-only, so nothing can be sent, swapped or offered from it
+    WatchOnly,
+}
+
+impl From<AccessDenied> for CommandError {
+    fn from(src: AccessDenied) -> Self {
+        Self::AccessDenied(src)
+    }
+}
+
+impl From<PreconditionFailed> for CommandError {
+    fn from(src: PreconditionFailed) -> Self {
+        Self::PreconditionChanged(src)
+    }
+}
+
+/// The keys the account is registered with mobilecoind with. A watch-only wallet
+/// only has the view private key and the spend public key.
+#[derive(Clone)]
+enum MonitorKey {
+    /// The account key, which can spend
+    Full(AccountKey),
+    /// The view account key, which can only see the balances and incoming payments
+    ViewOnly(ViewAccountKey),
+}
+
+/// Why the worker could not start, or register the account with mobilecoind
+#[derive(Clone, Debug, Display)]
+pub enum WorkerInitError {
+    /// Could not load the keyfile: {0}
+    Keyfile(String),
+    /// Could not load the view-only account file: {0}
+    ViewOnlyKeyfile(String),
+    /// Could not connect to mobilecoind: {0}
+    Connection(Arc<grpcio::Error>),
+    /// mobilecoind did not register the account: {0}
+    AddMonitor(String),
+    /// mobilecoind did not report the network status: {0}
+    NetworkStatus(String),
+    /// Failed to load token metadata: {0}
+    TokenMetadata(String),
+    /// Failed to load a client certificate: {0}
+    TlsIdentity(String),
+    /// Invalid proxy: {0}
+    Proxy(String),
+    /// mobilecoind is on network "{actual}", but "{expected}" was expected
+    ChainIdMismatch {
+        /// The chain id we expected
+        expected: String,
+        /// The chain id mobilecoind reported
+        actual: String,
+    },
+}
+
+impl WorkerInitError {
+    /// Classify an rpc error from registering the account with mobilecoind: either
+    /// we couldn't reach mobilecoind, or it answered with an error, which becomes
+    /// the variant made by `rejected`
+    pub fn from_rpc(err: grpcio::Error, rejected: fn(String) -> Self) -> Self {
+        match &err {
+            grpcio::Error::RpcFailure(status)
+                if status.code() != RpcStatusCode::UNAVAILABLE
+                    && status.code() != RpcStatusCode::DEADLINE_EXCEEDED =>
+            {
+                rejected(err.to_string())
+            }
+            _ => Self::Connection(Arc::new(err)),
+        }
+    }
+
+    /// What the user can do about the error
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Self::Keyfile(_) => {
+                "Check that --keyfile names a json file with a mnemonic or root entropy"
+            }
+            Self::ViewOnlyKeyfile(_) => {
+                "With --watch-only, --keyfile names a json file with only view_private_key \
+                 and spend_public_key"
+            }
+            Self::Connection(err) => {
+                let lowercase = err.to_string().to_lowercase();
+                if ["dns", "resolve", "resolution"]
+                    .iter()
+                    .any(|hint| lowercase.contains(hint))
+                {
+                    "Check the host name in --mobilecoind-uri"
+                } else if ["ssl", "tls", "handshake", "certificate"]
+                    .iter()
+                    .any(|hint| lowercase.contains(hint))
+                {
+                    "Check whether mobilecoind expects insecure-mobilecoind:// or mobilecoind://"
+                } else {
+                    "Check that mobilecoind is running, at the --mobilecoind-uri given"
+                }
+            }
+            Self::AddMonitor(_) => "Check the logs of mobilecoind for why it refused the account",
+            Self::NetworkStatus(_) => "Check that mobilecoind is connected to the network",
+            Self::TokenMetadata(_) => "Check the file given with --token-metadata",
+            Self::TlsIdentity(_) => {
+                "Check that the file holds the PEM certificate chain and private key"
+            }
+            Self::Proxy(_) => "Check --grpc-proxy, or the https_proxy environment variable",
+            Self::ChainIdMismatch { .. } => {
+                "Use a mobilecoind on the expected network, or fix --expected-chain-id"
+            }
+        }
+    }
+}
+
+impl std::error::Error for WorkerInitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Connection(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the worker registered the account with mobilecoind yet
+#[derive(Clone, Debug, Default)]
+pub enum InitStatus {
+    /// Registering the account with mobilecoind
+    #[default]
+    Connecting,
+    /// The account is registered, and the worker is polling
+    Ready,
+    /// Registering the account failed, until retry_init is called
+    Failed(WorkerInitError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn active() -> PollIntervals {
+        PollIntervals {
+            mobilecoind: Duration::from_millis(500),
+            deqs: Some(Duration::from_millis(250)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn active_polls_at_the_configured_rate() {
+        assert_eq!(
+            ActivityLevel::Active.poll_intervals(false, active()),
+            active()
+        );
+    }
+
+    #[test]
+    fn background_polls_at_most_every_two_seconds() {
+        let intervals = ActivityLevel::Background.poll_intervals(false, active());
+        assert_eq!(intervals.mobilecoind, Duration::from_secs(2));
+        assert_eq!(intervals.deqs, Some(Duration::from_secs(2)));
+        assert_eq!(intervals.utxos, Duration::from_secs(2));
+        assert_eq!(intervals.offers, OfferTracker::POLL_INTERVAL);
+        assert_eq!(intervals.pending, PendingTransaction::POLL_INTERVAL);
+    }
 
-        let sci = match SignedContingentInput::try_from(&proto_sci) {
-            Ok(sci) => sci,
-            Err(err) => {
-                event!(
-                    Level::ERROR,
-                    "mobilecoind generated a malformed sci: {}",
-                    err
-                );
-                let mut st = self.state.lock().unwrap();
-                st.errors.push_back(err.to_string());
-                return;
+    #[test]
+    fn hidden_stretches_every_poll_and_stops_the_deqs() {
+        let intervals = ActivityLevel::Hidden.poll_intervals(false, active());
+        let slow = Duration::from_secs(30);
+        assert_eq!(
+            intervals,
+            PollIntervals {
+                mobilecoind: slow,
+                deqs: None,
+                utxos: slow,
+                offers: slow,
+                pending: slow,
             }
-        };
+        );
+    }
 
-        if let Err(err) = sci.validate() {
-            event!(
-                Level::ERROR,
-                "mobilecoind generated an invalid sci: {}",
-                err
-            );
-            let mut st = self.state.lock().unwrap();
-            st.errors.push_back(err.to_string());
-            return;
+    #[test]
+    fn slower_configured_intervals_are_kept() {
+        let configured = PollIntervals {
+            mobilecoind: Duration::from_secs(60),
+            deqs: Some(Duration::from_secs(5)),
+            ..Default::default()
         };
+        let intervals = ActivityLevel::Background.poll_intervals(false, configured);
+        assert_eq!(intervals.mobilecoind, Duration::from_secs(60));
+        assert_eq!(intervals.deqs, Some(Duration::from_secs(5)));
+        let intervals = ActivityLevel::Hidden.poll_intervals(false, configured);
+        assert_eq!(intervals.mobilecoind, Duration::from_secs(60));
+    }
 
-        // Submit the generated sci to the deqs
-        let mut request = d_api::SubmitQuotesRequest::new();
-        request.set_quotes(vec![proto_sci].into());
-        let response = match self.deqs_client.as_ref().unwrap().submit_quotes(&request) {
-            Ok(resp) => resp,
-            Err(err) => {
-                event!(Level::ERROR, "deqs submit_quotes rpc: {}", err);
-                let mut st = self.state.lock().unwrap();
-                st.errors.push_back(err.to_string());
-                return;
-            }
-        };
-        // Handle any error statuses and error messages
-        if response.status_codes.len() > 1 {
-            event!(
-                Level::WARN,
-                "unexpectedly got {} status codes back",
-                response.status_codes.len()
-            );
+    #[test]
+    fn armed_automation_polls_as_if_active() {
+        for level in [
+            ActivityLevel::Active,
+            ActivityLevel::Background,
+            ActivityLevel::Hidden,
+        ] {
+            assert_eq!(level.poll_intervals(true, active()), active());
         }
-        let status_code = response.status_codes.get(0);
-        if status_code == Some(&d_api::QuoteStatusCode::CREATED) {
-            event!(Level::INFO, "submitted swap offer successfully");
-        } else {
-            let err_msg = response
-                .error_messages
-                .get(0)
-                .cloned()
-                .unwrap_or("no error message...".to_owned());
-            event!(
-                Level::ERROR,
-                "deqs error: {:?}: {}",
-                status_code
-                    .map(|c| format!("{:?}", c))
-                    .unwrap_or("no status".to_owned()),
-                err_msg
-            );
-            let mut st = self.state.lock().unwrap();
-            st.errors.push_back(err_msg);
+    }
+
+    #[test]
+    fn adaptive_interval_backs_off_while_idle() {
+        let base = Duration::from_millis(100);
+        let mut idle = AdaptiveInterval::default();
+        assert_eq!(idle.interval(base), base);
+        idle.record(false);
+        assert_eq!(idle.interval(base), base * 2);
+        for _ in 0..40 {
+            idle.record(false);
         }
+        assert_eq!(idle.interval(base), AdaptiveInterval::MAX_IDLE);
+        idle.record(true);
+        assert_eq!(idle.interval(base), base);
+        // A base interval above the cap is never shortened
+        let slow = Duration::from_secs(30);
+        idle.record(false);
+        assert_eq!(idle.interval(slow), slow);
     }
 
-    // Helper for offer_swap.
-    //
-    // Tries to construct a utxo with a specific value
-    fn get_specific_utxo(&self, from_amount: Amount) -> Result<mcd_api::UnspentTxOut, String> {
-        // Allow at most 5 errors
-        let mut retries = 5;
-        loop {
-            let mut request = mcd_api::GetUnspentTxOutListRequest::new();
-            request.set_monitor_id(self.monitor_id.clone());
-            request.set_subaddress_index(0);
-            request.set_token_id(*from_amount.token_id);
-            let response = match self
-                .mobilecoind_api_client
-                .get_unspent_tx_out_list(&request)
-            {
-                Ok(resp) => resp,
-                Err(err) => {
-                    let err_msg = format!("failed getting unspent tx out list: {err}");
-                    event!(Level::ERROR, err_msg);
-                    retries -= 1;
-                    if retries == 0 {
-                        return Err(err_msg);
-                    }
-                    std::thread::sleep(Duration::from_millis(200));
-                    continue;
-                }
-            };
+    fn book(key_images: &[u64]) -> Vec<ValidatedQuote> {
+        key_images
+            .iter()
+            .map(|key_image| {
+                ValidatedQuote::swap_fixture(
+                    0,
+                    *key_image,
+                    Amount::new(1_000, TokenId::MOB),
+                    Amount::new(2_000, TokenId::from(1)),
+                    false,
+                )
+            })
+            .collect()
+    }
 
-            if let Some(utxo) = response.output_list.iter().find(|utxo| {
-                utxo.token_id == *from_amount.token_id && utxo.value == from_amount.value
-            }) {
-                return Ok(utxo.clone());
-            }
-            retries -= 1;
-            if retries == 0 {
-                let err_msg = "failed to produce input of required value".to_owned();
-                event!(Level::ERROR, err_msg);
-                return Err(err_msg);
-            }
-            // Produce a self-payment in this amount, then wait for it to land
-            span!(Level::INFO, "self payment");
-            event!(Level::INFO, "attempting self payment before swap offer");
-            let mut outlay = mcd_api::Outlay::new();
-            outlay.set_value(from_amount.value);
-            outlay.set_receiver(self.monitor_public_address.clone());
-            let mut request = mcd_api::SendPaymentRequest::new();
-            request.set_sender_monitor_id(self.monitor_id.clone());
-            request.set_sender_subaddress(0);
-            request.set_token_id(*from_amount.token_id);
-            request.set_outlay_list(vec![outlay].into());
-            let mut response = match self.mobilecoind_api_client.send_payment(&request) {
-                Ok(resp) => resp,
-                Err(err) => {
-                    let err_msg = format!("failed submitting self-payment: {err}");
-                    event!(Level::ERROR, err_msg);
-                    retries -= 1;
-                    if retries == 0 {
-                        return Err(err_msg);
-                    }
-                    std::thread::sleep(Duration::from_millis(200));
-                    continue;
-                }
-            };
+    fn key_images(key_images: &[u64]) -> HashSet<KeyImage> {
+        key_images
+            .iter()
+            .map(|key_image| KeyImage::from(*key_image))
+            .collect()
+    }
 
-            // Coerce this into a SubmitTxResponse, so that we can use it with get_tx_status_as_sender
-            let mut submit_tx_response = mcd_api::SubmitTxResponse::new();
-            submit_tx_response.set_sender_tx_receipt(response.take_sender_tx_receipt());
-            submit_tx_response
-                .set_receiver_tx_receipt_list(response.take_receiver_tx_receipt_list());
-
-            // Wait for self payment to land
-            loop {
-                let resp = match self
-                    .mobilecoind_api_client
-                    .get_tx_status_as_sender(&submit_tx_response)
-                {
-                    Ok(resp) => resp,
-                    Err(err) => {
-                        event!(Level::ERROR, "get tx status: {}", err);
-                        std::thread::sleep(Duration::from_millis(200));
-                        continue;
-                    }
-                };
-                std::thread::sleep(Duration::from_millis(50));
-                if resp.status != TxStatus::Unknown && resp.status != TxStatus::Verified {
-                    event!(
-                        Level::WARN,
-                        "got a strange status from self payment Tx: {:?}",
-                        resp.status
-                    );
-                }
-                if resp.status != TxStatus::Unknown {
-                    break;
-                }
-            }
-            // Extra sleep, try to give the sync thread time to find the utxo
-            // FIXME: Should we block on a different call than get_tx_status?
-            // Or maybe keep track of the expected utxo and retry on get_unspent_utxos until we find it?
-            std::thread::sleep(Duration::from_millis(1000));
-        }
+    // Filter a book, with a ledger where `spent_key_images` are spent, returning the
+    // key images which were checked
+    fn filter_spent(
+        quotes: &mut Vec<ValidatedQuote>,
+        spent: &mut HashMap<KeyImage, bool>,
+        spent_key_images: &[u64],
+    ) -> HashSet<KeyImage> {
+        let ledger = key_images(spent_key_images);
+        let mut checked = HashSet::default();
+        Worker::filter_spent_quotes(quotes, spent, |unchecked| {
+            checked.extend(unchecked.iter().copied());
+            unchecked
+                .iter()
+                .map(|key_image| (*key_image, ledger.contains(key_image)))
+                .collect()
+        });
+        checked
     }
 
-    /// Act as the counterparty to a given swap
-    ///
-    /// Arguments:
-    /// sci - sci to fulfill
-    /// partial_fill_value - degree to fill it to
-    /// from_token_id - the token id we need to pay in order to fulfill the sci
-    /// fee_token_id - the token id to pay the fee in
-    pub fn perform_swap(
-        &self,
-        sci: SignedContingentInput,
-        partial_fill_value: u64,
-        from_token_id: TokenId,
-        fee_token_id: TokenId,
-    ) {
-        // First we have to get utxo list from mobilecoind
-        let mut retries = 3;
-        let mut response = loop {
-            let mut request = mcd_api::GetUnspentTxOutListRequest::new();
-            request.set_monitor_id(self.monitor_id.clone());
-            request.set_subaddress_index(0);
-            request.set_token_id(*from_token_id);
-            match self
-                .mobilecoind_api_client
-                .get_unspent_tx_out_list(&request)
-            {
-                Ok(resp) => break resp,
-                Err(err) => {
-                    let err_msg = format!("failed getting unspent tx out list: {err}");
-                    event!(Level::ERROR, err_msg);
-                    retries -= 1;
-                    if retries == 0 {
-                        let mut st = self.state.lock().unwrap();
-                        st.errors.push_back(err_msg);
-                        return;
-                    }
-                    std::thread::sleep(Duration::from_millis(200));
-                }
-            };
-        };
+    #[test]
+    fn quotes_with_spent_inputs_are_dropped() {
+        let mut spent = HashMap::default();
+        let mut quotes = book(&[1, 2, 3]);
+        let checked = filter_spent(&mut quotes, &mut spent, &[2]);
+        assert_eq!(checked, key_images(&[1, 2, 3]));
+        assert_eq!(
+            quotes
+                .iter()
+                .map(|quote| quote.id.key_image)
+                .collect::<HashSet<_>>(),
+            key_images(&[1, 3])
+        );
+    }
 
-        let mut sci_for_tx = mcd_api::SciForTx::new();
-        sci_for_tx.set_sci((&sci).into());
-        sci_for_tx.set_partial_fill_value(partial_fill_value);
+    #[test]
+    fn key_images_are_checked_once_while_they_are_served() {
+        let mut spent = HashMap::default();
+        filter_spent(&mut book(&[1, 2]), &mut spent, &[2]);
 
-        let mut req = mcd_api::GenerateMixedTxRequest::new();
-        req.set_sender_monitor_id(self.monitor_id.clone());
-        req.set_change_subaddress(0);
-        req.set_input_list(response.take_output_list());
-        req.set_scis(vec![sci_for_tx].into());
-        req.set_fee_token_id(*fee_token_id);
+        // Only the new quote is checked, and the known spent one is still dropped
+        let mut quotes = book(&[1, 2, 3]);
+        assert_eq!(
+            filter_spent(&mut quotes, &mut spent, &[2]),
+            key_images(&[3])
+        );
+        assert_eq!(quotes.len(), 2);
+        assert!(filter_spent(&mut book(&[1, 2, 3]), &mut spent, &[2]).is_empty());
 
-        let mut resp = match self.mobilecoind_api_client.generate_mixed_tx(&req) {
-            Ok(resp) => {
-                event!(Level::DEBUG, "generated swap tx successfully");
-                resp
-            }
-            Err(err) => {
-                event!(Level::ERROR, "failed to generate swap tx: {}", err);
-                let mut st = self.state.lock().unwrap();
-                st.errors.push_back(err.to_string());
-                return;
-            }
-        };
+        // A quote which left the book is checked again if it comes back
+        filter_spent(&mut book(&[3]), &mut spent, &[]);
+        assert_eq!(spent.len(), 1);
+        assert_eq!(
+            filter_spent(&mut book(&[1, 3]), &mut spent, &[]),
+            key_images(&[1])
+        );
+    }
 
-        let mut req = mcd_api::SubmitTxRequest::new();
-        req.set_tx_proposal(resp.take_tx_proposal());
+    #[test]
+    fn key_images_which_couldnt_be_checked_are_checked_again() {
+        let mut spent = HashMap::default();
+        let mut quotes = book(&[1]);
+        Worker::filter_spent_quotes(&mut quotes, &mut spent, |_| vec![]);
+        assert_eq!(quotes.len(), 1);
+        assert!(spent.is_empty());
+        assert_eq!(
+            filter_spent(&mut quotes, &mut spent, &[1]),
+            key_images(&[1])
+        );
+        assert!(quotes.is_empty());
+    }
 
-        match self.mobilecoind_api_client.submit_tx(&req) {
-            Ok(_resp) => {
-                event!(Level::INFO, "submitted swap tx successfully");
-            }
-            Err(err) => {
-                event!(Level::ERROR, "failed to submit swap tx: {}", err);
-                let mut st = self.state.lock().unwrap();
-                st.errors.push_back(err.to_string());
-            }
+    #[test]
+    fn quote_books_go_stale_past_the_max_age() {
+        let start = Instant::now();
+        let pair = (TokenId::MOB, TokenId::from(1));
+        let mut st = WorkerState {
+            max_quote_age: Duration::from_secs(30),
+            ..Default::default()
+        };
+        assert_eq!(st.quote_book_age(pair, start), None);
+        st.quote_books_polled.insert(pair, start);
+        // Only the polled direction has an age
+        assert_eq!(st.quote_book_age((pair.1, pair.0), start), None);
+
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(
+            st.quote_book_age(pair, at(30)),
+            Some(Duration::from_secs(30))
+        );
+        let fresh = |now| {
+            preflight::check_quotes_fresh(st.quote_book_age(pair, now).unwrap(), st.max_quote_age)
         };
+        assert_eq!(fresh(at(30)), Ok(()));
+        assert_eq!(
+            fresh(at(30) + Duration::from_millis(1)),
+            Err(PreconditionFailed::QuotesStale)
+        );
+        // A clock which went backwards doesn't make the book older
+        assert_eq!(
+            st.quote_book_age(pair, start - Duration::from_secs(1)),
+            Some(Duration::ZERO)
+        );
     }
 
-    /// Get the error at the front of the error queue, if any.
-    pub fn top_error(&self) -> Option<String> {
-        self.state.lock().unwrap().errors.get(0).cloned()
+    fn config_with_keyfile(keyfile: &std::path::Path) -> Config {
+        use clap::Parser;
+        Config::try_parse_from(["mobilecoind-buddy", "--keyfile", keyfile.to_str().unwrap()])
+            .unwrap()
     }
 
-    /// Pop the error from the front of the error queue, if any.
-    pub fn pop_error(&self) {
-        self.state.lock().unwrap().errors.pop_front();
+    fn init_error(config: Config) -> WorkerInitError {
+        match Worker::new(config) {
+            Ok(_) => panic!("the worker started"),
+            Err(err) => err,
+        }
     }
 
-    // Try to issue commands to mobilecoind to set up a new account, returning an
-    // error if any of them fail
-    //
-    // Returns monitor id, monitor public address, monitor b58 address, and the
-    // current network minimum fees
-    fn try_new_mobilecoind(
-        mobilecoind_api_client: &MobilecoindApiClient,
-        account_key: &AccountKey,
-    ) -> Result<MobilecoindSetupData, String> {
-        // Create a monitor using our account key
-        let monitor_id = {
-            let mut req = mcd_api::AddMonitorRequest::new();
-            req.set_account_key(account_key.into());
-            req.set_num_subaddresses(2);
-            req.set_name("mobilecoind-buddy".to_string());
+    // A supervised "client" which is the number of the channel it is on
+    fn supervised() -> Supervised<u32> {
+        let channels = AtomicU32::new(0);
+        Supervised::new(move || channels.fetch_add(1, Ordering::SeqCst))
+    }
 
-            let resp = mobilecoind_api_client
-                .add_monitor(&req)
-                .map_err(|err| format!("Failed adding a monitor: {err}"))?;
+    fn rpc_failure(code: RpcStatusCode) -> grpcio::Error {
+        grpcio::Error::RpcFailure(grpcio::RpcStatus::new(code))
+    }
 
-            resp.monitor_id
-        };
+    #[test]
+    fn channels_are_rebuilt_after_transport_failures_in_a_row() {
+        let client = supervised();
+        let unavailable = rpc_failure(RpcStatusCode::UNAVAILABLE);
+        let timed_out = rpc_failure(RpcStatusCode::DEADLINE_EXCEEDED);
+        for _ in 1..Supervised::<u32>::RECONNECT_AFTER {
+            assert!(!client.record_failure(&unavailable));
+        }
+        assert_eq!(client.get(), 0);
+        assert!(client.record_failure(&timed_out));
+        assert_eq!(client.get(), 1);
 
-        // Get the b58 public address for monitor
-        let monitor_b58_address = {
-            let mut req = mcd_api::GetPublicAddressRequest::new();
-            req.set_monitor_id(monitor_id.clone());
+        // The count starts over on the new channel
+        for _ in 1..Supervised::<u32>::RECONNECT_AFTER {
+            assert!(!client.record_failure(&unavailable));
+        }
+        assert!(client.record_failure(&unavailable));
+        assert_eq!(client.get(), 2);
+    }
 
-            let resp = mobilecoind_api_client
-                .get_public_address(&req)
-                .map_err(|err| format!("Failed getting public address: {err}"))?;
+    #[test]
+    fn answers_from_the_server_reset_the_failure_count() {
+        let client = supervised();
+        let unavailable = rpc_failure(RpcStatusCode::UNAVAILABLE);
+        for _ in 1..Supervised::<u32>::RECONNECT_AFTER {
+            assert!(!client.record_failure(&unavailable));
+        }
+        assert!(!client.record_failure(&rpc_failure(RpcStatusCode::NOT_FOUND)));
+        assert!(!client.record_failure(&unavailable));
+        assert!(!client.record_success());
+        for _ in 1..Supervised::<u32>::RECONNECT_AFTER {
+            assert!(!client.record_failure(&unavailable));
+        }
+        assert_eq!(client.get(), 0);
+    }
 
-            resp.b58_code
-        };
+    #[test]
+    fn the_first_success_after_a_reconnect_is_reported_once() {
+        let client = supervised();
+        let unavailable = rpc_failure(RpcStatusCode::UNAVAILABLE);
+        assert!(!client.record_success());
+        while !client.record_failure(&unavailable) {}
+        assert!(client.record_success());
+        assert!(!client.record_success());
 
-        let monitor_printable_wrapper = PrintableWrapper::b58_decode(monitor_b58_address.clone())
-            .expect("Could not decode b58 address");
-        assert!(monitor_printable_wrapper.has_public_address());
-        let monitor_public_address = monitor_printable_wrapper.get_public_address();
+        // Clones share the channel and the counts
+        let clone = client.clone();
+        while !clone.record_failure(&unavailable) {}
+        assert_eq!(client.get(), 2);
+        assert!(client.record_success());
+    }
 
-        // Get the network minimum fees and chain id
-        let (minimum_fees, chain_id) = {
-            let mut minimum_fees = HashMap::<TokenId, u64>::default();
+    #[test]
+    fn sync_fractions_stay_within_0_and_1() {
+        assert_eq!(Worker::sync_fraction(0, 0), None);
+        assert_eq!(Worker::sync_fraction(5, 0), None);
+        assert_eq!(Worker::sync_fraction(0, 10), Some(0.0));
+        assert_eq!(Worker::sync_fraction(5, 10), Some(0.5));
+        assert_eq!(Worker::sync_fraction(10, 10), Some(1.0));
+        // The monitor may be ahead of a reorganized ledger
+        assert_eq!(Worker::sync_fraction(12, 10), Some(1.0));
+        assert_eq!(Worker::sync_fraction(u64::MAX, u64::MAX), Some(1.0));
+        assert!(Worker::sync_fraction(1, u64::MAX).unwrap() < f32::EPSILON);
+    }
 
-            let mut resp = mobilecoind_api_client
-                .get_network_status(&Default::default())
-                .map_err(|err| format!("Failed getting network status: {err}"))?;
+    #[test]
+    fn fee_estimates_wait_for_the_payment_to_settle() {
+        let requested_at = Instant::now();
+        let mut estimate = FeeEstimate {
+            key: FeeEstimateKey {
+                value: 1,
+                token_id: TokenId::MOB,
+                recipient: String::new(),
+                sender_subaddress: 0,
+                inputs: HashSet::default(),
+            },
+            requested_at,
+            result: None,
+        };
+        assert_eq!(estimate.due_in(requested_at), Some(FeeEstimate::DEBOUNCE));
+        let later = requested_at + FeeEstimate::DEBOUNCE / 3;
+        assert_eq!(
+            estimate.due_in(later),
+            Some(FeeEstimate::DEBOUNCE - FeeEstimate::DEBOUNCE / 3)
+        );
+        let due = requested_at + FeeEstimate::DEBOUNCE * 2;
+        assert_eq!(estimate.due_in(due), Some(Duration::ZERO));
+        estimate.result = Some(Ok(400_000_000));
+        assert_eq!(estimate.due_in(due), None);
+    }
 
-            for (k, v) in resp.get_last_block_info().minimum_fees.iter() {
-                minimum_fees.insert(k.into(), *v);
+    #[test]
+    fn chain_ids_are_only_checked_when_expected() {
+        assert!(Worker::check_chain_id(Some("main"), "main").is_ok());
+        assert!(Worker::check_chain_id(None, "main").is_ok());
+        assert!(Worker::check_chain_id(None, "").is_ok());
+        match Worker::check_chain_id(Some("main"), "test") {
+            Err(WorkerInitError::ChainIdMismatch { expected, actual }) => {
+                assert_eq!((expected.as_str(), actual.as_str()), ("main", "test"))
             }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
 
-            (minimum_fees, resp.take_chain_id())
-        };
+    #[test]
+    fn the_expected_chain_id_is_optional() {
+        let keyfile = std::path::Path::new("account.json");
+        assert_eq!(config_with_keyfile(keyfile).expected_chain_id, None);
 
-        Ok(MobilecoindSetupData {
-            monitor_id,
-            monitor_public_address: monitor_public_address.clone(),
-            monitor_b58_address,
-            minimum_fees,
-            chain_id,
-        })
+        use clap::Parser;
+        let config = Config::try_parse_from([
+            "mobilecoind-buddy",
+            "--keyfile",
+            "account.json",
+            "--expected-chain-id",
+            "main",
+        ])
+        .unwrap();
+        assert_eq!(config.expected_chain_id.as_deref(), Some("main"));
     }
 
-    fn worker_thread_entrypoint(
-        monitor_id: Vec<u8>,
-        mobilecoind_api_client: MobilecoindApiClient,
-        deqs_client: Option<DeqsClient>,
-        minimum_fees: HashMap<TokenId, u64>,
-        state: Arc<Mutex<WorkerState>>,
-        stop_requested: Arc<AtomicBool>,
-    ) {
-        loop {
-            if stop_requested.load(Ordering::SeqCst) {
-                break;
-            }
-
-            event!(Level::TRACE, "worker: polling loop");
+    #[test]
+    fn tls_identities_are_optional_and_need_tls() {
+        let flag = "--deqs-tls-identity";
+        assert!(matches!(
+            Worker::load_tls_identity(None, false, flag),
+            Ok(None)
+        ));
 
-            if let Err(err) =
-                Self::poll_mobilecoind(&monitor_id, &mobilecoind_api_client, &minimum_fees, &state)
-            {
-                event!(Level::ERROR, "polling mobilecoind: {}", err);
-                {
-                    let mut st = state.lock().unwrap();
-                    // TODO: Maybe pop an error if there are many errors?
-                    if st.errors.len() < 3 {
-                        st.errors.push_back(err.to_string());
-                    }
-                }
-                // Back off for 500 ms when there is an error
-                std::thread::sleep(Duration::from_millis(500));
-                continue;
+        let path = std::env::temp_dir().join("buddy-no-such-identity.pem");
+        match Worker::load_tls_identity(Some(&path), false, flag) {
+            Err(WorkerInitError::TlsIdentity(err)) => {
+                assert_eq!(err, format!("{flag} needs a uri which uses TLS"))
             }
-
-            if let Some(deqs_client) = deqs_client.as_ref() {
-                if let Err(err) = Self::poll_deqs(deqs_client, &state) {
-                    event!(Level::ERROR, "polling deqs: {}", err);
-                    {
-                        let mut st = state.lock().unwrap();
-                        // TODO: Maybe pop an error if there are many errors?
-                        if st.errors.len() < 3 {
-                            st.errors.push_back(err.to_string());
-                        }
-                    }
-                    // Back off for 500 ms when there is an error
-                    std::thread::sleep(Duration::from_millis(500));
-                    continue;
-                }
+            other => panic!("unexpected {other:?}"),
+        }
+        match Worker::load_tls_identity(Some(&path), true, flag) {
+            Err(WorkerInitError::TlsIdentity(err)) => {
+                assert!(err.contains("buddy-no-such-identity.pem"))
             }
-
-            // Back off for 20 ms
-            std::thread::sleep(Duration::from_millis(20));
+            other => panic!("unexpected {other:?}"),
         }
     }
 
-    fn poll_mobilecoind(
-        monitor_id: &[u8],
-        client: &MobilecoindApiClient,
-        minimum_fees: &HashMap<TokenId, u64>,
-        state: &Arc<Mutex<WorkerState>>,
-    ) -> Result<(), grpcio::Error> {
-        span!(Level::TRACE, "poll mobilecoind");
-        // Check ledger status
-        {
-            event!(Level::TRACE, "worker: check ledger status");
-            let info = client.get_ledger_info(&Default::default())?;
-            let mut st = state.lock().unwrap();
-            st.total_blocks = info.block_count;
-        }
+    #[test]
+    fn watch_only_wallets_refuse_the_spend_key() {
+        let path = std::env::temp_dir().join(format!(
+            "buddy-watch-only-mnemonic-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"{"mnemonic": "legal winner thank year wave sausage"}"#,
+        )
+        .unwrap();
+        let mut config = config_with_keyfile(&path);
+        config.watch_only = true;
+        let err = init_error(config.clone());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(&err, WorkerInitError::ViewOnlyKeyfile(msg) if msg.contains("spend key")));
+        assert!(err.hint().contains("--watch-only"));
 
-        // Check monitor status
-        {
-            event!(Level::TRACE, "worker: check monitor status");
-            let mut req = mcd_api::GetMonitorStatusRequest::new();
-            req.set_monitor_id(monitor_id.to_owned());
-            let resp = client.get_monitor_status(&req)?;
+        let account_key = gift_account_key(&[7u8; 32]).unwrap();
+        assert!(matches!(
+            Worker::with_account_key(config, account_key),
+            Err(WorkerInitError::ViewOnlyKeyfile(_))
+        ));
+    }
 
-            let mut st = state.lock().unwrap();
-            st.synced_blocks = resp.get_status().next_block;
-        }
+    #[test]
+    fn missing_keyfiles_are_keyfile_errors() {
+        let path = std::env::temp_dir().join("buddy-no-such-keyfile.json");
+        let err = init_error(config_with_keyfile(&path));
+        assert!(
+            matches!(&err, WorkerInitError::Keyfile(msg) if msg.contains("buddy-no-such-keyfile"))
+        );
+        assert!(err.hint().contains("--keyfile"));
+    }
 
-        // Get balance
-        {
-            for token_id in minimum_fees.keys() {
-                event!(Level::TRACE, "worker: check balance: {}", *token_id);
-                // FIXME: We should also check some other subaddresses most likely
-                let mut req = mcd_api::GetBalanceRequest::new();
-                req.set_monitor_id(monitor_id.to_owned());
-                req.set_token_id(**token_id);
-                let resp = client.get_balance(&req)?;
+    #[test]
+    fn unreadable_keyfiles_are_keyfile_errors() {
+        let path =
+            std::env::temp_dir().join(format!("buddy-bad-keyfile-{}.json", std::process::id()));
+        std::fs::write(&path, "not json").unwrap();
+        let err = init_error(config_with_keyfile(&path));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(err, WorkerInitError::Keyfile(_)));
+    }
 
+    #[test]
+    fn state_is_usable_after_a_panic_holding_it() {
+        let state = Arc::new(Mutex::new(WorkerState::default()));
+        let command_id = lock(&state).commands.begin(WorkerCommand::Defragment {
+            token_id: TokenId::MOB,
+        });
+        let panicked = {
+            let state = state.clone();
+            std::thread::spawn(move || {
                 let mut st = state.lock().unwrap();
-                *st.balance.entry(*token_id).or_default() = resp.balance;
-            }
-        }
-        Ok(())
+                st.commands
+                    .set_status(command_id, CommandStatus::Submitting);
+                panic!("bug while executing");
+            })
+            .join()
+        };
+        assert!(panicked.is_err());
+        assert!(state.is_poisoned());
+
+        let mut st = lock(&state);
+        assert_eq!(st.commands.fail_submitting("crashed"), 1);
+        assert_eq!(
+            st.commands.status(command_id),
+            Some(CommandStatus::Failed("crashed".to_string()))
+        );
     }
 
-    fn poll_deqs(
-        client: &DeqsClient,
-        state: &Arc<Mutex<WorkerState>>,
-    ) -> Result<(), grpcio::Error> {
-        let maybe_tokens = { state.lock().unwrap().get_quotes_token_ids };
-        // Only do the poll if the ui thread told us we're looking at two particular tokens,
-        // and then only if they are different tokens.
-        if let Some((token1, token2)) = maybe_tokens {
-            if token1 == token2 {
-                return Ok(());
-            }
-            span!(Level::TRACE, "poll deqs");
+    #[test]
+    fn published_snapshots_dont_wait_for_the_worker() {
+        let state = Arc::new(Mutex::new(WorkerState::default()));
+        let published = Arc::new(RwLock::new(Published::default()));
+        let pair = (TokenId::MOB, TokenId::from(1));
+        let rounds = 200;
 
-            for (base_token_id, counter_token_id) in
-                vec![(token1, token2), (token2, token1)].into_iter()
-            {
-                let mut pair = d_api::Pair::new();
-                pair.set_base_token_id(*base_token_id);
-                pair.set_counter_token_id(*counter_token_id);
+        // The worker syncs a block at a time, replacing a large book each time, and
+        // keeps the state locked a while after publishing
+        let writer = {
+            let state = state.clone();
+            let published = published.clone();
+            std::thread::spawn(move || {
+                for round in 1..=rounds {
+                    {
+                        let mut st = state.lock().unwrap();
+                        st.synced_blocks = round;
+                        st.total_blocks = rounds;
+                        st.balance.insert(TokenId::MOB, round);
+                        st.balance_fetched = true;
+                        st.quote_books.insert(
+                            pair,
+                            QuoteBook {
+                                quotes: Arc::new(book(&(0..100).collect::<Vec<u64>>())),
+                                generation: round,
+                            },
+                        );
+                    }
+                    Published::publish(&state, &published);
+                    let _st = state.lock().unwrap();
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
 
-                let mut req = d_api::GetQuotesRequest::new();
-                req.set_pair(pair);
-                req.set_limit(QUOTES_LIMIT);
+        let mut slowest = Duration::ZERO;
+        let mut last_synced = 0;
+        while last_synced < rounds {
+            let started = Instant::now();
+            let snapshot = published.read().unwrap();
+            let (synced, total) = snapshot.sync_progress;
+            let generation = snapshot
+                .quote_books
+                .get(&pair)
+                .map_or(0, |book| book.generation);
+            let balance = snapshot
+                .balances
+                .balances
+                .get(&TokenId::MOB)
+                .copied()
+                .unwrap_or(0);
+            drop(snapshot);
+            slowest = slowest.max(started.elapsed());
 
-                event!(
-                    Level::TRACE,
-                    "getting quotes for pair {} / {}",
-                    *base_token_id,
-                    *counter_token_id
-                );
-                let resp = client.get_quotes(&req)?;
-                let validated_quotes: Vec<ValidatedQuote> = resp
-                    .get_quotes()
-                    .iter()
-                    .filter_map(|quote| match ValidatedQuote::try_from(quote) {
-                        Ok(validated_quote) => Some(validated_quote),
-                        Err(err) => {
-                            event!(Level::ERROR, "validating quote: {}", err);
-                            None
-                        }
-                    })
-                    .collect();
-                {
-                    let mut st = state.lock().unwrap();
-                    *st.quote_books
-                        .entry((base_token_id, counter_token_id))
-                        .or_default() = validated_quotes;
-                }
-            }
+            // Every snapshot is consistent, and they only move forward
+            assert!(synced >= last_synced);
+            assert!(synced <= total || total == 0);
+            assert_eq!(generation, synced);
+            assert_eq!(balance, synced);
+            last_synced = synced;
         }
-        Ok(())
+        writer.join().unwrap();
+        // Reads never waited for the state lock, which is held for a millisecond
+        // at a time; this bound only catches a deadlock or a lock held for the whole run
+        assert!(slowest < Duration::from_millis(500), "{slowest:?}");
     }
 }
-
-/// An error returned by the worker that prevented initialization.
-/// Errors that occur after initalization are logged, and sent to the self.errors queue for display to the user.
-#[derive(Clone, Debug, Display)]
-pub enum WorkerInitError {
-    /// Failed to initialize with mobilecoind
-    Mobilecoind,
-}